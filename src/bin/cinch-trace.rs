@@ -0,0 +1,213 @@
+extern crate getopts;
+extern crate rustc_serialize;
+extern crate cinch;
+
+// Reads captures written by the logger module
+
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::process;
+
+use getopts::Options;
+use rustc_serialize::json;
+
+use cinch::capture;
+use cinch::capture::decode;
+use cinch::capture::pcap;
+use cinch::capture::replay;
+use cinch::modules::registry::Registry;
+use cinch::parser::Source;
+use cinch::util::config::CinchConfig;
+
+
+fn print_usage(program: &str, opts: Options) {
+    let brief = format!("Usage: {} print FILE [options]\n       {} pcap FILE OUTPUT\n       {} replay FILE -c CONFIG",
+                        program,
+                        program,
+                        program);
+    print!("{}", opts.usage(&brief));
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    process::exit(1);
+}
+
+fn open(path: &str) -> capture::Reader<BufReader<File>> {
+
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => fail(&format!("Could not open {}: {}", path, e)),
+    };
+
+    match capture::Reader::new(BufReader::new(file)) {
+        Ok(reader) => reader,
+        Err(e) => fail(&format!("Could not read {}: {}", path, e)),
+    }
+}
+
+fn print(path: &str, filter: &decode::Filter) {
+
+    let mut decoder = decode::Decoder::new();
+
+    for record in open(path) {
+
+        let record = match record {
+            Ok(r) => r,
+            Err(e) => fail(&format!("Capture is corrupted: {}", e)),
+        };
+
+        // The decoder must see every record to follow the device's interfaces and endpoints
+        let lines = decoder.decode(&record);
+
+        if filter.matches(&record) {
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+    }
+}
+
+fn export(path: &str, output: &str) {
+
+    let out = match File::create(output) {
+        Ok(file) => BufWriter::new(file),
+        Err(e) => fail(&format!("Could not create {}: {}", output, e)),
+    };
+
+    match pcap::export(open(path), out) {
+        Ok(count) => println!("Wrote {} packets to {}", count, output),
+        Err(e) => fail(&format!("Could not export {}: {}", path, e)),
+    }
+}
+
+fn replay(path: &str, config_path: &str) {
+
+    let mut line = String::new();
+
+    if let Err(e) = File::open(config_path).and_then(|mut f| f.read_to_string(&mut line)) {
+        fail(&format!("Could not read {}: {}", config_path, e));
+    }
+
+    let config: CinchConfig = match json::decode(&line) {
+        Ok(c) => c,
+        Err(e) => fail(&format!("Invalid configuration {}: {}", config_path, e)),
+    };
+
+    let report = match replay::replay(open(path), Registry::new(), &config) {
+        Ok(r) => r,
+        Err(e) => fail(&format!("Replay failed: {}", e)),
+    };
+
+    for hit in &report.hits {
+
+        let mut actions = vec![];
+
+        if hit.port != 0 {
+            actions.push(format!("sent it to port {}", hit.port));
+        }
+
+        if hit.outputs == 0 {
+            actions.push("dropped it".to_string());
+        } else if hit.rewritten {
+            actions.push(format!("rewrote it ({} requests)", hit.outputs));
+        }
+
+        println!("record {} ({:?} {}): {} {}",
+                 hit.record,
+                 hit.source,
+                 decode::header_type_name(hit.h_type),
+                 hit.module,
+                 actions.join(" and "));
+    }
+
+    for record in &report.rejected {
+        println!("record {}: rejected by the parser", record);
+    }
+
+    for record in &report.ended {
+        println!("record {}: a module ended the session", record);
+    }
+
+    println!("{} records replayed, {} forwarded, {} replies, {} rejected",
+             report.records,
+             report.forwarded,
+             report.replies,
+             report.rejected.len());
+}
+
+fn main() {
+
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optopt("d", "direction", "only show requests sent by this machine", "red|blue");
+    opts.optopt("t", "type", "only show requests of this type (e.g., control, bulk, 101)", "TYPE");
+    opts.optopt("e", "endpoint", "only show data packets for this endpoint (e.g., 0x81)", "EP");
+    opts.optopt("c", "config", "configuration to replay the capture with", "PATH");
+    opts.optflag("h", "help", "print this help menu");
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => fail(&e.to_string()),
+    };
+
+    if matches.opt_present("h") || matches.free.len() < 2 {
+        print_usage(&program, opts);
+        return;
+    }
+
+    let mut filter = decode::Filter::new();
+
+    if let Some(d) = matches.opt_str("d") {
+        filter.source = match &d[..] {
+            "red" => Some(Source::Red),
+            "blue" => Some(Source::Blue),
+            _ => fail(&format!("Invalid direction {}", d)),
+        };
+    }
+
+    if let Some(t) = matches.opt_str("t") {
+        filter.h_type = match decode::header_type_from_name(&t) {
+            Some(v) => Some(v),
+            None => fail(&format!("Invalid type {}", t)),
+        };
+    }
+
+    if let Some(e) = matches.opt_str("e") {
+
+        let parsed = if e.starts_with("0x") {
+            u8::from_str_radix(&e[2..], 16)
+        } else {
+            e.parse::<u8>()
+        };
+
+        filter.ep = match parsed {
+            Ok(v) => Some(v),
+            Err(_) => fail(&format!("Invalid endpoint {}", e)),
+        };
+    }
+
+    match &matches.free[0][..] {
+        "print" => print(&matches.free[1], &filter),
+
+        "pcap" => {
+            match matches.free.get(2) {
+                Some(output) => export(&matches.free[1], output),
+                None => print_usage(&program, opts),
+            }
+        }
+
+        "replay" => {
+            match matches.opt_str("c") {
+                Some(config) => replay(&matches.free[1], &config),
+                None => print_usage(&program, opts),
+            }
+        }
+
+        _ => print_usage(&program, opts),
+    }
+}
@@ -0,0 +1,856 @@
+// Human-readable decoding of captured requests (used by the cinch-trace binary). All names are
+// derived from the constants in parser::usbr and usb, so the decoder and the checks agree on
+// what every value means.
+
+use std::collections::HashMap;
+use byteorder::{ByteOrder, LittleEndian};
+
+use capture::Record;
+use parser;
+use parser::usbr;
+use parser::Source;
+use usb;
+use usb::{bbb, hid, scsi};
+
+// Data dumps longer than this are cut short
+const MAX_DUMP: usize = 256;
+
+const HEADER_TYPES: &'static [(u32, &'static str)] =
+    &[(usbr::HeaderType::Hello as u32, "hello"),
+      (usbr::HeaderType::DeviceConnect as u32, "device connect"),
+      (usbr::HeaderType::DeviceDisconnect as u32, "device disconnect"),
+      (usbr::HeaderType::Reset as u32, "reset"),
+      (usbr::HeaderType::InterfaceInfo as u32, "interface info"),
+      (usbr::HeaderType::EpInfo as u32, "ep info"),
+      (usbr::HeaderType::SetConf as u32, "set conf"),
+      (usbr::HeaderType::GetConf as u32, "get conf"),
+      (usbr::HeaderType::ConfStatus as u32, "conf status"),
+      (usbr::HeaderType::SetAltSetting as u32, "set alt setting"),
+      (usbr::HeaderType::GetAltSetting as u32, "get alt setting"),
+      (usbr::HeaderType::AltSettingStatus as u32, "alt setting status"),
+      (usbr::HeaderType::StartIsoStream as u32, "start iso stream"),
+      (usbr::HeaderType::StopIsoStream as u32, "stop iso stream"),
+      (usbr::HeaderType::IsoStreamStatus as u32, "iso stream status"),
+      (usbr::HeaderType::StartIntReceiving as u32, "start int receiving"),
+      (usbr::HeaderType::StopIntReceiving as u32, "stop int receiving"),
+      (usbr::HeaderType::IntReceivingStatus as u32, "int receiving status"),
+      (usbr::HeaderType::AllocBulkStreams as u32, "alloc bulk streams"),
+      (usbr::HeaderType::FreeBulkStreams as u32, "free bulk streams"),
+      (usbr::HeaderType::BulkStreamsStatus as u32, "bulk streams status"),
+      (usbr::HeaderType::CancelDataPacket as u32, "cancel data packet"),
+      (usbr::HeaderType::FilterReject as u32, "filter reject"),
+      (usbr::HeaderType::FilterFilter as u32, "filter filter"),
+      (usbr::HeaderType::DeviceDisconnectAck as u32, "device disconnect ack"),
+      (usbr::HeaderType::StartBulkReceiving as u32, "start bulk receiving"),
+      (usbr::HeaderType::StopBulkReceiving as u32, "stop bulk receiving"),
+      (usbr::HeaderType::BulkReceivingStatus as u32, "bulk receiving status"),
+      (usbr::HeaderType::ControlPacket as u32, "control packet"),
+      (usbr::HeaderType::BulkPacket as u32, "bulk packet"),
+      (usbr::HeaderType::IsoPacket as u32, "iso packet"),
+      (usbr::HeaderType::IntPacket as u32, "int packet"),
+      (usbr::HeaderType::BufferedBulkPacket as u32, "buffered bulk packet")];
+
+
+pub fn header_type_name(h_type: u32) -> &'static str {
+
+    for &(ty, name) in HEADER_TYPES {
+        if h_type == ty {
+            return name;
+        }
+    }
+
+    "unknown"
+}
+
+// Accepts a header type number or name. Case, spaces, dashes and underscores are ignored, and
+// the "packet" suffix of data packets is optional (e.g., "bulk", "BulkPacket", "ep-info", "101").
+pub fn header_type_from_name(name: &str) -> Option<u32> {
+
+    if let Ok(v) = name.parse::<u32>() {
+        return Some(v);
+    }
+
+    let wanted = normalize(name);
+
+    for &(ty, ty_name) in HEADER_TYPES {
+
+        let ty_name = normalize(ty_name);
+
+        if wanted == ty_name || format!("{}packet", wanted) == ty_name {
+            return Some(ty);
+        }
+    }
+
+    None
+}
+
+fn normalize(name: &str) -> String {
+    name.chars().filter(|c| c.is_alphanumeric()).collect::<String>().to_lowercase()
+}
+
+pub fn status_name(status: u8) -> &'static str {
+
+    match status {
+        x if x == usbr::Result::Success as u8 => "success",
+        x if x == usbr::Result::Cancelled as u8 => "cancelled",
+        x if x == usbr::Result::Inval as u8 => "invalid",
+        x if x == usbr::Result::Ioerror as u8 => "io error",
+        x if x == usbr::Result::Stall as u8 => "stall",
+        x if x == usbr::Result::Timeout as u8 => "timeout",
+        x if x == usbr::Result::Babble as u8 => "babble",
+        _ => "unknown",
+    }
+}
+
+pub fn speed_name(speed: u8) -> &'static str {
+
+    match speed {
+        x if x == usbr::Speed::Slow as u8 => "low",
+        x if x == usbr::Speed::Full as u8 => "full",
+        x if x == usbr::Speed::High as u8 => "high",
+        x if x == usbr::Speed::Super as u8 => "super",
+        _ => "unknown",
+    }
+}
+
+pub fn class_name(class: u8) -> &'static str {
+
+    match class {
+        usb::CLASS_PER_INTERFACE => "per interface",
+        usb::CLASS_AUDIO => "audio",
+        usb::CLASS_COMM => "communications",
+        usb::CLASS_HID => "hid",
+        usb::CLASS_PHYSICAL => "physical",
+        usb::CLASS_STILL_IMAGE => "still image",
+        usb::CLASS_PRINTER => "printer",
+        usb::CLASS_MASS_STORAGE => "mass storage",
+        usb::CLASS_HUB => "hub",
+        usb::CLASS_CDC_DATA => "cdc data",
+        usb::CLASS_CSCID => "smart card",
+        usb::CLASS_CONTENT_SEC => "content security",
+        usb::CLASS_VIDEO => "video",
+        usb::CLASS_HEALTH => "health care",
+        usb::CLASS_AUDIO_VIDEO => "audio/video",
+        usb::CLASS_BILLBOARD => "billboard",
+        usb::CLASS_DIAGNOSTIC => "diagnostic",
+        usb::CLASS_WIRELESS_CONTROLLER => "wireless controller",
+        usb::CLASS_MISC => "miscellaneous",
+        usb::CLASS_APP_SPEC => "application specific",
+        usb::CLASS_VENDOR_SPEC => "vendor specific",
+        _ => "unknown",
+    }
+}
+
+pub fn request_name(request: u8) -> &'static str {
+
+    match request {
+        usb::REQ_GET_STATUS => "get status",
+        usb::REQ_CLEAR_FEATURE => "clear feature",
+        usb::REQ_SET_FEATURE => "set feature",
+        usb::REQ_SET_ADDRESS => "set address",
+        usb::REQ_GET_DESCRIPTOR => "get descriptor",
+        usb::REQ_SET_DESCRIPTOR => "set descriptor",
+        usb::REQ_GET_CONFIGURATION => "get configuration",
+        usb::REQ_SET_CONFIGURATION => "set configuration",
+        usb::REQ_GET_INTERFACE => "get interface",
+        usb::REQ_SET_INTERFACE => "set interface",
+        usb::REQ_SYNCH_FRAME => "synch frame",
+        usb::REQ_SET_SEL => "set sel",
+        usb::REQ_SET_ISOCH_DELAY => "set isoch delay",
+        _ => "unknown",
+    }
+}
+
+pub fn scsi_name(opcode: u8) -> &'static str {
+
+    match opcode {
+        scsi::TEST_UNIT_READY => "test unit ready",
+        scsi::REQUEST_SENSE => "request sense",
+        scsi::FORMAT_UNIT => "format unit",
+        scsi::WRITE_6 => "write(6)",
+        scsi::INQUIRY => "inquiry",
+        scsi::MODE_SELECT_6 => "mode select(6)",
+        scsi::MODE_SENSE_6 => "mode sense(6)",
+        scsi::START_STOP_UNIT => "start stop unit",
+        scsi::PREVENT_ALLOW_REMOVAL => "prevent allow medium removal",
+        scsi::READ_FORMAT_CAPACITIES => "read format capacities",
+        scsi::READ_CAPACITY_10 => "read capacity(10)",
+        scsi::READ_10 => "read(10)",
+        scsi::WRITE_10 => "write(10)",
+        scsi::VERIFY_10 => "verify(10)",
+        scsi::SYNCHRONIZE_CACHE_10 => "synchronize cache(10)",
+        scsi::UNMAP => "unmap",
+        scsi::MODE_SELECT_10 => "mode select(10)",
+        scsi::MODE_SENSE_10 => "mode sense(10)",
+        scsi::READ_16 => "read(16)",
+        scsi::WRITE_16 => "write(16)",
+        scsi::SYNCHRONIZE_CACHE_16 => "synchronize cache(16)",
+        scsi::SERVICE_ACTION_IN_16 => "service action in(16)",
+        scsi::READ_12 => "read(12)",
+        scsi::WRITE_12 => "write(12)",
+        _ => "unknown",
+    }
+}
+
+pub fn descriptor_name(desc_type: u8) -> &'static str {
+
+    match desc_type {
+        usb::DT_DEVICE => "device",
+        usb::DT_CONFIG => "config",
+        usb::DT_STRING => "string",
+        usb::DT_INTERFACE => "interface",
+        usb::DT_ENDPOINT => "endpoint",
+        usb::DT_DEVICE_QUALIFIER => "device qualifier",
+        usb::DT_OTHER_SPEED_CONFIG => "other speed config",
+        usb::DT_INTERFACE_POWER => "interface power",
+        usb::DT_OTG => "otg",
+        usb::DT_DEBUG => "debug",
+        usb::DT_INTERFACE_ASSOCIATION => "interface association",
+        usb::DT_BOS => "bos",
+        usb::DT_DEVICE_CAPABILITY => "device capability",
+        usb::DT_SS_ENDPOINT_COMP => "ss endpoint companion",
+        usb::DT_CS_INTERFACE => "class-specific interface",
+        usb::DT_CS_ENDPOINT => "class-specific endpoint",
+        hid::DT_HID => "hid",
+        hid::DT_HID_REPORT => "hid report",
+        hid::DT_HID_PHYSICAL => "hid physical",
+        _ => "unknown",
+    }
+}
+
+fn request_type_name(request_type: u8) -> String {
+
+    let dir = if request_type & usb::DIR_IN != 0 { "in" } else { "out" };
+
+    let ty = match request_type & usb::TYPE_MASK {
+        usb::TYPE_STANDARD => "standard",
+        usb::TYPE_CLASS => "class",
+        usb::TYPE_VENDOR => "vendor",
+        _ => "reserved",
+    };
+
+    let recipient = match request_type & usb::RECIP_MASK {
+        usb::RECIP_DEVICE => "device",
+        usb::RECIP_INTERFACE => "interface",
+        usb::RECIP_ENDPOINT => "endpoint",
+        usb::RECIP_OTHER => "other",
+        _ => "reserved",
+    };
+
+    format!("{}, {}, {}", dir, ty, recipient)
+}
+
+fn xfer_name(attributes: u8) -> &'static str {
+
+    match attributes & usb::ENDPOINT_XFERTYPE_MASK {
+        usb::ENDPOINT_XFER_CONTROL => "control",
+        usb::ENDPOINT_XFER_ISOC => "iso",
+        usb::ENDPOINT_XFER_BULK => "bulk",
+        _ => "int",
+    }
+}
+
+fn source_name(source: Source) -> &'static str {
+
+    match source {
+        Source::Red => "red -> blue",
+        Source::Blue => "blue -> red",
+    }
+}
+
+// Same mapping as usbredir's EP2I: OUT endpoints first, then IN endpoints
+fn ep_index(ep: u8) -> usize {
+    (((ep & usb::ENDPOINT_DIR_MASK) >> 3) | (ep & usb::ENDPOINT_NUMBER_MASK)) as usize
+}
+
+
+// Endpoint targeted by a data packet (None for everything else)
+pub fn endpoint(record: &Record) -> Option<u8> {
+
+    let th = &record.request.type_header;
+
+    match record.h_type {
+        x if x == usbr::HeaderType::ControlPacket as u32 ||
+             x == usbr::HeaderType::BulkPacket as u32 ||
+             x == usbr::HeaderType::IsoPacket as u32 ||
+             x == usbr::HeaderType::IntPacket as u32 => th.get(0).cloned(),
+
+        x if x == usbr::HeaderType::BufferedBulkPacket as u32 => th.get(8).cloned(),
+        _ => None,
+    }
+}
+
+
+// Selects which records to show. Empty fields match everything.
+#[derive(Default)]
+pub struct Filter {
+    pub source: Option<Source>,
+    pub h_type: Option<u32>,
+    pub ep: Option<u8>,
+}
+
+impl Filter {
+    pub fn new() -> Filter {
+        Filter::default()
+    }
+
+    pub fn matches(&self, record: &Record) -> bool {
+
+        if let Some(source) = self.source {
+            if source != record.source {
+                return false;
+            }
+        }
+
+        if let Some(h_type) = self.h_type {
+            if h_type != record.h_type {
+                return false;
+            }
+        }
+
+        if let Some(ep) = self.ep {
+            if endpoint(record) != Some(ep) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+
+// Decodes the records of a capture in order. The decoder remembers the interfaces and
+// endpoints announced by the red machine so that it knows which endpoints carry HID reports
+// or bulk-only storage commands.
+pub struct Decoder {
+    from_red: parser::Parser, // set up like the parsers in main (i.e., named after the receiver)
+    from_blue: parser::Parser,
+    interfaces: HashMap<u8, (u8, u8, u8)>, // interface -> (class, subclass, protocol)
+    ep_interface: HashMap<usize, u8>, // endpoint index -> interface
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            from_red: parser::Parser::new(Source::Blue),
+            from_blue: parser::Parser::new(Source::Red),
+            interfaces: HashMap::new(),
+            ep_interface: HashMap::new(),
+        }
+    }
+
+    // Returns the lines describing the record. The first line is a summary; the rest are
+    // indented details.
+    pub fn decode(&mut self, record: &Record) -> Vec<String> {
+
+        let req = &record.request;
+        let th = &req.type_header[..];
+
+        let mut lines = vec![format!("{}.{:06} [{}] {} {} (id {}, {} bytes)",
+                                     record.timestamp.as_secs(),
+                                     record.timestamp.subsec_nanos() / 1000,
+                                     record.session,
+                                     source_name(record.source),
+                                     header_type_name(record.h_type),
+                                     req.get_id(),
+                                     req.get_total_len())];
+
+        let parser = match record.source {
+            Source::Red => &self.from_red,
+            Source::Blue => &self.from_blue,
+        };
+
+        // Same structural checks as when the request was pulled off the wire
+        match parser.get_type_header_len(record.h_type, false) {
+            Ok(len) if len != th.len() => {
+                lines.push(format!("    malformed: type header is {} bytes, expected {}", th.len(), len));
+                return lines;
+            }
+
+            Err(e) => {
+                lines.push(format!("    malformed: {:?}", e));
+                return lines;
+            }
+
+            Ok(_) => {}
+        }
+
+        match record.h_type {
+
+            x if x == usbr::HeaderType::Hello as u32 => {
+                let version: String = th.iter().take_while(|&&c| c != 0).map(|&c| c as char).collect();
+                lines.push(format!("    version: {}", version));
+            }
+
+            x if x == usbr::HeaderType::DeviceConnect as u32 => {
+                lines.push(format!("    speed: {}, class: {} ({}/{}), vendor: 0x{:04x}, product: 0x{:04x}",
+                                   speed_name(th[0]),
+                                   class_name(th[1]),
+                                   th[2],
+                                   th[3],
+                                   LittleEndian::read_u16(&th[4..6]),
+                                   LittleEndian::read_u16(&th[6..8])));
+            }
+
+            x if x == usbr::HeaderType::FilterFilter as u32 => {
+                let rules: String = req.data.iter().take_while(|&&c| c != 0).map(|&c| c as char).collect();
+                lines.push(format!("    rules: {}", rules));
+            }
+
+            x if x == usbr::HeaderType::InterfaceInfo as u32 => self.decode_interface_info(th, &mut lines),
+
+            x if x == usbr::HeaderType::EpInfo as u32 => self.decode_ep_info(th, &mut lines),
+
+            x if x == usbr::HeaderType::ControlPacket as u32 => self.decode_control(record, &mut lines),
+
+            x if x == usbr::HeaderType::BulkPacket as u32 => {
+
+                let length = LittleEndian::read_u16(&th[2..4]) as u32 |
+                             (LittleEndian::read_u16(&th[8..10]) as u32) << 16;
+
+                lines.push(format!("    ep: 0x{:02x}, status: {}, length: {}",
+                                   th[0],
+                                   status_name(th[1]),
+                                   length));
+
+                if self.interface_of(th[0]).map_or(false, |(class, _, proto)| {
+                    class == usb::CLASS_MASS_STORAGE && proto == bbb::PR_BBB
+                }) {
+                    decode_bbb(&req.data, &mut lines);
+                } else {
+                    dump(&req.data, &mut lines);
+                }
+            }
+
+            x if x == usbr::HeaderType::IntPacket as u32 => {
+
+                lines.push(format!("    ep: 0x{:02x}, status: {}, length: {}",
+                                   th[0],
+                                   status_name(th[1]),
+                                   LittleEndian::read_u16(&th[2..4])));
+
+                match self.interface_of(th[0]) {
+                    Some((usb::CLASS_HID, subclass, proto)) => decode_hid_report(subclass, proto, &req.data, &mut lines),
+                    _ => dump(&req.data, &mut lines),
+                }
+            }
+
+            x if x == usbr::HeaderType::IsoPacket as u32 => {
+                lines.push(format!("    ep: 0x{:02x}, status: {}, length: {}",
+                                   th[0],
+                                   status_name(th[1]),
+                                   LittleEndian::read_u16(&th[2..4])));
+                dump(&req.data, &mut lines);
+            }
+
+            _ => {
+                if !th.is_empty() {
+                    lines.push(format!("    type header: {}", hex(th)));
+                }
+
+                dump(&req.data, &mut lines);
+            }
+        }
+
+        lines
+    }
+
+    fn interface_of(&self, ep: u8) -> Option<(u8, u8, u8)> {
+        self.ep_interface.get(&ep_index(ep)).and_then(|iface| self.interfaces.get(iface)).cloned()
+    }
+
+    fn decode_interface_info(&mut self, th: &[u8], lines: &mut Vec<String>) {
+
+        // count, then interface, class, subclass and protocol arrays (32 entries each)
+        let count = ::std::cmp::min(LittleEndian::read_u32(&th[0..4]) as usize, 32);
+
+        self.interfaces.clear();
+
+        for i in 0..count {
+
+            let (iface, class, subclass, proto) = (th[4 + i], th[36 + i], th[68 + i], th[100 + i]);
+
+            self.interfaces.insert(iface, (class, subclass, proto));
+            lines.push(format!("    interface {}: {} ({}/{})", iface, class_name(class), subclass, proto));
+        }
+    }
+
+    fn decode_ep_info(&mut self, th: &[u8], lines: &mut Vec<String>) {
+
+        // type, interval and interface arrays (32 entries each, indexed like ep_index)
+        self.ep_interface.clear();
+
+        for i in 0..32 {
+
+            let ep_type = th[i];
+
+            if ep_type == usbr::TransferType::Invalid as u8 {
+                continue;
+            }
+
+            let ep = if i < 16 { i as u8 } else { (i - 16) as u8 | usb::DIR_IN };
+
+            self.ep_interface.insert(i, th[64 + i]);
+            lines.push(format!("    ep 0x{:02x}: {}, interface {}, interval {}",
+                               ep,
+                               xfer_name(match ep_type {
+                                   x if x == usbr::TransferType::Control as u8 => usb::ENDPOINT_XFER_CONTROL,
+                                   x if x == usbr::TransferType::Iso as u8 => usb::ENDPOINT_XFER_ISOC,
+                                   x if x == usbr::TransferType::Bulk as u8 => usb::ENDPOINT_XFER_BULK,
+                                   _ => usb::ENDPOINT_XFER_INT,
+                               }),
+                               th[64 + i],
+                               th[32 + i]));
+        }
+    }
+
+    fn decode_control(&self, record: &Record, lines: &mut Vec<String>) {
+
+        let th = &record.request.type_header;
+        let data = &record.request.data;
+
+        let setup = usb::ControlRequest {
+            request_type: th[2],
+            request: th[1],
+            value: LittleEndian::read_u16(&th[4..6]),
+            index: LittleEndian::read_u16(&th[6..8]),
+            length: LittleEndian::read_u16(&th[8..10]),
+        };
+
+        let (request_type, request, value, index, length) =
+            (setup.request_type, setup.request, setup.value, setup.index, setup.length);
+
+        let name = match request_type & usb::TYPE_MASK {
+            usb::TYPE_STANDARD => request_name(request),
+            usb::TYPE_CLASS => self.class_request_name(request_type, request, index),
+            usb::TYPE_VENDOR => "vendor request",
+            _ => "reserved request",
+        };
+
+        lines.push(format!("    ep: 0x{:02x}, status: {}", th[0], status_name(th[3])));
+        lines.push(format!("    {} ({}) value: 0x{:04x}, index: 0x{:04x}, length: {}",
+                           name,
+                           request_type_name(request_type),
+                           value,
+                           index,
+                           length));
+
+        // Descriptors come back in the completion from the red machine
+        if record.source == Source::Red && request_type & usb::TYPE_MASK == usb::TYPE_STANDARD &&
+           request == usb::REQ_GET_DESCRIPTOR {
+            decode_descriptors(data, value, lines);
+        } else {
+            dump(data, lines);
+        }
+    }
+
+    fn class_request_name(&self, request_type: u8, request: u8, index: u16) -> &'static str {
+
+        if request_type & usb::RECIP_MASK != usb::RECIP_INTERFACE {
+            return "class request";
+        }
+
+        match self.interfaces.get(&(index as u8)) {
+
+            Some(&(usb::CLASS_HID, _, _)) => {
+                match request {
+                    hid::GET_REPORT => "get report",
+                    hid::GET_IDLE => "get idle",
+                    hid::GET_PROT => "get protocol",
+                    hid::SET_REPORT => "set report",
+                    hid::SET_IDLE => "set idle",
+                    hid::SET_PROT => "set protocol",
+                    _ => "hid request",
+                }
+            }
+
+            Some(&(usb::CLASS_MASS_STORAGE, _, bbb::PR_BBB)) => {
+                match request {
+                    bbb::RESET => "bulk-only reset",
+                    bbb::MAX_LUN => "get max lun",
+                    _ => "bulk-only request",
+                }
+            }
+
+            _ => "class request",
+        }
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder::new()
+    }
+}
+
+
+fn decode_descriptors(data: &[u8], value: u16, lines: &mut Vec<String>) {
+
+    let mut off = 0;
+
+    while off + usb::HEADER_SIZE <= data.len() {
+
+        let (length, desc_type) = (data[off] as usize, data[off + 1]);
+
+        if length < usb::HEADER_SIZE {
+            lines.push(format!("    invalid descriptor length {}", length));
+            break;
+        }
+
+        let end = ::std::cmp::min(off + length, data.len());
+        let body = &data[off + usb::HEADER_SIZE..end];
+
+        match desc_type {
+
+            usb::DT_DEVICE if body.len() >= usb::DEVICE_DESC_SIZE => {
+
+                let desc = usb::DeviceDescriptor {
+                    bcd_usb: LittleEndian::read_u16(&body[0..2]),
+                    device_class: body[2],
+                    device_subclass: body[3],
+                    device_protocol: body[4],
+                    max_packet_size0: body[5],
+                    id_vendor: LittleEndian::read_u16(&body[6..8]),
+                    id_product: LittleEndian::read_u16(&body[8..10]),
+                    bcd_device: LittleEndian::read_u16(&body[10..12]),
+                    manufacturer: body[12],
+                    product: body[13],
+                    serial_number: body[14],
+                    num_configurations: body[15],
+                };
+
+                let (bcd_usb, class, vendor, product, configs) =
+                    (desc.bcd_usb, desc.device_class, desc.id_vendor, desc.id_product, desc.num_configurations);
+
+                lines.push(format!("    device: usb {:x}.{:02x}, class: {}, vendor: 0x{:04x}, product: 0x{:04x}, \
+                                    configurations: {}",
+                                   bcd_usb >> 8,
+                                   bcd_usb & 0xff,
+                                   class_name(class),
+                                   vendor,
+                                   product,
+                                   configs));
+            }
+
+            usb::DT_CONFIG | usb::DT_OTHER_SPEED_CONFIG if body.len() >= usb::CONFIG_DESC_SIZE => {
+
+                let desc = usb::ConfigDescriptor {
+                    total_length: LittleEndian::read_u16(&body[0..2]),
+                    num_interfaces: body[2],
+                    configuration_value: body[3],
+                    configuration: body[4],
+                    attributes: body[5],
+                    max_power: body[6],
+                };
+
+                let (total, ifaces, value, attributes, power) =
+                    (desc.total_length, desc.num_interfaces, desc.configuration_value, desc.attributes, desc.max_power);
+
+                lines.push(format!("    {}: value: {}, interfaces: {}, total length: {}, attributes: 0x{:02x}, \
+                                    max power: {} mA",
+                                   descriptor_name(desc_type),
+                                   value,
+                                   ifaces,
+                                   total,
+                                   attributes,
+                                   power as u32 * 2));
+            }
+
+            usb::DT_INTERFACE if body.len() >= usb::INTERFACE_DESC_SIZE => {
+
+                let desc = usb::InterfaceDescriptor {
+                    interface_number: body[0],
+                    alternate_setting: body[1],
+                    num_endpoints: body[2],
+                    interface_class: body[3],
+                    interface_subclass: body[4],
+                    interface_protocol: body[5],
+                    interface: body[6],
+                };
+
+                let (number, alt, eps, class, subclass, proto) = (desc.interface_number,
+                                                                  desc.alternate_setting,
+                                                                  desc.num_endpoints,
+                                                                  desc.interface_class,
+                                                                  desc.interface_subclass,
+                                                                  desc.interface_protocol);
+
+                lines.push(format!("      interface {} (alt {}): {} ({}/{}), endpoints: {}",
+                                   number,
+                                   alt,
+                                   class_name(class),
+                                   subclass,
+                                   proto,
+                                   eps));
+            }
+
+            usb::DT_ENDPOINT if body.len() >= usb::ENDPOINT_DESC_SIZE => {
+
+                let desc = usb::EndpointDescriptor {
+                    endpoint_address: body[0],
+                    attributes: body[1],
+                    max_packet_size: LittleEndian::read_u16(&body[2..4]),
+                    interval: body[4],
+                };
+
+                let (address, attributes, max_packet, interval) =
+                    (desc.endpoint_address, desc.attributes, desc.max_packet_size, desc.interval);
+
+                lines.push(format!("        endpoint 0x{:02x}: {}, max packet: {}, interval: {}",
+                                   address,
+                                   xfer_name(attributes),
+                                   max_packet,
+                                   interval));
+            }
+
+            usb::DT_STRING if value & 0xff == 0 => {
+                let langs: Vec<String> = body.chunks(2)
+                    .filter(|c| c.len() == 2)
+                    .map(|c| format!("0x{:04x}", LittleEndian::read_u16(c)))
+                    .collect();
+
+                lines.push(format!("    languages: {}", langs.join(", ")));
+            }
+
+            usb::DT_STRING => {
+                let chars: Vec<u16> = body.chunks(2)
+                    .filter(|c| c.len() == 2)
+                    .map(|c| LittleEndian::read_u16(c))
+                    .collect();
+
+                lines.push(format!("    string {}: {:?}", value & 0xff, String::from_utf16_lossy(&chars)));
+            }
+
+            hid::DT_HID if body.len() + usb::HEADER_SIZE >= hid::DESC_MIN_SIZE => {
+
+                lines.push(format!("      hid {:x}.{:02x}, country: {}, descriptors: {}",
+                                   body[1],
+                                   body[0],
+                                   body[2],
+                                   body[3]));
+
+                for class_desc in body[4..].chunks(hid::CLASS_DESC_SIZE).filter(|c| c.len() == hid::CLASS_DESC_SIZE) {
+                    lines.push(format!("        {}: {} bytes",
+                                       descriptor_name(class_desc[0]),
+                                       LittleEndian::read_u16(&class_desc[1..3])));
+                }
+            }
+
+            _ => {
+                lines.push(format!("    {} descriptor (0x{:02x}): {}",
+                                   descriptor_name(desc_type),
+                                   desc_type,
+                                   hex(body)));
+            }
+        }
+
+        off += length;
+    }
+
+    if off < data.len() {
+        lines.push(format!("    trailing bytes: {}", hex(&data[off..])));
+    }
+}
+
+
+fn decode_bbb(data: &[u8], lines: &mut Vec<String>) {
+
+    let size = data.len();
+
+    if size >= bbb::HEADER_SIZE {
+
+        let header = bbb::CommandHeader {
+            signature: LittleEndian::read_u32(&data[0..4]),
+            tag: LittleEndian::read_u32(&data[4..8]),
+        };
+
+        if header.signature == bbb::CBW_SIGN && size == bbb::HEADER_SIZE + bbb::CBW_SIZE {
+
+            let body = &data[bbb::HEADER_SIZE..];
+            let mut cbw = bbb::CommandBlockWrapper {
+                transfer_length: LittleEndian::read_u32(&body[0..4]),
+                flags: body[4],
+                cb_lun: body[5],
+                cb_length: body[6],
+                bcbw: [0; 16],
+            };
+
+            cbw.bcbw.copy_from_slice(&body[7..23]);
+
+            let cb_len = ::std::cmp::min(cbw.cb_length as usize, cbw.bcbw.len());
+
+            lines.push(format!("    cbw tag: 0x{:08x}, transfer length: {}, direction: {}, lun: {}, \
+                                command: {} ({})",
+                               header.tag,
+                               cbw.transfer_length,
+                               if cbw.flags & usb::DIR_IN != 0 { "in" } else { "out" },
+                               cbw.cb_lun,
+                               scsi_name(cbw.bcbw[0]),
+                               hex(&cbw.bcbw[..cb_len])));
+            return;
+        }
+
+        if header.signature == bbb::CSW_SIGN && size == bbb::HEADER_SIZE + bbb::CSW_SIZE {
+
+            let csw = bbb::CommandStatusWrapper {
+                data_residue: LittleEndian::read_u32(&data[8..12]),
+                status: data[12],
+            };
+
+            lines.push(format!("    csw tag: 0x{:08x}, residue: {}, status: {}",
+                               header.tag,
+                               csw.data_residue,
+                               match csw.status {
+                                   bbb::STAT_OK => "ok",
+                                   bbb::STAT_FAIL => "failed",
+                                   bbb::STAT_PHASE => "phase error",
+                                   _ => "invalid",
+                               }));
+            return;
+        }
+    }
+
+    dump(data, lines);
+}
+
+
+fn decode_hid_report(subclass: u8, proto: u8, data: &[u8], lines: &mut Vec<String>) {
+
+    if data.is_empty() {
+        return;
+    }
+
+    if subclass == hid::SUBCLASS_BOOT && proto == hid::PROTO_KEYBOARD && data.len() >= 8 {
+
+        let keys: Vec<String> = data[2..8].iter().filter(|&&k| k != 0).map(|k| format!("0x{:02x}", k)).collect();
+        lines.push(format!("    keyboard modifiers: 0x{:02x}, keys: [{}]", data[0], keys.join(", ")));
+
+    } else if subclass == hid::SUBCLASS_BOOT && proto == hid::PROTO_MOUSE && data.len() >= 3 {
+
+        lines.push(format!("    mouse buttons: 0x{:02x}, x: {}, y: {}",
+                           data[0],
+                           data[1] as i8,
+                           data[2] as i8));
+
+    } else {
+        lines.push(format!("    hid report: {}", hex(data)));
+    }
+}
+
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(" ")
+}
+
+fn dump(data: &[u8], lines: &mut Vec<String>) {
+
+    let shown = ::std::cmp::min(data.len(), MAX_DUMP);
+
+    for (i, chunk) in data[..shown].chunks(16).enumerate() {
+        lines.push(format!("    {:04x}: {}", i * 16, hex(chunk)));
+    }
+
+    if shown < data.len() {
+        lines.push(format!("    ... {} more bytes", data.len() - shown));
+    }
+}
@@ -0,0 +1,212 @@
+// Capture format written by modules::logger::Logger.
+//
+// A capture starts with an 8-byte magic ("CINCHCAP") and a little-endian u32 version. It is
+// followed by records, each of which is:
+//
+//   u32  length of the rest of the record
+//   u64  timestamp (seconds since the epoch)
+//   u32  timestamp (nanoseconds)
+//   u8   source (0: red machine, 1: blue machine)
+//   u32  session id
+//   u32  usbr header type
+//   u32  length of the type header
+//   [u8; 16] usbr header
+//   type header
+//   data (the rest of the record)
+//
+// All integers are little-endian.
+
+pub mod decode;
+pub mod pcap;
+pub mod replay;
+
+use std::io;
+use std::io::{Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use parser;
+use parser::usbr;
+use parser::{Request, Source};
+
+pub const MAGIC: &'static [u8; 8] = b"CINCHCAP";
+pub const VERSION: u32 = 1;
+
+// Everything in a record after the length prefix, except the type header and data
+const RECORD_FIXED_SIZE: usize = 8 + 4 + 1 + 4 + 4 + 4 + usbr::REDIR_HEADER_SIZE;
+
+pub struct Record {
+    pub timestamp: Duration, // since the epoch
+    pub source: Source, // machine that sent the request
+    pub session: u32,
+    pub h_type: u32,
+    pub request: Request,
+}
+
+pub struct Writer<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> Writer<W> {
+    // Writes the capture header
+    pub fn new(mut inner: W) -> io::Result<Writer<W>> {
+
+        inner.write_all(MAGIC)?;
+        inner.write_u32::<LittleEndian>(VERSION)?;
+
+        Ok(Writer { inner: inner })
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        self.write_parts(record.timestamp, record.source, record.session, &record.request)
+    }
+
+    // Records a request as seen right now
+    pub fn write_request(&mut self, source: Source, session: u32, req: &Request) -> io::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.write_parts(now, source, session, req)
+    }
+
+    fn write_parts(&mut self,
+                   timestamp: Duration,
+                   source: Source,
+                   session: u32,
+                   req: &Request)
+                   -> io::Result<()> {
+
+        let len = RECORD_FIXED_SIZE + req.type_header.len() + req.data.len();
+
+        self.inner.write_u32::<LittleEndian>(len as u32)?;
+        self.inner.write_u64::<LittleEndian>(timestamp.as_secs())?;
+        self.inner.write_u32::<LittleEndian>(timestamp.subsec_nanos())?;
+        self.inner.write_u8(match source {
+                Source::Red => 0,
+                Source::Blue => 1,
+            })?;
+        self.inner.write_u32::<LittleEndian>(session)?;
+        self.inner.write_u32::<LittleEndian>(req.get_type())?;
+        self.inner.write_u32::<LittleEndian>(req.type_header.len() as u32)?;
+        self.inner.write_all(&req.header)?;
+        self.inner.write_all(&req.type_header)?;
+        self.inner.write_all(&req.data)?;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+// Iterates over the records of a capture. Iteration stops at the end of the capture or at the
+// first error (which is returned).
+pub struct Reader<R: Read> {
+    inner: R,
+    done: bool,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl<R: Read> Reader<R> {
+    // Reads and checks the capture header
+    pub fn new(mut inner: R) -> io::Result<Reader<R>> {
+
+        let mut magic = [0; 8];
+        inner.read_exact(&mut magic)?;
+
+        if &magic != MAGIC {
+            return Err(invalid("not a cinch capture"));
+        }
+
+        if inner.read_u32::<LittleEndian>()? != VERSION {
+            return Err(invalid("unsupported capture version"));
+        }
+
+        Ok(Reader {
+            inner: inner,
+            done: false,
+        })
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+
+        let len = match self.inner.read_u32::<LittleEndian>() {
+            Ok(v) => v as usize,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        if len < RECORD_FIXED_SIZE {
+            return Err(invalid("record is too short"));
+        }
+
+        if len > RECORD_FIXED_SIZE + parser::MAX_REQUEST_SIZE {
+            return Err(invalid("record is too long"));
+        }
+
+        let secs = self.inner.read_u64::<LittleEndian>()?;
+        let nanos = self.inner.read_u32::<LittleEndian>()?;
+
+        let source = match self.inner.read_u8()? {
+            0 => Source::Red,
+            1 => Source::Blue,
+            _ => return Err(invalid("invalid record source")),
+        };
+
+        let session = self.inner.read_u32::<LittleEndian>()?;
+        let h_type = self.inner.read_u32::<LittleEndian>()?;
+        let type_len = self.inner.read_u32::<LittleEndian>()? as usize;
+
+        if type_len > len - RECORD_FIXED_SIZE {
+            return Err(invalid("type header is longer than the record"));
+        }
+
+        let mut header = [0; usbr::REDIR_HEADER_SIZE];
+        self.inner.read_exact(&mut header)?;
+
+        let mut type_header = vec![0; type_len];
+        self.inner.read_exact(&mut type_header)?;
+
+        let mut data = vec![0; len - RECORD_FIXED_SIZE - type_len];
+        self.inner.read_exact(&mut data)?;
+
+        Ok(Some(Record {
+            timestamp: Duration::new(secs, nanos),
+            source: source,
+            session: session,
+            h_type: h_type,
+            request: Request {
+                header: header,
+                type_header: type_header,
+                data: data,
+                reply: false,
+            },
+        }))
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+
+        if self.done {
+            return None;
+        }
+
+        match self.read_record() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
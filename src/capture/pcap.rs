@@ -0,0 +1,145 @@
+// Export of captures to pcap with the Linux usbmon link type, so that captures can be opened
+// with Wireshark. Requests from blue become URB submissions and requests from red become URB
+// completions. Only data packets have a usbmon equivalent; everything else is skipped.
+
+use std::io;
+use std::io::{Read, Write};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use capture::{Reader, Record};
+use parser::usbr;
+use parser::Source;
+
+pub const LINKTYPE_USB_LINUX: u32 = 189;
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const SNAPLEN: u32 = 0x40000;
+const USBMON_HEADER_SIZE: usize = 48;
+
+// usbmon transfer types
+const XFER_ISO: u8 = 0;
+const XFER_INT: u8 = 1;
+const XFER_CONTROL: u8 = 2;
+const XFER_BULK: u8 = 3;
+
+// usbredir hides the real bus and address, so every capture is a single device
+const BUS: u16 = 1;
+const DEVICE: u8 = 1;
+
+const EINPROGRESS: i32 = -115;
+
+// Maps a usbr status to the errno usbmon would report
+fn urb_status(status: u8) -> i32 {
+    match status {
+        x if x == usbr::Result::Success as u8 => 0,
+        x if x == usbr::Result::Cancelled as u8 => -2, // ENOENT
+        x if x == usbr::Result::Inval as u8 => -22, // EINVAL
+        x if x == usbr::Result::Stall as u8 => -32, // EPIPE
+        x if x == usbr::Result::Timeout as u8 => -110, // ETIMEDOUT
+        x if x == usbr::Result::Babble as u8 => -75, // EOVERFLOW
+        _ => -71, // EPROTO
+    }
+}
+
+pub fn write_header<W: Write>(out: &mut W) -> io::Result<()> {
+
+    out.write_u32::<LittleEndian>(PCAP_MAGIC)?;
+    out.write_u16::<LittleEndian>(2)?; // version major
+    out.write_u16::<LittleEndian>(4)?; // version minor
+    out.write_i32::<LittleEndian>(0)?; // timezone
+    out.write_u32::<LittleEndian>(0)?; // timestamp accuracy
+    out.write_u32::<LittleEndian>(SNAPLEN)?;
+    out.write_u32::<LittleEndian>(LINKTYPE_USB_LINUX)?;
+
+    Ok(())
+}
+
+// Writes the record as a pcap packet. Returns false if the record has no usbmon equivalent.
+pub fn write_record<W: Write>(out: &mut W, record: &Record) -> io::Result<bool> {
+
+    let th = &record.request.type_header;
+    let data = &record.request.data;
+    let submit = record.source == Source::Blue;
+
+    let mut setup: Option<[u8; 8]> = None;
+
+    // (transfer type, endpoint, status, urb length)
+    let (xfer, ep, status, length) = match record.h_type {
+
+        x if x == usbr::HeaderType::ControlPacket as u32 && th.len() >= 10 => {
+
+            if submit {
+                // bmRequestType, bRequest, wValue, wIndex, wLength
+                setup = Some([th[2], th[1], th[4], th[5], th[6], th[7], th[8], th[9]]);
+            }
+
+            (XFER_CONTROL, th[0] | (th[2] & 0x80), th[3], LittleEndian::read_u16(&th[8..10]) as u32)
+        }
+
+        x if x == usbr::HeaderType::BulkPacket as u32 && th.len() >= 8 => {
+
+            let mut length = LittleEndian::read_u16(&th[2..4]) as u32;
+
+            if th.len() >= 10 {
+                length |= (LittleEndian::read_u16(&th[8..10]) as u32) << 16;
+            }
+
+            (XFER_BULK, th[0], th[1], length)
+        }
+
+        x if x == usbr::HeaderType::IntPacket as u32 && th.len() >= 4 => {
+            (XFER_INT, th[0], th[1], LittleEndian::read_u16(&th[2..4]) as u32)
+        }
+
+        x if x == usbr::HeaderType::IsoPacket as u32 && th.len() >= 4 => {
+            (XFER_ISO, th[0], th[1], LittleEndian::read_u16(&th[2..4]) as u32)
+        }
+
+        _ => return Ok(false),
+    };
+
+    let captured = ::std::cmp::min(data.len(), SNAPLEN as usize - USBMON_HEADER_SIZE);
+
+    // pcap packet header
+    out.write_u32::<LittleEndian>(record.timestamp.as_secs() as u32)?;
+    out.write_u32::<LittleEndian>(record.timestamp.subsec_nanos() / 1000)?;
+    out.write_u32::<LittleEndian>((USBMON_HEADER_SIZE + captured) as u32)?;
+    out.write_u32::<LittleEndian>((USBMON_HEADER_SIZE + data.len()) as u32)?;
+
+    // usbmon header
+    out.write_u64::<LittleEndian>(record.request.get_id())?;
+    out.write_u8(if submit { b'S' } else { b'C' })?;
+    out.write_u8(xfer)?;
+    out.write_u8(ep)?;
+    out.write_u8(DEVICE)?;
+    out.write_u16::<LittleEndian>(BUS)?;
+    out.write_u8(if setup.is_some() { 0 } else { b'-' })?;
+    out.write_u8(if data.is_empty() { b'<' } else { 0 })?;
+    out.write_i64::<LittleEndian>(record.timestamp.as_secs() as i64)?;
+    out.write_i32::<LittleEndian>((record.timestamp.subsec_nanos() / 1000) as i32)?;
+    out.write_i32::<LittleEndian>(if submit { EINPROGRESS } else { urb_status(status) })?;
+    out.write_u32::<LittleEndian>(length)?;
+    out.write_u32::<LittleEndian>(captured as u32)?;
+    out.write_all(&setup.unwrap_or([0; 8]))?;
+
+    out.write_all(&data[..captured])?;
+
+    Ok(true)
+}
+
+// Converts a whole capture. Returns the number of packets written.
+pub fn export<R: Read, W: Write>(reader: Reader<R>, mut out: W) -> io::Result<usize> {
+
+    let mut count = 0;
+
+    write_header(&mut out)?;
+
+    for record in reader {
+        if write_record(&mut out, &record?)? {
+            count += 1;
+        }
+    }
+
+    out.flush()?;
+    Ok(count)
+}
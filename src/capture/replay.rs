@@ -0,0 +1,197 @@
+// Offline replay of captures through a module pipeline. Every captured request goes through
+// the same parser and module calls as in the proxy (Parser::pull_next_request, then
+// Parser::process_request with the session's modules), but nothing is sent anywhere. Each
+// module is wrapped in a modules::probe::Probe, so the report says which requests every module
+// diverted, dropped or rewrote.
+
+use std::io;
+use std::io::{BufReader, BufWriter, Cursor, Read};
+use std::sync::Arc;
+use std::sync::mpsc;
+
+use capture::{Reader, Record};
+use modules::Modules;
+use modules::probe::{Hit, Probe, Recorder};
+use modules::registry::Registry;
+use parser;
+use parser::usbr;
+use parser::{HasHandlers, Parser, ParserState, Source};
+use util::config::CinchConfig;
+
+pub struct Report {
+    pub records: usize, // records replayed
+    pub rejected: Vec<usize>, // records the parser refused (the proxy would have discarded them)
+    pub ended: Vec<usize>, // records after which a module ended the session
+    pub forwarded: usize, // requests sent on to the other machine
+    pub replies: usize, // requests sent back to the machine they came from
+    pub hits: Vec<Hit>,
+}
+
+// One device session of the capture, set up like handle_blue_machine in main
+struct Session {
+    id: u32,
+    blue_parser: Parser, // parses requests from blue
+    red_parser: Parser, // parses requests from red
+    blue_handler: Modules,
+    red_handler: Modules,
+    blue_tx: mpsc::Sender<ParserState>,
+    blue_rx: mpsc::Receiver<ParserState>,
+    red_tx: mpsc::Sender<ParserState>,
+    red_rx: mpsc::Receiver<ParserState>,
+    ended: bool,
+}
+
+impl Session {
+    fn new(id: u32, registry: &Registry, config: &CinchConfig) -> Result<Session, String> {
+
+        let mut red_parser = Parser::new(Source::Blue);
+        let mut blue_parser = Parser::new(Source::Red);
+
+        let caps = parser::gen_caps();
+
+        red_parser.init("parser for red", &caps);
+        blue_parser.init("parser for blue", &caps);
+
+        let (red_tx, red_rx) = mpsc::channel();
+        let (blue_tx, blue_rx) = mpsc::channel();
+
+        let (blue_handler, red_handler) = registry.build(config)?;
+
+        Ok(Session {
+            id: id,
+            blue_parser: blue_parser,
+            red_parser: red_parser,
+            blue_handler: blue_handler,
+            red_handler: red_handler,
+            blue_tx: blue_tx,
+            blue_rx: blue_rx,
+            red_tx: red_tx,
+            red_rx: red_rx,
+            ended: false,
+        })
+    }
+
+    // Returns the outputs of the modules, or None if the parser rejected the request
+    fn process(&mut self, record: &Record) -> Option<Vec<parser::Request>> {
+
+        let (parser, handlers, tx, rx) = match record.source {
+            Source::Blue => (&mut self.blue_parser, &self.blue_handler, &self.blue_tx, &self.red_rx),
+            Source::Red => (&mut self.red_parser, &self.red_handler, &self.red_tx, &self.blue_rx),
+        };
+
+        while let Ok(state) = rx.try_recv() {
+            parser.process_state_change(state);
+        }
+
+        let mut input = BufReader::new(Cursor::new(wire_bytes(parser, &record.request)));
+
+        let request = match parser.pull_next_request(&mut input) {
+            Ok(v) => v,
+            Err(_) => return None,
+        };
+
+        let outputs = match parser.process_request(handlers, request, tx) {
+            Ok(v) => v,
+            Err(_) => return None,
+        };
+
+        // Keeps the parser's hello handling in step with the proxy
+        let forwarded = outputs.iter().filter(|r| !r.reply).cloned().collect();
+        let _ = parser.push_outputs(&mut BufWriter::new(io::sink()), forwarded);
+
+        if handlers.session_ended() {
+            self.ended = true;
+        }
+
+        Some(outputs)
+    }
+}
+
+// The request as it appeared on the wire (the first request of a session has a 32-bit id)
+fn wire_bytes(parser: &Parser, req: &parser::Request) -> Vec<u8> {
+
+    let header_len = if parser.state < ParserState::HelloR {
+        usbr::REDIR_HEADER_SIZE - 4
+    } else {
+        usbr::REDIR_HEADER_SIZE
+    };
+
+    let mut bytes = req.header[..header_len].to_vec();
+    bytes.extend_from_slice(&req.type_header);
+    bytes.extend_from_slice(&req.data);
+    bytes
+}
+
+// The logger is left out so that replaying a capture does not write a new one
+fn replay_config(config: &CinchConfig) -> CinchConfig {
+
+    let mut config = config.clone();
+
+    config.log = false;
+
+    if let Some(ref mut pipeline) = config.pipeline {
+        pipeline.retain(|m| m.name != "logger");
+    }
+
+    config
+}
+
+pub fn replay<R: Read>(reader: Reader<R>,
+                       mut registry: Registry,
+                       config: &CinchConfig)
+                       -> Result<Report, String> {
+
+    let config = replay_config(config);
+    let recorder = Arc::new(Recorder::new());
+    let probe_recorder = recorder.clone();
+
+    registry.wrap(move |name, module| Arc::new(Probe::new(name, module, probe_recorder.clone())));
+
+    let mut report = Report {
+        records: 0,
+        rejected: vec![],
+        ended: vec![],
+        forwarded: 0,
+        replies: 0,
+        hits: vec![],
+    };
+
+    let mut session: Option<Session> = None;
+
+    for (index, record) in reader.enumerate() {
+
+        let record = record.map_err(|e| format!("could not read record {}: {}", index, e))?;
+
+        if session.as_ref().map_or(true, |s| s.id != record.session) {
+            session = Some(Session::new(record.session, &registry, &config)?);
+        }
+
+        let session = session.as_mut().unwrap();
+
+        // The proxy would have closed the connections by now
+        if session.ended {
+            continue;
+        }
+
+        recorder.set_record(index);
+        report.records += 1;
+
+        match session.process(&record) {
+            Some(outputs) => {
+                let replies = outputs.iter().filter(|r| r.reply).count();
+
+                report.replies += replies;
+                report.forwarded += outputs.len() - replies;
+            }
+
+            None => report.rejected.push(index),
+        }
+
+        if session.ended {
+            report.ended.push(index);
+        }
+    }
+
+    report.hits = recorder.take_hits();
+    Ok(report)
+}
@@ -8,8 +8,10 @@ extern crate conv;
 #[macro_use]
 extern crate custom_derive;
 extern crate rustc_serialize;
+extern crate time;
 
 pub mod parser;
+pub mod capture;
 pub mod modules;
 pub mod usb;
 pub mod util;
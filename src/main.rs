@@ -1,7 +1,6 @@
 extern crate env_logger;
 #[macro_use]
 extern crate log;
-extern crate time;
 extern crate rustc_serialize;
 extern crate getopts;
 extern crate cinch;
@@ -10,11 +9,12 @@ extern crate cinch;
 // Core
 use std::io::prelude::*;
 use std::fs::File;
-use std::net::{TcpListener, TcpStream};
+use std::net::{Shutdown, TcpListener, TcpStream};
 use std::io::{BufReader, BufWriter};
 use std::thread;
 use std::sync::mpsc; // for channel to communicate between threads
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 // To parse configuration
 use rustc_serialize::json;
@@ -44,7 +44,8 @@ pub struct CinchEndpoint<T: parser::HasHandlers, R: Read, W: Write> {
     parser: parser::Parser,
 
     reader: BufReader<R>, // read endpoint (e.g., from blue machine)
-    writer: BufWriter<W>, // write endpoint (e.g., to red machine)
+    writer: Arc<Mutex<BufWriter<W>>>, // write endpoint (e.g., to red machine)
+    reply_writer: Arc<Mutex<BufWriter<W>>>, // back to the read endpoint (e.g., to blue machine)
 
     handlers: T, // handlers for processing requests
 
@@ -58,7 +59,8 @@ impl<T, R, W> CinchEndpoint<T, R, W>
           W: Write {
     fn new(parser: parser::Parser,
            reader: BufReader<R>,
-           writer: BufWriter<W>,
+           writer: Arc<Mutex<BufWriter<W>>>,
+           reply_writer: Arc<Mutex<BufWriter<W>>>,
            handlers: T,
            tx: mpsc::Sender<parser::ParserState>,
            rx: mpsc::Receiver<parser::ParserState>)
@@ -68,60 +70,83 @@ impl<T, R, W> CinchEndpoint<T, R, W>
             parser: parser,
             reader: reader,
             writer: writer,
+            reply_writer: reply_writer,
             handlers: handlers,
             tx: tx,
             rx: rx,
         }
     }
 
-    fn process_requests(&mut self) {
+    // Runs until either stream fails, a module ends the session, or the endpoint for the
+    // other direction goes away (all three of which end with Ok(()) or an error).
+    fn process_requests(&mut self) -> Result<(), parser::ParseError> {
 
         loop {
 
-            if self.parser.state < parser::ParserState::Connected {
-
-                // Update parser state during the initial device configuration phase
-                loop {
-                    match self.rx.try_recv() {
-                        Ok(v) => {
-                            self.parser.process_state_change(v);
-                        }
-                        Err(e) if e == mpsc::TryRecvError::Empty => {
-                            break;
-                        }
-                        Err(e) => {
-                            panic!("Unknown error processing state change {:?}", e);
-                        }
+            // Update parser state from the other endpoint (device configuration, and disconnects
+            // that send it back to waiting for the next device)
+            loop {
+                match self.rx.try_recv() {
+                    Ok(v) => {
+                        self.parser.process_state_change(v);
+                    }
+                    Err(mpsc::TryRecvError::Empty) => {
+                        break;
+                    }
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        return Ok(());
                     }
                 }
             }
 
             // Pull request is blocking
-            let request = self.parser.pull_next_request(&mut self.reader);
-            let outputs = self.parser.process_request(&self.handlers, request, &self.tx);
-            self.parser.push_outputs(&mut self.writer, outputs);
-            self.writer.flush().unwrap();
+            let request = self.parser.pull_next_request(&mut self.reader)?;
+            let outputs = self.parser.process_request(&self.handlers, request, &self.tx)?;
+            let (replies, outputs): (Vec<_>, Vec<_>) = outputs.into_iter().partition(|r| r.reply);
+
+            // The other endpoint writes to the same streams, so each batch is written under
+            // the stream's lock.
+            if !outputs.is_empty() {
+                let mut writer = self.writer.lock().unwrap();
+                self.parser.push_outputs(&mut writer, outputs).map_err(parser::ParseError::Io)?;
+                writer.flush().map_err(parser::ParseError::Io)?;
+            }
+
+            if !replies.is_empty() {
+                let mut writer = self.reply_writer.lock().unwrap();
+                self.parser.push_replies(&mut writer, replies).map_err(parser::ParseError::Io)?;
+                writer.flush().map_err(parser::ParseError::Io)?;
+            }
+
+            if self.handlers.session_ended() {
+                info!("A module ended the device session");
+                return Ok(());
+            }
 
         }
     }
 }
 
-fn gen_caps() -> [u32; 1] {
-
-    let mut caps: [u32; 1] = [0];
+// Closing both directions of both streams unblocks whichever endpoint is still waiting on a
+// read, so the pair winds down together and only this device session ends.
+fn shutdown_streams(desc: &str, result: Result<(), parser::ParseError>, streams: &[&TcpStream]) {
 
-    parser::set_cap(&mut caps, parser::usbr::Caps::BulkStreams as usize);
-    parser::set_cap(&mut caps, parser::usbr::Caps::ConnectDeviceVersion as usize);
-    parser::set_cap(&mut caps, parser::usbr::Caps::EpInfoMaxPacketSize as usize);
-    parser::set_cap(&mut caps, parser::usbr::Caps::Cap64BitsIds as usize);
-    parser::set_cap(&mut caps, parser::usbr::Caps::Cap32BitsBulkLength as usize);
-    parser::set_cap(&mut caps, parser::usbr::Caps::BulkReceiving as usize);
+    match result {
+        Ok(_) => debug!("{} endpoint stopped", desc),
+        Err(parser::ParseError::Eof) => info!("{} machine closed the connection", desc),
+        Err(e) => error!("[E000-Cinch] {} endpoint failed: {:?}", desc, e),
+    }
 
-    caps
+    for stream in streams {
+        // The stream may already be closed by the other endpoint; nothing left to do then.
+        let _ = stream.shutdown(Shutdown::Both);
+    }
 }
 
 
-fn handle_blue_machine(blue_stream: TcpStream, config: util::config::CinchConfig) {
+fn handle_blue_machine(blue_stream: TcpStream,
+                       config: util::config::CinchConfig,
+                       registry: Arc<modules::registry::Registry>) {
 
     let red_stream = TcpStream::connect(&config.red_addr[..]).unwrap();
 
@@ -136,13 +161,17 @@ fn handle_blue_machine(blue_stream: TcpStream, config: util::config::CinchConfig
     let red_stream_write = red_stream.try_clone().unwrap();
     let blue_stream_write = blue_stream.try_clone().unwrap();
 
+    // Handles used to tear down the session once either endpoint stops
+    let red_stream_close = red_stream.try_clone().unwrap();
+    let blue_stream_close = blue_stream.try_clone().unwrap();
+
 
     // Create parsers
     let mut red_parser = parser::Parser::new(parser::Source::Blue);
     let mut blue_parser = parser::Parser::new(parser::Source::Red);
 
     // Init parsers
-    let caps: [u32; 1] = gen_caps();
+    let caps = parser::gen_caps();
 
     red_parser.init("parser for red", &caps); // pretends to be guest (blue machine)
     blue_parser.init("parser for blue", &caps); //pretends to be host (red machine)
@@ -152,110 +181,61 @@ fn handle_blue_machine(blue_stream: TcpStream, config: util::config::CinchConfig
     let (blue_tx, blue_rx) = mpsc::channel();
 
 
-    // Initialize handlers with module terminals and non-terminals
-
-    let mut blue_handler = modules::Modules::new();
-    let mut red_handler = modules::Modules::new();
-
-    // Add the default module (null)
-    let null_module = Arc::new(modules::null::Null::new());
-    let null_module_clone = null_module.clone();
-
-    blue_handler.add_terminal(0, null_module);
-    red_handler.add_terminal(0, null_module_clone);
-
-
-    // Add non-default modules
-
-    let mut index: usize = 0; // index of module in the non-terminal chain
-
-    if config.log {
-
-        let log_name = format!("{}-{}.{}",
-                               config.log_prefix,
-                               time::strftime("%d-%b-%Y-%H-%M-%S", &time::now()).unwrap(),
-                               "log");
-
-        // Module for logging requests
-        let logger_module = Arc::new(modules::logger::Logger::new(&log_name));
-        let logger_module_clone = logger_module.clone();
-
-        // The flow is: logger -> * -> null
-        blue_handler.add_nonterminal(index, 0, logger_module);
-        red_handler.add_nonterminal(index, 0, logger_module_clone);
-        index += 1;
-    }
-
-    if config.checks_active {
-
-        // Module for checking correctness of control packets
-        let checks_module = Arc::new(modules::control_checks::ControlCheck::new(&config.third_party_folder));
-        let checks_module_clone = checks_module.clone();
-
-        // The flow is: * -> checks -> reset or *
-        blue_handler.add_nonterminal(index, 0, checks_module);
-        red_handler.add_nonterminal(index, 0, checks_module_clone);
-
-        index += 1;
-    }
-
-    if config.patch_active {
-
-        // Module for applying patches
-        let patch_module = Arc::new(modules::patcher::Patcher::new(&config.patches[..]));
-        // let patch_module_clone = patch_module.clone();
-
-        // The flow for red endpoint is: * -> patcher -> reset or null
-        // blue_handler.add_nonterminal(index, 0, patch_module);
-        red_handler.add_nonterminal(index, 0, patch_module);
-
-        if config.checks_active {
-
-            // The flowfor red endpoint is: * -> patcher or reset -> reset or null
-
-            // Module that resets communication
-            let reset_module = Arc::new(modules::reset::Reset::new());
-            // let reset_module_clone = reset_module.clone();
-
-            // blue_handler.add_nonterminal(index, 1, reset_module);
-            red_handler.add_nonterminal(index, 1, reset_module);
+    // Build the module graph for this session (see util::config::ModuleConfig)
+    let (blue_handler, red_handler) = match registry.build(&config) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("[E002-Cinch] Could not build module pipeline: {}", e);
+            let _ = blue_stream.shutdown(Shutdown::Both);
+            let _ = red_stream.shutdown(Shutdown::Both);
+            return;
         }
-    }
-
-    if config.checks_active || config.patch_active {
-
-        // Module that resets communication
-        let reset_module = Arc::new(modules::reset::Reset::new());
-        let reset_module_clone = reset_module.clone();
-
-
-        blue_handler.add_terminal(1, reset_module);
-        red_handler.add_terminal(1, reset_module_clone);
-    }
+    };
 
 
     // Create endpoints
 
+    // Both endpoints write to both streams (forwarded requests and replies)
+    let red_writer = Arc::new(Mutex::new(BufWriter::new(red_stream_write)));
+    let blue_writer = Arc::new(Mutex::new(BufWriter::new(blue_stream_write)));
+
     let mut blue_end = CinchEndpoint::new(blue_parser,
                                           BufReader::new(blue_stream),
-                                          BufWriter::new(red_stream_write),
+                                          red_writer.clone(),
+                                          blue_writer.clone(),
                                           blue_handler,
                                           blue_tx,
                                           red_rx);
 
 
+    let red_close = red_stream_close.try_clone().unwrap();
+    let blue_close = blue_stream_close.try_clone().unwrap();
+
     // launch red endpoint on its own thread
-    thread::spawn(move || {
+    let red_thread = thread::spawn(move || {
         let mut red_end = CinchEndpoint::new(red_parser,
                                              BufReader::new(red_stream),
-                                             BufWriter::new(blue_stream_write),
+                                             blue_writer,
+                                             red_writer,
                                              red_handler,
                                              red_tx,
                                              blue_rx);
-        red_end.process_requests();
+        let result = red_end.process_requests();
+        shutdown_streams("Red", result, &[&red_close, &blue_close]);
     });
 
-    blue_end.process_requests();
+    let result = blue_end.process_requests();
+    shutdown_streams("Blue", result, &[&blue_stream_close, &red_stream_close]);
+
+    // Dropping blue_end disconnects the channel, which stops the red endpoint if it was
+    // waiting on a state change rather than on a read.
+    drop(blue_end);
+
+    if red_thread.join().is_err() {
+        error!("[E001-Cinch] Red endpoint thread panicked");
+    }
+
+    println!("Session with blue machine has ended");
 }
 
 
@@ -312,6 +292,30 @@ fn main() {
     // Setup logging
     env_logger::init().unwrap();
 
+    // Third-party checks and patches, reloaded for new sessions when their folders change
+    let rules = match modules::rules::Rules::load(&config) {
+        Ok(rules) => Arc::new(rules),
+        Err(e) => panic!("Invalid rules: {}", e),
+    };
+
+    let interval = config.reload_interval.unwrap_or(modules::rules::DEFAULT_RELOAD_INTERVAL);
+
+    if interval > 0 {
+        modules::rules::watch(rules.clone(), Duration::from_secs(interval));
+    }
+
+    // Modules that can appear in the pipeline
+    let registry = Arc::new(modules::registry::Registry::with_rules(rules));
+
+    let pipeline = match config.pipeline {
+        Some(ref pipeline) => pipeline.clone(),
+        None => modules::registry::default_pipeline(&config),
+    };
+
+    if let Err(e) = registry.validate(&config, &pipeline) {
+        panic!("Invalid module pipeline: {}", e);
+    }
+
     let listener = TcpListener::bind(&config.cinch_addr[..]).unwrap();
 
     for stream in listener.incoming() {
@@ -319,11 +323,12 @@ fn main() {
         println!("Blue machine has connected");
 
         let config_clone = config.clone();
+        let registry_clone = registry.clone();
 
         match stream {
             Ok(stream) => {
                 thread::spawn(move || {
-                    handle_blue_machine(stream, config_clone);
+                    handle_blue_machine(stream, config_clone, registry_clone);
                 });
             }
 
@@ -0,0 +1,428 @@
+#![allow(unused_variables)]
+
+use std::cmp;
+use std::fs::File;
+use std::io::prelude::*;
+use std::sync::Mutex;
+use byteorder::{ByteOrder, LittleEndian};
+
+use modules::fingerprint::Fingerprinter;
+use parser;
+use parser::usbr;
+use parser::{Request, Source};
+use usb;
+use util::config::{AuthorizationConfig, AuthorizationRule};
+
+// Port for devices that are denied (e.g., to reset)
+pub const PORT_DENY: u8 = 1;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Allow,
+    Deny,
+    Ask,
+}
+
+impl Outcome {
+    pub fn from_name(name: &str) -> Option<Outcome> {
+        match name {
+            "allow" => Some(Outcome::Allow),
+            "deny" => Some(Outcome::Deny),
+            "ask" => Some(Outcome::Ask),
+            _ => None,
+        }
+    }
+}
+
+// Whether a rule matches what is known about the device so far
+#[derive(PartialEq)]
+enum Match {
+    Yes,
+    No,
+    Unknown, // depends on descriptors that have not been read yet
+}
+
+struct Rule {
+    outcome: Outcome,
+    vendor_id: Option<(u16, u16)>,
+    product_id: Option<(u16, u16)>,
+    bcd_device: Option<(u16, u16)>,
+    class: Option<u8>,
+    subclass: Option<u8>,
+    protocol: Option<u8>,
+    serial: Option<String>,
+    speed: Option<u8>,
+    fingerprint: Option<String>,
+}
+
+// What the device told us about itself
+struct Device {
+    speed: u8,
+    vendor_id: u16,
+    product_id: u16,
+    bcd_device: u16,
+    classes: Vec<(u8, u8, u8)>, // class, subclass and protocol of the device and of its interfaces
+    descriptors: Fingerprinter, // device descriptor, configurations and strings read so far
+}
+
+struct State {
+    interfaces: Vec<(u8, u8, u8)>, // from the interface info that precedes the connect
+    device: Option<Device>,
+    outcome: Option<Outcome>, // None while rules depend on descriptors that were not read
+}
+
+// Decides which devices may attach, from the metadata in the connect packet and then from the
+// descriptors, serial number and fingerprint of the device (see fingerprint.rs). Denied devices
+// go out PORT_DENY as soon as rules say so. Devices to ask about (and devices still undecided)
+// are never configured: blue's set configuration is refused unless the device is listed in the
+// approvals file, which an operator can edit while cinch runs. The module must see both
+// directions.
+pub struct Authorizer {
+    rules: Vec<Rule>,
+    default: Outcome,
+    approvals: Option<String>,
+    state: Mutex<State>,
+}
+
+// A hex ID (e.g., 0781) or an inclusive range (e.g., 5500-55ff)
+fn parse_range(range: &str) -> Option<(u16, u16)> {
+
+    let mut parts = range.splitn(2, '-');
+
+    let low = parts.next().and_then(|v| u16::from_str_radix(v.trim(), 16).ok())?;
+
+    let high = match parts.next() {
+        Some(v) => u16::from_str_radix(v.trim(), 16).ok()?,
+        None => low,
+    };
+
+    if low > high {
+        return None;
+    }
+
+    Some((low, high))
+}
+
+fn parse_speed(speed: &str) -> Option<u8> {
+    match speed {
+        "low" => Some(usbr::Speed::Slow as u8),
+        "full" => Some(usbr::Speed::Full as u8),
+        "high" => Some(usbr::Speed::High as u8),
+        "super" => Some(usbr::Speed::Super as u8),
+        _ => None,
+    }
+}
+
+fn in_range(range: Option<(u16, u16)>, value: u16) -> bool {
+    range.iter().all(|&(low, high)| low <= value && value <= high)
+}
+
+impl Rule {
+    fn from_config(rule: &AuthorizationRule) -> Result<Rule, String> {
+
+        let range = |field: &Option<String>, name: &str| -> Result<Option<(u16, u16)>, String> {
+            match *field {
+                Some(ref v) => parse_range(v).map(Some).ok_or_else(|| format!("invalid {} {}", name, v)),
+                None => Ok(None),
+            }
+        };
+
+        let speed = match rule.speed {
+            Some(ref v) => Some(parse_speed(v).ok_or_else(|| format!("invalid speed {}", v))?),
+            None => None,
+        };
+
+        Ok(Rule {
+            outcome: Outcome::from_name(&rule.action).ok_or_else(|| format!("invalid action {}", rule.action))?,
+            vendor_id: range(&rule.vendor_id, "vendor_id")?,
+            product_id: range(&rule.product_id, "product_id")?,
+            bcd_device: range(&rule.bcd_device, "bcd_device")?,
+            class: rule.class,
+            subclass: rule.subclass,
+            protocol: rule.protocol,
+            serial: rule.serial.clone(),
+            speed: speed,
+            fingerprint: rule.fingerprint.as_ref().map(|v| v.to_lowercase()),
+        })
+    }
+
+    fn matches(&self, dev: &Device) -> Match {
+
+        let class = (self.class.is_none() && self.subclass.is_none() && self.protocol.is_none()) ||
+                    dev.classes.iter().any(|&(c, s, p)| {
+            self.class.iter().all(|v| *v == c) && self.subclass.iter().all(|v| *v == s) &&
+            self.protocol.iter().all(|v| *v == p)
+        });
+
+        if !in_range(self.vendor_id, dev.vendor_id) || !in_range(self.product_id, dev.product_id) ||
+           !in_range(self.bcd_device, dev.bcd_device) || !class ||
+           self.speed.iter().any(|v| *v != dev.speed) {
+            return Match::No;
+        }
+
+        let serial = match self.serial {
+            None => Match::Yes,
+            Some(ref serial) => {
+                match (dev.descriptors.serial_index(), dev.descriptors.serial()) {
+                    (Some(0), _) => Match::No,
+                    (_, Some(v)) => if v == *serial { Match::Yes } else { Match::No },
+                    _ => Match::Unknown,
+                }
+            }
+        };
+
+        let fingerprint = match self.fingerprint {
+            None => Match::Yes,
+            Some(ref fingerprint) => {
+                match dev.descriptors.fingerprint() {
+                    Some(v) => if v == *fingerprint { Match::Yes } else { Match::No },
+                    None => Match::Unknown,
+                }
+            }
+        };
+
+        match (serial, fingerprint) {
+            (Match::No, _) | (_, Match::No) => Match::No,
+            (Match::Yes, Match::Yes) => Match::Yes,
+            _ => Match::Unknown,
+        }
+    }
+}
+
+impl Authorizer {
+    pub fn new(config: &AuthorizationConfig) -> Result<Authorizer, String> {
+
+        let mut rules = vec![];
+
+        for rule in &config.rules {
+            rules.push(Rule::from_config(rule)?);
+        }
+
+        let default = match config.default {
+            Some(ref v) => Outcome::from_name(v).ok_or_else(|| format!("invalid default {}", v))?,
+            None => Outcome::Deny,
+        };
+
+        Ok(Authorizer {
+            rules: rules,
+            default: default,
+            approvals: config.approvals.clone(),
+            state: Mutex::new(State {
+                interfaces: vec![],
+                device: None,
+                outcome: None,
+            }),
+        })
+    }
+
+    // The outcome of the first rule that matches (None if a rule before it cannot tell yet).
+    // Once the descriptors are complete, rules that still cannot tell do not match.
+    fn evaluate(&self, dev: &Device, complete: bool) -> Option<Outcome> {
+
+        for rule in &self.rules {
+            match rule.matches(dev) {
+                Match::Yes => return Some(rule.outcome),
+                Match::Unknown if !complete => return None,
+                _ => {}
+            }
+        }
+
+        Some(self.default)
+    }
+
+    // Whether the approvals file lists the device as vid:pid or vid:pid:serial
+    fn approved(&self, dev: &Device) -> bool {
+
+        let path = match self.approvals {
+            Some(ref v) => v,
+            None => return false,
+        };
+
+        let mut contents = String::new();
+
+        if let Err(e) = File::open(path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            error!("[E001-Authorizer] Could not read approvals {}: {}", path, e);
+            return false;
+        }
+
+        let id = format!("{:04x}:{:04x}", dev.vendor_id, dev.product_id);
+        let serial = dev.descriptors.serial().map(|s| format!("{}:{}", id, s));
+
+        contents.lines()
+            .map(|l| l.trim())
+            .any(|l| l.eq_ignore_ascii_case(&id) || serial.iter().any(|s| l == s))
+    }
+
+    // Re-evaluates the rules once descriptors tell us more. Denied devices go out PORT_DENY.
+    fn update(&self, state: &mut State, req: Request) -> (u8, Vec<Request>) {
+
+        let outcome = match state.device {
+            Some(ref dev) if state.outcome.is_none() => self.evaluate(dev, false),
+            _ => return (0, vec![req]),
+        };
+
+        state.outcome = outcome;
+
+        if outcome == Some(Outcome::Deny) {
+            error!("[E002-Authorizer] Device denied after reading its descriptors");
+            return (PORT_DENY, vec![req]);
+        }
+
+        (0, vec![req])
+    }
+
+    // Blue wants to configure the device. Returns true if it may.
+    fn may_configure(&self, state: &mut State) -> bool {
+
+        let outcome = match state.device {
+            Some(ref dev) => {
+                match state.outcome {
+                    Some(v) => v,
+                    None => self.evaluate(dev, true).unwrap_or(self.default),
+                }
+            }
+
+            None => return true, // not a device session yet
+        };
+
+        state.outcome = Some(outcome);
+
+        match outcome {
+            Outcome::Allow => true,
+            Outcome::Deny => false,
+
+            Outcome::Ask => {
+                let dev = state.device.as_ref().unwrap();
+
+                if self.approved(dev) {
+                    info!("Device {:04x}:{:04x} approved", dev.vendor_id, dev.product_id);
+                    state.outcome = Some(Outcome::Allow);
+                    return true;
+                }
+
+                error!("[E003-Authorizer] Device {:04x}:{:04x} (serial {}) needs approval. Add it to the \
+                        approvals file and reattach it.",
+                       dev.vendor_id,
+                       dev.product_id,
+                       dev.descriptors.serial().unwrap_or_else(|| "none".to_string()));
+                false
+            }
+        }
+    }
+
+    // Blue's request to configure the device is answered without reaching it
+    fn refuse(&self, req: Request) -> (u8, Vec<Request>) {
+
+        let mut type_header = req.type_header.clone();
+
+        let req = if req.get_type() == usbr::HeaderType::SetConf as u32 {
+            let conf = type_header[0];
+            Request::new(usbr::HeaderType::ConfStatus as u32,
+                         req.get_id(),
+                         vec![usbr::Result::Stall as u8, conf],
+                         vec![])
+        } else {
+            type_header[3] = usbr::Result::Stall as u8; // status of the control packet
+            Request::new(req.get_type(), req.get_id(), type_header, vec![])
+        };
+
+        (0, vec![req.into_reply()])
+    }
+}
+
+
+impl parser::HasHandlers for Authorizer {
+    fn handle_interface_info(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        // count, then interface, class, subclass and protocol arrays (32 entries each)
+        let th = &req.type_header;
+        let count = cmp::min(LittleEndian::read_u32(&th[0..4]) as usize, 32);
+        let mut state = self.state.lock().unwrap();
+
+        let interfaces: Vec<(u8, u8, u8)> = (0..count).map(|i| (th[36 + i], th[68 + i], th[100 + i])).collect();
+
+        if let Some(ref mut dev) = state.device {
+            dev.classes.extend(interfaces.iter().cloned());
+        }
+
+        state.interfaces = interfaces;
+
+        (0, vec![req])
+    }
+
+    fn handle_connect(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        let h_ptr = req.type_header.as_ptr() as *const usbr::ConnectHeader;
+        let h: &usbr::ConnectHeader = unsafe { &*h_ptr };
+        let mut state = self.state.lock().unwrap();
+
+        let mut classes = vec![(h.class, h.subclass, h.proto)];
+        classes.extend(state.interfaces.iter().cloned());
+
+        let dev = Device {
+            speed: h.speed,
+            vendor_id: h.vendor_id,
+            product_id: h.product_id,
+            bcd_device: h.version_bcd,
+            classes: classes,
+            descriptors: Fingerprinter::new(),
+        };
+
+        state.outcome = self.evaluate(&dev, false);
+
+        let outcome = state.outcome;
+        let (vendor_id, product_id) = (dev.vendor_id, dev.product_id);
+        state.device = Some(dev);
+
+        if outcome == Some(Outcome::Deny) {
+            error!("[E004-Authorizer] Device {:04x}:{:04x} denied", vendor_id, product_id);
+            return (PORT_DENY, vec![req]);
+        }
+
+        (0, vec![req])
+    }
+
+    fn handle_control_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        let h_ptr = req.type_header.as_ptr() as *const usbr::ControlPacketHeader;
+        let h: &usbr::ControlPacketHeader = unsafe { &*h_ptr };
+        let mut state = self.state.lock().unwrap();
+
+        let standard = (h.requesttype & (usb::TYPE_MASK | usb::RECIP_MASK)) == usb::TYPE_STANDARD | usb::RECIP_DEVICE;
+
+        if source == Source::Blue && standard && h.request == usb::REQ_SET_CONFIGURATION {
+            if self.may_configure(&mut state) {
+                return (0, vec![req]);
+            }
+
+            return self.refuse(req);
+        }
+
+        if source != Source::Red || !standard || h.request != usb::REQ_GET_DESCRIPTOR ||
+           h.status != usbr::Result::Success as u8 {
+            return (0, vec![req]);
+        }
+
+        let added = match state.device {
+            Some(ref mut dev) => dev.descriptors.add(h, &req.data),
+            None => false,
+        };
+
+        if !added {
+            return (0, vec![req]);
+        }
+
+        self.update(&mut state, req)
+    }
+
+    fn handle_set_conf(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        let mut state = self.state.lock().unwrap();
+
+        if source != Source::Blue || self.may_configure(&mut state) {
+            return (0, vec![req]);
+        }
+
+        self.refuse(req)
+    }
+}
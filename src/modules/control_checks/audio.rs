@@ -0,0 +1,730 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+
+use usb;
+use parser::usbr;
+use parser::Source;
+use byteorder::{ByteOrder, LittleEndian};
+
+
+// A terminal, unit or clock entity of an audio control interface
+struct Entity {
+    subtype: u8,
+    sources: Vec<u8>, // terminals and units it takes its input from
+    clocks: Vec<u8>, // clock entities it takes its clock from (UAC2)
+    channels: usize, // controls of a feature unit (the master control and one per channel)
+}
+
+pub struct AudioControlCheck {
+    entities: HashMap<u8, HashMap<u8, Entity>>, // entities of each audio control interface, by id
+}
+
+// How a control is laid out
+enum Control {
+    Invalid, // no such entity, control or channel
+    Unknown, // controls we do not check (e.g., of processing units)
+    Fixed(usize, bool), // size of the value and whether it has a range (UAC2)
+}
+
+
+// Class-specific descriptors of type dt that start at off (this call updates off)
+fn class_descs<'a>(data: &'a [u8], off: &mut usize, dt: u8) -> Option<Vec<&'a [u8]>> {
+
+    let mut descs = vec![];
+
+    while data.len() >= *off + usb::HEADER_SIZE && data[*off + 1] == dt {
+
+        let length: usize = data[*off] as usize;
+
+        if length < usb::audio::DESC_MIN_SIZE || data.len() < *off + length {
+            error!("[E001-AUDIO] Invalid class-specific descriptor length {}", length);
+            return None;
+        }
+
+        descs.push(&data[*off..*off + length]);
+        *off += length;
+    }
+
+    Some(descs)
+}
+
+// UAC2 controls are pairs of bits (none, read-only, invalid and read-write)
+fn check_controls_v2(controls: u32) -> bool {
+    (0..16).all(|i| (controls >> (2 * i)) & 0x03 != 0x02)
+}
+
+fn is_clock(subtype: u8, v2: bool) -> bool {
+    v2 && subtype >= usb::audio::CLOCK_SOURCE && subtype <= usb::audio::CLOCK_MULTIPLIER
+}
+
+fn is_terminal(subtype: u8) -> bool {
+    subtype == usb::audio::INPUT_TERMINAL || subtype == usb::audio::OUTPUT_TERMINAL
+}
+
+
+// Terminals and units of UAC1 (Section 4.3.2 in UAC 1.0). Fills in the inputs of entity.
+fn check_entity_v1(desc: &[u8], entity: &mut Entity) -> bool {
+
+    let len = desc.len();
+    let pins = |at: usize| if len > at { desc[at] as usize } else { 0 };
+
+    match desc[2] {
+
+        usb::audio::INPUT_TERMINAL if len == 12 => {}
+
+        usb::audio::OUTPUT_TERMINAL if len == 9 => entity.sources.push(desc[7]),
+
+        usb::audio::MIXER_UNIT if pins(4) > 0 && len >= 10 + pins(4) => {
+            entity.sources = desc[5..5 + pins(4)].to_vec();
+        }
+
+        usb::audio::SELECTOR_UNIT if pins(4) > 0 && len == 6 + pins(4) => {
+            entity.sources = desc[5..5 + pins(4)].to_vec();
+        }
+
+        // bControlSize bytes of controls for the master channel and each logical channel. Only
+        // bits 0-9 are defined.
+        usb::audio::FEATURE_UNIT if pins(5) > 0 && len > 7 && (len - 7) % pins(5) == 0 => {
+
+            let size = pins(5);
+            entity.sources.push(desc[4]);
+            entity.channels = (len - 7) / size;
+
+            return desc[6..len - 1].chunks(size).all(|c| {
+                c.iter().enumerate().all(|(i, b)| {
+                    match i {
+                        0 => true,
+                        1 => b & 0xfc == 0,
+                        _ => *b == 0,
+                    }
+                })
+            });
+        }
+
+        usb::audio::PROCESSING_UNIT_V1 if pins(6) > 0 && len >= 13 + pins(6) + pins(11 + pins(6)) => {
+            entity.sources = desc[7..7 + pins(6)].to_vec();
+        }
+
+        usb::audio::EXTENSION_UNIT_V1 if pins(6) > 0 && len == 13 + pins(6) + pins(11 + pins(6)) => {
+            entity.sources = desc[7..7 + pins(6)].to_vec();
+        }
+
+        _ => return false,
+    }
+
+    true
+}
+
+// Terminals, units and clock entities of UAC2 (Section 4.7.2 in UAC 2.0). Fills in the inputs
+// of entity.
+fn check_entity_v2(desc: &[u8], entity: &mut Entity) -> bool {
+
+    let len = desc.len();
+    let pins = |at: usize| if len > at { desc[at] as usize } else { 0 };
+
+    match desc[2] {
+
+        usb::audio::INPUT_TERMINAL if len == 17 => {
+            entity.clocks.push(desc[7]);
+            return check_controls_v2(LittleEndian::read_u16(&desc[14..16]) as u32);
+        }
+
+        usb::audio::OUTPUT_TERMINAL if len == 12 => {
+            entity.sources.push(desc[7]);
+            entity.clocks.push(desc[8]);
+            return check_controls_v2(LittleEndian::read_u16(&desc[9..11]) as u32);
+        }
+
+        usb::audio::MIXER_UNIT if pins(4) > 0 && len >= 13 + pins(4) => {
+            entity.sources = desc[5..5 + pins(4)].to_vec();
+        }
+
+        usb::audio::SELECTOR_UNIT if pins(4) > 0 && len == 7 + pins(4) => {
+            entity.sources = desc[5..5 + pins(4)].to_vec();
+        }
+
+        // 4 bytes of controls for the master channel and each logical channel
+        usb::audio::FEATURE_UNIT if len >= 10 && (len - 6) % 4 == 0 => {
+            entity.sources.push(desc[4]);
+            entity.channels = (len - 6) / 4;
+            return desc[5..len - 1].chunks(4).all(|c| check_controls_v2(LittleEndian::read_u32(c)));
+        }
+
+        usb::audio::EFFECT_UNIT if len >= 12 && (len - 8) % 4 == 0 => {
+            entity.sources.push(desc[6]);
+            return desc[7..len - 1].chunks(4).all(|c| check_controls_v2(LittleEndian::read_u32(c)));
+        }
+
+        usb::audio::PROCESSING_UNIT_V2 if pins(6) > 0 && len >= 17 + pins(6) => {
+            entity.sources = desc[7..7 + pins(6)].to_vec();
+        }
+
+        usb::audio::EXTENSION_UNIT_V2 if pins(6) > 0 && len == 16 + pins(6) => {
+            entity.sources = desc[7..7 + pins(6)].to_vec();
+        }
+
+        // clock type and whether it is synchronized to the SOF
+        usb::audio::CLOCK_SOURCE if len == 8 => {
+            return desc[4] & 0xf8 == 0 && check_controls_v2(desc[5] as u32);
+        }
+
+        usb::audio::CLOCK_SELECTOR if pins(4) > 0 && len == 7 + pins(4) => {
+            entity.clocks = desc[5..5 + pins(4)].to_vec();
+        }
+
+        usb::audio::CLOCK_MULTIPLIER if len == 7 => entity.clocks.push(desc[4]),
+
+        usb::audio::SAMPLE_RATE_CONVERTER if len == 8 => {
+            entity.sources.push(desc[4]);
+            entity.clocks.extend_from_slice(&desc[5..7]);
+        }
+
+        _ => return false,
+    }
+
+    true
+}
+
+// Whether following the inputs of entity id leads back to an entity that is being visited
+fn has_loop(entities: &HashMap<u8, Entity>, id: u8, visiting: &mut HashSet<u8>, done: &mut HashSet<u8>) -> bool {
+
+    if done.contains(&id) {
+        return false;
+    }
+
+    if !visiting.insert(id) {
+        return true;
+    }
+
+    let found = match entities.get(&id) {
+        Some(e) => e.sources.iter().chain(e.clocks.iter()).any(|i| has_loop(entities, *i, visiting, done)),
+        None => false,
+    };
+
+    visiting.remove(&id);
+    done.insert(id);
+
+    found
+}
+
+// Format type descriptors (Section 2 in the UAC 1.0 and 2.0 format specifications)
+fn check_format(desc: &[u8], v2: bool) -> bool {
+
+    let len = desc.len();
+
+    if len < 4 {
+        return false;
+    }
+
+    // Sampling frequencies are a continuous range, or a list of discrete ones (3 bytes each)
+    let freqs = |at: usize| if len > at {
+        match desc[at] {
+            0 => 6,
+            n => 3 * n as usize,
+        }
+    } else {
+        0
+    };
+
+    // Bytes per sample (1 to 4), and the bits of those that are used
+    let bits = |at: usize| len > at + 1 && desc[at] >= 1 && desc[at] <= 4 && desc[at + 1] >= 1 &&
+                           desc[at + 1] <= 8 * desc[at];
+
+    match (v2, desc[3]) {
+
+        (false, usb::audio::FORMAT_TYPE_I) |
+        (false, usb::audio::FORMAT_TYPE_III) => len > 7 && desc[4] > 0 && bits(5) && len == 8 + freqs(7),
+
+        (false, usb::audio::FORMAT_TYPE_II) => len > 8 && len == 9 + freqs(8),
+
+        (true, usb::audio::FORMAT_TYPE_I) |
+        (true, usb::audio::FORMAT_TYPE_III) => len == 6 && bits(4),
+
+        (true, usb::audio::FORMAT_TYPE_II) => len == 8,
+
+        (true, usb::audio::FORMAT_TYPE_IV) => len == 4,
+
+        _ => false,
+    }
+}
+
+// Class-specific descriptors of MIDI streaming interfaces (Section 6.1.2 in USB MIDI 1.0)
+fn check_midi_desc(descs: &[&[u8]]) -> bool {
+
+    match descs.first() {
+        Some(h) if h[2] == usb::audio::MS_HEADER && h.len() == 7 => {}
+        _ => {
+            error!("[E002-AUDIO] MIDI streaming interface without header");
+            return false;
+        }
+    }
+
+    for desc in &descs[1..] {
+
+        let len = desc.len();
+
+        // jacks are embedded (1) or external (2)
+        let valid = match desc[2] {
+            usb::audio::MIDI_IN_JACK => len == 6 && (desc[3] == 1 || desc[3] == 2),
+            usb::audio::MIDI_OUT_JACK => len > 5 && (desc[3] == 1 || desc[3] == 2) && len == 7 + 2 * desc[5] as usize,
+            usb::audio::MIDI_ELEMENT => len > 4 && len >= 10 + 2 * desc[4] as usize,
+            _ => false,
+        };
+
+        if !valid {
+            error!("[E003-AUDIO] Invalid MIDI streaming descriptor 0x{:x} of length {}",
+                   desc[2],
+                   len);
+            return false;
+        }
+    }
+
+    true
+}
+
+
+impl AudioControlCheck {
+    pub fn new() -> AudioControlCheck {
+        AudioControlCheck { entities: HashMap::new() }
+    }
+
+    // Class-specific descriptors that follow an audio interface and come before its endpoints
+    // (this call updates off)
+    pub fn check_interface(&mut self, iface: &usb::InterfaceDescriptor, data: &[u8], off: &mut usize) -> bool {
+
+        if iface.interface_protocol != usb::audio::UAC_VERSION_1 &&
+           iface.interface_protocol != usb::audio::UAC_VERSION_2 {
+            error!("[E004-AUDIO] Unknown audio class version 0x{:x}", iface.interface_protocol);
+            return false;
+        }
+
+        let descs = match class_descs(data, off, usb::DT_CS_INTERFACE) {
+            Some(v) => v,
+            None => return false,
+        };
+
+        match iface.interface_subclass {
+            usb::audio::SC_AUDIOCONTROL => self.check_control_desc(iface, &descs),
+            usb::audio::SC_AUDIOSTREAMING => self.check_streaming_desc(iface, &descs),
+            usb::audio::SC_MIDISTREAMING => check_midi_desc(&descs),
+            _ => {
+                error!("[E005-AUDIO] Unknown audio interface subclass 0x{:x}",
+                       iface.interface_subclass);
+                false
+            }
+        }
+    }
+
+    // The header, then the terminals, units and clock entities of an audio control interface.
+    // Every input of an entity must be another entity of the interface (and a clock entity for
+    // clock inputs), and following inputs must not lead in a circle.
+    fn check_control_desc(&mut self, iface: &usb::InterfaceDescriptor, descs: &[&[u8]]) -> bool {
+
+        let v2: bool = iface.interface_protocol == usb::audio::UAC_VERSION_2;
+
+        let header: &[u8] = match descs.first() {
+            Some(h) if h[2] == usb::audio::HEADER => h,
+            _ => {
+                error!("[E006-AUDIO] Audio control interface without header");
+                return false;
+            }
+        };
+
+        // UAC1 lists the streaming interfaces of the collection; UAC2 has a category and controls
+        let valid = if v2 {
+            header.len() == 9 && LittleEndian::read_u16(&header[3..5]) == 0x0200 && header[8] & 0xfc == 0
+        } else {
+            header.len() >= 8 && header.len() == 8 + header[7] as usize &&
+            LittleEndian::read_u16(&header[3..5]) == 0x0100 && !header[8..].contains(&iface.interface_number)
+        };
+
+        if !valid {
+            error!("[E007-AUDIO] Invalid audio control header of length {}", header.len());
+            return false;
+        }
+
+        // wTotalLength covers the header and every entity
+        let total: usize = LittleEndian::read_u16(&header[if v2 { 6 } else { 5 }..]) as usize;
+        let length: usize = descs.iter().map(|d| d.len()).sum();
+
+        if total != length {
+            error!("[E008-AUDIO] Audio control descriptors total {} bytes, not {}", length, total);
+            return false;
+        }
+
+        let mut entities: HashMap<u8, Entity> = HashMap::new();
+
+        for desc in &descs[1..] {
+
+            let mut entity = Entity {
+                subtype: desc[2],
+                sources: vec![],
+                clocks: vec![],
+                channels: 0,
+            };
+
+            let valid = if desc.len() <= 3 {
+                false
+            } else if v2 {
+                check_entity_v2(desc, &mut entity)
+            } else {
+                check_entity_v1(desc, &mut entity)
+            };
+
+            if !valid {
+                error!("[E009-AUDIO] Invalid audio control descriptor 0x{:x} of length {}",
+                       desc[2],
+                       desc.len());
+                return false;
+            }
+
+            if desc[3] == 0 || entities.insert(desc[3], entity).is_some() {
+                error!("[E010-AUDIO] Invalid or repeated entity id {}", desc[3]);
+                return false;
+            }
+        }
+
+        for (id, entity) in &entities {
+
+            let sources = entity.sources.iter().all(|s| entities.get(s).iter().any(|e| !is_clock(e.subtype, v2)));
+            let clocks = entity.clocks.iter().all(|c| entities.get(c).iter().any(|e| is_clock(e.subtype, v2)));
+
+            if !sources || !clocks {
+                error!("[E011-AUDIO] Entity {} has an input that is not in the interface", id);
+                return false;
+            }
+        }
+
+        let mut done: HashSet<u8> = HashSet::new();
+
+        for id in entities.keys() {
+            if has_loop(&entities, *id, &mut HashSet::new(), &mut done) {
+                error!("[E012-AUDIO] The inputs of entity {} form a loop", id);
+                return false;
+            }
+        }
+
+        self.entities.insert(iface.interface_number, entities);
+
+        true
+    }
+
+    // The general descriptor, which links the interface to a terminal, and the format type
+    // descriptor of an audio streaming interface. Alternate settings without endpoints (i.e.,
+    // zero bandwidth) may have neither.
+    fn check_streaming_desc(&self, iface: &usb::InterfaceDescriptor, descs: &[&[u8]]) -> bool {
+
+        let v2: bool = iface.interface_protocol == usb::audio::UAC_VERSION_2;
+
+        if descs.is_empty() && iface.num_endpoints == 0 {
+            return true;
+        }
+
+        let general: &[u8] = match descs.first() {
+            Some(g) if g[2] == usb::audio::AS_GENERAL => g,
+            _ => {
+                error!("[E013-AUDIO] Audio streaming interface without general descriptor");
+                return false;
+            }
+        };
+
+        let valid = if v2 {
+            general.len() == 16 && check_controls_v2(general[4] as u32) && general[10] > 0
+        } else {
+            general.len() == 7
+        };
+
+        if !valid {
+            error!("[E014-AUDIO] Invalid audio streaming general descriptor of length {}",
+                   general.len());
+            return false;
+        }
+
+        let link: u8 = general[3];
+
+        if !self.entities.values().any(|e| e.get(&link).iter().any(|t| is_terminal(t.subtype))) {
+            error!("[E015-AUDIO] Audio streaming interface linked to unknown terminal {}", link);
+            return false;
+        }
+
+        match descs.get(1) {
+            Some(f) if f[2] == usb::audio::FORMAT_TYPE && check_format(f, v2) => {}
+            _ => {
+                error!("[E016-AUDIO] Missing or invalid format type descriptor");
+                return false;
+            }
+        }
+
+        for desc in &descs[2..] {
+
+            let valid = if v2 {
+                desc[2] == usb::audio::ENCODER || desc[2] == usb::audio::DECODER
+            } else {
+                desc[2] == usb::audio::FORMAT_SPECIFIC
+            };
+
+            if !valid {
+                error!("[E017-AUDIO] Unknown audio streaming descriptor 0x{:x}", desc[2]);
+                return false;
+            }
+        }
+
+        true
+    }
+
+    // Class-specific descriptor that may follow an endpoint of an audio interface (this call
+    // updates off)
+    pub fn check_endpoint(&self, iface: &usb::InterfaceDescriptor, data: &[u8], off: &mut usize) -> bool {
+
+        let v2: bool = iface.interface_protocol == usb::audio::UAC_VERSION_2;
+
+        let descs = match class_descs(data, off, usb::DT_CS_ENDPOINT) {
+            Some(v) => v,
+            None => return false,
+        };
+
+        if descs.len() > 1 {
+            error!("[E018-AUDIO] More than one class-specific endpoint descriptor");
+            return false;
+        }
+
+        for desc in descs {
+
+            let len = desc.len();
+
+            // Attributes and lock delay units (undefined, milliseconds or samples)
+            let valid = match iface.interface_subclass {
+                usb::audio::SC_AUDIOSTREAMING if v2 => {
+                    desc[2] == usb::audio::EP_GENERAL && len == 8 && desc[3] & 0x7f == 0 &&
+                    check_controls_v2(desc[4] as u32) && desc[5] <= 2
+                }
+
+                usb::audio::SC_AUDIOSTREAMING => {
+                    desc[2] == usb::audio::EP_GENERAL && len == 7 && desc[3] & 0x7c == 0 && desc[4] <= 2
+                }
+
+                // embedded jacks of the endpoint
+                usb::audio::SC_MIDISTREAMING => {
+                    desc[2] == usb::audio::MS_GENERAL && len > 3 && len == 4 + desc[3] as usize
+                }
+
+                _ => false,
+            };
+
+            if !valid {
+                error!("[E019-AUDIO] Invalid class-specific endpoint descriptor 0x{:x} of length {}",
+                       desc[2],
+                       len);
+                return false;
+            }
+        }
+
+        true
+    }
+
+    // Layout of the control that h addresses: a control of the endpoint (wIndex), or of an entity
+    // (high byte of wIndex) of the interface. The high byte of wValue selects the control, and
+    // the low byte the channel.
+    fn control(&self, h: &usbr::ControlPacketHeader, iface: &usb::InterfaceDescriptor, v2: bool) -> Control {
+
+        let selector: u8 = (h.value >> 8) as u8;
+        let channel: u8 = h.value as u8;
+
+        if (h.requesttype & usb::RECIP_MASK) == usb::RECIP_ENDPOINT {
+            return match (v2, selector) {
+                (false, usb::audio::EP_SAMPLING_FREQ) => Control::Fixed(3, false),
+                (false, usb::audio::EP_PITCH) => Control::Fixed(1, false),
+                (true, usb::audio::EP_PITCH_V2) |
+                (true, usb::audio::EP_DATA_OVERRUN) |
+                (true, usb::audio::EP_DATA_UNDERRUN) => Control::Fixed(1, false),
+                _ => {
+                    error!("[E020-AUDIO] Unknown endpoint control 0x{:x}", selector);
+                    Control::Invalid
+                }
+            };
+        }
+
+        let id: u8 = (h.index >> 8) as u8;
+
+        // Controls of the interface itself
+        if id == 0 {
+            return Control::Unknown;
+        }
+
+        let entity = match self.entities.get(&iface.interface_number).and_then(|e| e.get(&id)) {
+            Some(v) => v,
+            None => {
+                error!("[E021-AUDIO] Request for unknown entity {} of interface {}",
+                       id,
+                       iface.interface_number);
+                return Control::Invalid;
+            }
+        };
+
+        let control = match entity.subtype {
+
+            usb::audio::FEATURE_UNIT => {
+
+                // 0xff addresses all channels
+                if channel as usize >= entity.channels && channel != 0xff {
+                    error!("[E022-AUDIO] Request for channel {} of feature unit {} with {} controls",
+                           channel,
+                           id,
+                           entity.channels);
+                    return Control::Invalid;
+                }
+
+                match selector {
+                    usb::audio::FU_MUTE => Control::Fixed(1, false),
+                    usb::audio::FU_VOLUME => Control::Fixed(2, true),
+                    usb::audio::FU_BASS |
+                    usb::audio::FU_MID |
+                    usb::audio::FU_TREBLE => Control::Fixed(1, true),
+                    usb::audio::FU_GRAPHIC_EQUALIZER => Control::Unknown,
+                    usb::audio::FU_AUTOMATIC_GAIN => Control::Fixed(1, false),
+                    usb::audio::FU_DELAY => Control::Fixed(if v2 { 4 } else { 2 }, true),
+                    usb::audio::FU_BASS_BOOST |
+                    usb::audio::FU_LOUDNESS => Control::Fixed(1, false),
+                    usb::audio::FU_INPUT_GAIN |
+                    usb::audio::FU_INPUT_GAIN_PAD if v2 => Control::Fixed(2, true),
+                    usb::audio::FU_PHASE_INVERTER |
+                    usb::audio::FU_UNDERFLOW |
+                    usb::audio::FU_OVERFLOW if v2 => Control::Fixed(1, false),
+                    _ => Control::Invalid,
+                }
+            }
+
+            usb::audio::CLOCK_SOURCE if v2 => {
+                match selector {
+                    usb::audio::CS_SAM_FREQ => Control::Fixed(4, true),
+                    usb::audio::CS_CLOCK_VALID => Control::Fixed(1, false),
+                    _ => Control::Invalid,
+                }
+            }
+
+            usb::audio::CLOCK_SELECTOR if v2 => {
+                match selector {
+                    usb::audio::CX_CLOCK_SELECTOR => Control::Fixed(1, false),
+                    _ => Control::Invalid,
+                }
+            }
+
+            usb::audio::CLOCK_MULTIPLIER if v2 => {
+                match selector {
+                    usb::audio::CM_NUMERATOR |
+                    usb::audio::CM_DENOMINATOR => Control::Fixed(2, false),
+                    _ => Control::Invalid,
+                }
+            }
+
+            _ => Control::Unknown,
+        };
+
+        if let Control::Invalid = control {
+            error!("[E023-AUDIO] Unknown control 0x{:x} of entity {}", selector, id);
+        }
+
+        control
+    }
+
+    // Class requests of audio interfaces (Section 5.2 in UAC 1.0 and UAC 2.0). Values of the
+    // controls we know must have the control's size, and UAC2 ranges must hold whole subranges.
+    pub fn check_audio_request(&self,
+                               h: &usbr::ControlPacketHeader,
+                               data: &[u8],
+                               source: Source,
+                               iface: &usb::InterfaceDescriptor)
+                               -> bool {
+
+        let v2: bool = iface.interface_protocol == usb::audio::UAC_VERSION_2;
+        let transfer_in: bool = (h.requesttype & usb::DIR_IN) == usb::DIR_IN;
+
+        // requests with a data-in stage (UAC2 ranges can only be read)
+        let dir_in: bool = match (v2, h.request) {
+
+            (false, usb::audio::SET_CUR) |
+            (false, usb::audio::SET_MIN) |
+            (false, usb::audio::SET_MAX) |
+            (false, usb::audio::SET_RES) |
+            (false, usb::audio::SET_MEM) => false,
+
+            (false, usb::audio::GET_CUR) |
+            (false, usb::audio::GET_MIN) |
+            (false, usb::audio::GET_MAX) |
+            (false, usb::audio::GET_RES) |
+            (false, usb::audio::GET_MEM) |
+            (false, usb::audio::GET_STAT) |
+            (true, usb::audio::RANGE) => true,
+
+            (true, usb::audio::CUR) |
+            (true, usb::audio::MEM) => transfer_in,
+
+            _ => {
+                error!("[E024-AUDIO] Unknown request type 0x{:x}", h.request);
+                return false;
+            }
+        };
+
+        if transfer_in != dir_in {
+            error!("[E025-AUDIO] Request 0x{:x} has the wrong direction", h.request);
+            return false;
+        }
+
+        // The device returns data only for data-in requests, and no more than was asked for
+        if source == Source::Red && ((!transfer_in && !data.is_empty()) || data.len() > h.length as usize) {
+            error!("[E026-AUDIO] Invalid response length {} to request 0x{:x}", data.len(), h.request);
+            return false;
+        }
+
+        let (size, has_range) = match self.control(h, iface, v2) {
+            Control::Invalid => return false,
+            Control::Unknown => return true,
+            Control::Fixed(size, range) => (size, range),
+        };
+
+        // Memory and status requests have layouts of their own
+        match (v2, h.request) {
+            (false, usb::audio::SET_MEM) |
+            (false, usb::audio::GET_MEM) |
+            (false, usb::audio::GET_STAT) |
+            (true, usb::audio::MEM) => return true,
+            _ => {}
+        }
+
+        let range: bool = v2 && h.request == usb::audio::RANGE;
+
+        if range && !has_range {
+            error!("[E027-AUDIO] Range request for a control without a range");
+            return false;
+        }
+
+        if source == Source::Blue && !transfer_in && (data.len() != size || h.length as usize != size) {
+            error!("[E028-AUDIO] Invalid value length {} for a control of {} bytes",
+                   data.len(),
+                   size);
+            return false;
+        }
+
+        if source == Source::Red && transfer_in && h.status == usbr::Result::Success as u8 {
+
+            // The device may return fewer bytes than the value has only if it was asked for fewer
+            let expected: usize = if range && data.len() >= usb::audio::RANGE_HEADER_SIZE {
+                usb::audio::RANGE_HEADER_SIZE + LittleEndian::read_u16(&data[0..2]) as usize * 3 * size
+            } else if range {
+                usb::audio::RANGE_HEADER_SIZE
+            } else {
+                size
+            };
+
+            if data.len() != cmp::min(expected, h.length as usize) {
+                error!("[E029-AUDIO] Response of {} bytes to request 0x{:x}, expected {}",
+                       data.len(),
+                       h.request,
+                       expected);
+                return false;
+            }
+        }
+
+        true
+    }
+}
@@ -1,12 +1,39 @@
+use std::collections::HashMap;
+
 use usb;
 use parser::usbr;
 use parser::Source;
 use byteorder::{ByteOrder, LittleEndian};
+use super::scsi;
+
 
+// Phase of the bulk-only transport on an interface (Section 5 of the BBB spec)
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Phase {
+    Command, // waiting for a CBW
+    Data, // moving the bytes announced by the CBW
+    Status, // waiting for the CSW
+}
+
+// The state of the bulk endpoints of one mass storage interface. The transport runs one command
+// at a time across all LUNs, so the phase belongs to the interface and the LUN to the command.
+struct Transport {
+    phase: Phase,
+    max_lun: u8, // reported by MAX_LUN (0 if the host never asked)
+    tag: u32,
+    lun: u8,
+    transfer_length: u32,
+    transfer_in: bool,
+    transferred: u32, // data bytes moved so far in the data phase
+    cbw: Option<usb::bbb::CommandBlockWrapper>, // command being run
+    response: Vec<u8>, // start of its data-in (see scsi::MAX_RESPONSE)
+    scsi: scsi::ScsiCheck,
+}
 
 pub struct BBBControlCheck {
     header: Option<usb::bbb::CommandHeader>,
     cbw: Option<usb::bbb::CommandBlockWrapper>,
+    transports: HashMap<u8, Transport>, // interface number -> transport state
 }
 
 macro_rules! parse_b_descriptor {
@@ -43,10 +70,14 @@ macro_rules! parse_b_descriptor {
 
 impl BBBControlCheck {
     pub fn new() -> BBBControlCheck {
-        BBBControlCheck { header: None, cbw: None }
+        BBBControlCheck {
+            header: None,
+            cbw: None,
+            transports: HashMap::new(),
+        }
     }
 
-    pub fn check_control_req(&self, h: &usbr::ControlPacketHeader, data: &[u8], source: Source) -> bool {
+    pub fn check_control_req(&mut self, h: &usbr::ControlPacketHeader, data: &[u8], source: Source) -> bool {
 
         match h.request {
 
@@ -69,6 +100,11 @@ impl BBBControlCheck {
                     return false;
                 }
 
+                // Reset recovery aborts whatever command was running
+                if source == Source::Blue {
+                    self.transport(h.index as u8).phase = Phase::Command;
+                }
+
                 true
             }
 
@@ -95,6 +131,8 @@ impl BBBControlCheck {
                         error!("[E008-BBB] Invalid max LUN of {}", data[0]);
                         return false;
                     }
+
+                    self.transport(h.index as u8).max_lun = data[0];
                 }
 
                 true
@@ -199,4 +237,288 @@ impl BBBControlCheck {
 
         true
     }
+
+
+    fn transport(&mut self, iface: u8) -> &mut Transport {
+        self.transports.entry(iface).or_insert_with(Transport::new)
+    }
+
+    // Checks a bulk packet on an endpoint of the bulk-only interface iface. Requests from blue
+    // carry the CBW and data-out; responses from red carry data-in and the CSW. On failure,
+    // returns the name of the check that failed: "bbb_transport" for the wrappers and phases,
+    // "scsi" for the commands they carry.
+    pub fn check_bulk_packet(&mut self,
+                             iface: u8,
+                             h: &usbr::BulkPacketHeader,
+                             data: &[u8],
+                             source: Source)
+                             -> Result<(), &'static str> {
+
+        let transfer_in = (h.ep & usb::DIR_IN) == usb::DIR_IN;
+        let status = h.status;
+        let transport = self.transport(iface);
+        let phase = transport.phase;
+
+        match (source, transfer_in) {
+
+            (Source::Blue, false) => {
+
+                if !transport.host_out(data) {
+                    return Err("bbb_transport");
+                }
+
+                // A new command
+                if phase == Phase::Command && !transport.scsi.check_command(&transport.cbw.unwrap()) {
+                    return Err("scsi");
+                }
+            }
+
+            (Source::Red, true) => {
+
+                if !transport.device_in(status, data) {
+                    return Err("bbb_transport");
+                }
+
+                // The CSW ended the command, so its data-in is complete
+                if phase != Phase::Command && transport.phase == Phase::Command && transport.transfer_in &&
+                   !transport.scsi.check_response(&transport.cbw.unwrap(),
+                                                  &transport.response,
+                                                  transport.transferred as usize) {
+                    return Err("scsi");
+                }
+            }
+
+            (Source::Red, false) => transport.device_out(status),
+
+            // Blue asking for data-in or the CSW carries no payload
+            (Source::Blue, true) => {}
+        }
+
+        Ok(())
+    }
+}
+
+
+impl Transport {
+    fn new() -> Transport {
+        Transport {
+            phase: Phase::Command,
+            max_lun: 0,
+            tag: 0,
+            lun: 0,
+            transfer_length: 0,
+            transfer_in: false,
+            transferred: 0,
+            cbw: None,
+            response: vec![],
+            scsi: scsi::ScsiCheck::new(),
+        }
+    }
+
+    // Bytes sent by blue on the bulk-out endpoint: a CBW or data-out
+    fn host_out(&mut self, data: &[u8]) -> bool {
+
+        match self.phase {
+
+            Phase::Command => self.check_cbw(data),
+
+            Phase::Data if !self.transfer_in => {
+
+                self.transferred = self.transferred.saturating_add(data.len() as u32);
+
+                if self.transferred > self.transfer_length {
+                    error!("[E019-BBB] Host sent {} bytes for a transfer of {}",
+                           self.transferred,
+                           self.transfer_length);
+                    return false;
+                }
+
+                if self.transferred == self.transfer_length {
+                    self.phase = Phase::Status;
+                }
+
+                true
+            }
+
+            Phase::Data => {
+                error!("[E020-BBB] Host sent data during a data-in phase");
+                false
+            }
+
+            Phase::Status => {
+                error!("[E021-BBB] Host sent data while waiting for CSW {}", self.tag);
+                false
+            }
+        }
+    }
+
+    // Completion of a bulk-out transfer. A stall ends the data phase early, and the host then
+    // clears the halt and reads the CSW.
+    fn device_out(&mut self, status: u8) {
+        if status == usbr::Result::Stall as u8 && self.phase == Phase::Data && !self.transfer_in {
+            self.phase = Phase::Status;
+        }
+    }
+
+    // Bytes sent by red on the bulk-in endpoint: data-in or a CSW
+    fn device_in(&mut self, status: u8, data: &[u8]) -> bool {
+
+        match self.phase {
+
+            Phase::Command => {
+                if !data.is_empty() {
+                    error!("[E022-BBB] Device sent {} bytes without a command", data.len());
+                    return false;
+                }
+            }
+
+            Phase::Data if self.transfer_in => {
+
+                // The device may end the data phase early with a short packet or a stall, in
+                // which case the next thing it sends is the CSW
+                if self.transferred < self.transfer_length && self.is_csw(data) {
+                    return self.check_csw(data);
+                }
+
+                self.transferred = self.transferred.saturating_add(data.len() as u32);
+
+                let room = scsi::MAX_RESPONSE.saturating_sub(self.response.len());
+                self.response.extend_from_slice(&data[..::std::cmp::min(room, data.len())]);
+
+                if self.transferred > self.transfer_length {
+                    error!("[E023-BBB] Device sent {} bytes for a transfer of {}",
+                           self.transferred,
+                           self.transfer_length);
+                    return false;
+                }
+
+                if self.transferred == self.transfer_length || status == usbr::Result::Stall as u8 {
+                    self.phase = Phase::Status;
+                }
+            }
+
+            // Nothing comes in during data-out but the CSW of a command the device cut short
+            Phase::Data | Phase::Status => {
+                if !data.is_empty() {
+                    return self.check_csw(data);
+                }
+            }
+        }
+
+        true
+    }
+
+    fn is_csw(&self, data: &[u8]) -> bool {
+        data.len() == usb::bbb::HEADER_SIZE + usb::bbb::CSW_SIZE &&
+        LittleEndian::read_u32(&data[..4]) == usb::bbb::CSW_SIGN &&
+        LittleEndian::read_u32(&data[4..8]) == self.tag
+    }
+
+    fn check_cbw(&mut self, data: &[u8]) -> bool {
+
+        if data.len() != usb::bbb::HEADER_SIZE + usb::bbb::CBW_SIZE {
+            error!("[E024-BBB] Invalid CBW length {}", data.len());
+            return false;
+        }
+
+        let header = parse_b_descriptor!(0, &data[..usb::bbb::HEADER_SIZE]);
+
+        if header.signature != usb::bbb::CBW_SIGN {
+            error!("[E025-BBB] Invalid CBW signature 0x{:x}", header.signature);
+            return false;
+        }
+
+        let cbw = parse_b_descriptor!(usb::bbb::CBW_SIGN, &data[usb::bbb::HEADER_SIZE..]);
+
+        // Only the direction bit of the flags is defined
+        if (cbw.flags & 0x7f) != 0 {
+            error!("[E026-BBB] Invalid CBW flags 0x{:x}", cbw.flags);
+            return false;
+        }
+
+        if (cbw.cb_lun & 0xf0) != 0 || cbw.cb_lun > self.max_lun {
+            error!("[E027-BBB] Invalid LUN {} (max LUN is {})", cbw.cb_lun, self.max_lun);
+            return false;
+        }
+
+        if cbw.cb_length < 1 || cbw.cb_length > 16 {
+            error!("[E028-BBB] Invalid command block length {}", cbw.cb_length);
+            return false;
+        }
+
+        self.tag = header.tag;
+        self.lun = cbw.cb_lun;
+        self.transfer_length = cbw.transfer_length;
+        self.transfer_in = (cbw.flags & usb::DIR_IN) == usb::DIR_IN;
+        self.transferred = 0;
+        self.cbw = Some(cbw);
+        self.response.clear();
+
+        self.phase = if cbw.transfer_length == 0 { Phase::Status } else { Phase::Data };
+
+        true
+    }
+
+    fn check_csw(&mut self, data: &[u8]) -> bool {
+
+        if data.len() != usb::bbb::HEADER_SIZE + usb::bbb::CSW_SIZE {
+            error!("[E029-BBB] Invalid CSW length {}", data.len());
+            return false;
+        }
+
+        let header = parse_b_descriptor!(0, &data[..usb::bbb::HEADER_SIZE]);
+
+        if header.signature != usb::bbb::CSW_SIGN {
+            error!("[E030-BBB] Invalid CSW signature 0x{:x}", header.signature);
+            return false;
+        }
+
+        if header.tag != self.tag {
+            error!("[E031-BBB] CSW tag {} does not match CBW tag {}", header.tag, self.tag);
+            return false;
+        }
+
+        let csw = parse_b_descriptor!(usb::bbb::CSW_SIGN, &data[usb::bbb::HEADER_SIZE..]);
+
+        // The residue is what the device did not process. It cannot claim to have sent less
+        // data-in than it did, or to have taken more data-out than the host sent.
+        let unmoved = self.transfer_length.saturating_sub(self.transferred);
+
+        match csw.status {
+
+            x if x == usb::bbb::STAT_OK || x == usb::bbb::STAT_FAIL => {
+
+                if csw.data_residue > self.transfer_length {
+                    error!("[E032-BBB] Data residue {} higher than transfer length {}",
+                           csw.data_residue,
+                           self.transfer_length);
+                    return false;
+                }
+
+                if self.transfer_in && csw.data_residue > unmoved {
+                    error!("[E033-BBB] Data residue {} but only {} bytes were not sent",
+                           csw.data_residue,
+                           unmoved);
+                    return false;
+                }
+
+                if !self.transfer_in && csw.data_residue < unmoved {
+                    error!("[E034-BBB] Data residue {} but {} bytes were never sent",
+                           csw.data_residue,
+                           unmoved);
+                    return false;
+                }
+            }
+
+            usb::bbb::STAT_PHASE => {}
+
+            _ => {
+                error!("[E035-BBB] Invalid CSW status 0x{:x} for LUN {}", csw.status, self.lun);
+                return false;
+            }
+        }
+
+        self.phase = Phase::Command;
+        true
+    }
 }
@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+
+use usb;
+use parser::usbr;
+use parser::Source;
+use byteorder::{ByteOrder, LittleEndian};
+
+
+pub struct CdcControlCheck {
+    partial: HashMap<u8, Vec<u8>>, // notification split across packets, by interface
+}
+
+
+// Line coding structure (Section 6.2.13 in PSTN 1.2)
+fn check_line_coding(data: &[u8]) -> bool {
+
+    if data.len() != usb::cdc::LINE_CODING_SIZE {
+        error!("[E001-CDC] Invalid line coding length {}", data.len());
+        return false;
+    }
+
+    // stop bits: 1, 1.5 or 2
+    if data[4] > 2 {
+        error!("[E002-CDC] Invalid stop bits {}", data[4]);
+        return false;
+    }
+
+    // parity: none, odd, even, mark or space
+    if data[5] > 4 {
+        error!("[E003-CDC] Invalid parity {}", data[5]);
+        return false;
+    }
+
+    match data[6] {
+        5 | 6 | 7 | 8 | 16 => {}
+        _ => {
+            error!("[E004-CDC] Invalid data bits {}", data[6]);
+            return false;
+        }
+    }
+
+    true
+}
+
+
+// Class requests of abstract control model interfaces (Section 6.2 in PSTN 1.2)
+pub fn check_acm_request(h: &usbr::ControlPacketHeader, data: &[u8], source: Source) -> bool {
+
+    let transfer_in: bool = (h.requesttype & usb::DIR_IN) == usb::DIR_IN;
+
+    // requests with data-in and data-out stages
+    let dir_in: bool = match h.request {
+
+        usb::cdc::GET_ENCAPSULATED_RESPONSE |
+        usb::cdc::GET_COMM_FEATURE |
+        usb::cdc::GET_LINE_CODING => true,
+
+        usb::cdc::SEND_ENCAPSULATED_COMMAND |
+        usb::cdc::SET_COMM_FEATURE |
+        usb::cdc::CLEAR_COMM_FEATURE |
+        usb::cdc::SET_LINE_CODING |
+        usb::cdc::SET_CONTROL_LINE_STATE |
+        usb::cdc::SEND_BREAK => false,
+
+        _ => {
+            error!("[E005-CDC] Unknown request type 0x{:x}", h.request);
+            return false;
+        }
+    };
+
+    if transfer_in != dir_in {
+        error!("[E006-CDC] Request 0x{:x} has the wrong direction", h.request);
+        return false;
+    }
+
+    // The device returns data only for data-in requests, and no more than was asked for
+    if source == Source::Red && ((!transfer_in && !data.is_empty()) || data.len() > h.length as usize) {
+        error!("[E007-CDC] Invalid response length {} to request 0x{:x}", data.len(), h.request);
+        return false;
+    }
+
+    match h.request {
+
+        usb::cdc::SET_LINE_CODING => {
+            if source == Source::Blue && !check_line_coding(data) {
+                return false;
+            }
+        }
+
+        usb::cdc::GET_LINE_CODING => {
+            if source == Source::Red && h.status == usbr::Result::Success as u8 && !check_line_coding(data) {
+                return false;
+            }
+        }
+
+        usb::cdc::SET_CONTROL_LINE_STATE => {
+            // only DTR (bit 0) and RTS (bit 1)
+            let (value, length) = (h.value, h.length);
+
+            if value & 0xfffc != 0 || length != 0 {
+                error!("[E008-CDC] Invalid control line state 0x{:x} or length {}", value, length);
+                return false;
+            }
+        }
+
+        _ => {}
+    }
+
+    true
+}
+
+
+// Notifications of abstract control model interfaces (Section 6.3 in PSTN 1.2)
+fn check_notification_fields(data: &[u8], inum: u8) -> bool {
+
+    let value = LittleEndian::read_u16(&data[2..4]);
+    let index = LittleEndian::read_u16(&data[4..6]);
+    let length = LittleEndian::read_u16(&data[6..8]) as usize;
+
+    if data[0] != usb::DIR_IN | usb::TYPE_CLASS | usb::RECIP_INTERFACE || index != inum as u16 {
+        error!("[E009-CDC] Invalid notification request type 0x{:x} or interface {}",
+               data[0],
+               index);
+        return false;
+    }
+
+    let valid = match data[1] {
+        usb::cdc::NETWORK_CONNECTION => value <= 1 && length == 0,
+        usb::cdc::RESPONSE_AVAILABLE => value == 0 && length == 0,
+
+        // only bits 0-6 of the UART state are defined
+        usb::cdc::SERIAL_STATE => {
+            value == 0 && length == usb::cdc::SERIAL_STATE_SIZE &&
+            LittleEndian::read_u16(&data[usb::cdc::NOTIFICATION_HEADER_SIZE..]) & 0xff80 == 0
+        }
+
+        _ => {
+            error!("[E010-CDC] Unknown notification 0x{:x}", data[1]);
+            return false;
+        }
+    };
+
+    if !valid {
+        error!("[E011-CDC] Invalid notification 0x{:x} with value 0x{:x} and length {}",
+               data[1],
+               value,
+               length);
+        return false;
+    }
+
+    true
+}
+
+
+// Functional descriptors that follow a communications interface (Section 5.2.3 in CDC
+// 1.2). The header must come first, and abstract control model interfaces must have an
+// ACM descriptor. Other functional descriptors (e.g., of networking subclasses) only need a
+// valid length. This call updates off.
+pub fn check_functional_desc(iface: &usb::InterfaceDescriptor, data: &[u8], off: &mut usize) -> bool {
+
+    let mut header: bool = false;
+    let mut acm: bool = false;
+
+    while data.len() >= *off + usb::cdc::FD_MIN_SIZE && data[*off + 1] == usb::DT_CS_INTERFACE {
+
+        let length: usize = data[*off] as usize;
+        let subtype: u8 = data[*off + 2];
+
+        if length < usb::cdc::FD_MIN_SIZE || data.len() < *off + length {
+            error!("[E012-CDC] Invalid functional descriptor length {}", length);
+            return false;
+        }
+
+        let desc = &data[*off..*off + length];
+        *off += length;
+
+        if !header && subtype != usb::cdc::FD_HEADER {
+            error!("[E013-CDC] Functional descriptor 0x{:x} before the header", subtype);
+            return false;
+        }
+
+        match subtype {
+
+            usb::cdc::FD_HEADER => {
+                if header || length != usb::cdc::FD_HEADER_SIZE ||
+                   !super::check_bcd(LittleEndian::read_u16(&desc[3..5])) {
+                    error!("[E014-CDC] Invalid or repeated header descriptor");
+                    return false;
+                }
+
+                header = true;
+            }
+
+            // only bits 0 (handles call management) and 1 (over the data interface)
+            usb::cdc::FD_CALL_MANAGEMENT => {
+                if length != usb::cdc::FD_CALL_MANAGEMENT_SIZE || desc[3] & 0xfc != 0 {
+                    error!("[E015-CDC] Invalid call management descriptor");
+                    return false;
+                }
+            }
+
+            // only bits 0-3 of the capabilities
+            usb::cdc::FD_ACM => {
+                if acm || length != usb::cdc::FD_ACM_SIZE || desc[3] & 0xf0 != 0 {
+                    error!("[E016-CDC] Invalid or repeated ACM descriptor");
+                    return false;
+                }
+
+                acm = true;
+            }
+
+            // the controlling interface is this one, and it cannot be its own subordinate
+            usb::cdc::FD_UNION => {
+                if length < usb::cdc::FD_UNION_MIN_SIZE || desc[3] != iface.interface_number ||
+                   desc[4..].contains(&iface.interface_number) {
+                    error!("[E017-CDC] Invalid union descriptor for interface {}",
+                           iface.interface_number);
+                    return false;
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    if !header {
+        error!("[E018-CDC] Communications interface {} without header descriptor",
+               iface.interface_number);
+        return false;
+    }
+
+    if iface.interface_subclass == usb::cdc::SC_ACM && !acm {
+        error!("[E019-CDC] ACM interface {} without ACM descriptor", iface.interface_number);
+        return false;
+    }
+
+    true
+}
+
+
+impl CdcControlCheck {
+    pub fn new() -> CdcControlCheck {
+        CdcControlCheck { partial: HashMap::new() }
+    }
+
+    // Red's packet on the notification endpoint of ACM interface inum. Notifications longer than
+    // the endpoint's packets come in several, and a short packet ends them.
+    pub fn check_int_packet(&mut self, inum: u8, max_packet: usize, data: &[u8]) -> bool {
+
+        if data.is_empty() && !self.partial.contains_key(&inum) {
+            return true;
+        }
+
+        let mut notification = self.partial.remove(&inum).unwrap_or_default();
+        notification.extend_from_slice(data);
+
+        // The header tells the length of the whole notification
+        let total: usize = if notification.len() >= usb::cdc::NOTIFICATION_HEADER_SIZE {
+            usb::cdc::NOTIFICATION_HEADER_SIZE + LittleEndian::read_u16(&notification[6..8]) as usize
+        } else {
+            usb::cdc::NOTIFICATION_HEADER_SIZE
+        };
+
+        if notification.len() < total {
+
+            if data.len() < max_packet {
+                error!("[E020-CDC] Notification ends after {} of {} bytes", notification.len(), total);
+                return false;
+            }
+
+            self.partial.insert(inum, notification);
+            return true;
+        }
+
+        if notification.len() != total {
+            error!("[E021-CDC] Notification of {} bytes is {} bytes long", total, notification.len());
+            return false;
+        }
+
+        check_notification_fields(&notification, inum)
+    }
+}
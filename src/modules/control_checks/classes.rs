@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use usb;
+use util::config::DeviceClassConfig;
+
+
+// A kind of device and the interface classes it may expose (e.g., a flash drive only has mass
+// storage interfaces, so one that also has a keyboard or a network interface is not a flash drive)
+struct Role {
+    name: String,
+    primary: Option<u8>,
+    classes: Vec<u8>,
+}
+
+// The interface classes that each configuration of a device (by VID:PID) had the first time it
+// was described. Shared by every session, so a device that comes back as something else (e.g.,
+// storage that re-enumerates as a keyboard) is caught.
+pub struct ClassHistory {
+    seen: Mutex<HashMap<(u16, u16, u8), Vec<u8>>>,
+}
+
+impl ClassHistory {
+    pub fn new() -> ClassHistory {
+        ClassHistory { seen: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl Default for ClassHistory {
+    fn default() -> ClassHistory {
+        ClassHistory::new()
+    }
+}
+
+
+pub struct ClassPolicy {
+    roles: Vec<Role>,
+    devices: HashMap<(u16, u16), String>, // VID:PID -> role name
+    history: Arc<ClassHistory>,
+}
+
+fn parse_id(id: &str) -> Option<(u16, u16)> {
+
+    let mut parts = id.split(':');
+
+    let vid = parts.next().and_then(|v| u16::from_str_radix(v, 16).ok())?;
+    let pid = parts.next().and_then(|v| u16::from_str_radix(v, 16).ok())?;
+
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some((vid, pid))
+}
+
+// Classes of the interface descriptors in a complete configuration descriptor (which has
+// already been checked), in the order in which they appear
+pub fn config_classes(data: &[u8]) -> Vec<u8> {
+
+    let mut classes = vec![];
+    let mut off: usize = 0;
+
+    while off + usb::HEADER_SIZE <= data.len() {
+
+        let length = data[off] as usize;
+
+        if length < usb::HEADER_SIZE {
+            break;
+        }
+
+        if data[off + 1] == usb::DT_INTERFACE && off + usb::HEADER_SIZE + usb::INTERFACE_DESC_SIZE <= data.len() {
+            // class is the fifth field of the interface descriptor
+            classes.push(data[off + usb::HEADER_SIZE + 3]);
+        }
+
+        off += length;
+    }
+
+    classes
+}
+
+impl ClassPolicy {
+    // Built-in roles: storage and HID devices may only expose their own class
+    pub fn new(history: Arc<ClassHistory>) -> ClassPolicy {
+
+        let role = |name: &str, class: u8| {
+            Role {
+                name: name.to_string(),
+                primary: Some(class),
+                classes: vec![class],
+            }
+        };
+
+        ClassPolicy {
+            roles: vec![role("storage", usb::CLASS_MASS_STORAGE), role("hid", usb::CLASS_HID)],
+            devices: HashMap::new(),
+            history: history,
+        }
+    }
+
+    // Builds the policy from the "device_classes" configuration entry
+    pub fn from_config(config: &DeviceClassConfig, history: Arc<ClassHistory>) -> Result<ClassPolicy, String> {
+
+        let roles: Vec<Role> = config.roles
+            .iter()
+            .map(|r| {
+                Role {
+                    name: r.name.clone(),
+                    primary: r.primary,
+                    classes: r.classes.clone(),
+                }
+            })
+            .collect();
+
+        let mut devices = HashMap::new();
+
+        if let Some(ref map) = config.devices {
+            for (id, role) in map {
+
+                let id = match parse_id(id) {
+                    Some(v) => v,
+                    None => return Err(format!("invalid VID:PID {} in device_classes", id)),
+                };
+
+                if !roles.iter().any(|r| &r.name == role) {
+                    return Err(format!("unknown role {} in device_classes", role));
+                }
+
+                devices.insert(id, role.clone());
+            }
+        }
+
+        Ok(ClassPolicy {
+            roles: roles,
+            devices: devices,
+            history: history,
+        })
+    }
+
+    fn role(&self, vendor_id: u16, product_id: u16, classes: &[u8]) -> Option<&Role> {
+
+        match self.devices.get(&(vendor_id, product_id)) {
+            Some(name) => self.roles.iter().find(|r| &r.name == name),
+            None => {
+                let primary = classes.first()?;
+                self.roles.iter().find(|r| r.primary == Some(*primary))
+            }
+        }
+    }
+
+    // Checks the interface classes of configuration index of a device. Devices without a role
+    // may expose any classes, but must expose the same ones every time.
+    pub fn check(&self, vendor_id: u16, product_id: u16, index: u8, classes: &[u8]) -> bool {
+
+        if let Some(role) = self.role(vendor_id, product_id, classes) {
+            if let Some(class) = classes.iter().find(|c| !role.classes.contains(c)) {
+                error!("[E001-Classes] Device {:04x}:{:04x} ({}) exposes an interface of class 0x{:x}",
+                       vendor_id,
+                       product_id,
+                       role.name,
+                       class);
+                return false;
+            }
+        }
+
+        let mut set = classes.to_vec();
+        set.sort();
+        set.dedup();
+
+        let mut seen = self.history.seen.lock().unwrap();
+        let previous = seen.entry((vendor_id, product_id, index)).or_insert_with(|| set.clone());
+
+        if *previous != set {
+            error!("[E002-Classes] Device {:04x}:{:04x} changed the interface classes of configuration {} \
+                    from {:?} to {:?}",
+                   vendor_id,
+                   product_id,
+                   index,
+                   previous,
+                   set);
+            return false;
+        }
+
+        true
+    }
+}
@@ -49,6 +49,69 @@ macro_rules! parse_hid_descriptor {
 
 pub struct HidControlCheck {
     descs: HashMap<(u8, u8), usb::hid::HidDescriptor>, // maps interface to hid descriptor
+    layouts: HashMap<(u8, u8), ReportLayout>, // maps interface to its compiled report descriptor
+    boot: HashMap<u8, bool>, // interfaces that blue switched to the boot protocol
+    pending: HashMap<u8, usize>, // bytes left of an input report split across packets
+}
+
+
+// Limits from the Linux HID core, which rejects descriptors that exceed them
+const MAX_REPORT_SIZE: u32 = 256;
+const MAX_REPORT_COUNT: u32 = 12288;
+const MAX_REPORT_BYTES: u32 = 16384;
+const MAX_PUSH: usize = 4;
+
+// Boot protocol reports (Appendix B, Page 59 in spec/usb-hid.pdf) fit in 8 bytes
+const BOOT_REPORT_MAX: usize = 8;
+
+
+// Global items that shape the reports. Push and pop save and restore all of them.
+#[derive(Copy, Clone, Default)]
+struct Globals {
+    report_size: u32,
+    report_count: u32,
+    logical_min: i32,
+    logical_max: i64,
+    ranged: bool, // a logical maximum was declared
+    report_id: u8,
+}
+
+
+// An input item: count values of size bits each, starting offset bits into the report (after
+// the report id)
+struct Field {
+    offset: u32,
+    size: u32,
+    count: u32,
+    logical_min: i32,
+    logical_max: i64,
+    ranged: bool, // variable data with a logical range and no null state
+}
+
+// What the report descriptor declares: the fields of each input report, by report id (0 if
+// the device does not use report ids)
+pub struct ReportLayout {
+    numbered: bool,
+    input: HashMap<u8, Vec<Field>>,
+}
+
+
+fn item_unsigned(data: &[u8]) -> u32 {
+    match data.len() {
+        1 => data[0] as u32,
+        2 => LittleEndian::read_u16(data) as u32,
+        4 => LittleEndian::read_u32(data),
+        _ => 0,
+    }
+}
+
+fn item_signed(data: &[u8]) -> i32 {
+    match data.len() {
+        1 => data[0] as i8 as i32,
+        2 => LittleEndian::read_i16(data) as i32,
+        4 => LittleEndian::read_i32(data),
+        _ => 0,
+    }
 }
 
 
@@ -261,6 +324,205 @@ fn check_hid_item_fields(item: &usb::hid::HidReportItem) -> bool {
 }
 
 
+impl ReportLayout {
+
+    // Follows the global state through the items (Section 6.2.2.7, Pages 35-37 in
+    // spec/usb-hid.pdf) and adds up the size of each report
+    pub fn compile(items: &[usb::hid::HidReportItem]) -> Option<ReportLayout> {
+
+        let mut globals = Globals::default();
+        let mut stack: Vec<Globals> = vec![];
+        let mut ids: Vec<u8> = vec![];
+        let mut input: HashMap<u8, Vec<Field>> = HashMap::new();
+        let mut unnumbered = false;
+
+        for item in items {
+
+            let tag = (item.attributes & usb::hid::ITEM_TAG_MASK) >> 4;
+
+            match (item.attributes & usb::hid::ITEM_TYPE_MASK) >> 2 {
+
+                usb::hid::ITEM_GLOBAL => {
+
+                    match tag {
+                        usb::hid::TAG_REPORT_SIZE => globals.report_size = item_unsigned(&item.data),
+                        usb::hid::TAG_REPORT_COUNT => globals.report_count = item_unsigned(&item.data),
+                        usb::hid::TAG_LOGIC_MIN => globals.logical_min = item_signed(&item.data),
+
+                        // Descriptors often put 255 in a single byte. Like the Linux HID core,
+                        // the maximum is only signed when the minimum is negative.
+                        usb::hid::TAG_LOGIC_MAX => {
+                            globals.logical_max = if globals.logical_min < 0 {
+                                item_signed(&item.data) as i64
+                            } else {
+                                item_unsigned(&item.data) as i64
+                            };
+
+                            globals.ranged = true;
+                        }
+
+                        usb::hid::TAG_REPORT_ID => {
+                            let id = item_unsigned(&item.data);
+
+                            if id > 0xff {
+                                error!("[E056-HID] hid report id {} does not fit in a byte", id);
+                                return None;
+                            }
+
+                            globals.report_id = id as u8;
+                            ids.push(id as u8);
+                        }
+
+                        usb::hid::TAG_PUSH => {
+                            if stack.len() >= MAX_PUSH {
+                                error!("[E057-HID] hid push items nested too deep");
+                                return None;
+                            }
+
+                            stack.push(globals);
+                        }
+
+                        usb::hid::TAG_POP => {
+                            globals = match stack.pop() {
+                                Some(g) => g,
+                                None => {
+                                    error!("[E058-HID] hid pop item without push");
+                                    return None;
+                                }
+                            };
+                        }
+
+                        _ => {}
+                    }
+                }
+
+                usb::hid::ITEM_MAIN => {
+
+                    if tag != usb::hid::TAG_INPUT && tag != usb::hid::TAG_OUTPUT && tag != usb::hid::TAG_FEATURE {
+                        continue;
+                    }
+
+                    if globals.report_size > MAX_REPORT_SIZE || globals.report_count > MAX_REPORT_COUNT {
+                        error!("[E059-HID] hid report size {} or count {} too large",
+                               globals.report_size,
+                               globals.report_count);
+                        return None;
+                    }
+
+                    if (globals.logical_min as i64) > globals.logical_max {
+                        error!("[E060-HID] hid logical minimum {} above logical maximum {}",
+                               globals.logical_min,
+                               globals.logical_max);
+                        return None;
+                    }
+
+                    if globals.report_id == 0 {
+                        unnumbered = true;
+                    }
+
+                    if tag == usb::hid::TAG_INPUT {
+
+                        let flags: u8 = item.data.first().cloned().unwrap_or(0);
+                        let fields = input.entry(globals.report_id).or_default();
+                        let offset: u32 = fields.iter().map(|f| f.size * f.count).sum();
+                        let bits: u32 = offset + globals.report_size * globals.report_count;
+
+                        if bits > MAX_REPORT_BYTES * 8 {
+                            error!("[E061-HID] hid input report {} too long ({} bits)",
+                                   globals.report_id,
+                                   bits);
+                            return None;
+                        }
+
+                        fields.push(Field {
+                            offset: offset,
+                            size: globals.report_size,
+                            count: globals.report_count,
+                            logical_min: globals.logical_min,
+                            logical_max: globals.logical_max,
+                            ranged: globals.ranged &&
+                                    flags & (usb::hid::MAIN_CONSTANT | usb::hid::MAIN_VARIABLE |
+                                             usb::hid::MAIN_NULL_STATE) ==
+                                    usb::hid::MAIN_VARIABLE,
+                        });
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        // Section 6.2.2.7, Page 36 in spec/usb-hid.pdf: once a report id is declared, every
+        // report must have one
+        if !ids.is_empty() && unnumbered {
+            error!("[E062-HID] hid reports with and without report ids");
+            return None;
+        }
+
+        Some(ReportLayout {
+            numbered: !ids.is_empty(),
+            input: input,
+        })
+    }
+
+    // Length in bytes of input report id, including the report id byte if reports have one
+    fn input_length(&self, id: u8) -> Option<usize> {
+        let bits: u32 = self.input.get(&id)?.iter().map(|f| f.size * f.count).sum();
+        let bytes = ((bits + 7) >> 3) as usize;
+        Some(if self.numbered { bytes + 1 } else { bytes })
+    }
+
+    // Values of the variable fields of a whole input report must be in their logical range.
+    // Array fields hold usage indexes (out of range meaning no usage), so they are not checked.
+    fn check_values(&self, report: &[u8]) -> bool {
+
+        let (id, values) = if self.numbered { (report[0], &report[1..]) } else { (0, report) };
+
+        let fields = match self.input.get(&id) {
+            Some(v) => v,
+            None => return true,
+        };
+
+        for f in fields.iter().filter(|f| f.ranged && f.size > 0 && f.size <= 32) {
+            for i in 0..f.count {
+
+                let value = field_value(values, f.offset + i * f.size, f.size, f.logical_min < 0);
+
+                if value < f.logical_min as i64 || value > f.logical_max {
+                    error!("[E066-HID] input report {} has value {} outside {}..{}",
+                           id,
+                           value,
+                           f.logical_min,
+                           f.logical_max);
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+}
+
+// The size-bit value at bit offset of a report (Section 8.4, Page 55 in spec/usb-hid.pdf: fields
+// are little-endian and packed without padding). Signed values are in two's complement.
+fn field_value(data: &[u8], offset: u32, size: u32, signed: bool) -> i64 {
+
+    let mut value: i64 = 0;
+
+    for bit in 0..size {
+        let pos = (offset + bit) as usize;
+        if (data[pos >> 3] >> (pos & 7)) & 1 == 1 {
+            value |= 1 << bit;
+        }
+    }
+
+    if signed && (value >> (size - 1)) & 1 == 1 {
+        value -= 1 << size;
+    }
+
+    value
+}
+
 pub fn check_hid_request(h: &usbr::ControlPacketHeader, data: &[u8], source: Source) -> bool {
 
     match h.request {
@@ -354,7 +616,12 @@ pub fn check_hid_request(h: &usbr::ControlPacketHeader, data: &[u8], source: Sou
 
 impl HidControlCheck {
     pub fn new() -> HidControlCheck {
-        HidControlCheck { descs: HashMap::new() }
+        HidControlCheck {
+            descs: HashMap::new(),
+            layouts: HashMap::new(),
+            boot: HashMap::new(),
+            pending: HashMap::new(),
+        }
     }
 
     pub fn check_hid_desc(&mut self,
@@ -384,7 +651,7 @@ impl HidControlCheck {
     }
 
 
-    pub fn check_hid_report_desc(&self, data: &[u8], inum: u8, alt: u8) -> bool {
+    pub fn check_hid_report_desc(&mut self, data: &[u8], inum: u8, alt: u8) -> bool {
 
         let desc: &usb::hid::HidDescriptor = match self.descs.get(&(inum, alt)) {
             None => {
@@ -410,10 +677,12 @@ impl HidControlCheck {
         // Go through each item in the report
 
         let mut off: usize = 0;
+        let mut items = vec![];
 
         while off < desc.desc[0].descriptor_length as usize {
-            if !self.check_hid_report_item(data, &mut off) {
-                return false;
+            match self.check_hid_report_item(data, &mut off) {
+                Some(item) => items.push(item),
+                None => return false,
             }
         }
 
@@ -422,15 +691,24 @@ impl HidControlCheck {
             return false;
         }
 
+        // Add the report layout to our model
+        match ReportLayout::compile(&items) {
+            Some(layout) => {
+                self.layouts.insert((inum, alt), layout);
+            }
+
+            None => return false,
+        }
+
         true
     }
 
 
-    pub fn check_hid_report_item(&self, data: &[u8], off: &mut usize) -> bool {
+    pub fn check_hid_report_item(&self, data: &[u8], off: &mut usize) -> Option<usb::hid::HidReportItem> {
 
         if data.len() < *off + usb::hid::REPORT_MIN_SIZE {
             error!("[E036-HID] Not enough payload for report item.");
-            return false;
+            return None;
         }
 
         let mut item = parse_hid_descriptor!(usb::hid::DT_HID_REPORT, &data[*off..]);
@@ -447,13 +725,13 @@ impl HidControlCheck {
                        item_size,
                        item.attributes & usb::hid::ITEM_TYPE_MASK);
 
-                return false;
+                return None;
             }
 
             // Parse the first (required) part of the optional item data.
             if data.len() < *off + item_size {
                 error!("[E038-HID] not enough payload for report item");
-                return false;
+                return None;
             }
 
             item.data.extend_from_slice(&data[*off..*off + item_size]);
@@ -463,7 +741,7 @@ impl HidControlCheck {
 
             if data.len() < *off + data_size {
                 error!("[E039-HID not enough payload for report item optional data");
-                return false;
+                return None;
             }
 
             item.data.extend_from_slice(&data[*off..*off + data_size]);
@@ -477,7 +755,7 @@ impl HidControlCheck {
 
             if (item.attributes & usb::hid::ITEM_TYPE_MASK) == (0x03 << 2) {
                 error!("[E040-HID] Invalid type in short format item");
-                return false;
+                return None;
             }
 
             let item_size = if (item.attributes & usb::hid::ITEM_SIZE_MASK) == 3 {
@@ -489,18 +767,18 @@ impl HidControlCheck {
             // Parse the optional item data.
             if data.len() < *off + item_size {
                 error!("[E041-HID] Not enough payload for report item optional data");
-                return false;
+                return None;
             }
 
             item.data.extend_from_slice(&data[*off..*off + item_size]);
             *off += item_size;
 
             if !check_hid_item_fields(&item) {
-                return false;
+                return None;
             }
         }
 
-        true
+        Some(item)
     }
 
     pub fn check_hid_get_desc(&mut self, h: &usbr::ControlPacketHeader, data: &[u8], inum: u8, alt: u8) -> bool {
@@ -551,4 +829,69 @@ impl HidControlCheck {
 
         false
     }
+
+    // Blue switched interface inum to the boot (0) or report (1) protocol
+    pub fn set_protocol(&mut self, inum: u8, protocol: u16) {
+        self.boot.insert(inum, protocol == 0);
+        self.pending.remove(&inum);
+    }
+
+    // An interrupt in packet that red sent on an endpoint of interface inum. Reports longer than
+    // the endpoint's max packet size arrive in several packets.
+    pub fn check_int_packet(&mut self, inum: u8, alt: u8, max_packet: usize, data: &[u8]) -> bool {
+
+        if data.is_empty() {
+            return true;
+        }
+
+        if *self.boot.get(&inum).unwrap_or(&false) {
+            if data.len() > BOOT_REPORT_MAX {
+                error!("[E063-HID] boot protocol report of {} bytes", data.len());
+                return false;
+            }
+
+            return true;
+        }
+
+        // Without a report descriptor there is nothing to check against
+        let layout = match self.layouts.get(&(inum, alt)) {
+            Some(v) => v,
+            None => return true,
+        };
+
+        let continued: bool = self.pending.contains_key(&inum);
+
+        let expected = match self.pending.remove(&inum) {
+            Some(v) => v,
+
+            None => {
+                let id = if layout.numbered { data[0] } else { 0 };
+
+                match layout.input_length(id) {
+                    Some(v) => v,
+                    None => {
+                        error!("[E064-HID] input report with undeclared report id {}", id);
+                        return false;
+                    }
+                }
+            }
+        };
+
+        if data.len() < expected && data.len() == max_packet {
+            self.pending.insert(inum, expected - data.len());
+            return true;
+        }
+
+        if data.len() != expected {
+            error!("[E065-HID] input report of {} bytes, expected {}", data.len(), expected);
+            return false;
+        }
+
+        // Only reports that arrive in a single packet have all their values here
+        if !continued && !layout.check_values(data) {
+            return false;
+        }
+
+        true
+    }
 }
@@ -0,0 +1,321 @@
+use std::cmp;
+
+use usb;
+use parser::usbr;
+use parser::Source;
+use byteorder::{ByteOrder, LittleEndian};
+
+
+pub struct HubControlCheck {
+    ports: Option<u8>, // downstream ports that the hub descriptor declares
+    ss: bool, // the hub has a SuperSpeed hub descriptor
+}
+
+
+// Bytes of a bitmap with a bit for the hub (bit 0) and one for each port
+fn bitmap_size(ports: u8) -> usize {
+    (ports as usize + 1 + 7) / 8
+}
+
+// Whether a bitmap only has bits for ports that exist (and for the hub, if hub_bit)
+fn check_bitmap(bitmap: &[u8], ports: u8, hub_bit: bool) -> bool {
+    bitmap.iter().enumerate().all(|(i, b)| {
+        (0..8).all(|bit| {
+            let n: usize = 8 * i + bit;
+            (b >> bit) & 1 == 0 || (n <= ports as usize && (n != 0 || hub_bit))
+        })
+    })
+}
+
+// Port features that hubs of each kind have (Table 11-17 in spec/usb2.pdf and Table 10-9 in
+// spec/usb3.pdf)
+fn check_port_feature(selector: u16, ss: bool) -> bool {
+    match selector {
+
+        usb::hub::PORT_CONNECTION |
+        usb::hub::PORT_ENABLE |
+        usb::hub::PORT_OVER_CURRENT |
+        usb::hub::PORT_RESET |
+        usb::hub::PORT_POWER |
+        usb::hub::C_PORT_CONNECTION |
+        usb::hub::C_PORT_OVER_CURRENT |
+        usb::hub::C_PORT_RESET => true,
+
+        usb::hub::PORT_SUSPEND |
+        usb::hub::PORT_LOW_SPEED |
+        usb::hub::C_PORT_ENABLE |
+        usb::hub::C_PORT_SUSPEND |
+        usb::hub::PORT_TEST |
+        usb::hub::PORT_INDICATOR => !ss,
+
+        usb::hub::PORT_LINK_STATE |
+        usb::hub::PORT_U1_TIMEOUT |
+        usb::hub::PORT_U2_TIMEOUT |
+        usb::hub::C_PORT_LINK_STATE |
+        usb::hub::C_PORT_CONFIG_ERROR |
+        usb::hub::PORT_REMOTE_WAKE_MASK |
+        usb::hub::BH_PORT_RESET |
+        usb::hub::C_BH_PORT_RESET |
+        usb::hub::FORCE_LINKPM_ACCEPT => ss,
+
+        _ => false,
+    }
+}
+
+// Hub status (Section 11.24.2.6 in spec/usb2.pdf): only local power and over-current
+fn check_hub_status(data: &[u8]) -> bool {
+
+    if data.len() != usb::hub::STATUS_SIZE {
+        error!("[E001-HUB] Invalid hub status length {}", data.len());
+        return false;
+    }
+
+    let status = LittleEndian::read_u16(&data[0..2]);
+    let change = LittleEndian::read_u16(&data[2..4]);
+
+    if status & 0xfffc != 0 || change & 0xfffc != 0 {
+        error!("[E002-HUB] Hub status 0x{:x} or change 0x{:x} uses reserved bits",
+               status,
+               change);
+        return false;
+    }
+
+    true
+}
+
+
+impl HubControlCheck {
+    pub fn new() -> HubControlCheck {
+        HubControlCheck {
+            ports: None,
+            ss: false,
+        }
+    }
+
+    // Hub descriptor (Section 11.23.2.1 in spec/usb2.pdf and 10.15.2.1 in spec/usb3.pdf). The
+    // host may ask for the first bytes only.
+    fn check_hub_desc(&mut self, h: &usbr::ControlPacketHeader, data: &[u8]) -> bool {
+
+        let dt: u8 = (h.value >> 8) as u8;
+        let ss: bool = dt == usb::hub::DT_SS_HUB;
+
+        if data.is_empty() || data.len() != cmp::min(data[0] as usize, h.length as usize) ||
+           (data.len() > 1 && data[1] != dt) || (!ss && dt != usb::hub::DT_HUB) {
+            error!("[E003-HUB] Invalid hub descriptor length {} or type 0x{:x}", data.len(), dt);
+            return false;
+        }
+
+        if data.len() < 3 {
+            return true;
+        }
+
+        let ports: u8 = data[2];
+        let max: u8 = if ss { usb::hub::MAX_SS_PORTS } else { usb::hub::MAX_PORTS };
+
+        if ports == 0 || ports > max {
+            error!("[E004-HUB] Invalid number of ports {}", ports);
+            return false;
+        }
+
+        let length: usize = if ss {
+            usb::hub::SS_HUB_DESC_SIZE
+        } else {
+            usb::hub::HUB_DESC_FIXED_SIZE + 2 * bitmap_size(ports)
+        };
+
+        if data[0] as usize != length {
+            error!("[E005-HUB] Hub descriptor length {} for {} ports", data[0], ports);
+            return false;
+        }
+
+        // power switching, compound device, over-current protection, TT think time and port
+        // indicators (USB 3 hubs have neither of the last two)
+        if data.len() >= 5 && LittleEndian::read_u16(&data[3..5]) & if ss { 0xffe0 } else { 0xff00 } != 0 {
+            error!("[E006-HUB] Hub characteristics use reserved bits");
+            return false;
+        }
+
+        // Bit 0 of DeviceRemovable is reserved
+        if data.len() == length {
+
+            let removable = if ss { &data[10..12] } else { &data[7..7 + bitmap_size(ports)] };
+
+            if !check_bitmap(removable, ports, false) {
+                error!("[E007-HUB] DeviceRemovable has bits for ports the hub does not have");
+                return false;
+            }
+        }
+
+        self.ports = Some(ports);
+        self.ss = ss;
+
+        true
+    }
+
+    // Port status (Section 11.24.2.7 in spec/usb2.pdf and 10.16.2.6 in spec/usb3.pdf)
+    fn check_port_status(&self, h: &usbr::ControlPacketHeader, data: &[u8]) -> bool {
+
+        let value: u16 = h.value;
+        let status_type: u16 = if self.ss { value } else { usb::hub::PORT_STATUS_STANDARD };
+
+        let size: usize = match status_type {
+            usb::hub::PORT_STATUS_STANDARD |
+            usb::hub::PORT_STATUS_PD => usb::hub::STATUS_SIZE,
+            usb::hub::PORT_STATUS_EXT => usb::hub::EXT_STATUS_SIZE,
+            _ => 0,
+        };
+
+        if size == 0 || (!self.ss && value != 0) || data.len() != size {
+            error!("[E008-HUB] Invalid port status type {} or length {}", value, data.len());
+            return false;
+        }
+
+        if status_type == usb::hub::PORT_STATUS_PD {
+            return true;
+        }
+
+        let status = LittleEndian::read_u16(&data[0..2]);
+        let change = LittleEndian::read_u16(&data[2..4]);
+
+        // USB 2 ports are not both low and high speed
+        let valid = if self.ss {
+            status & 0xe004 == 0 && change & 0xff06 == 0
+        } else {
+            status & 0xe0e0 == 0 && change & 0xffe0 == 0 && status & 0x0600 != 0x0600
+        };
+
+        if !valid {
+            error!("[E009-HUB] Port status 0x{:x} or change 0x{:x} uses reserved bits",
+                   status,
+                   change);
+            return false;
+        }
+
+        // speed ids and lane counts
+        if status_type == usb::hub::PORT_STATUS_EXT && LittleEndian::read_u32(&data[4..8]) & 0xffff0000 != 0 {
+            error!("[E010-HUB] Extended port status uses reserved bits");
+            return false;
+        }
+
+        true
+    }
+
+    // Class requests to the hub (recipient device) or to one of its ports (recipient other)
+    pub fn check_hub_request(&mut self, h: &usbr::ControlPacketHeader, data: &[u8], source: Source) -> bool {
+
+        let transfer_in: bool = (h.requesttype & usb::DIR_IN) == usb::DIR_IN;
+        let port_request: bool = (h.requesttype & usb::RECIP_MASK) == usb::RECIP_OTHER;
+
+        // requests with a data-in stage, and requests to ports
+        let (dir_in, to_port) = match h.request {
+            usb::hub::GET_STATUS => (true, port_request),
+            usb::hub::CLEAR_FEATURE |
+            usb::hub::SET_FEATURE => (false, port_request),
+            usb::hub::GET_DESCRIPTOR => (true, false),
+            usb::hub::SET_DESCRIPTOR |
+            usb::hub::SET_HUB_DEPTH => (false, false),
+            usb::hub::GET_TT_STATE |
+            usb::hub::GET_PORT_ERR_COUNT => (true, true),
+            usb::hub::CLEAR_TT_BUFFER |
+            usb::hub::RESET_TT |
+            usb::hub::STOP_TT => (false, true),
+            _ => {
+                error!("[E011-HUB] Unknown request type 0x{:x}", h.request);
+                return false;
+            }
+        };
+
+        if transfer_in != dir_in || port_request != to_port {
+            error!("[E012-HUB] Request 0x{:x} has the wrong direction or recipient", h.request);
+            return false;
+        }
+
+        // The device returns data only for data-in requests, and no more than was asked for
+        if source == Source::Red && ((!transfer_in && !data.is_empty()) || data.len() > h.length as usize) {
+            error!("[E013-HUB] Invalid response length {} to request 0x{:x}", data.len(), h.request);
+            return false;
+        }
+
+        // The low byte of wIndex is the port (the high byte may select a test mode or indicator)
+        if port_request {
+
+            let port: u8 = h.index as u8;
+
+            if !self.ports.iter().any(|n| port >= 1 && port <= *n) {
+                error!("[E014-HUB] Request for port {} of a hub with {:?} ports", port, self.ports);
+                return false;
+            }
+        }
+
+        let success: bool = source == Source::Red && h.status == usbr::Result::Success as u8;
+
+        match h.request {
+
+            usb::hub::GET_DESCRIPTOR => {
+                if success && !self.check_hub_desc(h, data) {
+                    return false;
+                }
+            }
+
+            usb::hub::GET_STATUS => {
+                if success && port_request && !self.check_port_status(h, data) {
+                    return false;
+                }
+
+                if success && !port_request && !check_hub_status(data) {
+                    return false;
+                }
+            }
+
+            usb::hub::CLEAR_FEATURE |
+            usb::hub::SET_FEATURE => {
+
+                let valid = if port_request {
+                    check_port_feature(h.value, self.ss)
+                } else {
+                    (h.value == usb::hub::C_HUB_LOCAL_POWER || h.value == usb::hub::C_HUB_OVER_CURRENT) &&
+                    h.index == 0
+                };
+
+                if !valid || h.length != 0 {
+                    let (value, index) = (h.value, h.index);
+                    error!("[E015-HUB] Invalid feature {} for index {}", value, index);
+                    return false;
+                }
+            }
+
+            usb::hub::GET_PORT_ERR_COUNT => {
+                if success && data.len() != 2 {
+                    error!("[E016-HUB] Invalid port error count length {}", data.len());
+                    return false;
+                }
+            }
+
+            _ => {}
+        }
+
+        true
+    }
+
+    // Red's packet on the status change endpoint: a bitmap with a bit for the hub and for each
+    // of its ports (Section 11.12.4 in spec/usb2.pdf)
+    pub fn check_int_packet(&self, data: &[u8]) -> bool {
+
+        let ports: u8 = match self.ports {
+            Some(v) => v,
+            None => {
+                error!("[E017-HUB] Status change before the hub descriptor");
+                return false;
+            }
+        };
+
+        if data.is_empty() || data.len() > bitmap_size(ports) || !check_bitmap(data, ports, true) {
+            error!("[E018-HUB] Status change of {} bytes for a hub with {} ports",
+                   data.len(),
+                   ports);
+            return false;
+        }
+
+        true
+    }
+}
@@ -1,4 +1,4 @@
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
 use byteorder::{ByteOrder, LittleEndian};
 use conv::TryFrom;
@@ -8,6 +8,8 @@ use parser::usbr;
 use parser::{HasHandlers, Request, Source};
 use usb;
 use util::lockext::RwLockExt;
+use modules::policy::CheckPolicy;
+use modules::fingerprint::{Fingerprinter, FingerprintDb};
 
 
 macro_rules! parse_descriptor {
@@ -200,11 +202,17 @@ macro_rules! get_current_interface {
 // Import class-specific modules
 mod hid;
 mod bbb;
+mod scsi;
 mod printer;
-mod third_party;
+mod cdc;
+mod audio;
+mod hub;
+mod video;
+pub mod classes;
+pub mod third_party;
+pub mod vendor;
 
 const NO_MATCH: u8 = 0; // request is valid
-const MATCH: u8 = 1;
 
 struct ConfigNode {
     desc: usb::ConfigDescriptor,
@@ -254,20 +262,30 @@ pub struct ControlCheck {
     vdev: RwLock<VirtualDevice>,
     hid_checks: RwLock<hid::HidControlCheck>,
     bbb_checks: RwLock<bbb::BBBControlCheck>,
+    cdc_checks: RwLock<cdc::CdcControlCheck>,
+    audio_checks: RwLock<audio::AudioControlCheck>,
+    hub_checks: RwLock<hub::HubControlCheck>,
+    video_checks: RwLock<video::VideoControlCheck>,
     third_party: RwLock<third_party::Patcher>,
+    policy: CheckPolicy,
+    classes: classes::ClassPolicy,
+    fingerprint: RwLock<Fingerprinter>,
+    fingerprints: Option<FingerprintDb>,
+    vendor: Arc<vendor::VendorSet>, // vendor requests that devices may receive
 }
 
 
+// $check names the check that failed; the policy maps it to the port (action) to take.
 macro_rules! control_match {
 
-    ($req:expr, $msg:expr) => {
-        error!("[E000a] Invalid {}", $msg);
-        return (MATCH, vec![$req]);
+    ($self_:ident, $req:expr, $check:expr) => {
+        error!("[E000a] Invalid {}", $check);
+        return ($self_.policy.action($check).port(), vec![$req]);
     };
 
-    ($req:expr, $fmt:expr, $($args:tt)*) => {
+    ($self_:ident, $req:expr, $check:expr, $fmt:expr, $($args:tt)*) => {
         error!(concat!("[E000b] Invalid ", $fmt), $($args)*);
-        return (MATCH, vec![$req]);
+        return ($self_.policy.action($check).port(), vec![$req]);
     };
 
 }
@@ -328,8 +346,8 @@ fn check_device_csp(class: u8, sc: u8, prot: u8) -> bool {
 
         usb::CLASS_PER_INTERFACE |
         usb::CLASS_BILLBOARD => (sc == 0x00, prot == 0x00),
-        usb::CLASS_COMM => (sc > 0x00 && sc < 0x0e, prot == 0x00),
-        usb::CLASS_HUB => (sc == 0x00, prot >= 0x01 && prot <= 0x03),
+        usb::CLASS_COMM => (sc < 0x0e, prot == 0x00), // CDC 1.2 devices use subclass 0
+        usb::CLASS_HUB => (sc == 0x00, prot <= 0x03), // full, high (single or multiple TTs) and super speed
         usb::CLASS_DIAGNOSTIC => (sc == 0x01, prot == 0x01),
         usb::CLASS_MISC => (sc == 0x01 || sc == 0x02, prot == 0x01 || prot == 0x02),
         usb::CLASS_VENDOR_SPEC => (true, true),
@@ -361,7 +379,7 @@ fn check_interface_csp(iface: &usb::InterfaceDescriptor, dev: &usb::DeviceDescri
         usb::CLASS_HUB => {
             dflag = d_class == usb::CLASS_HUB;
             sflag = i_sc == 0;
-            pflag = i_proto == 0;
+            pflag = i_proto <= 0x02; // alternate settings of hubs with single or multiple TTs
         }
 
         usb::CLASS_AUDIO => {
@@ -1217,15 +1235,51 @@ fn check_cap_container_fields(con: &usb::ContainerIdDescriptor) -> bool {
 }
 
 impl ControlCheck {
-    pub fn new(third_party_folder: &str) -> ControlCheck {
+    pub fn new(third_party_folder: &str, policy: CheckPolicy) -> ControlCheck {
+        ControlCheck::with_patcher(third_party::Patcher::new(third_party_folder), policy)
+    }
+
+    // Uses third-party checks that were already loaded (see modules::rules)
+    pub fn with_checks(checks: Arc<third_party::CheckSet>, policy: CheckPolicy) -> ControlCheck {
+        ControlCheck::with_patcher(third_party::Patcher::with_checks(checks), policy)
+    }
+
+    fn with_patcher(third_party: third_party::Patcher, policy: CheckPolicy) -> ControlCheck {
         ControlCheck {
             vdev: RwLock::new(VirtualDevice::new()),
             hid_checks: RwLock::new(hid::HidControlCheck::new()),
             bbb_checks: RwLock::new(bbb::BBBControlCheck::new()),
-            third_party: RwLock::new(third_party::Patcher::new(third_party_folder)),
+            cdc_checks: RwLock::new(cdc::CdcControlCheck::new()),
+            audio_checks: RwLock::new(audio::AudioControlCheck::new()),
+            hub_checks: RwLock::new(hub::HubControlCheck::new()),
+            video_checks: RwLock::new(video::VideoControlCheck::new()),
+            third_party: RwLock::new(third_party),
+            policy: policy,
+            classes: classes::ClassPolicy::new(Arc::new(classes::ClassHistory::new())),
+            fingerprint: RwLock::new(Fingerprinter::new()),
+            fingerprints: None,
+            vendor: Arc::new(vendor::VendorSet::empty()),
         }
     }
 
+    // Replaces the built-in class policy (see classes.rs)
+    pub fn with_classes(mut self, classes: classes::ClassPolicy) -> ControlCheck {
+        self.classes = classes;
+        self
+    }
+
+    // Checks the fingerprints of devices with a serial number against the known ones in db
+    pub fn with_fingerprints(mut self, db: FingerprintDb) -> ControlCheck {
+        self.fingerprints = Some(db);
+        self
+    }
+
+    // Allows the vendor requests in vendor (without it, every vendor request fails the check)
+    pub fn with_vendor_requests(mut self, vendor: Arc<vendor::VendorSet>) -> ControlCheck {
+        self.vendor = vendor;
+        self
+    }
+
 
     fn check_get_config(&self, data: &[u8]) -> bool {
 
@@ -1513,7 +1567,7 @@ impl ControlCheck {
                         return false;
                     }
 
-                } else if i_hdr.length as usize == usb::INTERFACE_ASSOC_DESC_SIZE &&
+                } else if i_hdr.length as usize == usb::INTERFACE_ASSOC_DESC_SIZE + usb::HEADER_SIZE &&
                    i_hdr.descriptor_type == usb::DT_INTERFACE_ASSOCIATION {
 
                     // note that this increments off after interface desc and all ep descs
@@ -1636,31 +1690,103 @@ impl ControlCheck {
 
         match iface.interface_class {
 
-            x if x == usb::CLASS_AUDIO || x == usb::CLASS_AUDIO_VIDEO => {
+            usb::CLASS_AUDIO => {
+
+                // Class-specific descriptors come before the endpoints (this call updates off)
+                let mut audio = self.audio_checks.write().unwrap();
+
+                if !audio.check_interface(&iface, data, off) {
+                    return false;
+                }
+
+                // Endpoints of UAC1 interfaces have 2 more bytes (refresh and synch address)
+                let ep_size = if iface.interface_protocol == usb::audio::UAC_VERSION_1 {
+                    usb::AUDIO_EP_DESC_SIZE + usb::HEADER_SIZE
+                } else {
+                    usb::ENDPOINT_DESC_SIZE + usb::HEADER_SIZE
+                };
+
+                // drop so it can be used in endpoint desc
+                drop(vdev);
 
                 for i in 0..iface.num_endpoints {
 
-                    if data.len() < *off + usb::HEADER_SIZE {
-                        error!("[E070] Not enough payload for endpoint {} header", i);
+                    if data.len() < *off + ep_size {
+                        error!("[E070] Not enough payload for endpoint {}", i);
                         return false;
                     }
 
                     let header = unsafe { parse_descriptor!(0, &data[*off..]) };
+
+                    if header.length as usize != ep_size || header.descriptor_type != usb::DT_ENDPOINT {
+
+                        error!("[E071] Invalid audio endpoint {} length {} or type 0x{:x}",
+                               i,
+                               header.length,
+                               header.descriptor_type);
+                        return false;
+                    }
+
                     *off += usb::HEADER_SIZE;
 
-                    if header.length as usize != usb::AUDIO_EP_DESC_SIZE + usb::HEADER_SIZE ||
+                    // this call updates the value of "off"
+                    if !self.check_endpoint_desc(data, &iface, index, off) {
+                        return false;
+                    }
+
+                    *off += ep_size - usb::ENDPOINT_DESC_SIZE - usb::HEADER_SIZE;
+
+                    if !audio.check_endpoint(&iface, data, off) {
+                        return false;
+                    }
+                }
+
+                return true;
+            }
+
+            usb::CLASS_VIDEO => {
+
+                // Class-specific descriptors come before the endpoints (this call updates off)
+                let mut video = self.video_checks.write().unwrap();
+
+                if !video.check_interface(&iface, data, off) {
+                    return false;
+                }
+
+                // drop so it can be used in endpoint desc
+                drop(vdev);
+
+                for i in 0..iface.num_endpoints {
+
+                    if data.len() < *off + usb::ENDPOINT_DESC_SIZE + usb::HEADER_SIZE {
+                        error!("[E168] Not enough payload for video endpoint {}", i);
+                        return false;
+                    }
+
+                    let header = unsafe { parse_descriptor!(0, &data[*off..]) };
+
+                    if header.length as usize != usb::ENDPOINT_DESC_SIZE + usb::HEADER_SIZE ||
                        header.descriptor_type != usb::DT_ENDPOINT {
 
-                        error!("[E071] Invalid audio endpoint {} length {} or type 0x{:x}",
+                        error!("[E169] Invalid video endpoint {} length {} or type 0x{:x}",
                                i,
                                header.length,
                                header.descriptor_type);
                         return false;
                     }
 
-                    // TODO: implement check for audio
-                    unimplemented!();
+                    *off += usb::HEADER_SIZE;
+
+                    // this call updates the value of "off"
+                    if !self.check_endpoint_desc(data, &iface, index, off) {
+                        return false;
+                    }
 
+                    // The interrupt endpoint of video control interfaces has a class-specific
+                    // descriptor
+                    if !video.check_endpoint(&iface, data, off) {
+                        return false;
+                    }
                 }
 
                 return true;
@@ -1696,6 +1822,14 @@ impl ControlCheck {
                 }
             }
 
+            usb::CLASS_COMM => {
+
+                // Functional descriptors come before the endpoints (this call updates off)
+                if !cdc::check_functional_desc(&iface, data, off) {
+                    return false;
+                }
+            }
+
             _ => {}
         }
 
@@ -1859,22 +1993,12 @@ impl ControlCheck {
 
     fn check_interface_assoc_desc(&self, data: &[u8], off: &mut usize) -> bool {
 
-        if data.len() < *off + usb::INTERFACE_ASSOC_DESC_SIZE + usb::HEADER_SIZE {
+        // The caller has already checked the header
+        if data.len() < *off + usb::INTERFACE_ASSOC_DESC_SIZE {
             error!("[E100] Not enough payload for interface assoc descriptor");
             return false;
         }
 
-        let header = unsafe { parse_descriptor!(0, &data[*off..]) };
-        *off += usb::HEADER_SIZE;
-
-        if header.length as usize != usb::INTERFACE_ASSOC_DESC_SIZE + usb::HEADER_SIZE ||
-           header.descriptor_type != usb::DT_INTERFACE_ASSOCIATION {
-            error!("[E101] Incorrect length ({}) or type for iface assoc desc ({:x})",
-                   header.length,
-                   header.descriptor_type);
-            return false;
-        }
-
         let iface = unsafe { parse_descriptor!(usb::DT_INTERFACE_ASSOCIATION, &data[*off..]) };
         *off += usb::INTERFACE_ASSOC_DESC_SIZE;
 
@@ -2471,10 +2595,95 @@ impl ControlCheck {
                h.requesttype & usb::RECIP_MASK);
         false
     }
+
+    // Interface classes of a complete configuration descriptor against the class policy
+    fn check_device_classes(&self, h: &usbr::ControlPacketHeader, data: &[u8]) -> bool {
+
+        if (h.requesttype & usb::RECIP_MASK) != usb::RECIP_DEVICE || (h.value >> 8) as u8 != usb::DT_CONFIG ||
+           h.status != usbr::Result::Success as u8 || data.len() <= usb::CONFIG_DESC_SIZE + usb::HEADER_SIZE ||
+           LittleEndian::read_u16(&data[2..4]) as usize != data.len() {
+            return true;
+        }
+
+        let desc = match self.vdev.read().unwrap().desc {
+            Some(v) => v,
+            None => return true,
+        };
+
+        self.classes.check(desc.id_vendor, desc.id_product, h.value as u8, &classes::config_classes(data))
+    }
+
+    // Adds a descriptor (which has already been checked) to the device's fingerprint. Once the
+    // fingerprint is complete, a known device must present the same one as before.
+    fn check_fingerprint(&self, h: &usbr::ControlPacketHeader, data: &[u8]) -> bool {
+
+        let mut fp = self.fingerprint.write().unwrap();
+
+        if fp.fingerprint().is_some() || !fp.add(h, data) {
+            return true;
+        }
+
+        let fingerprint = match fp.fingerprint() {
+            Some(v) => v,
+            None => return true,
+        };
+
+        match fp.identity() {
+            Some(identity) => {
+                info!("Device {} has fingerprint {}", identity, fingerprint);
+                self.fingerprints.iter().all(|db| db.check(&identity, &fingerprint))
+            }
+
+            None => {
+                info!("Device without a serial number has fingerprint {}", fingerprint);
+                true
+            }
+        }
+    }
+
+    // Descriptors of endpoint ep and of the interface that owns it in the current configuration
+    // and alternate settings (None if no interface has it)
+    fn endpoint_interface(&self, ep: u8) -> Option<(usb::InterfaceDescriptor, usb::EndpointDescriptor)> {
+
+        let vdev = self.vdev.read().unwrap();
+
+        let config = vdev.chosen_conf.and_then(|c| vdev.configs.get(&c))?;
+
+        for (i_num, alts) in &config.interfaces {
+
+            let iface = vdev.chosen_interfaces.get(i_num).and_then(|alt| alts.get(alt));
+
+            if let Some(iface) = iface {
+                if let Some(endpoint) = iface.endpoints.get(&(ep & 0x8f)) {
+                    return Some((iface.desc, endpoint.desc));
+                }
+            }
+        }
+
+        None
+    }
 }
 
 
 impl HasHandlers for ControlCheck {
+    // A device plugged in later in the session is modeled from scratch
+    fn handle_disconnect(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        if source == Source::Red {
+            *self.vdev.write().unwrap() = VirtualDevice::new();
+            *self.hid_checks.write().unwrap() = hid::HidControlCheck::new();
+            *self.bbb_checks.write().unwrap() = bbb::BBBControlCheck::new();
+            *self.cdc_checks.write().unwrap() = cdc::CdcControlCheck::new();
+            *self.audio_checks.write().unwrap() = audio::AudioControlCheck::new();
+            *self.hub_checks.write().unwrap() = hub::HubControlCheck::new();
+            *self.video_checks.write().unwrap() = video::VideoControlCheck::new();
+            *self.fingerprint.write().unwrap() = Fingerprinter::new();
+            self.third_party.write().unwrap().reset();
+        }
+
+        (NO_MATCH, vec![req])
+    }
+
     fn handle_control_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
 
         let h_ptr = req.type_header.as_ptr() as *const usbr::ControlPacketHeader;
@@ -2492,47 +2701,55 @@ impl HasHandlers for ControlCheck {
                 usb::REQ_GET_STATUS => {
                     if transfer_in && source == Source::Red && !check_get_status(h, &req.data) {
 
-                        control_match!(req, "get_status");
+                        control_match!(self, req, "get_status");
                     }
                 }
 
                 usb::REQ_CLEAR_FEATURE => {
                     if !transfer_in && !req.data.is_empty() {
-                        control_match!(req, "clear feature");
+                        control_match!(self, req, "clear_feature");
                     }
                 }
 
                 usb::REQ_SET_FEATURE => {
                     if !transfer_in && !req.data.is_empty() {
-                        control_match!(req, "set feature");
+                        control_match!(self, req, "set_feature");
                     }
                 }
 
                 usb::REQ_GET_DESCRIPTOR => {
                     if transfer_in && source == Source::Red && !self.check_get_descriptor(h, &req.data) {
 
-                        control_match!(req, "get descriptor");
+                        control_match!(self, req, "get_descriptor");
+                    }
+
+                    if transfer_in && source == Source::Red && !self.check_device_classes(h, &req.data) {
+                        control_match!(self, req, "device_classes");
+                    }
+
+                    if transfer_in && source == Source::Red && !self.check_fingerprint(h, &req.data) {
+                        control_match!(self, req, "fingerprint");
                     }
                 }
 
                 usb::REQ_SET_DESCRIPTOR => {
                     if !transfer_in && source == Source::Red && !req.data.is_empty() {
 
-                        control_match!(req, "set descriptor");
+                        control_match!(self, req, "set_descriptor");
                     }
                 }
 
                 usb::REQ_GET_CONFIGURATION => {
                     if transfer_in && source == Source::Red && !self.check_get_config(&req.data) {
 
-                        control_match!(req, "get config");
+                        control_match!(self, req, "get_config");
                     }
                 }
 
                 usb::REQ_SET_CONFIGURATION => {
 
                     if !transfer_in && source == Source::Red && !req.data.is_empty() {
-                        control_match!(req, "set config");
+                        control_match!(self, req, "set_config");
                     }
 
                     if source == Source::Blue {
@@ -2543,14 +2760,14 @@ impl HasHandlers for ControlCheck {
 
                 usb::REQ_GET_INTERFACE => {
                     if !transfer_in && source == Source::Red && !self.check_get_interface(h, &req.data) {
-                        control_match!(req, "get interface");
+                        control_match!(self, req, "get_interface");
                     }
                 }
 
                 usb::REQ_SET_INTERFACE => {
 
                     if !transfer_in && source == Source::Red && !req.data.is_empty() {
-                        control_match!(req, "set interface");
+                        control_match!(self, req, "set_interface");
                     }
 
                     if source == Source::Blue {
@@ -2562,42 +2779,69 @@ impl HasHandlers for ControlCheck {
                 usb::REQ_SYNCH_FRAME => {
                     if transfer_in && source == Source::Red && req.data.len() != 2 {
 
-                        control_match!(req, "synch frame");
+                        control_match!(self, req, "synch_frame");
                     }
                 }
 
                 usb::REQ_SET_ADDRESS => {
                     if !transfer_in && source == Source::Red && !req.data.is_empty() {
 
-                        control_match!(req, "set address");
+                        control_match!(self, req, "set_address");
                     }
                 }
 
                 _ => {
 
-                    control_match!(req, "usb request: {}", h.request);
+                    control_match!(self, req, "standard_request", "usb request: {}", h.request);
 
                 }
             }
 
         } else if req_type == usb::TYPE_CLASS {
 
-            let vdev = self.vdev.read().unwrap();
-            let interface = get_current_interface!(vdev, h.index as u8);
+            // Hub requests go to the hub itself or to one of its downstream ports
+            let recip: u8 = h.requesttype & usb::RECIP_MASK;
+            let hub: bool = self.vdev.read().unwrap().desc.as_ref()
+                                .map(|d| d.device_class == usb::CLASS_HUB).unwrap_or(false);
 
-            if interface.is_none() {
+            if hub && (recip == usb::RECIP_DEVICE || recip == usb::RECIP_OTHER) {
+
+                let mut hub_check = self.hub_checks.write().unwrap();
 
-                control_match!(req, "request interface");
+                if !hub_check.check_hub_request(h, &req.data, source) {
+                    control_match!(self, req, "hub_request");
+                }
 
+                return (NO_MATCH, vec![req]);
             }
 
-            let desc = &interface.unwrap().desc;
+            // Requests to an endpoint (e.g., its audio sampling frequency) belong to the
+            // interface that owns it
+            let desc = if (h.requesttype & usb::RECIP_MASK) == usb::RECIP_ENDPOINT {
+                self.endpoint_interface(h.index as u8).map(|(desc, _)| desc)
+            } else {
+                let vdev = self.vdev.read().unwrap();
+                get_current_interface!(vdev, h.index as u8).map(|i| i.desc)
+            };
+
+            if desc.is_none() {
+
+                control_match!(self, req, "request_interface");
+
+            }
+
+            let desc = &desc.unwrap();
 
             match desc.interface_class {
 
                 usb::CLASS_HID => {
                     if !hid::check_hid_request(h, &req.data, source) {
-                        control_match!(req, "hid request checks");
+                        control_match!(self, req, "hid_request");
+                    }
+
+                    if source == Source::Blue && h.request == usb::hid::SET_PROT {
+                        let mut hid = self.hid_checks.write().unwrap();
+                        hid.set_protocol(desc.interface_number, h.value);
                     }
                 }
 
@@ -2607,14 +2851,37 @@ impl HasHandlers for ControlCheck {
                         let mut bbb = self.bbb_checks.write().unwrap();
 
                         if !bbb.check_bbb_request(h, &req.data, source) {
-                            control_match!(req, "bbb request checks");
+                            control_match!(self, req, "bbb_request");
                         }
                     }
                 }
 
                 usb::CLASS_PRINTER => {
                     if !printer::check_printer_request(h, &req.data, source) {
-                        control_match!(req, "printer request checks");
+                        control_match!(self, req, "printer_request");
+                    }
+                }
+
+                usb::CLASS_AUDIO => {
+                    let audio = self.audio_checks.read().unwrap();
+
+                    if !audio.check_audio_request(h, &req.data, source, desc) {
+                        control_match!(self, req, "audio_request");
+                    }
+                }
+
+                usb::CLASS_VIDEO => {
+                    let video = self.video_checks.read().unwrap();
+
+                    if !video.check_video_request(h, &req.data, source, desc) {
+                        control_match!(self, req, "video_request");
+                    }
+                }
+
+                usb::CLASS_COMM => {
+                    if desc.interface_subclass == usb::cdc::SC_ACM &&
+                       !cdc::check_acm_request(h, &req.data, source) {
+                        control_match!(self, req, "cdc_request");
                     }
                 }
 
@@ -2624,8 +2891,88 @@ impl HasHandlers for ControlCheck {
             // TODO check other classes
 
 
+        } else if req_type == usb::TYPE_VENDOR {
+
+            let dev = self.vdev.read().unwrap().desc;
+
+            if !dev.iter().any(|d| self.vendor.check(d, h, &req.data, source)) {
+                control_match!(self, req, "vendor_request");
+            }
+
         } else {
-            control_match!(req, "type of control request");
+            control_match!(self, req, "request_type");
+        }
+
+        (NO_MATCH, vec![req])
+    }
+
+    fn handle_bulk_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        let h_ptr = req.type_header.as_ptr() as *const usbr::BulkPacketHeader;
+        let h: &usbr::BulkPacketHeader = unsafe { &*h_ptr };
+
+        // Bulk endpoints are only checked for classes with a transport we understand
+        if let Some((desc, _)) = self.endpoint_interface(h.ep) {
+
+            if desc.interface_class == usb::CLASS_MASS_STORAGE &&
+               desc.interface_protocol == usb::bbb::PR_BBB {
+
+                let mut bbb = self.bbb_checks.write().unwrap();
+
+                if let Err(check) = bbb.check_bulk_packet(desc.interface_number, h, &req.data, source) {
+                    control_match!(self, req, check);
+                }
+            }
+        }
+
+        (NO_MATCH, vec![req])
+    }
+
+    fn handle_int_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        let h_ptr = req.type_header.as_ptr() as *const usbr::IntPacketHeader;
+        let h: &usbr::IntPacketHeader = unsafe { &*h_ptr };
+
+        // Input reports of HID interfaces are checked against their report descriptor, the
+        // notifications of ACM interfaces against the notifications they may send, and the
+        // status changes of hubs against their ports
+        if source == Source::Red && (h.ep & usb::DIR_IN) == usb::DIR_IN &&
+           h.status == usbr::Result::Success as u8 {
+
+            if let Some((desc, ep)) = self.endpoint_interface(h.ep) {
+
+                // bits 11-12 are additional transactions per microframe
+                let max_packet = (ep.max_packet_size & 0x7ff) as usize *
+                                 (1 + ((ep.max_packet_size >> 11) & 0x03) as usize);
+
+                if desc.interface_class == usb::CLASS_HID {
+
+                    let mut hid = self.hid_checks.write().unwrap();
+
+                    if !hid.check_int_packet(desc.interface_number,
+                                             desc.alternate_setting,
+                                             max_packet,
+                                             &req.data) {
+                        control_match!(self, req, "hid_report");
+                    }
+
+                } else if desc.interface_class == usb::CLASS_COMM && desc.interface_subclass == usb::cdc::SC_ACM {
+
+                    let mut cdc = self.cdc_checks.write().unwrap();
+
+                    if !cdc.check_int_packet(desc.interface_number, max_packet, &req.data) {
+                        control_match!(self, req, "cdc_notification");
+                    }
+
+                } else if desc.interface_class == usb::CLASS_HUB {
+
+                    let hub = self.hub_checks.read().unwrap();
+
+                    if !hub.check_int_packet(&req.data) {
+                        control_match!(self, req, "hub_status_change");
+                    }
+                }
+            }
         }
 
         (NO_MATCH, vec![req])
@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+
+use usb;
+use usb::scsi;
+use byteorder::{BigEndian, ByteOrder};
+
+
+// Bytes of each data-in response that are kept for checking. Everything the checks look at
+// (INQUIRY strings, capacity, sense and mode headers) fits well within it.
+pub const MAX_RESPONSE: usize = 256;
+
+// What a LUN said about its medium in READ CAPACITY
+#[derive(Copy, Clone)]
+struct Capacity {
+    last_lba: u64,
+    block_length: u32,
+}
+
+// Checks the SCSI commands that blue sends in CBWs and the data-in that red answers with. A
+// response is checked against the command that asked for it, and reads and writes against the
+// capacity their LUN reported.
+pub struct ScsiCheck {
+    capacities: HashMap<u8, Capacity>, // LUN -> capacity
+}
+
+// Length of a command block given its group code (SPC-4, section 4.2.5.1)
+fn command_length(opcode: u8) -> Option<usize> {
+    match opcode >> 5 {
+        0 => Some(6),
+        1 | 2 => Some(10),
+        4 => Some(16),
+        5 => Some(12),
+        _ => None, // reserved or vendor specific
+    }
+}
+
+// The command block of a CBW, or None if it is too short for its operation code
+fn command_block(cbw: &usb::bbb::CommandBlockWrapper) -> Option<&[u8]> {
+
+    let cb = &cbw.bcbw[..::std::cmp::min(cbw.cb_length as usize, cbw.bcbw.len())];
+
+    match cb.first().map(|&opcode| command_length(opcode)) {
+        Some(Some(length)) if cb.len() < length => None,
+        Some(_) => Some(cb),
+        None => None,
+    }
+}
+
+// Allocation length of the commands whose response is checked
+fn allocation_length(cb: &[u8]) -> Option<u32> {
+
+    match cb[0] {
+        scsi::REQUEST_SENSE | scsi::MODE_SENSE_6 => Some(cb[4] as u32),
+        scsi::INQUIRY => Some(BigEndian::read_u16(&cb[3..5]) as u32),
+        scsi::MODE_SENSE_10 => Some(BigEndian::read_u16(&cb[7..9]) as u32),
+        scsi::READ_CAPACITY_10 => Some(scsi::READ_CAPACITY_10_SIZE as u32),
+
+        scsi::SERVICE_ACTION_IN_16 if (cb[1] & scsi::SA_MASK) == scsi::SA_READ_CAPACITY_16 => {
+            Some(BigEndian::read_u32(&cb[10..14]))
+        }
+
+        _ => None,
+    }
+}
+
+// (logical block address, number of blocks, data-in) of a read or write
+fn block_transfer(cb: &[u8]) -> Option<(u64, u32, bool)> {
+
+    match cb[0] {
+        scsi::READ_10 | scsi::WRITE_10 => {
+            Some((BigEndian::read_u32(&cb[2..6]) as u64,
+                  BigEndian::read_u16(&cb[7..9]) as u32,
+                  cb[0] == scsi::READ_10))
+        }
+
+        scsi::READ_12 | scsi::WRITE_12 => {
+            Some((BigEndian::read_u32(&cb[2..6]) as u64,
+                  BigEndian::read_u32(&cb[6..10]),
+                  cb[0] == scsi::READ_12))
+        }
+
+        scsi::READ_16 | scsi::WRITE_16 => {
+            Some((BigEndian::read_u64(&cb[2..10]),
+                  BigEndian::read_u32(&cb[10..14]),
+                  cb[0] == scsi::READ_16))
+        }
+
+        _ => None,
+    }
+}
+
+fn check_block_length(block_length: u32) -> bool {
+
+    if !block_length.is_power_of_two() ||
+       !(scsi::BLOCK_LENGTH_MIN..=scsi::BLOCK_LENGTH_MAX).contains(&block_length) {
+        error!("[E011-SCSI] Invalid block length {}", block_length);
+        return false;
+    }
+
+    true
+}
+
+fn check_inquiry(cb: &[u8], data: &[u8], len: usize) -> bool {
+
+    if (cb[1] & scsi::INQUIRY_EVPD) != 0 {
+
+        // Vital product data page
+        if data.len() < scsi::VPD_HEADER_SIZE {
+            return true;
+        }
+
+        if data[1] != cb[2] {
+            error!("[E012-SCSI] Asked for VPD page 0x{:x}, got 0x{:x}", cb[2], data[1]);
+            return false;
+        }
+
+        let page_length = scsi::VPD_HEADER_SIZE + BigEndian::read_u16(&data[2..4]) as usize;
+
+        if len > page_length {
+            error!("[E013-SCSI] Returned {} bytes of a {} byte VPD page", len, page_length);
+            return false;
+        }
+
+        return true;
+    }
+
+    if data.is_empty() {
+        return true;
+    }
+
+    if (data[0] >> 5) == scsi::QUALIFIER_RESERVED {
+        error!("[E014-SCSI] Reserved peripheral qualifier in 0x{:x}", data[0]);
+        return false;
+    }
+
+    let device_type = data[0] & 0x1f;
+
+    if (scsi::TYPE_RESERVED_MIN..=scsi::TYPE_RESERVED_MAX).contains(&device_type) {
+        error!("[E015-SCSI] Reserved peripheral device type 0x{:x}", device_type);
+        return false;
+    }
+
+    if data.len() < scsi::INQUIRY_HEADER_SIZE {
+        return true;
+    }
+
+    let total = scsi::INQUIRY_HEADER_SIZE + data[4] as usize;
+
+    if total < scsi::INQUIRY_STD_SIZE {
+        error!("[E016-SCSI] Standard INQUIRY data of {} bytes (at least {})",
+               total,
+               scsi::INQUIRY_STD_SIZE);
+        return false;
+    }
+
+    if len > total {
+        error!("[E017-SCSI] Returned {} bytes of {} bytes of INQUIRY data", len, total);
+        return false;
+    }
+
+    // Vendor, product and revision are printable ASCII
+    let end = ::std::cmp::min(data.len(), scsi::INQUIRY_STD_SIZE);
+
+    if end > 8 && data[8..end].iter().any(|c| !(0x20..=0x7e).contains(c)) {
+        error!("[E018-SCSI] Non-printable identification in INQUIRY data");
+        return false;
+    }
+
+    true
+}
+
+fn check_sense(data: &[u8], len: usize) -> bool {
+
+    if data.is_empty() {
+        return true;
+    }
+
+    let code = data[0] & scsi::SENSE_CODE_MASK;
+
+    let key_off = match code {
+        scsi::SENSE_FIXED_CURRENT | scsi::SENSE_FIXED_DEFERRED => 2,
+        scsi::SENSE_DESC_CURRENT | scsi::SENSE_DESC_DEFERRED => 1,
+
+        _ => {
+            error!("[E019-SCSI] Invalid sense data response code 0x{:x}", code);
+            return false;
+        }
+    };
+
+    if data.len() > key_off && (data[key_off] & scsi::SENSE_KEY_MASK) == scsi::SENSE_KEY_RESERVED {
+        error!("[E020-SCSI] Reserved sense key");
+        return false;
+    }
+
+    if data.len() < scsi::SENSE_HEADER_SIZE {
+        return true;
+    }
+
+    let total = scsi::SENSE_HEADER_SIZE + data[7] as usize;
+
+    if len > total {
+        error!("[E021-SCSI] Returned {} bytes of {} bytes of sense data", len, total);
+        return false;
+    }
+
+    // Descriptor format: the descriptors must tile the additional sense bytes exactly
+    if key_off == 1 && len == total && data.len() == total {
+
+        let mut off = scsi::SENSE_HEADER_SIZE;
+
+        while off < total {
+
+            if off + 2 > total || off + 2 + data[off + 1] as usize > total {
+                error!("[E022-SCSI] Sense data descriptor at {} overflows the sense data", off);
+                return false;
+            }
+
+            off += 2 + data[off + 1] as usize;
+        }
+    }
+
+    true
+}
+
+fn check_mode_sense(opcode: u8, data: &[u8], len: usize) -> bool {
+
+    let (header_size, total, desc_length, desc_size) = if opcode == scsi::MODE_SENSE_6 {
+
+        if data.len() < scsi::MODE_HEADER_6_SIZE {
+            return true;
+        }
+
+        (scsi::MODE_HEADER_6_SIZE, data[0] as usize + 1, data[3] as usize, scsi::BLOCK_DESC_SIZE)
+
+    } else {
+
+        if data.len() < scsi::MODE_HEADER_10_SIZE {
+            return true;
+        }
+
+        let desc_size = if (data[4] & scsi::MODE_LONGLBA) != 0 {
+            scsi::LONG_BLOCK_DESC_SIZE
+        } else {
+            scsi::BLOCK_DESC_SIZE
+        };
+
+        (scsi::MODE_HEADER_10_SIZE,
+         BigEndian::read_u16(&data[0..2]) as usize + 2,
+         BigEndian::read_u16(&data[6..8]) as usize,
+         desc_size)
+    };
+
+    if len > total {
+        error!("[E023-SCSI] Returned {} bytes of {} bytes of mode data", len, total);
+        return false;
+    }
+
+    if header_size + desc_length > total || desc_length % desc_size != 0 {
+        error!("[E024-SCSI] Invalid block descriptor length {} for {} bytes of mode data",
+               desc_length,
+               total);
+        return false;
+    }
+
+    true
+}
+
+
+impl ScsiCheck {
+    pub fn new() -> ScsiCheck {
+        ScsiCheck { capacities: HashMap::new() }
+    }
+
+    // Checks the command block of a CBW that already passed the transport checks
+    pub fn check_command(&self, cbw: &usb::bbb::CommandBlockWrapper) -> bool {
+
+        let cb = match command_block(cbw) {
+            Some(v) => v,
+            None => {
+                error!("[E001-SCSI] Command 0x{:x} in a {} byte command block",
+                       cbw.bcbw[0],
+                       cbw.cb_length);
+                return false;
+            }
+        };
+
+        let opcode = cb[0];
+        let transfer_in = (cbw.flags & usb::DIR_IN) == usb::DIR_IN;
+
+        if opcode == scsi::TEST_UNIT_READY && cbw.transfer_length != 0 {
+            error!("[E002-SCSI] TEST UNIT READY with a transfer length of {}",
+                   cbw.transfer_length);
+            return false;
+        }
+
+        if allocation_length(cb).is_some() && cbw.transfer_length != 0 && !transfer_in {
+            error!("[E003-SCSI] Command 0x{:x} returns data but the CBW is data-out", opcode);
+            return false;
+        }
+
+        if let Some((lba, blocks, read)) = block_transfer(cb) {
+
+            if cbw.transfer_length != 0 && transfer_in != read {
+                error!("[E004-SCSI] Command 0x{:x} with the wrong transfer direction", opcode);
+                return false;
+            }
+
+            if let Some(capacity) = self.capacities.get(&cbw.cb_lun) {
+
+                let expected = blocks as u64 * capacity.block_length as u64;
+
+                if cbw.transfer_length as u64 != expected {
+                    error!("[E005-SCSI] Transfer length {} for {} blocks of {} bytes",
+                           cbw.transfer_length,
+                           blocks,
+                           capacity.block_length);
+                    return false;
+                }
+
+                let end = lba.saturating_add(blocks as u64);
+
+                if end > capacity.last_lba.saturating_add(1) {
+                    error!("[E006-SCSI] Blocks {} to {} are beyond the last block {} of LUN {}",
+                           lba,
+                           end,
+                           capacity.last_lba,
+                           cbw.cb_lun);
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    // Checks the data-in of a completed command. data holds the first MAX_RESPONSE bytes of the
+    // len bytes the device sent.
+    pub fn check_response(&mut self, cbw: &usb::bbb::CommandBlockWrapper, data: &[u8], len: usize) -> bool {
+
+        let cb = match command_block(cbw) {
+            Some(v) => v,
+            None => return true,
+        };
+
+        let allocation = match allocation_length(cb) {
+            Some(v) => v as usize,
+            None => return true,
+        };
+
+        if len > allocation {
+            error!("[E007-SCSI] Command 0x{:x} returned {} bytes for an allocation length of {}",
+                   cb[0],
+                   len,
+                   allocation);
+            return false;
+        }
+
+        match cb[0] {
+
+            scsi::INQUIRY => check_inquiry(cb, data, len),
+            scsi::REQUEST_SENSE => check_sense(data, len),
+            x if x == scsi::MODE_SENSE_6 || x == scsi::MODE_SENSE_10 => check_mode_sense(x, data, len),
+
+            scsi::READ_CAPACITY_10 => {
+
+                if len == 0 {
+                    return true;
+                }
+
+                if len != scsi::READ_CAPACITY_10_SIZE {
+                    error!("[E008-SCSI] READ CAPACITY(10) returned {} bytes", len);
+                    return false;
+                }
+
+                let last_lba = BigEndian::read_u32(&data[0..4]);
+                let block_length = BigEndian::read_u32(&data[4..8]);
+
+                if !check_block_length(block_length) {
+                    return false;
+                }
+
+                // The medium is too large for READ CAPACITY(10); the host will ask again with
+                // READ CAPACITY(16)
+                if last_lba != 0xffffffff {
+                    self.set_capacity(cbw.cb_lun, last_lba as u64, block_length);
+                }
+
+                true
+            }
+
+            scsi::SERVICE_ACTION_IN_16 => {
+
+                // READ CAPACITY(16), the only service action with an allocation length
+                if len == 0 {
+                    return true;
+                }
+
+                if len < ::std::cmp::min(allocation, scsi::READ_CAPACITY_16_MIN_SIZE) {
+                    error!("[E009-SCSI] READ CAPACITY(16) returned {} bytes", len);
+                    return false;
+                }
+
+                if data.len() < scsi::READ_CAPACITY_16_MIN_SIZE {
+                    return true;
+                }
+
+                let last_lba = BigEndian::read_u64(&data[0..8]);
+                let block_length = BigEndian::read_u32(&data[8..12]);
+
+                if !check_block_length(block_length) {
+                    return false;
+                }
+
+                if last_lba == u64::MAX {
+                    error!("[E010-SCSI] READ CAPACITY(16) returned an invalid last block");
+                    return false;
+                }
+
+                self.set_capacity(cbw.cb_lun, last_lba, block_length);
+                true
+            }
+
+            _ => true,
+        }
+    }
+
+    fn set_capacity(&mut self, lun: u8, last_lba: u64, block_length: u32) {
+        info!("LUN {} has {} blocks of {} bytes", lun, last_lba + 1, block_length);
+        self.capacities.insert(lun, Capacity { last_lba: last_lba, block_length: block_length });
+    }
+}
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::io::prelude::*;
 use std::fs;
-use std::rc::Rc;
+use std::sync::Arc;
 use rustc_serialize::json;
 
 use usb;
@@ -34,33 +34,40 @@ struct CompliancePatch {
     constraints: Vec<Constraint>,
 }
 
-pub struct Patcher {
-    patches: HashMap<(u16, u16), Rc<CompliancePatch>>,
-    satisfied: HashMap<(u16, u16, u16), u16>, // constraint -> satisfied?
-
-    num_ifs: u8,
-    num_eps: u8,
+// Third-party checks read from a folder. One set is shared by every session started while
+// it is current (see modules::rules).
+pub struct CheckSet {
+    patches: HashMap<(u16, u16), Arc<CompliancePatch>>,
 }
 
-// TODO: Change from string matches to enum matches... Problem is json doesn't support enum :(
-impl Patcher {
-    pub fn new(dir_path: &str) -> Patcher {
+impl CheckSet {
+    pub fn empty() -> CheckSet {
+        CheckSet { patches: HashMap::new() }
+    }
+
+    // A missing folder is an empty set, but every file in the folder must be a valid check
+    pub fn load(dir_path: &str) -> Result<CheckSet, String> {
 
         let mut patches = HashMap::new();
 
         if let Ok(dir) = fs::read_dir(dir_path) {
             for entry in dir {
-                let mut file = match fs::File::open(&entry.unwrap().path()) {
-                    Ok(file) => file,
-                    Err(e) => panic!("[E000-TP] Could not open file {}", e),
-                };
+
+                let path = entry.map_err(|e| format!("could not list {}: {}", dir_path, e))?.path();
+
+                let mut file = fs::File::open(&path)
+                    .map_err(|e| format!("could not open {}: {}", path.display(), e))?;
 
                 // read file
                 let mut json_line = String::new();
-                file.read_to_string(&mut json_line).unwrap();
+                file.read_to_string(&mut json_line)
+                    .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
 
                 // decode file and add
-                let patch: Rc<CompliancePatch> = Rc::new(json::decode(&json_line).unwrap());
+                let patch: Arc<CompliancePatch> = match json::decode(&json_line) {
+                    Ok(v) => Arc::new(v),
+                    Err(e) => return Err(format!("invalid check {}: {}", path.display(), e)),
+                };
 
                 for id in &patch.ids {
                     patches.insert((id.vendor_id, id.product_id), patch.clone());
@@ -68,13 +75,53 @@ impl Patcher {
             }
         }
 
-        Patcher { patches: patches, satisfied: HashMap::new(), num_ifs: 0, num_eps: 0 }
+        Ok(CheckSet { patches: patches })
+    }
+
+    // Number of devices covered by the set
+    pub fn len(&self) -> usize {
+        self.patches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patches.is_empty()
+    }
+}
+
+
+pub struct Patcher {
+    checks: Arc<CheckSet>,
+    satisfied: HashMap<(u16, u16, u16), u16>, // constraint -> satisfied?
+
+    num_ifs: u8,
+    num_eps: u8,
+}
+
+// TODO: Change from string matches to enum matches... Problem is json doesn't support enum :(
+impl Patcher {
+    pub fn new(dir_path: &str) -> Patcher {
+
+        match CheckSet::load(dir_path) {
+            Ok(checks) => Patcher::with_checks(Arc::new(checks)),
+            Err(e) => panic!("[E000-TP] {}", e),
+        }
+    }
+
+    pub fn with_checks(checks: Arc<CheckSet>) -> Patcher {
+        Patcher { checks: checks, satisfied: HashMap::new(), num_ifs: 0, num_eps: 0 }
+    }
+
+    // Forgets what it learned about the device (e.g., once it disconnects)
+    pub fn reset(&mut self) {
+        self.satisfied.clear();
+        self.num_ifs = 0;
+        self.num_eps = 0;
     }
 
 
     pub fn check_config_fields(&mut self, config: &usb::ConfigDescriptor, dev: &usb::DeviceDescriptor) -> bool {
 
-        if let Some(ref patch) = self.patches.get(&(dev.id_vendor, dev.id_product)) {
+        if let Some(ref patch) = self.checks.patches.get(&(dev.id_vendor, dev.id_product)) {
             for constraint in &patch.constraints {
                 if constraint.desc_type == "configuration" {
 
@@ -121,7 +168,7 @@ impl Patcher {
 
     pub fn check_iface_fields(&mut self, iface: &usb::InterfaceDescriptor, dev: &usb::DeviceDescriptor) -> bool {
 
-        if let Some(ref patch) = self.patches.get(&(dev.id_vendor, dev.id_product)) {
+        if let Some(ref patch) = self.checks.patches.get(&(dev.id_vendor, dev.id_product)) {
             for constraint in &patch.constraints {
                 if constraint.desc_type == "interface" {
 
@@ -203,7 +250,7 @@ impl Patcher {
 
     pub fn check_endpoint_fields(&mut self, ep: &usb::EndpointDescriptor, dev: &usb::DeviceDescriptor) -> bool {
 
-        if let Some(ref patch) = self.patches.get(&(dev.id_vendor, dev.id_product)) {
+        if let Some(ref patch) = self.checks.patches.get(&(dev.id_vendor, dev.id_product)) {
             for constraint in &patch.constraints {
                 if constraint.desc_type == "endpoint" {
 
@@ -0,0 +1,198 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::fs;
+use std::sync::Arc;
+use rustc_serialize::json;
+
+use usb;
+use parser::usbr;
+use parser::Source;
+
+
+#[derive(RustcDecodable)]
+struct Range {
+    min: u16,
+    max: u16,
+}
+
+impl Range {
+    fn contains(&self, value: u16) -> bool {
+        value >= self.min && value <= self.max
+    }
+}
+
+// A family of vendor requests. Absent wValue and wIndex ranges allow any value, and an absent
+// length means the request has no data stage.
+#[derive(RustcDecodable)]
+struct VendorRequest {
+    direction: String, // in or out
+    request: Range,
+    value: Option<Range>,
+    index: Option<Range>,
+    length: Option<Range>, // bytes of the data stage
+}
+
+#[derive(RustcDecodable)]
+struct DeviceId {
+    vendor_id: u16,
+    product_id: u16,
+}
+
+#[derive(RustcDecodable)]
+struct VendorPolicy {
+    ids: Vec<DeviceId>,
+    requests: Vec<VendorRequest>,
+}
+
+// Vendor requests that devices (by VID:PID) may receive, read from a folder. One set is shared by
+// every session started while it is current (see modules::rules).
+pub struct VendorSet {
+    policies: HashMap<(u16, u16), Arc<VendorPolicy>>,
+}
+
+
+// Ranges must not be empty, and directions must be in or out
+fn check_policy(policy: &VendorPolicy) -> Result<(), String> {
+
+    for r in &policy.requests {
+
+        if r.direction != "in" && r.direction != "out" {
+            return Err(format!("unknown direction {}", r.direction));
+        }
+
+        let ranges = [Some(&r.request), r.value.as_ref(), r.index.as_ref(), r.length.as_ref()];
+
+        if ranges.iter().any(|range| range.iter().any(|v| v.min > v.max)) || r.request.max > 0xff {
+            return Err(format!("invalid range for request {}", r.request.min));
+        }
+    }
+
+    Ok(())
+}
+
+
+impl VendorSet {
+    pub fn empty() -> VendorSet {
+        VendorSet { policies: HashMap::new() }
+    }
+
+    // A missing folder is an empty set, but every .json file in the folder must be a valid
+    // policy. Other entries (subdirectories, editor backups) are skipped.
+    pub fn load(dir_path: &str) -> Result<VendorSet, String> {
+
+        let mut policies = HashMap::new();
+
+        if let Ok(dir) = fs::read_dir(dir_path) {
+            for entry in dir {
+
+                let path = entry.map_err(|e| format!("could not list {}: {}", dir_path, e))?.path();
+
+                if !path.is_file() || path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                    continue;
+                }
+
+                let mut file = fs::File::open(&path)
+                    .map_err(|e| format!("could not open {}: {}", path.display(), e))?;
+
+                let mut json_line = String::new();
+                file.read_to_string(&mut json_line)
+                    .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+
+                let policy: Arc<VendorPolicy> = match json::decode(&json_line) {
+                    Ok(v) => Arc::new(v),
+                    Err(e) => return Err(format!("invalid vendor policy {}: {}", path.display(), e)),
+                };
+
+                check_policy(&policy).map_err(|e| format!("invalid vendor policy {}: {}", path.display(), e))?;
+
+                for id in &policy.ids {
+                    policies.insert((id.vendor_id, id.product_id), policy.clone());
+                }
+            }
+        }
+
+        Ok(VendorSet { policies: policies })
+    }
+
+    // Number of devices covered by the set
+    pub fn len(&self) -> usize {
+        self.policies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.policies.is_empty()
+    }
+
+    // Vendor requests must belong to a family that the device's policy allows. The blue machine
+    // may ask for no more than the family's longest data stage, and the device must return a
+    // length in the family's range (unless it was asked for fewer bytes).
+    pub fn check(&self,
+                 dev: &usb::DeviceDescriptor,
+                 h: &usbr::ControlPacketHeader,
+                 data: &[u8],
+                 source: Source)
+                 -> bool {
+
+        // The descriptor is packed, so its fields are copied rather than borrowed
+        let (vendor_id, product_id) = (dev.id_vendor, dev.id_product);
+
+        let policy = match self.policies.get(&(vendor_id, product_id)) {
+            Some(v) => v,
+            None => {
+                error!("[E001-VENDOR] No vendor request policy for {:04x}:{:04x}", vendor_id, product_id);
+                return false;
+            }
+        };
+
+        let transfer_in: bool = (h.requesttype & usb::DIR_IN) == usb::DIR_IN;
+        let (value, index, length) = (h.value, h.index, h.length);
+
+        // For data-out requests, wLength is the length of the data stage
+        let allowed = policy.requests.iter().find(|r| {
+            (r.direction == "in") == transfer_in && r.request.contains(h.request as u16) &&
+            r.value.iter().all(|v| v.contains(value)) && r.index.iter().all(|i| i.contains(index)) &&
+            match r.length {
+                Some(ref l) if transfer_in => length <= l.max,
+                Some(ref l) => l.contains(length),
+                None => length == 0,
+            }
+        });
+
+        let r = match allowed {
+            Some(v) => v,
+            None => {
+                error!("[E002-VENDOR] Vendor request 0x{:x} (value 0x{:x}, index 0x{:x}, length {}) not allowed",
+                       h.request,
+                       value,
+                       index,
+                       length);
+                return false;
+            }
+        };
+
+        if source == Source::Blue && !transfer_in && data.len() != length as usize {
+            error!("[E003-VENDOR] Vendor request with {} of {} bytes", data.len(), length);
+            return false;
+        }
+
+        if source == Source::Red && h.status == usbr::Result::Success as u8 {
+
+            let valid = match r.length {
+                Some(ref l) if transfer_in => {
+                    data.len() <= length as usize && data.len() >= cmp::min(l.min, length) as usize
+                }
+                _ => data.is_empty(),
+            };
+
+            if !valid {
+                error!("[E004-VENDOR] Invalid response length {} to vendor request 0x{:x}",
+                       data.len(),
+                       h.request);
+                return false;
+            }
+        }
+
+        true
+    }
+}
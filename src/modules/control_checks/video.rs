@@ -0,0 +1,737 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+
+use usb;
+use parser::usbr;
+use parser::Source;
+use byteorder::{ByteOrder, LittleEndian};
+
+
+// A terminal or unit of a video control interface
+struct Entity {
+    subtype: u8,
+    terminal_type: u16, // of terminals
+    sources: Vec<u8>, // terminals and units it takes its input from
+}
+
+// A frame descriptor of a video format
+struct Frame {
+    width: u16,
+    height: u16,
+    max_buffer: u32, // dwMaxVideoFrameBufferSize (0 if the descriptor has none)
+}
+
+// A format descriptor of a video streaming interface and its frames, by index
+struct Format {
+    subtype: u8,
+    frame_subtype: Option<u8>, // subtype of its frame descriptors (some formats have none)
+    num_frames: u8,
+    bits_per_pixel: u8, // of uncompressed formats
+    frames: HashMap<u8, Frame>,
+}
+
+pub struct VideoControlCheck {
+    entities: HashMap<u8, HashMap<u8, Entity>>, // entities of each video control interface, by id
+    versions: HashMap<u8, u16>, // class version of each video streaming interface
+    formats: HashMap<u8, HashMap<u8, Format>>, // formats of each video streaming interface, by index
+}
+
+
+// Class-specific descriptors of type dt that start at off (this call updates off)
+fn class_descs<'a>(data: &'a [u8], off: &mut usize, dt: u8) -> Option<Vec<&'a [u8]>> {
+
+    let mut descs = vec![];
+
+    while data.len() >= *off + usb::HEADER_SIZE && data[*off + 1] == dt {
+
+        let length: usize = data[*off] as usize;
+
+        if length < usb::video::DESC_MIN_SIZE || data.len() < *off + length {
+            error!("[E001-VIDEO] Invalid class-specific descriptor length {}", length);
+            return None;
+        }
+
+        descs.push(&data[*off..*off + length]);
+        *off += length;
+    }
+
+    Some(descs)
+}
+
+fn is_output_type(terminal_type: u16) -> bool {
+    terminal_type >= usb::video::OTT_VENDOR_SPECIFIC && terminal_type < usb::video::EXTERNAL_VENDOR_SPECIFIC
+}
+
+fn is_input_type(terminal_type: u16) -> bool {
+    terminal_type >= usb::video::ITT_VENDOR_SPECIFIC && terminal_type < usb::video::OTT_VENDOR_SPECIFIC
+}
+
+
+// Terminals and units (Section 3.7.2 in UVC 1.5). Fills in the inputs of entity.
+fn check_entity(desc: &[u8], entity: &mut Entity) -> bool {
+
+    let len = desc.len();
+    let pins = |at: usize| if len > at { desc[at] as usize } else { 0 };
+
+    match desc[2] {
+
+        // Camera terminals have focal lengths and bControlSize bytes of controls
+        usb::video::VC_INPUT_TERMINAL if len >= 8 => {
+            entity.terminal_type = LittleEndian::read_u16(&desc[4..6]);
+
+            if entity.terminal_type == usb::video::ITT_CAMERA {
+                return len > 14 && len == 15 + pins(14);
+            }
+
+            return !is_output_type(entity.terminal_type);
+        }
+
+        usb::video::VC_OUTPUT_TERMINAL if len >= 9 => {
+            entity.terminal_type = LittleEndian::read_u16(&desc[4..6]);
+            entity.sources.push(desc[7]);
+            return !is_input_type(entity.terminal_type);
+        }
+
+        usb::video::VC_SELECTOR_UNIT if pins(4) > 0 && len == 6 + pins(4) => {
+            entity.sources = desc[5..5 + pins(4)].to_vec();
+        }
+
+        // UVC 1.0 processing units have no video standards
+        usb::video::VC_PROCESSING_UNIT if len > 7 && (len == 9 + pins(7) || len == 10 + pins(7)) => {
+            entity.sources.push(desc[4]);
+        }
+
+        usb::video::VC_EXTENSION_UNIT if len > 22 && len == 24 + pins(21) + pins(22 + pins(21)) => {
+            entity.sources = desc[22..22 + pins(21)].to_vec();
+        }
+
+        usb::video::VC_ENCODING_UNIT if len == 13 => entity.sources.push(desc[4]),
+
+        _ => return false,
+    }
+
+    true
+}
+
+// Whether following the inputs of entity id leads back to an entity that is being visited
+fn has_loop(entities: &HashMap<u8, Entity>, id: u8, visiting: &mut HashSet<u8>, done: &mut HashSet<u8>) -> bool {
+
+    if done.contains(&id) {
+        return false;
+    }
+
+    if !visiting.insert(id) {
+        return true;
+    }
+
+    let found = match entities.get(&id) {
+        Some(e) => e.sources.iter().any(|i| has_loop(entities, *i, visiting, done)),
+        None => false,
+    };
+
+    visiting.remove(&id);
+    done.insert(id);
+
+    found
+}
+
+// Format descriptors (Section 3 of the uncompressed, MJPEG and frame-based payload
+// specifications). The default frame must be one of the format's frames.
+fn check_format(desc: &[u8]) -> Option<Format> {
+
+    let len = desc.len();
+
+    let mut format = Format {
+        subtype: desc[2],
+        frame_subtype: None,
+        num_frames: 0,
+        bits_per_pixel: 0,
+        frames: HashMap::new(),
+    };
+
+    let default_frame: u8 = match desc[2] {
+
+        usb::video::VS_FORMAT_UNCOMPRESSED if len == usb::video::FORMAT_UNCOMPRESSED_SIZE => {
+            format.frame_subtype = Some(usb::video::VS_FRAME_UNCOMPRESSED);
+            format.bits_per_pixel = desc[21];
+            desc[22]
+        }
+
+        usb::video::VS_FORMAT_MJPEG if len == usb::video::FORMAT_MJPEG_SIZE => {
+            format.frame_subtype = Some(usb::video::VS_FRAME_MJPEG);
+            desc[6]
+        }
+
+        usb::video::VS_FORMAT_FRAME_BASED if len == usb::video::FORMAT_FRAME_BASED_SIZE => {
+            format.frame_subtype = Some(usb::video::VS_FRAME_FRAME_BASED);
+            desc[22]
+        }
+
+        // UVC 1.5 formats, whose frames we do not look into
+        usb::video::VS_FORMAT_H264 |
+        usb::video::VS_FORMAT_H264_SIMULCAST if len > 5 => {
+            format.frame_subtype = Some(usb::video::VS_FRAME_H264);
+            desc[5]
+        }
+
+        usb::video::VS_FORMAT_VP8 |
+        usb::video::VS_FORMAT_VP8_SIMULCAST if len > 5 => {
+            format.frame_subtype = Some(usb::video::VS_FRAME_VP8);
+            desc[5]
+        }
+
+        // formats without frame descriptors
+        usb::video::VS_FORMAT_MPEG2TS |
+        usb::video::VS_FORMAT_DV |
+        usb::video::VS_FORMAT_STREAM_BASED if len > 4 => return Some(format),
+
+        _ => return None,
+    };
+
+    format.num_frames = desc[4];
+
+    if format.num_frames == 0 || default_frame == 0 || default_frame > format.num_frames {
+        return None;
+    }
+
+    if format.subtype == usb::video::VS_FORMAT_UNCOMPRESSED && format.bits_per_pixel == 0 {
+        return None;
+    }
+
+    Some(format)
+}
+
+// Uncompressed, MJPEG and frame-based frame descriptors. Frame intervals are a continuous range
+// or a list of discrete ones (4 bytes each), and none of them is 0.
+fn check_frame(desc: &[u8]) -> Option<Frame> {
+
+    let len = desc.len();
+
+    if len < usb::video::FRAME_FIXED_SIZE {
+        return None;
+    }
+
+    // Frame-based frames have no frame buffer size, so the interval type comes 4 bytes earlier
+    let frame_based: bool = desc[2] == usb::video::VS_FRAME_FRAME_BASED;
+    let interval_type: usize = desc[if frame_based { 21 } else { 25 }] as usize;
+
+    let frame = Frame {
+        width: LittleEndian::read_u16(&desc[5..7]),
+        height: LittleEndian::read_u16(&desc[7..9]),
+        max_buffer: if frame_based { 0 } else { LittleEndian::read_u32(&desc[17..21]) },
+    };
+
+    let min_rate = LittleEndian::read_u32(&desc[9..13]);
+    let max_rate = LittleEndian::read_u32(&desc[13..17]);
+
+    let intervals: Vec<u32> = desc[usb::video::FRAME_FIXED_SIZE..].chunks(4)
+                                                                   .filter(|c| c.len() == 4)
+                                                                   .map(LittleEndian::read_u32)
+                                                                   .collect();
+
+    let valid_intervals = if interval_type == 0 {
+        len == usb::video::FRAME_CONTINUOUS_SIZE && intervals[0] > 0 && intervals[0] <= intervals[1] &&
+        (intervals[0] == intervals[1] || intervals[2] > 0)
+    } else {
+        len == usb::video::FRAME_FIXED_SIZE + 4 * interval_type && intervals.iter().all(|i| *i > 0)
+    };
+
+    if frame.width == 0 || frame.height == 0 || min_rate > max_rate || !valid_intervals {
+        return None;
+    }
+
+    Some(frame)
+}
+
+// Still image frame (Section 3.9.2.5 in UVC 1.5): image sizes (4 bytes each) and compression
+// patterns
+fn check_still_frame(desc: &[u8]) -> bool {
+
+    let len = desc.len();
+
+    if len < 5 {
+        return false;
+    }
+
+    let sizes: usize = 4 * desc[4] as usize;
+
+    len > 5 + sizes && len == 6 + sizes + desc[5 + sizes] as usize
+}
+
+
+impl VideoControlCheck {
+    pub fn new() -> VideoControlCheck {
+        VideoControlCheck {
+            entities: HashMap::new(),
+            versions: HashMap::new(),
+            formats: HashMap::new(),
+        }
+    }
+
+    // Class-specific descriptors that follow a video interface and come before its endpoints
+    // (this call updates off)
+    pub fn check_interface(&mut self, iface: &usb::InterfaceDescriptor, data: &[u8], off: &mut usize) -> bool {
+
+        let descs = match class_descs(data, off, usb::DT_CS_INTERFACE) {
+            Some(v) => v,
+            None => return false,
+        };
+
+        match iface.interface_subclass {
+            usb::video::SC_VIDEOCONTROL => self.check_control_desc(iface, &descs),
+            usb::video::SC_VIDEOSTREAMING => self.check_streaming_desc(iface, &descs),
+            _ => {
+                error!("[E002-VIDEO] Unknown video interface subclass 0x{:x}",
+                       iface.interface_subclass);
+                false
+            }
+        }
+    }
+
+    // The header, then the terminals and units of a video control interface. Every input of an
+    // entity must be another entity of the interface, and following inputs must not lead in a
+    // circle.
+    fn check_control_desc(&mut self, iface: &usb::InterfaceDescriptor, descs: &[&[u8]]) -> bool {
+
+        let header: &[u8] = match descs.first() {
+            Some(h) if h[2] == usb::video::VC_HEADER => h,
+            _ => {
+                error!("[E003-VIDEO] Video control interface without header");
+                return false;
+            }
+        };
+
+        // The header lists the streaming interfaces of the collection
+        let valid = header.len() >= 12 && header.len() == 12 + header[11] as usize &&
+                    !header[12..].contains(&iface.interface_number);
+
+        if !valid {
+            error!("[E004-VIDEO] Invalid video control header of length {}", header.len());
+            return false;
+        }
+
+        let version: u16 = LittleEndian::read_u16(&header[3..5]);
+
+        if version != usb::video::UVC_1_0 && version != usb::video::UVC_1_1 && version != usb::video::UVC_1_5 {
+            error!("[E005-VIDEO] Unknown video class version 0x{:x}", version);
+            return false;
+        }
+
+        // wTotalLength covers the header and every entity
+        let total: usize = LittleEndian::read_u16(&header[5..7]) as usize;
+        let length: usize = descs.iter().map(|d| d.len()).sum();
+
+        if total != length {
+            error!("[E006-VIDEO] Video control descriptors total {} bytes, not {}", length, total);
+            return false;
+        }
+
+        let mut entities: HashMap<u8, Entity> = HashMap::new();
+
+        for desc in &descs[1..] {
+
+            let mut entity = Entity {
+                subtype: desc[2],
+                terminal_type: 0,
+                sources: vec![],
+            };
+
+            if desc.len() <= 3 || !check_entity(desc, &mut entity) {
+                error!("[E007-VIDEO] Invalid video control descriptor 0x{:x} of length {}",
+                       desc[2],
+                       desc.len());
+                return false;
+            }
+
+            if desc[3] == 0 || entities.insert(desc[3], entity).is_some() {
+                error!("[E008-VIDEO] Invalid or repeated entity id {}", desc[3]);
+                return false;
+            }
+        }
+
+        for (id, entity) in &entities {
+            if !entity.sources.iter().all(|s| entities.contains_key(s)) {
+                error!("[E009-VIDEO] Entity {} has an input that is not in the interface", id);
+                return false;
+            }
+        }
+
+        let mut done: HashSet<u8> = HashSet::new();
+
+        for id in entities.keys() {
+            if has_loop(&entities, *id, &mut HashSet::new(), &mut done) {
+                error!("[E010-VIDEO] The inputs of entity {} form a loop", id);
+                return false;
+            }
+        }
+
+        for inum in &header[12..] {
+            self.versions.insert(*inum, version);
+        }
+
+        self.entities.insert(iface.interface_number, entities);
+
+        true
+    }
+
+    // The input or output header of a video streaming interface, which links it to a streaming
+    // terminal, and its formats, each followed by its frames. Only the first alternate setting
+    // has these.
+    fn check_streaming_desc(&mut self, iface: &usb::InterfaceDescriptor, descs: &[&[u8]]) -> bool {
+
+        if iface.alternate_setting != 0 {
+
+            if !descs.is_empty() {
+                error!("[E011-VIDEO] Class-specific descriptors in alternate setting {}",
+                       iface.alternate_setting);
+                return false;
+            }
+
+            return true;
+        }
+
+        let header: &[u8] = match descs.first() {
+            Some(h) if h[2] == usb::video::VS_INPUT_HEADER || h[2] == usb::video::VS_OUTPUT_HEADER => h,
+            _ => {
+                error!("[E012-VIDEO] Video streaming interface without header");
+                return false;
+            }
+        };
+
+        let len = header.len();
+        let input: bool = header[2] == usb::video::VS_INPUT_HEADER;
+        let num_formats: usize = if len > 3 { header[3] as usize } else { 0 };
+
+        // bControlSize bytes of controls for each format (UVC 1.0 output headers have none)
+        let valid = if input {
+            len >= 13 && len == 13 + num_formats * header[12] as usize && header[9] <= 3 &&
+            header[10] <= 1 && header[11] <= 1
+        } else {
+            len == 8 || (len >= 9 && len == 9 + num_formats * header[8] as usize)
+        };
+
+        if !valid || num_formats == 0 {
+            error!("[E013-VIDEO] Invalid video streaming header of length {}", len);
+            return false;
+        }
+
+        // wTotalLength covers the header, formats and frames
+        let total: usize = LittleEndian::read_u16(&header[4..6]) as usize;
+        let length: usize = descs.iter().map(|d| d.len()).sum();
+
+        if total != length {
+            error!("[E014-VIDEO] Video streaming descriptors total {} bytes, not {}", length, total);
+            return false;
+        }
+
+        // Input headers carry video to the host, so they link to an output terminal
+        let link: u8 = header[if input { 8 } else { 7 }];
+        let subtype: u8 = if input { usb::video::VC_OUTPUT_TERMINAL } else { usb::video::VC_INPUT_TERMINAL };
+
+        let linked = self.entities.values().any(|e| {
+            e.get(&link).iter().any(|t| t.subtype == subtype && t.terminal_type == usb::video::TT_STREAMING)
+        });
+
+        if !linked {
+            error!("[E015-VIDEO] Video streaming interface linked to unknown terminal {}", link);
+            return false;
+        }
+
+        let mut formats: HashMap<u8, Format> = HashMap::new();
+        let mut last: Option<u8> = None; // index of the last format
+
+        for desc in &descs[1..] {
+
+            let len = desc.len();
+
+            let valid = match desc[2] {
+
+                usb::video::VS_FORMAT_UNCOMPRESSED |
+                usb::video::VS_FORMAT_MJPEG |
+                usb::video::VS_FORMAT_FRAME_BASED |
+                usb::video::VS_FORMAT_H264 |
+                usb::video::VS_FORMAT_H264_SIMULCAST |
+                usb::video::VS_FORMAT_VP8 |
+                usb::video::VS_FORMAT_VP8_SIMULCAST |
+                usb::video::VS_FORMAT_MPEG2TS |
+                usb::video::VS_FORMAT_DV |
+                usb::video::VS_FORMAT_STREAM_BASED => {
+
+                    let index: u8 = if len > 3 { desc[3] } else { 0 };
+
+                    match check_format(desc) {
+                        Some(f) if index > 0 && index as usize <= num_formats && !formats.contains_key(&index) => {
+                            formats.insert(index, f);
+                            last = Some(index);
+                            true
+                        }
+                        _ => false,
+                    }
+                }
+
+                // frames follow their format, and have an index of one of its frames
+                usb::video::VS_FRAME_UNCOMPRESSED |
+                usb::video::VS_FRAME_MJPEG |
+                usb::video::VS_FRAME_FRAME_BASED |
+                usb::video::VS_FRAME_H264 |
+                usb::video::VS_FRAME_VP8 => {
+
+                    let format = match last.and_then(|i| formats.get_mut(&i)) {
+                        Some(f) if f.frame_subtype == Some(desc[2]) => f,
+                        _ => {
+                            error!("[E016-VIDEO] Frame descriptor 0x{:x} without its format", desc[2]);
+                            return false;
+                        }
+                    };
+
+                    let index: u8 = if len > 3 { desc[3] } else { 0 };
+
+                    let frame = if desc[2] == usb::video::VS_FRAME_H264 || desc[2] == usb::video::VS_FRAME_VP8 {
+                        Some(Frame {
+                            width: 0,
+                            height: 0,
+                            max_buffer: 0,
+                        })
+                    } else {
+                        check_frame(desc)
+                    };
+
+                    match frame {
+                        Some(f) if index > 0 && index <= format.num_frames && !format.frames.contains_key(&index) => {
+                            format.frames.insert(index, f);
+                            true
+                        }
+                        _ => false,
+                    }
+                }
+
+                usb::video::VS_STILL_IMAGE_FRAME => last.is_some() && check_still_frame(desc),
+
+                // color primaries, transfer characteristics and matrix coefficients
+                usb::video::VS_COLORFORMAT => {
+                    last.is_some() && len == usb::video::COLORFORMAT_SIZE && desc[3] <= 5 &&
+                    desc[4] <= 7 && desc[5] <= 5
+                }
+
+                _ => {
+                    error!("[E017-VIDEO] Unknown video streaming descriptor 0x{:x}", desc[2]);
+                    return false;
+                }
+            };
+
+            if !valid {
+                error!("[E018-VIDEO] Invalid video streaming descriptor 0x{:x} of length {}",
+                       desc[2],
+                       len);
+                return false;
+            }
+        }
+
+        if formats.len() != num_formats ||
+           formats.values().any(|f| f.frame_subtype.is_some() && f.frames.len() != f.num_frames as usize) {
+            error!("[E019-VIDEO] Video streaming interface has {} of {} formats or frames are missing",
+                   formats.len(),
+                   num_formats);
+            return false;
+        }
+
+        self.formats.insert(iface.interface_number, formats);
+
+        true
+    }
+
+    // Class-specific descriptor that follows the interrupt endpoint of a video control interface
+    // (this call updates off)
+    pub fn check_endpoint(&self, iface: &usb::InterfaceDescriptor, data: &[u8], off: &mut usize) -> bool {
+
+        let descs = match class_descs(data, off, usb::DT_CS_ENDPOINT) {
+            Some(v) => v,
+            None => return false,
+        };
+
+        for desc in &descs {
+
+            // the largest interrupt transfer
+            let valid = iface.interface_subclass == usb::video::SC_VIDEOCONTROL && descs.len() == 1 &&
+                        desc[2] == usb::video::EP_INTERRUPT && desc.len() == usb::video::EP_INTERRUPT_SIZE &&
+                        LittleEndian::read_u16(&desc[3..5]) > 0;
+
+            if !valid {
+                error!("[E020-VIDEO] Invalid class-specific endpoint descriptor 0x{:x} of length {}",
+                       desc[2],
+                       desc.len());
+                return false;
+            }
+        }
+
+        true
+    }
+
+    // Probe and commit controls (Section 4.3.1.1 in UVC 1.5), whose size depends on the class
+    // version. The format and frame the device reports must exist, and dwMaxVideoFrameSize must
+    // fit the frame.
+    fn check_probe(&self, h: &usbr::ControlPacketHeader, data: &[u8], source: Source, inum: u8) -> bool {
+
+        let size: usize = match self.versions.get(&inum) {
+            Some(&usb::video::UVC_1_0) => usb::video::PROBE_SIZE_1_0,
+            Some(&usb::video::UVC_1_1) => usb::video::PROBE_SIZE_1_1,
+            Some(&usb::video::UVC_1_5) => usb::video::PROBE_SIZE_1_5,
+            _ => {
+                error!("[E021-VIDEO] Probe or commit for interface {} outside a collection", inum);
+                return false;
+            }
+        };
+
+        if source == Source::Blue && h.request == usb::video::SET_CUR &&
+           (data.len() != size || h.length as usize != size) {
+            error!("[E022-VIDEO] Probe or commit of {} bytes, expected {}", data.len(), size);
+            return false;
+        }
+
+        if source != Source::Red || h.status != usbr::Result::Success as u8 || h.request == usb::video::SET_CUR {
+            return true;
+        }
+
+        // The device may return fewer bytes only if it was asked for fewer
+        if data.len() != cmp::min(size, h.length as usize) {
+            error!("[E023-VIDEO] Probe or commit response of {} bytes, expected {}", data.len(), size);
+            return false;
+        }
+
+        // Minimum, maximum and resolution values are not settings, and format and frame are 0
+        // until the host has negotiated them
+        if (h.request != usb::video::GET_CUR && h.request != usb::video::GET_DEF) ||
+           data.len() < usb::video::PROBE_SIZE_1_0 || data[2] == 0 || data[3] == 0 {
+            return true;
+        }
+
+        let format = match self.formats.get(&inum).and_then(|f| f.get(&data[2])) {
+            Some(v) => v,
+            None => {
+                error!("[E024-VIDEO] Probe or commit with unknown format {}", data[2]);
+                return false;
+            }
+        };
+
+        if format.frame_subtype.is_none() {
+            return true;
+        }
+
+        let frame = match format.frames.get(&data[3]) {
+            Some(v) => v,
+            None => {
+                error!("[E025-VIDEO] Probe or commit with unknown frame {} of format {}",
+                       data[3],
+                       data[2]);
+                return false;
+            }
+        };
+
+        // Frames fit in the frame buffer (uncompressed frames may also have exactly the size of
+        // the image, since some devices declare a smaller buffer)
+        let max_frame: u64 = LittleEndian::read_u32(&data[18..22]) as u64;
+        let image: u64 = frame.width as u64 * frame.height as u64 * format.bits_per_pixel as u64 / 8;
+
+        let valid = max_frame > 0 &&
+                    (frame.max_buffer == 0 || max_frame <= frame.max_buffer as u64 ||
+                     (format.subtype == usb::video::VS_FORMAT_UNCOMPRESSED && max_frame == image));
+
+        if !valid {
+            error!("[E026-VIDEO] dwMaxVideoFrameSize {} for a frame of {}x{}",
+                   max_frame,
+                   frame.width,
+                   frame.height);
+            return false;
+        }
+
+        true
+    }
+
+    // Class requests of video interfaces (Section 4.2 in UVC 1.5)
+    pub fn check_video_request(&self,
+                               h: &usbr::ControlPacketHeader,
+                               data: &[u8],
+                               source: Source,
+                               iface: &usb::InterfaceDescriptor)
+                               -> bool {
+
+        let transfer_in: bool = (h.requesttype & usb::DIR_IN) == usb::DIR_IN;
+
+        // requests with a data-in stage
+        let dir_in: bool = match h.request {
+
+            usb::video::SET_CUR => false,
+
+            usb::video::GET_CUR |
+            usb::video::GET_MIN |
+            usb::video::GET_MAX |
+            usb::video::GET_RES |
+            usb::video::GET_LEN |
+            usb::video::GET_INFO |
+            usb::video::GET_DEF => true,
+
+            _ => {
+                error!("[E027-VIDEO] Unknown request type 0x{:x}", h.request);
+                return false;
+            }
+        };
+
+        if transfer_in != dir_in {
+            error!("[E028-VIDEO] Request 0x{:x} has the wrong direction", h.request);
+            return false;
+        }
+
+        // The device returns data only for data-in requests, and no more than was asked for
+        if source == Source::Red && ((!transfer_in && !data.is_empty()) || data.len() > h.length as usize) {
+            error!("[E029-VIDEO] Invalid response length {} to request 0x{:x}", data.len(), h.request);
+            return false;
+        }
+
+        // The high byte of wIndex selects an entity of video control interfaces, and the high byte
+        // of wValue the control
+        let id: u8 = (h.index >> 8) as u8;
+
+        if iface.interface_subclass == usb::video::SC_VIDEOCONTROL && id != 0 &&
+           !self.entities.get(&iface.interface_number).iter().any(|e| e.contains_key(&id)) {
+            error!("[E030-VIDEO] Request for unknown entity {} of interface {}",
+                   id,
+                   iface.interface_number);
+            return false;
+        }
+
+        let success: bool = source == Source::Red && h.status == usbr::Result::Success as u8;
+
+        match h.request {
+
+            // only bits 0-5 of the capabilities
+            usb::video::GET_INFO => {
+                if success && (data.len() != 1 || data[0] & 0xc0 != 0) {
+                    error!("[E031-VIDEO] Invalid control capabilities");
+                    return false;
+                }
+
+                return true;
+            }
+
+            usb::video::GET_LEN => {
+                if success && data.len() != 2 {
+                    error!("[E032-VIDEO] Invalid control length of {} bytes", data.len());
+                    return false;
+                }
+
+                return true;
+            }
+
+            _ => {}
+        }
+
+        let selector: u8 = (h.value >> 8) as u8;
+
+        if iface.interface_subclass == usb::video::SC_VIDEOSTREAMING &&
+           (selector == usb::video::VS_PROBE_CONTROL || selector == usb::video::VS_COMMIT_CONTROL) {
+            return self.check_probe(h, data, source, iface.interface_number);
+        }
+
+        true
+    }
+}
@@ -0,0 +1,25 @@
+#![allow(unused_variables)]
+
+use parser;
+use parser::{Request, Source};
+
+// Silently discards every request it receives (the "drop" action).
+pub struct Discard;
+
+impl Discard {
+    pub fn new() -> Discard {
+        Discard
+    }
+
+    fn discard(&self, req: Request) -> (u8, Vec<Request>) {
+        debug!("Dropping request of type {} (id {})", req.get_type(), req.get_id());
+        (0, vec![])
+    }
+}
+
+
+impl parser::HasHandlers for Discard {
+    fn handle_request(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        self.discard(req)
+    }
+}
@@ -0,0 +1,116 @@
+#![allow(unused_variables)]
+
+use std::cmp;
+use std::sync::Mutex;
+use byteorder::{ByteOrder, LittleEndian};
+
+use parser;
+use parser::filter;
+use parser::usbr;
+use parser::{Request, Source};
+
+// Port for rejected devices when red cannot be told (e.g., to reset)
+pub const PORT_REJECT: u8 = 1;
+
+
+// Applies a usbredir filter (see parser/filter.rs) to the devices that red offers. Cinch sends
+// its rules to red right after blue's hello, and answers the connect of a device that the rules
+// reject with a filter reject, so blue never sees the device. Blue's own filter is not forwarded
+// (red keeps a single filter, and blue applies its own anyway). Red's hello must advertise the
+// filter capability for the reject; otherwise the connect goes out PORT_REJECT. The module must
+// see both directions.
+pub struct DeviceFilter {
+    rules: Vec<filter::Rule>,
+    state: Mutex<State>,
+}
+
+struct State {
+    red_filter: bool, // red has the filter capability
+    interfaces: Vec<(u8, u8, u8)>, // from the interface info that precedes the connect
+}
+
+// Whether the capabilities in a hello include the filter capability
+fn hello_has_filter(data: &[u8]) -> bool {
+    let caps: Vec<u32> = data.chunks(4).filter(|c| c.len() == 4).map(LittleEndian::read_u32).collect();
+    !caps.is_empty() && parser::has_cap(&caps, usbr::Caps::Filter as usize)
+}
+
+impl DeviceFilter {
+    pub fn new(rules: &str) -> Result<DeviceFilter, String> {
+        Ok(DeviceFilter {
+            rules: filter::parse(rules)?,
+            state: Mutex::new(State {
+                red_filter: false,
+                interfaces: vec![],
+            }),
+        })
+    }
+}
+
+
+impl parser::HasHandlers for DeviceFilter {
+    fn handle_hello(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
+
+        if source == Source::Red {
+            self.state.lock().unwrap().red_filter = hello_has_filter(&req.data);
+            return (0, vec![req]);
+        }
+
+        // Blue's hello reaches red as cinch's, so red must know that it may get filter packets
+        if !req.data.is_empty() && !hello_has_filter(&req.data) {
+            let mut caps = [LittleEndian::read_u32(&req.data[0..4])];
+            parser::set_cap(&mut caps, usbr::Caps::Filter as usize);
+            LittleEndian::write_u32(&mut req.data[0..4], caps[0]);
+        }
+
+        let rules = Request::new(usbr::HeaderType::FilterFilter as u32, 0, vec![], filter::to_packet(&self.rules));
+
+        (0, vec![req, rules])
+    }
+
+    fn handle_filter_filter(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        if source == Source::Blue {
+            debug!("Not forwarding blue's filter {}",
+                   String::from_utf8_lossy(&req.data[..req.data.len() - 1]));
+            return (0, vec![]);
+        }
+
+        (0, vec![req])
+    }
+
+    fn handle_interface_info(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        // count, then interface, class, subclass and protocol arrays (32 entries each)
+        let th = &req.type_header;
+        let count = cmp::min(LittleEndian::read_u32(&th[0..4]) as usize, 32);
+
+        self.state.lock().unwrap().interfaces = (0..count).map(|i| (th[36 + i], th[68 + i], th[100 + i])).collect();
+
+        (0, vec![req])
+    }
+
+    fn handle_connect(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        let h_ptr = req.type_header.as_ptr() as *const usbr::ConnectHeader;
+        let h: &usbr::ConnectHeader = unsafe { &*h_ptr };
+        let state = self.state.lock().unwrap();
+
+        // The header is packed, so its fields are copied rather than borrowed
+        let (vendor_id, product_id) = (h.vendor_id, h.product_id);
+
+        if filter::check(&self.rules, h.class, &state.interfaces, vendor_id, product_id, h.version_bcd) {
+            return (0, vec![req]);
+        }
+
+        error!("[E001-Filter] Device {:04x}:{:04x} rejected by the filter", vendor_id, product_id);
+
+        if !state.red_filter {
+            return (PORT_REJECT, vec![req]);
+        }
+
+        let reject = Request::new(usbr::HeaderType::FilterReject as u32, 0, vec![], vec![]);
+
+        (0, vec![reject.into_reply()])
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use byteorder::{ByteOrder, LittleEndian};
+
+use parser::usbr;
+use usb;
+
+// Offsets in the device descriptor
+const DEVICE_VENDOR: usize = 8;
+const DEVICE_PRODUCT: usize = 10;
+const DEVICE_STRINGS: [usize; 3] = [14, 15, 16]; // manufacturer, product and serial number
+const DEVICE_NUM_CONFIGS: usize = 17;
+
+// 64-bit FNV-1a, which stays the same across builds (unlike the hasher of the standard library)
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn fnv(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |h, b| (h ^ *b as u64).wrapping_mul(FNV_PRIME))
+}
+
+
+// Collects the descriptors that red returns to blue's GET_DESCRIPTOR requests. Once it has the
+// device descriptor, every configuration descriptor and the manufacturer, product and serial
+// number strings, they hash to the device's fingerprint. Descriptors are hashed as sent, so the
+// fingerprint covers the whole configuration tree (including class-specific descriptors).
+pub struct Fingerprinter {
+    device: Option<Vec<u8>>,
+    configs: BTreeMap<u8, Vec<u8>>, // complete configuration descriptors by index
+    strings: HashMap<u8, Vec<u8>>, // string descriptors by index (first language seen)
+}
+
+impl Fingerprinter {
+    pub fn new() -> Fingerprinter {
+        Fingerprinter {
+            device: None,
+            configs: BTreeMap::new(),
+            strings: HashMap::new(),
+        }
+    }
+
+    // Red's response to a GET_DESCRIPTOR. Returns true if it is part of the fingerprint.
+    pub fn add(&mut self, h: &usbr::ControlPacketHeader, data: &[u8]) -> bool {
+
+        if h.request != usb::REQ_GET_DESCRIPTOR || h.status != usbr::Result::Success as u8 ||
+           h.requesttype != usb::DIR_IN | usb::TYPE_STANDARD | usb::RECIP_DEVICE {
+            return false;
+        }
+
+        let index = h.value as u8;
+
+        match (h.value >> 8) as u8 {
+
+            usb::DT_DEVICE if data.len() == usb::DEVICE_DESC_SIZE + usb::HEADER_SIZE => {
+                self.device = Some(data.to_vec());
+            }
+
+            // Only the complete descriptor (not the first 9 bytes that tell its total length)
+            usb::DT_CONFIG if data.len() >= 4 && LittleEndian::read_u16(&data[2..4]) as usize == data.len() => {
+                self.configs.insert(index, data.to_vec());
+            }
+
+            // Only the whole string (not a probe for its length)
+            usb::DT_STRING if index != 0 && data.len() >= usb::HEADER_SIZE && data[0] as usize == data.len() &&
+                              !self.strings.contains_key(&index) => {
+                self.strings.insert(index, data.to_vec());
+            }
+
+            _ => return false,
+        }
+
+        true
+    }
+
+    // Index of the serial number string (None until the device descriptor arrives)
+    pub fn serial_index(&self) -> Option<u8> {
+        self.device.as_ref().map(|d| d[DEVICE_STRINGS[2]])
+    }
+
+    pub fn serial(&self) -> Option<String> {
+
+        let desc = self.serial_index().and_then(|i| self.strings.get(&i))?;
+
+        let units: Vec<u16> = desc[usb::HEADER_SIZE..]
+            .chunks(2)
+            .filter(|c| c.len() == 2)
+            .map(LittleEndian::read_u16)
+            .collect();
+
+        Some(String::from_utf16_lossy(&units))
+    }
+
+    // vid:pid:serial of devices with a serial number, which tells one device from another
+    pub fn identity(&self) -> Option<String> {
+
+        let device = self.device.as_ref()?;
+
+        Some(format!("{:04x}:{:04x}:{}",
+                     LittleEndian::read_u16(&device[DEVICE_VENDOR..]),
+                     LittleEndian::read_u16(&device[DEVICE_PRODUCT..]),
+                     self.serial()?))
+    }
+
+    // None until every descriptor that goes into the fingerprint has arrived
+    pub fn fingerprint(&self) -> Option<String> {
+
+        let device = self.device.as_ref()?;
+        let mut hash = fnv(FNV_OFFSET, device);
+
+        for index in 0..device[DEVICE_NUM_CONFIGS] {
+            let config = self.configs.get(&index)?;
+            hash = fnv(hash, config);
+        }
+
+        for off in &DEVICE_STRINGS {
+
+            let string: &[u8] = match device[*off] {
+                0 => &[],
+                i => self.strings.get(&i)?,
+            };
+
+            // The length keeps strings from running into each other
+            let mut length = [0; 2];
+            LittleEndian::write_u16(&mut length, string.len() as u16);
+
+            hash = fnv(fnv(hash, &length), string);
+        }
+
+        Some(format!("{:016x}", hash))
+    }
+}
+
+impl Default for Fingerprinter {
+    fn default() -> Fingerprinter {
+        Fingerprinter::new()
+    }
+}
+
+
+// The fingerprints of known devices, one "vid:pid:serial fingerprint" line per device. The
+// file is read on every lookup, so it can be shared by sessions and edited while cinch runs.
+pub struct FingerprintDb {
+    path: String,
+}
+
+impl FingerprintDb {
+    pub fn new(path: &str) -> FingerprintDb {
+        FingerprintDb { path: path.to_string() }
+    }
+
+    pub fn lookup(&self, identity: &str) -> Result<Option<String>, String> {
+
+        let mut contents = String::new();
+
+        match File::open(&self.path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("could not read {}: {}", self.path, e)),
+        }
+
+        // The serial number may contain spaces, so the fingerprint is the last word
+        Ok(contents.lines()
+            .filter_map(|l| {
+                let l = l.trim();
+                l.rfind(' ').map(|i| (&l[..i], &l[i + 1..]))
+            })
+            .find(|&(id, _)| id.trim_end() == identity)
+            .map(|(_, fp)| fp.to_string()))
+    }
+
+    pub fn record(&self, identity: &str, fingerprint: &str) -> Result<(), String> {
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| writeln!(f, "{} {}", identity, fingerprint))
+            .map_err(|e| format!("could not write {}: {}", self.path, e))
+    }
+
+    // Records devices seen for the first time. Returns false if a known device presents a
+    // different fingerprint than it did before.
+    pub fn check(&self, identity: &str, fingerprint: &str) -> bool {
+
+        let result = match self.lookup(identity) {
+            Ok(Some(ref known)) if known != fingerprint => {
+                error!("[E001-Fingerprint] Device {} has fingerprint {}, but was known as {}",
+                       identity,
+                       fingerprint,
+                       known);
+                return false;
+            }
+
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => self.record(identity, fingerprint),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            error!("[E002-Fingerprint] {}", e);
+        }
+
+        true
+    }
+}
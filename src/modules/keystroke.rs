@@ -0,0 +1,251 @@
+#![allow(unused_variables)]
+
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use byteorder::{ByteOrder, LittleEndian};
+
+use parser;
+use parser::usbr;
+use parser::{Request, Source};
+use usb;
+use usb::hid;
+
+// Defaults for the thresholds that the configuration does not set
+pub const DEFAULT_MIN_DELAY: u64 = 500; // milliseconds
+pub const DEFAULT_MAX_RATE: usize = 25; // key presses per second
+
+// Chords that open a shell or a run dialog: (modifiers, key)
+const CHORDS: [(u8, u8, &str); 5] = [(hid::MOD_GUI, hid::KEY_R, "GUI+R"),
+                                     (hid::MOD_GUI, hid::KEY_X, "GUI+X"),
+                                     (hid::MOD_GUI, hid::KEY_SPACE, "GUI+Space"),
+                                     (hid::MOD_ALT, hid::KEY_F2, "Alt+F2"),
+                                     (hid::MOD_CTRL | hid::MOD_ALT, hid::KEY_T, "Ctrl+Alt+T")];
+
+
+// Detects keyboards that inject keystrokes (e.g., BadUSB or rubber ducky devices): keys pressed
+// too soon after the device is enumerated, faster than a human types, or chords that open a
+// shell. Once a keyboard gives itself away, the module raises an alert and drops the interrupt
+// packets of its interface until the device goes away; other interfaces of the device (e.g., the
+// mass storage of a rubber ducky) are up to the other modules. It only needs to see red: interface
+// and endpoint info tell it which endpoints belong to keyboards.
+pub struct KeystrokeDetector {
+    min_delay: Option<Duration>,
+    max_rate: Option<usize>,
+    chords: bool,
+    state: Mutex<State>,
+}
+
+struct State {
+    keyboards: HashMap<u8, bool>, // interface -> keyboard
+    ep_interface: HashMap<usize, u8>, // interrupt endpoint index -> interface
+    enumerated: Option<Instant>, // when red last described the device's interfaces
+    keys: HashMap<u8, Vec<u8>>, // interface -> keys down in its last report
+    presses: VecDeque<Instant>, // key presses in the last second
+    quarantined: HashSet<u8>, // interfaces caught injecting keystrokes
+}
+
+impl State {
+    // A new device starts with a clean slate
+    fn forget_device(&mut self) {
+        self.quarantined.clear();
+        self.keys.clear();
+        self.presses.clear();
+    }
+}
+
+// Same numbering as the arrays of the ep info header
+fn ep_index(ep: u8) -> usize {
+    (((ep & usb::ENDPOINT_DIR_MASK) >> 3) | (ep & usb::ENDPOINT_NUMBER_MASK)) as usize
+}
+
+// Modifiers and keys of a boot keyboard report, which keyboards also use in report protocol
+// (possibly after a report id)
+fn parse_report(data: &[u8]) -> Option<(u8, Vec<u8>)> {
+
+    let report = match data.len() {
+        hid::BOOT_KEYBOARD_SIZE => data,
+        x if x == hid::BOOT_KEYBOARD_SIZE + 1 => &data[1..],
+        _ => return None,
+    };
+
+    let keys = report[hid::BOOT_KEYBOARD_KEYS..]
+        .iter()
+        .cloned()
+        .filter(|k| *k > hid::KEY_ERROR_UNDEFINED)
+        .collect();
+
+    Some((report[0], keys))
+}
+
+// Both sides of a modifier count (e.g., left or right GUI)
+fn chord_name(modifiers: u8, key: u8) -> Option<&'static str> {
+
+    CHORDS.iter()
+        .find(|&&(mods, k, _)| {
+            k == key && (0..4).all(|bit| {
+                let mask = mods & (0x11 << bit);
+                mask == 0 || (modifiers & mask) != 0
+            })
+        })
+        .map(|&(_, _, name)| name)
+}
+
+impl KeystrokeDetector {
+    // min_delay and max_rate of 0 disable their check
+    pub fn new(min_delay: u64, max_rate: usize, chords: bool) -> KeystrokeDetector {
+        KeystrokeDetector {
+            min_delay: if min_delay > 0 { Some(Duration::from_millis(min_delay)) } else { None },
+            max_rate: if max_rate > 0 { Some(max_rate) } else { None },
+            chords: chords,
+            state: Mutex::new(State {
+                keyboards: HashMap::new(),
+                ep_interface: HashMap::new(),
+                enumerated: None,
+                keys: HashMap::new(),
+                presses: VecDeque::new(),
+                quarantined: HashSet::new(),
+            }),
+        }
+    }
+
+    // A keyboard report from red. Returns why the keys could not have come from a human.
+    fn check_report(&self, state: &mut State, iface: u8, data: &[u8]) -> Option<String> {
+
+        let (modifiers, keys) = parse_report(data)?;
+        let now = Instant::now();
+
+        let pressed: Vec<u8> = {
+            let previous = state.keys.entry(iface).or_default();
+            keys.iter().cloned().filter(|k| !previous.contains(k)).collect()
+        };
+
+        state.keys.insert(iface, keys);
+
+        if pressed.is_empty() {
+            return None;
+        }
+
+        if let (Some(min_delay), Some(enumerated)) = (self.min_delay, state.enumerated) {
+            let elapsed = now.duration_since(enumerated);
+
+            if elapsed < min_delay {
+                return Some(format!("typing {:?} after enumeration", elapsed));
+            }
+        }
+
+        if self.chords {
+            if let Some(name) = pressed.iter().filter_map(|k| chord_name(modifiers, *k)).next() {
+                return Some(format!("pressed {}", name));
+            }
+        }
+
+        if let Some(max_rate) = self.max_rate {
+
+            state.presses.retain(|t| now.duration_since(*t) < Duration::from_secs(1));
+
+            for _ in &pressed {
+                state.presses.push_back(now);
+            }
+
+            if state.presses.len() > max_rate {
+                return Some(format!("{} key presses in under a second", state.presses.len()));
+            }
+        }
+
+        None
+    }
+}
+
+impl Default for KeystrokeDetector {
+    fn default() -> KeystrokeDetector {
+        KeystrokeDetector::new(DEFAULT_MIN_DELAY, DEFAULT_MAX_RATE, true)
+    }
+}
+
+
+impl parser::HasHandlers for KeystrokeDetector {
+    fn handle_interface_info(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        // count, then interface, class, subclass and protocol arrays (32 entries each)
+        let th = &req.type_header;
+        let count = cmp::min(LittleEndian::read_u32(&th[0..4]) as usize, 32);
+        let mut state = self.state.lock().unwrap();
+
+        state.keyboards.clear();
+        state.keys.clear();
+        state.enumerated = Some(Instant::now());
+
+        for i in 0..count {
+            state.keyboards.insert(th[4 + i],
+                                   th[36 + i] == usb::CLASS_HID && th[100 + i] == hid::PROTO_KEYBOARD);
+        }
+
+        (0, vec![req])
+    }
+
+    fn handle_ep_info(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        // type, interval and interface arrays (32 entries each)
+        let th = &req.type_header;
+        let mut state = self.state.lock().unwrap();
+
+        state.ep_interface.clear();
+
+        for i in 0..32 {
+            if th[i] == usbr::TransferType::Interrupt as u8 {
+                state.ep_interface.insert(i, th[64 + i]);
+            }
+        }
+
+        (0, vec![req])
+    }
+
+    fn handle_connect(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        self.state.lock().unwrap().forget_device();
+        (0, vec![req])
+    }
+
+    fn handle_disconnect(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        self.state.lock().unwrap().forget_device();
+        (0, vec![req])
+    }
+
+    fn handle_int_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        if source != Source::Red {
+            return (0, vec![req]);
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        let iface = match state.ep_interface.get(&ep_index(req.type_header[0])) {
+            Some(i) => *i,
+            None => return (0, vec![req]),
+        };
+
+        if state.quarantined.contains(&iface) {
+            debug!("Dropping interrupt packet (id {}) of quarantined interface {}",
+                   req.get_id(),
+                   iface);
+            return (0, vec![]);
+        }
+
+        if !*state.keyboards.get(&iface).unwrap_or(&false) ||
+           req.type_header[1] != usbr::Result::Success as u8 {
+            return (0, vec![req]);
+        }
+
+        if let Some(reason) = self.check_report(&mut state, iface, &req.data) {
+            error!("[E001-Keystroke] Keystroke injection by interface {} ({}). Quarantining the interface.",
+                   iface,
+                   reason);
+
+            state.quarantined.insert(iface);
+            return (0, vec![]);
+        }
+
+        (0, vec![req])
+    }
+}
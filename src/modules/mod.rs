@@ -3,6 +3,18 @@ pub mod null;
 pub mod control_checks;
 pub mod logger;
 pub mod patcher;
+pub mod policy;
+pub mod discard;
+pub mod stall;
+pub mod rewrite;
+pub mod readonly;
+pub mod keystroke;
+pub mod authorizer;
+pub mod fingerprint;
+pub mod filter;
+pub mod registry;
+pub mod probe;
+pub mod rules;
 
 use std::sync::Arc;
 use std::collections::HashMap;
@@ -15,31 +27,48 @@ pub struct Modules {
     terminal: HashMap<u8, Arc<parser::HasHandlers>>,
 }
 
+// Each stage of nonterminals may drop a request or turn it into several. Every request a
+// module emits continues to the next stage through the port that the module returned,
+// except replies, which are on their way back to the source and skip the rest of the chain.
+// Emitted requests may be of another type (e.g., reset turns anything into a disconnect), so
+// each module handles a request according to its own type.
 macro_rules! traverse_modules {
-    ($self_:ident, $source:expr, $req:ident, $h_type:ident) => {{
+    ($self_:ident, $source:expr, $req:ident) => {{
 
-        let mut params: (u8, Vec<Request>) = (0, vec![]); // (port, out) tuple
+        let mut pending: Vec<(u8, Request)> = vec![(0, $req)]; // (port, request) pairs
+        let mut outputs: Vec<Request> = vec![];
 
         for module in &$self_.nonterminals {
 
-            params = match module.get(&params.0) {
-                Some(v) => v.$h_type($source, $req),
-                None => panic!("Invalid port for nonterminal module"),
-            };
-
-            // By requirement, intermediary modules cannot produce more than 1 response
-            assert_eq!(params.1.len(), 1);
-            $req = match params.1.pop() {
-                Some(v) => v,
-                None => panic!("nonterminal module returned nothing"),
-            };
+            let mut next: Vec<(u8, Request)> = vec![];
+
+            for (port, req) in pending {
+
+                let (out_port, out) = match module.get(&port) {
+                    Some(v) => parser::dispatch_request(&**v, $source, req),
+                    None => panic!("Invalid port for nonterminal module"),
+                };
+
+                for r in out {
+                    if r.reply {
+                        outputs.push(r);
+                    } else {
+                        next.push((out_port, r));
+                    }
+                }
+            }
+
+            pending = next;
         }
 
-        assert!((params.0 as usize) < $self_.terminal.len());
-        match $self_.terminal.get(&params.0) {
-            Some(v) => v.$h_type($source, $req),
-            None => panic!("terminal module did not return anything"),
+        for (port, req) in pending {
+            match $self_.terminal.get(&port) {
+                Some(v) => outputs.extend(parser::dispatch_request(&**v, $source, req).1),
+                None => panic!("Invalid port for terminal module"),
+            }
         }
+
+        (0, outputs)
     }}
 }
 
@@ -69,130 +98,143 @@ unsafe impl Send for Modules {}
 
 #[allow(unused_variables)]
 impl parser::HasHandlers for Modules {
-    fn handle_hello(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_hello)
+    fn session_ended(&self) -> bool {
+        self.nonterminals.iter().flat_map(|stage| stage.values()).any(|m| m.session_ended()) ||
+        self.terminal.values().any(|m| m.session_ended())
+    }
+
+    fn handle_hello(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
+    }
+
+    fn handle_connect(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
+    }
+
+    fn handle_disconnect(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_connect(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_connect)
+    fn handle_disconnect_ack(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_disconnect(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_disconnect)
+    fn handle_reset(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_disconnect_ack(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_disconnect_ack)
+    fn handle_cancel_data_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_reset(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_reset)
+    fn handle_interface_info(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_cancel_data_packet(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_cancel_data_packet)
+    fn handle_ep_info(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_interface_info(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_interface_info)
+    fn handle_get_conf(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_ep_info(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_ep_info)
+    fn handle_set_conf(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_get_conf(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_get_conf)
+    fn handle_conf_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_set_conf(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_set_conf)
+    fn handle_get_alt_setting(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_conf_status(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_conf_status)
+    fn handle_set_alt_setting(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_get_alt_setting(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_get_alt_setting)
+    fn handle_alt_setting_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_set_alt_setting(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_set_alt_setting)
+    fn handle_start_iso_stream(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_alt_setting_status(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_alt_setting_status)
+    fn handle_stop_iso_stream(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_start_iso_stream(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_start_iso_stream)
+    fn handle_iso_stream_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_stop_iso_stream(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_stop_iso_stream)
+    fn handle_start_int_receiving(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_iso_stream_status(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_iso_stream_status)
+    fn handle_stop_int_receiving(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_start_int_receiving(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_start_int_receiving)
+    fn handle_int_receiving_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_stop_int_receiving(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_stop_int_receiving)
+    fn handle_alloc_bulk_streams(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_int_receiving_status(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_int_receiving_status)
+    fn handle_free_bulk_streams(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_alloc_bulk_streams(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_alloc_bulk_streams)
+    fn handle_bulk_streams_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_free_bulk_streams(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_free_bulk_streams)
+    fn handle_start_bulk_receiving(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_bulk_streams_status(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_bulk_streams_status)
+    fn handle_stop_bulk_receiving(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_start_bulk_receiving(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_start_bulk_receiving)
+    fn handle_bulk_receiving_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_stop_bulk_receiving(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_stop_bulk_receiving)
+    fn handle_filter_reject(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_bulk_receiving_status(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_bulk_receiving_status)
+    fn handle_filter_filter(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
 
     // Data packets
 
-    fn handle_control_packet(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_control_packet)
+    fn handle_control_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_bulk_packet(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_bulk_packet)
+    fn handle_bulk_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_int_packet(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_int_packet)
+    fn handle_int_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_iso_packet(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_iso_packet)
+    fn handle_iso_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 
-    fn handle_buffered_bulk_packet(&self, source: Source, mut req: Request) -> (u8, Vec<Request>) {
-        traverse_modules!(self, source, req, handle_buffered_bulk_packet)
+    fn handle_buffered_bulk_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        traverse_modules!(self, source, req)
     }
 }
@@ -1,6 +1,7 @@
 use std::io::prelude::*;
 use std::fs;
 use std::cell::RefCell;
+use std::sync::Arc;
 use std::fs::File;
 use std::collections::HashMap;
 use rustc_serialize::json;
@@ -28,8 +29,14 @@ struct Patch {
     data: String, // hex-encoded
 }
 
-pub struct Patcher {
+// Patches read from a folder. One set is shared by every session started while it is current
+// (see modules::rules).
+pub struct PatchSet {
     patches: Vec<Patch>,
+}
+
+pub struct Patcher {
+    patches: Arc<PatchSet>,
     counts: RefCell<HashMap<u32, u16>>,
 }
 
@@ -41,35 +48,66 @@ impl PatchMetadata {
 
 
 
-impl Patcher {
-    pub fn new(dir_path: &str) -> Patcher {
+impl PatchSet {
+    pub fn empty() -> PatchSet {
+        PatchSet { patches: vec![] }
+    }
 
-        let mut patcher = Patcher { patches: vec![], counts: RefCell::new(HashMap::new()) };
+    // Every file in the folder must be a valid patch
+    pub fn load(dir_path: &str) -> Result<PatchSet, String> {
 
-        for entry in fs::read_dir(dir_path).unwrap() {
+        let mut patches = vec![];
+        let dir = fs::read_dir(dir_path).map_err(|e| format!("could not list {}: {}", dir_path, e))?;
 
-            let mut file = match File::open(&entry.unwrap().path()) {
-                Ok(file) => file,
-                Err(e) => panic!("[E000-Patcher] Could not open file {}", e),
-            };
+        for entry in dir {
+
+            let path = entry.map_err(|e| format!("could not list {}: {}", dir_path, e))?.path();
+
+            let mut file = File::open(&path).map_err(|e| format!("could not open {}: {}", path.display(), e))?;
 
             // read file
             let mut json_line = String::new();
-            file.read_to_string(&mut json_line).unwrap();
+            file.read_to_string(&mut json_line)
+                .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
 
             // decode file
-            let patch: Patch = json::decode(&json_line).unwrap();
-
-            // Insert count entry
-            if !patcher.counts.borrow().contains_key(&patch.meta.patch_id) {
-                patcher.counts.borrow_mut().insert(patch.meta.patch_id, patch.meta.min_matches);
+            match json::decode(&json_line) {
+                Ok(patch) => patches.push(patch),
+                Err(e) => return Err(format!("invalid patch {}: {}", path.display(), e)),
             }
+        }
+
+        Ok(PatchSet { patches: patches })
+    }
+
+    pub fn len(&self) -> usize {
+        self.patches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patches.is_empty()
+    }
+}
+
+
+impl Patcher {
+    pub fn new(dir_path: &str) -> Patcher {
+
+        match PatchSet::load(dir_path) {
+            Ok(patches) => Patcher::with_patches(Arc::new(patches)),
+            Err(e) => panic!("[E000-Patcher] {}", e),
+        }
+    }
+
+    pub fn with_patches(patches: Arc<PatchSet>) -> Patcher {
+
+        let mut counts = HashMap::new();
 
-            // Insert patch into our list
-            patcher.patches.push(patch);
+        for patch in &patches.patches {
+            counts.entry(patch.meta.patch_id).or_insert(patch.meta.min_matches);
         }
 
-        patcher
+        Patcher { patches: patches, counts: RefCell::new(counts) }
     }
 
     fn check_control_packet(&self, req: &Request) -> bool {
@@ -77,7 +115,7 @@ impl Patcher {
         let h_ptr = req.type_header.as_ptr() as *const usbr::ControlPacketHeader;
         let h: &usbr::ControlPacketHeader = unsafe { &*h_ptr };
 
-        for patch in &self.patches {
+        for patch in &self.patches.patches {
 
             if patch.meta.p_type != "control" {
                 continue;
@@ -109,7 +147,7 @@ impl Patcher {
 
     fn check_bulk_packet(&self, req: &Request) -> bool {
 
-        for patch in &self.patches {
+        for patch in &self.patches.patches {
 
             if patch.meta.p_type != "bulk" {
                 continue;
@@ -143,7 +181,7 @@ impl Patcher {
         let h_ptr = req.type_header.as_ptr() as *const usbr::ConnectHeader;
         let h: &usbr::ConnectHeader = unsafe { &*h_ptr };
 
-        for patch in &self.patches {
+        for patch in &self.patches.patches {
 
             if patch.meta.p_type != "connect" {
                 continue;
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+// What happens to a request that fails a check. Each action is a different output port of
+// the checking module, so the module graph decides which module carries it out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Action {
+    Pass,
+    Reset,
+    Drop,
+    Stall,
+    Rewrite,
+}
+
+pub const PORT_PASS: u8 = 0;
+pub const PORT_RESET: u8 = 1;
+pub const PORT_DROP: u8 = 2;
+pub const PORT_STALL: u8 = 3;
+pub const PORT_REWRITE: u8 = 4;
+
+impl Action {
+    pub fn from_name(name: &str) -> Option<Action> {
+        match name {
+            "pass" => Some(Action::Pass),
+            "reset" => Some(Action::Reset),
+            "drop" => Some(Action::Drop),
+            "stall" => Some(Action::Stall),
+            "rewrite" => Some(Action::Rewrite),
+            _ => None,
+        }
+    }
+
+    pub fn port(&self) -> u8 {
+        match *self {
+            Action::Pass => PORT_PASS,
+            Action::Reset => PORT_RESET,
+            Action::Drop => PORT_DROP,
+            Action::Stall => PORT_STALL,
+            Action::Rewrite => PORT_REWRITE,
+        }
+    }
+}
+
+
+// Maps the name of a check (e.g., "get_descriptor") to the action taken when it fails.
+// Checks that are not listed use the default action, which is reset unless configured.
+pub struct CheckPolicy {
+    default: Action,
+    actions: HashMap<String, Action>,
+}
+
+impl CheckPolicy {
+    pub fn new() -> CheckPolicy {
+        CheckPolicy {
+            default: Action::Reset,
+            actions: HashMap::new(),
+        }
+    }
+
+    // Builds the policy from the "check_actions" configuration entry. The key "default"
+    // replaces the default action.
+    pub fn from_config(config: &HashMap<String, String>) -> Result<CheckPolicy, String> {
+
+        let mut policy = CheckPolicy::new();
+
+        for (check, name) in config {
+
+            let action = match Action::from_name(name) {
+                Some(v) => v,
+                None => return Err(format!("unknown action {} for check {}", name, check)),
+            };
+
+            if check == "default" {
+                policy.default = action;
+            } else {
+                policy.actions.insert(check.clone(), action);
+            }
+        }
+
+        Ok(policy)
+    }
+
+    pub fn action(&self, check: &str) -> Action {
+        match self.actions.get(check) {
+            Some(v) => *v,
+            None => self.default,
+        }
+    }
+
+    // Ports (other than PORT_PASS) of the actions that failed checks may take
+    pub fn ports(&self) -> Vec<u8> {
+
+        let mut ports: Vec<u8> = self.actions.values().chain(Some(&self.default)).map(|a| a.port()).collect();
+
+        ports.retain(|p| *p != PORT_PASS);
+        ports.sort();
+        ports.dedup();
+        ports
+    }
+}
+
+impl Default for CheckPolicy {
+    fn default() -> CheckPolicy {
+        CheckPolicy::new()
+    }
+}
@@ -0,0 +1,236 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use parser;
+use parser::{Request, Source};
+
+
+// Something a module did to a request other than passing it on unchanged through port 0
+pub struct Hit {
+    pub record: usize, // index of the request in the capture being replayed
+    pub module: String,
+    pub source: Source,
+    pub h_type: u32,
+    pub port: u8, // port the module returned
+    pub outputs: usize, // number of requests the module emitted (0 if it dropped the request)
+    pub rewritten: bool, // the module changed the request or emitted several
+}
+
+// Collects the hits of every probe in a session. The replay sets the current record before
+// handing each request to the modules.
+pub struct Recorder {
+    record: AtomicUsize,
+    hits: Mutex<Vec<Hit>>,
+}
+
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder {
+            record: AtomicUsize::new(0),
+            hits: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn set_record(&self, record: usize) {
+        self.record.store(record, Ordering::SeqCst);
+    }
+
+    pub fn take_hits(&self) -> Vec<Hit> {
+        let mut hits = self.hits.lock().unwrap();
+        hits.drain(..).collect()
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Recorder {
+        Recorder::new()
+    }
+}
+
+
+macro_rules! probe_module {
+    ($self_:ident, $source:expr, $req:ident, $h_type:ident) => {{
+
+        let input = $req.clone();
+        let (port, out) = $self_.inner.$h_type($source, $req);
+
+        $self_.observe($source, &input, port, &out);
+        (port, out)
+    }}
+}
+
+// Wraps a module and reports to a Recorder whenever the module diverts, drops or rewrites a
+// request. Used by capture::replay (see Registry::wrap).
+pub struct Probe {
+    name: String,
+    inner: Arc<parser::HasHandlers>,
+    recorder: Arc<Recorder>,
+}
+
+impl Probe {
+    pub fn new(name: &str, inner: Arc<parser::HasHandlers>, recorder: Arc<Recorder>) -> Probe {
+        Probe {
+            name: name.to_string(),
+            inner: inner,
+            recorder: recorder,
+        }
+    }
+
+    fn observe(&self, source: Source, input: &Request, port: u8, out: &[Request]) {
+
+        let rewritten = out.len() > 1 || out.iter().any(|r| r != input);
+
+        if port == 0 && out.len() == 1 && !rewritten {
+            return;
+        }
+
+        self.recorder.hits.lock().unwrap().push(Hit {
+            record: self.recorder.record.load(Ordering::SeqCst),
+            module: self.name.clone(),
+            source: source,
+            h_type: input.get_type(),
+            port: port,
+            outputs: out.len(),
+            rewritten: rewritten,
+        });
+    }
+}
+
+
+impl parser::HasHandlers for Probe {
+    fn session_ended(&self) -> bool {
+        self.inner.session_ended()
+    }
+
+    fn handle_hello(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_hello)
+    }
+
+    fn handle_connect(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_connect)
+    }
+
+    fn handle_disconnect(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_disconnect)
+    }
+
+    fn handle_disconnect_ack(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_disconnect_ack)
+    }
+
+    fn handle_reset(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_reset)
+    }
+
+    fn handle_cancel_data_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_cancel_data_packet)
+    }
+
+    fn handle_interface_info(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_interface_info)
+    }
+
+    fn handle_ep_info(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_ep_info)
+    }
+
+    fn handle_get_conf(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_get_conf)
+    }
+
+    fn handle_set_conf(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_set_conf)
+    }
+
+    fn handle_conf_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_conf_status)
+    }
+
+    fn handle_get_alt_setting(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_get_alt_setting)
+    }
+
+    fn handle_set_alt_setting(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_set_alt_setting)
+    }
+
+    fn handle_alt_setting_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_alt_setting_status)
+    }
+
+    fn handle_start_iso_stream(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_start_iso_stream)
+    }
+
+    fn handle_stop_iso_stream(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_stop_iso_stream)
+    }
+
+    fn handle_iso_stream_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_iso_stream_status)
+    }
+
+    fn handle_start_int_receiving(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_start_int_receiving)
+    }
+
+    fn handle_stop_int_receiving(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_stop_int_receiving)
+    }
+
+    fn handle_int_receiving_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_int_receiving_status)
+    }
+
+    fn handle_alloc_bulk_streams(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_alloc_bulk_streams)
+    }
+
+    fn handle_free_bulk_streams(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_free_bulk_streams)
+    }
+
+    fn handle_bulk_streams_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_bulk_streams_status)
+    }
+
+    fn handle_start_bulk_receiving(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_start_bulk_receiving)
+    }
+
+    fn handle_stop_bulk_receiving(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_stop_bulk_receiving)
+    }
+
+    fn handle_bulk_receiving_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_bulk_receiving_status)
+    }
+
+    fn handle_filter_reject(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_filter_reject)
+    }
+
+    fn handle_filter_filter(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_filter_filter)
+    }
+
+    fn handle_control_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_control_packet)
+    }
+
+    fn handle_bulk_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_bulk_packet)
+    }
+
+    fn handle_int_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_int_packet)
+    }
+
+    fn handle_iso_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_iso_packet)
+    }
+
+    fn handle_buffered_bulk_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        probe_module!(self, source, req, handle_buffered_bulk_packet)
+    }
+}
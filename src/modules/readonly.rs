@@ -0,0 +1,347 @@
+#![allow(unused_variables)]
+
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use byteorder::{ByteOrder, LittleEndian};
+
+use parser;
+use parser::usbr;
+use parser::{Request, Source};
+use usb;
+use usb::bbb;
+use usb::scsi;
+
+
+// Makes bulk-only mass storage devices read-only. Commands that would change the medium never
+// reach the device: the module answers them itself with a failed CSW, and the REQUEST SENSE
+// that follows with DATA PROTECT / WRITE PROTECTED. MODE SENSE responses get the write-protect
+// bit, so that guests mount the device read-only to begin with. The module must see both
+// directions: interface and endpoint info from red tell it which endpoints to watch. It must
+// also come before checks, which would otherwise expect the device to answer the commands that
+// the module answers (Registry::validate enforces it).
+pub struct ReadOnly {
+    state: Mutex<State>,
+}
+
+// A command that the module answers instead of the device
+struct Answer {
+    tag: u32,
+    transfer_length: u32,
+    data_out: u32, // data-out bytes that blue has yet to send
+    data_in: Option<Vec<u8>>, // what blue gets when it reads data-in (None once sent)
+    sent: u32,
+    status: u8,
+}
+
+struct State {
+    storage: HashMap<u8, bool>, // interface -> bulk-only mass storage
+    ep_interface: HashMap<usize, u8>, // endpoint index -> interface
+    answer: Option<Answer>,
+    data_out: u32, // data-out bytes of a forwarded command that blue has yet to send
+    mode_sense: Option<u8>, // MODE SENSE whose data-in has not come back yet
+    refused: bool, // a command was refused and blue has not asked for sense data yet
+}
+
+// Offsets in the bulk packet header
+const BULK_STATUS: usize = 1;
+const BULK_LENGTH: [usize; 4] = [2, 3, 8, 9]; // length (u16) and length_high (u16)
+
+// Commands that write to the medium or change what is on it
+fn is_write(opcode: u8) -> bool {
+    matches!(opcode,
+             scsi::WRITE_6 | scsi::WRITE_10 | scsi::WRITE_12 | scsi::WRITE_16 | scsi::SYNCHRONIZE_CACHE_10 |
+             scsi::SYNCHRONIZE_CACHE_16 | scsi::FORMAT_UNIT | scsi::UNMAP)
+}
+
+// Same numbering as the arrays of the ep info header
+fn ep_index(ep: u8) -> usize {
+    (((ep & usb::ENDPOINT_DIR_MASK) >> 3) | (ep & usb::ENDPOINT_NUMBER_MASK)) as usize
+}
+
+// A bulk packet that answers req (sent by blue) with a successful transfer of data
+fn bulk_reply(req: &Request, data: Vec<u8>) -> Request {
+
+    let mut type_header = req.type_header.clone();
+    type_header[BULK_STATUS] = usbr::Result::Success as u8;
+
+    // Completions of data-out keep the length blue sent; data-in gets the length of data
+    if !data.is_empty() {
+
+        let mut length = [0; 4];
+        LittleEndian::write_u16(&mut length[0..2], data.len() as u16);
+        LittleEndian::write_u16(&mut length[2..4], (data.len() >> 16) as u16);
+
+        let header_len = type_header.len();
+        for (i, off) in BULK_LENGTH.iter().enumerate().filter(|&(_, off)| *off < header_len) {
+            type_header[*off] = length[i];
+        }
+    }
+
+    Request::new(req.get_type(), req.get_id(), type_header, data).into_reply()
+}
+
+impl Answer {
+    fn csw(&self) -> Vec<u8> {
+
+        let mut csw = vec![0; bbb::HEADER_SIZE + bbb::CSW_SIZE];
+
+        LittleEndian::write_u32(&mut csw[0..4], bbb::CSW_SIGN);
+        LittleEndian::write_u32(&mut csw[4..8], self.tag);
+        LittleEndian::write_u32(&mut csw[8..12], self.transfer_length.saturating_sub(self.sent));
+        csw[12] = self.status;
+
+        csw
+    }
+}
+
+impl ReadOnly {
+    pub fn new() -> ReadOnly {
+        ReadOnly {
+            state: Mutex::new(State {
+                storage: HashMap::new(),
+                ep_interface: HashMap::new(),
+                answer: None,
+                data_out: 0,
+                mode_sense: None,
+                refused: false,
+            }),
+        }
+    }
+
+    fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.answer = None;
+        state.data_out = 0;
+        state.mode_sense = None;
+    }
+
+    // A CBW from blue. Returns the reply if the module answers the command itself.
+    fn command(&self, state: &mut State, req: &Request) -> Option<Request> {
+
+        let data = &req.data;
+        let tag = LittleEndian::read_u32(&data[4..8]);
+        let transfer_length = LittleEndian::read_u32(&data[8..12]);
+        let transfer_in = (data[12] & usb::DIR_IN) == usb::DIR_IN;
+        let opcode = data[15];
+
+        let refused = state.refused;
+        state.refused = false;
+
+        let answer = if is_write(opcode) {
+
+            info!("Refusing SCSI command 0x{:x} (tag 0x{:x})", opcode, tag);
+            state.refused = true;
+
+            Answer {
+                tag: tag,
+                transfer_length: transfer_length,
+                data_out: if transfer_in { 0 } else { transfer_length },
+                data_in: None,
+                sent: 0,
+                status: bbb::STAT_FAIL,
+            }
+
+        } else if opcode == scsi::REQUEST_SENSE && refused {
+
+            // Fixed format sense data: DATA PROTECT, WRITE PROTECTED
+            let mut sense = vec![0; scsi::SENSE_FIXED_SIZE];
+            sense[0] = scsi::SENSE_FIXED_CURRENT;
+            sense[2] = scsi::SENSE_KEY_DATA_PROTECT;
+            sense[7] = (scsi::SENSE_FIXED_SIZE - scsi::SENSE_HEADER_SIZE) as u8;
+            sense[12] = scsi::ASC_WRITE_PROTECTED;
+
+            sense.truncate(cmp::min(data[19] as usize, transfer_length as usize));
+
+            Answer {
+                tag: tag,
+                transfer_length: transfer_length,
+                data_out: 0,
+                data_in: if sense.is_empty() { None } else { Some(sense) },
+                sent: 0,
+                status: bbb::STAT_OK,
+            }
+
+        } else {
+
+            if transfer_in && (opcode == scsi::MODE_SENSE_6 || opcode == scsi::MODE_SENSE_10) {
+                state.mode_sense = Some(opcode);
+            }
+
+            if !transfer_in {
+                state.data_out = transfer_length;
+            }
+
+            return None;
+        };
+
+        state.answer = Some(answer);
+        Some(bulk_reply(req, vec![]))
+    }
+
+    fn handle_blue_out(&self, state: &mut State, req: Request) -> (u8, Vec<Request>) {
+
+        let len = req.data.len() as u32;
+
+        if let Some(ref mut answer) = state.answer {
+            if answer.data_out > 0 {
+                answer.data_out = answer.data_out.saturating_sub(len);
+                return (0, vec![bulk_reply(&req, vec![])]);
+            }
+        }
+
+        if state.data_out > 0 {
+            state.data_out = state.data_out.saturating_sub(len);
+            return (0, vec![req]);
+        }
+
+        if req.data.len() == bbb::HEADER_SIZE + bbb::CBW_SIZE &&
+           LittleEndian::read_u32(&req.data[0..4]) == bbb::CBW_SIGN {
+
+            if let Some(reply) = self.command(state, &req) {
+                return (0, vec![reply]);
+            }
+        }
+
+        (0, vec![req])
+    }
+
+    // Blue reads data-in or the CSW of a command that the module answers
+    fn handle_blue_in(&self, state: &mut State, req: Request) -> (u8, Vec<Request>) {
+
+        let (reply, done) = match state.answer {
+
+            Some(ref mut answer) => {
+                match answer.data_in.take() {
+                    Some(data) => {
+                        answer.sent = data.len() as u32;
+                        (bulk_reply(&req, data), false)
+                    }
+
+                    None => (bulk_reply(&req, answer.csw()), true),
+                }
+            }
+
+            None => return (0, vec![req]),
+        };
+
+        // The CSW ends the command
+        if done {
+            state.answer = None;
+        }
+
+        (0, vec![reply])
+    }
+
+    // Data-in from the device: sets the write-protect bit of MODE SENSE responses
+    fn handle_red_in(&self, state: &mut State, mut req: Request) -> (u8, Vec<Request>) {
+
+        let opcode = match state.mode_sense {
+            Some(v) => v,
+            None => return (0, vec![req]),
+        };
+
+        state.mode_sense = None;
+
+        // No data (e.g., a stall or straight to the CSW)
+        if req.data.len() == bbb::HEADER_SIZE + bbb::CSW_SIZE &&
+           LittleEndian::read_u32(&req.data[0..4]) == bbb::CSW_SIGN {
+            return (0, vec![req]);
+        }
+
+        let off = if opcode == scsi::MODE_SENSE_6 { scsi::MODE_PARAM_6 } else { scsi::MODE_PARAM_10 };
+
+        if req.data.len() > off {
+            req.data[off] |= scsi::MODE_WP;
+        }
+
+        (0, vec![req])
+    }
+}
+
+impl Default for ReadOnly {
+    fn default() -> ReadOnly {
+        ReadOnly::new()
+    }
+}
+
+
+impl parser::HasHandlers for ReadOnly {
+    fn handle_reset(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        self.clear();
+        (0, vec![req])
+    }
+
+    fn handle_interface_info(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        // count, then interface, class, subclass and protocol arrays (32 entries each)
+        let th = &req.type_header;
+        let count = cmp::min(LittleEndian::read_u32(&th[0..4]) as usize, 32);
+        let mut state = self.state.lock().unwrap();
+
+        state.storage.clear();
+
+        for i in 0..count {
+            state.storage.insert(th[4 + i],
+                                 th[36 + i] == usb::CLASS_MASS_STORAGE && th[100 + i] == bbb::PR_BBB);
+        }
+
+        (0, vec![req])
+    }
+
+    fn handle_ep_info(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        // type, interval and interface arrays (32 entries each)
+        let th = &req.type_header;
+        let mut state = self.state.lock().unwrap();
+
+        state.ep_interface.clear();
+
+        for i in 0..32 {
+            if th[i] == usbr::TransferType::Bulk as u8 {
+                state.ep_interface.insert(i, th[64 + i]);
+            }
+        }
+
+        (0, vec![req])
+    }
+
+    fn handle_control_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        // Bulk-only mass storage reset
+        if source == Source::Blue && req.type_header.len() > 2 && req.type_header[1] == bbb::RESET &&
+           req.type_header[2] == usb::TYPE_CLASS | usb::RECIP_INTERFACE | usb::DIR_OUT {
+            self.clear();
+        }
+
+        (0, vec![req])
+    }
+
+    fn handle_bulk_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        if req.type_header.len() <= BULK_STATUS {
+            return (0, vec![req]);
+        }
+
+        let ep = req.type_header[0];
+        let mut state = self.state.lock().unwrap();
+
+        let storage = {
+            let iface = state.ep_interface.get(&ep_index(ep));
+            iface.and_then(|i| state.storage.get(i)).cloned().unwrap_or(false)
+        };
+
+        if !storage {
+            return (0, vec![req]);
+        }
+
+        let transfer_in = (ep & usb::DIR_IN) == usb::DIR_IN;
+
+        match (source, transfer_in) {
+            (Source::Blue, false) => self.handle_blue_out(&mut state, req),
+            (Source::Blue, true) => self.handle_blue_in(&mut state, req),
+            (Source::Red, true) => self.handle_red_in(&mut state, req),
+            (Source::Red, false) => (0, vec![req]),
+        }
+    }
+}
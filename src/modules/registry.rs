@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Arc;
+use time;
+
+use parser;
+use modules::{Modules, authorizer, control_checks, discard, filter, keystroke, logger, null, patcher, readonly,
+              reset, rewrite, stall};
+use modules::control_checks::classes::{ClassHistory, ClassPolicy};
+use modules::control_checks::vendor::VendorSet;
+use modules::fingerprint::FingerprintDb;
+use modules::rules::Rules;
+use modules::policy::{CheckPolicy, PORT_PASS, PORT_RESET, PORT_DROP, PORT_STALL, PORT_REWRITE};
+use util::config::{CinchConfig, ModuleConfig};
+
+
+// Creates a new instance of a module for a device session
+pub type Constructor = Box<Fn(&CinchConfig) -> Result<Arc<parser::HasHandlers>, String> + Send + Sync>;
+
+// Lists the non-zero ports that a module may send requests out of with the given configuration
+pub type Ports = Box<Fn(&CinchConfig) -> Result<Vec<u8>, String> + Send + Sync>;
+
+// Modules that must come before others in each direction where both run. Commands that readonly
+// answers itself never reach the device, so checks must not see them either.
+const ORDER: &[(&str, &str)] = &[("readonly", "checks")];
+
+// Knows how to build every module by name, and builds the module graph for a session from the
+// pipeline in the configuration. New modules only need to be registered here.
+pub struct Registry {
+    constructors: HashMap<String, Constructor>,
+    ports: HashMap<String, Ports>, // modules without an entry only use port 0
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+
+        let mut registry = Registry {
+            constructors: HashMap::new(),
+            ports: HashMap::new(),
+        };
+
+        registry.register("null", Box::new(|_| Ok(Arc::new(null::Null::new()))));
+        registry.register("reset", Box::new(|_| Ok(Arc::new(reset::Reset::new()))));
+        registry.register("drop", Box::new(|_| Ok(Arc::new(discard::Discard::new()))));
+        registry.register("stall", Box::new(|_| Ok(Arc::new(stall::Stall::new()))));
+        registry.register("readonly", Box::new(|_| Ok(Arc::new(readonly::ReadOnly::new()))));
+
+        registry.register("keystroke",
+                          Box::new(|config| {
+            let (min_delay, max_rate, chords) = match config.keystrokes {
+                Some(ref k) => (k.min_delay, k.max_rate, k.chords),
+                None => (None, None, None),
+            };
+
+            Ok(Arc::new(keystroke::KeystrokeDetector::new(min_delay.unwrap_or(keystroke::DEFAULT_MIN_DELAY),
+                                                          max_rate.unwrap_or(keystroke::DEFAULT_MAX_RATE),
+                                                          chords.unwrap_or(true))))
+        }));
+
+        registry.register("authorize",
+                          Box::new(|config| {
+            match config.authorization {
+                Some(ref authorization) => Ok(Arc::new(authorizer::Authorizer::new(authorization)?)),
+                None => Err("the authorize module needs an authorization entry".to_string()),
+            }
+        }));
+
+        registry.declare_ports("authorize", Box::new(|_| Ok(vec![authorizer::PORT_DENY])));
+
+        registry.register("filter",
+                          Box::new(|config| {
+            match config.filter {
+                Some(ref rules) => Ok(Arc::new(filter::DeviceFilter::new(rules)?)),
+                None => Err("the filter module needs a filter entry".to_string()),
+            }
+        }));
+
+        registry.declare_ports("filter", Box::new(|_| Ok(vec![filter::PORT_REJECT])));
+
+        registry.register("rewrite",
+                          Box::new(|config| {
+            Ok(Arc::new(match config.rewrites {
+                Some(ref dir) => rewrite::Rewriter::new(dir)?,
+                None => rewrite::Rewriter::empty(),
+            }))
+        }));
+
+        registry.declare_ports("rewrite",
+                               Box::new(|config| {
+            if let Some(ref dir) = config.rewrites {
+                rewrite::Rewriter::new(dir)?;
+            }
+
+            Ok(vec![])
+        }));
+
+        registry.register("logger",
+                          Box::new(|config| {
+            let log_name = format!("{}-{}.{}",
+                                   config.log_prefix,
+                                   time::strftime("%d-%b-%Y-%H-%M-%S", &time::now()).unwrap(),
+                                   "log");
+
+            Ok(Arc::new(logger::Logger::new(&log_name)))
+        }));
+
+        // Every session of this registry remembers the classes of the devices seen before
+        let history = Arc::new(ClassHistory::new());
+
+        registry.register("checks",
+                          Box::new(move |config| {
+            let checks = control_checks::ControlCheck::new(&config.third_party_folder, check_policy(config)?);
+            let checks = checks.with_classes(class_policy(config, history.clone())?)
+                .with_vendor_requests(Arc::new(vendor_set(config)?));
+            Ok(Arc::new(with_fingerprints(checks, config)))
+        }));
+
+        registry.declare_ports("checks",
+                               Box::new(|config| {
+            vendor_set(config)?;
+            check_ports(config)
+        }));
+
+        registry.register("patcher",
+                          Box::new(|config| Ok(Arc::new(patcher::Patcher::new(&config.patches[..])))));
+
+        registry.declare_ports("patcher", Box::new(|_| Ok(vec![1])));
+
+        registry
+    }
+
+    // Like new, but checks, patcher and rewrite take their rules from the given sets (which may
+    // be reloaded while cinch runs) instead of reading their folders for every session
+    pub fn with_rules(rules: Arc<Rules>) -> Registry {
+
+        let mut registry = Registry::new();
+        let patch_rules = rules.clone();
+        let rewrite_rules = rules.clone();
+        let history = Arc::new(ClassHistory::new());
+
+        registry.register("checks",
+                          Box::new(move |config| {
+            let checks = control_checks::ControlCheck::with_checks(rules.checks(), check_policy(config)?);
+            let checks = checks.with_classes(class_policy(config, history.clone())?)
+                .with_vendor_requests(rules.vendor_requests());
+            Ok(Arc::new(with_fingerprints(checks, config)))
+        }));
+
+        // The vendor requests were checked when the rules were loaded
+        registry.declare_ports("checks", Box::new(check_ports));
+
+        registry.register("patcher",
+                          Box::new(move |_| Ok(Arc::new(patcher::Patcher::with_patches(patch_rules.patches())))));
+
+        registry.register("rewrite",
+                          Box::new(move |_| Ok(Arc::new(rewrite::Rewriter::with_rules(rewrite_rules.rewrites())))));
+
+        // The rewrite rules were checked when the rules were loaded
+        registry.declare_ports("rewrite", Box::new(|_| Ok(vec![])));
+
+        registry
+    }
+
+    pub fn register(&mut self, name: &str, constructor: Constructor) {
+        self.constructors.insert(name.to_string(), constructor);
+    }
+
+    // Pipelines must connect every port that the module declares. Declaring the ports may also
+    // check the configuration of the module, so that errors show up before any session starts.
+    pub fn declare_ports(&mut self, name: &str, ports: Ports) {
+        self.ports.insert(name.to_string(), ports);
+    }
+
+    // Replaces every module with wrapper(name, module) when it is built (e.g., to observe
+    // what each module does with the requests it sees)
+    pub fn wrap<F>(&mut self, wrapper: F)
+        where F: Fn(&str, Arc<parser::HasHandlers>) -> Arc<parser::HasHandlers> + Send + Sync + 'static
+    {
+
+        let wrapper = Arc::new(wrapper);
+        let constructors = mem::replace(&mut self.constructors, HashMap::new());
+
+        for (name, constructor) in constructors {
+
+            let wrapper = wrapper.clone();
+            let module_name = name.clone();
+
+            self.register(&name,
+                          Box::new(move |config| Ok(wrapper(&module_name, constructor(config)?))));
+        }
+    }
+
+    // Checks that every module and port in the pipeline makes sense without building anything
+    pub fn validate(&self, config: &CinchConfig, pipeline: &[ModuleConfig]) -> Result<(), String> {
+
+        for entry in pipeline {
+
+            if !self.constructors.contains_key(&entry.name) {
+                return Err(format!("unknown module {}", entry.name));
+            }
+
+            let ports = parse_ports(entry)?;
+
+            for (_, target) in &ports {
+                if !self.constructors.contains_key(target) {
+                    return Err(format!("unknown module {} (port target of {})", target, entry.name));
+                }
+            }
+
+            if let Some(declared) = self.ports.get(&entry.name) {
+                for port in declared(config)? {
+                    if !ports.iter().any(|&(p, _)| p == port) {
+                        return Err(format!("port {} of {} does not lead to any module", port, entry.name));
+                    }
+                }
+            }
+        }
+
+        let blue: Vec<&str> = pipeline.iter().filter(|m| m.blue.unwrap_or(true)).map(|m| &m.name[..]).collect();
+        let red: Vec<&str> = pipeline.iter().filter(|m| m.red.unwrap_or(true)).map(|m| &m.name[..]).collect();
+
+        for &(first, second) in ORDER {
+            for names in &[&blue, &red] {
+
+                let first_pos = names.iter().position(|n| *n == first);
+                let second_pos = names.iter().rposition(|n| *n == second);
+
+                if let (Some(f), Some(s)) = (first_pos, second_pos) {
+                    if f > s {
+                        return Err(format!("{} must come before {}", first, second));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Builds the handlers for one device session. Returns the handlers for requests coming
+    // from the blue machine and for requests coming from the red machine (in that order).
+    pub fn build(&self, config: &CinchConfig) -> Result<(Modules, Modules), String> {
+
+        match config.pipeline {
+            Some(ref pipeline) => self.build_pipeline(config, pipeline),
+            None => self.build_pipeline(config, &default_pipeline(config)),
+        }
+    }
+
+    pub fn build_pipeline(&self,
+                          config: &CinchConfig,
+                          pipeline: &[ModuleConfig])
+                          -> Result<(Modules, Modules), String> {
+
+        self.validate(config, pipeline)?;
+
+        // A module listed more than once (or used in both directions) is a single instance,
+        // so it sees all traffic of the session.
+        let mut instances: HashMap<String, Arc<parser::HasHandlers>> = HashMap::new();
+
+        let blue = self.build_direction(config,
+                                        pipeline.iter().filter(|m| m.blue.unwrap_or(true)),
+                                        &mut instances)?;
+
+        let red = self.build_direction(config,
+                                       pipeline.iter().filter(|m| m.red.unwrap_or(true)),
+                                       &mut instances)?;
+
+        Ok((blue, red))
+    }
+
+    fn instance(&self,
+                config: &CinchConfig,
+                name: &str,
+                instances: &mut HashMap<String, Arc<parser::HasHandlers>>)
+                -> Result<Arc<parser::HasHandlers>, String> {
+
+        if let Some(module) = instances.get(name) {
+            return Ok(module.clone());
+        }
+
+        let module = match self.constructors.get(name) {
+            Some(constructor) => constructor(config)?,
+            None => return Err(format!("unknown module {}", name)),
+        };
+
+        instances.insert(name.to_string(), module.clone());
+        Ok(module)
+    }
+
+    // Port 0 of each module leads to the next module in the chain (or to null at the end).
+    // Every other port leads to its target, which sits alongside the next module (or among
+    // the terminals if there is no next module).
+    fn build_direction<'a, I>(&self,
+                              config: &CinchConfig,
+                              pipeline: I,
+                              instances: &mut HashMap<String, Arc<parser::HasHandlers>>)
+                              -> Result<Modules, String>
+        where I: Iterator<Item = &'a ModuleConfig>
+    {
+
+        let mut modules = Modules::new();
+        let mut targets: Vec<(u8, String)> = vec![]; // non-zero ports of the previous module
+        let mut index: usize = 0;
+
+        for entry in pipeline {
+
+            modules.add_nonterminal(index, PORT_PASS, self.instance(config, &entry.name, instances)?);
+
+            for &(port, ref target) in &targets {
+                modules.add_nonterminal(index, port, self.instance(config, target, instances)?);
+            }
+
+            targets = parse_ports(entry)?;
+            index += 1;
+        }
+
+        modules.add_terminal(PORT_PASS, self.instance(config, "null", instances)?);
+
+        for &(port, ref target) in &targets {
+            modules.add_terminal(port, self.instance(config, target, instances)?);
+        }
+
+        Ok(modules)
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::new()
+    }
+}
+
+
+fn check_policy(config: &CinchConfig) -> Result<CheckPolicy, String> {
+
+    match config.check_actions {
+        Some(ref actions) => CheckPolicy::from_config(actions),
+        None => Ok(CheckPolicy::new()),
+    }
+}
+
+// Ports of the actions that failed checks may take. The class policy is built only to check it.
+fn check_ports(config: &CinchConfig) -> Result<Vec<u8>, String> {
+
+    class_policy(config, Arc::new(ClassHistory::new()))?;
+    Ok(check_policy(config)?.ports())
+}
+
+fn class_policy(config: &CinchConfig, history: Arc<ClassHistory>) -> Result<ClassPolicy, String> {
+
+    match config.device_classes {
+        Some(ref classes) => ClassPolicy::from_config(classes, history),
+        None => Ok(ClassPolicy::new(history)),
+    }
+}
+
+fn vendor_set(config: &CinchConfig) -> Result<VendorSet, String> {
+
+    match config.vendor_requests {
+        Some(ref folder) => VendorSet::load(folder),
+        None => Ok(VendorSet::empty()),
+    }
+}
+
+fn with_fingerprints(checks: control_checks::ControlCheck, config: &CinchConfig) -> control_checks::ControlCheck {
+
+    match config.fingerprints {
+        Some(ref path) => checks.with_fingerprints(FingerprintDb::new(path)),
+        None => checks,
+    }
+}
+
+fn parse_ports(entry: &ModuleConfig) -> Result<Vec<(u8, String)>, String> {
+
+    let mut ports = vec![];
+
+    if let Some(ref map) = entry.ports {
+        for (port, target) in map {
+
+            match port.parse::<u8>() {
+                Ok(PORT_PASS) => {
+                    return Err(format!("port 0 of {} always leads to the next module", entry.name));
+                }
+
+                Ok(v) => ports.push((v, target.clone())),
+                Err(_) => return Err(format!("invalid port {} for {}", port, entry.name)),
+            }
+        }
+    }
+
+    Ok(ports)
+}
+
+
+// The pipeline implied by the log, patch_active and checks_active flags:
+// logger -> checks -> patcher (red only) -> null, with failed checks going to their action.
+pub fn default_pipeline(config: &CinchConfig) -> Vec<ModuleConfig> {
+
+    let mut pipeline = vec![];
+
+    if config.log {
+        pipeline.push(ModuleConfig::new("logger"));
+    }
+
+    if config.filter.is_some() {
+
+        let mut entry = ModuleConfig::new("filter");
+        entry.ports = Some(port_map(&[(filter::PORT_REJECT, "reset")]));
+
+        pipeline.push(entry);
+    }
+
+    if config.checks_active {
+
+        let mut checks = ModuleConfig::new("checks");
+        checks.ports = Some(port_map(&[(PORT_RESET, "reset"),
+                                       (PORT_DROP, "drop"),
+                                       (PORT_STALL, "stall"),
+                                       (PORT_REWRITE, "rewrite")]));
+
+        pipeline.push(checks);
+    }
+
+    if config.patch_active {
+
+        let mut patcher = ModuleConfig::new("patcher");
+        patcher.blue = Some(false);
+        patcher.ports = Some(port_map(&[(1, "reset")]));
+
+        pipeline.push(patcher);
+    }
+
+    pipeline
+}
+
+fn port_map(ports: &[(u8, &str)]) -> HashMap<String, String> {
+    ports.iter().map(|&(port, target)| (port.to_string(), target.to_string())).collect()
+}
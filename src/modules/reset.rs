@@ -1,162 +1,50 @@
 #![allow(unused_variables)]
 
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use parser;
-// use parser::usbr;
+use parser::usbr;
 use parser::{Request, Source};
 
+// Ends the device session. Blue is told the device is gone (DeviceDisconnect) and red is
+// asked to reset it (Reset), whichever side sent the offending request. The endpoints are
+// then signaled (via session_ended) to close both connections.
 #[derive(Default)]
-pub struct Reset;
-
-// #[cfg_attr(rustfmt, rustfmt_skip)]
-// const RESET_HEADER: [u8; usbr::REDIR_HEADER_SIZE] = [0, 0, 0, usbr::HeaderType::DeviceDisconnect as u8,
-//                                                    0, 0, 0, 0,
-//                                                     0, 0, 0, 0, 0, 0, 0, 0];
+pub struct Reset {
+    ended: AtomicBool,
+}
 
 impl Reset {
     pub fn new() -> Reset {
-        Reset
-    }
-
-    fn reset(&self) -> (u8, Vec<Request>) {
-
-        panic!("Ending connection because a packet did not pass all checks");
-
-        //        (0, vec![Request {
-        //                 header: RESET_HEADER,
-        //                 type_header: vec![],
-        //                 data: vec![],
-        //                 }])
-    }
-}
-
-
-impl parser::HasHandlers for Reset {
-    // I'm sure there is a macro that could generate the stuff below, but whatever.
-
-    fn handle_hello(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_connect(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_disconnect(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_disconnect_ack(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_reset(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_cancel_data_packet(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_interface_info(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_ep_info(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_get_conf(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_set_conf(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_conf_status(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
+        Reset { ended: AtomicBool::new(false) }
     }
 
-    fn handle_get_alt_setting(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
+    fn reset(&self, source: Source) -> (u8, Vec<Request>) {
 
-    fn handle_set_alt_setting(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
+        if self.ended.swap(true, Ordering::SeqCst) {
+            // We already told the other side; anything still in flight is discarded.
+            return (0, vec![]);
+        }
 
-    fn handle_alt_setting_status(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
+        warn!("Ending connection because a packet did not pass all checks");
 
-    fn handle_start_iso_stream(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
+        let disconnect = Request::new(usbr::HeaderType::DeviceDisconnect as u32, 0, vec![], vec![]);
+        let reset = Request::new(usbr::HeaderType::Reset as u32, 0, vec![], vec![]);
 
-    fn handle_stop_iso_stream(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_iso_stream_status(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_start_int_receiving(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_stop_int_receiving(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_int_receiving_status(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_alloc_bulk_streams(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_free_bulk_streams(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_bulk_streams_status(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_start_bulk_receiving(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_stop_bulk_receiving(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_bulk_receiving_status(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-
-    // Data packets
-
-    fn handle_control_packet(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
-
-    fn handle_bulk_packet(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
+        match source {
+            Source::Red => (0, vec![disconnect, reset.into_reply()]),
+            Source::Blue => (0, vec![reset, disconnect.into_reply()]),
+        }
     }
+}
 
-    fn handle_int_packet(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
-    }
 
-    fn handle_iso_packet(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
+impl parser::HasHandlers for Reset {
+    fn session_ended(&self) -> bool {
+        self.ended.load(Ordering::SeqCst)
     }
 
-    fn handle_buffered_bulk_packet(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
-        self.reset()
+    fn handle_request(&self, source: Source, _: Request) -> (u8, Vec<Request>) {
+        self.reset(source)
     }
 }
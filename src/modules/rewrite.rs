@@ -0,0 +1,177 @@
+#![allow(unused_variables)]
+
+use std::io::prelude::*;
+use std::fs;
+use std::fs::File;
+use std::sync::Arc;
+use rustc_serialize::json;
+use rustc_serialize::hex::FromHex;
+
+use parser;
+use parser::usbr;
+use parser::{Request, Source};
+
+
+// Replaces the payload of a control transfer that matches request/requesttype (and
+// optionally value and index) with a known-good one.
+#[derive(RustcDecodable)]
+struct RewriteRule {
+    request: u8,
+    requesttype: u8,
+    value: Option<u16>,
+    index: Option<u16>,
+    data: String, // hex-encoded replacement payload
+}
+
+struct Rewrite {
+    request: u8,
+    requesttype: u8,
+    value: Option<u16>,
+    index: Option<u16>,
+    data: Vec<u8>,
+}
+
+// Rewrite rules read from a folder. One set is shared by every session started while it is
+// current (see modules::rules).
+pub struct RewriteSet {
+    rules: Vec<Rewrite>,
+}
+
+// Carries out the "rewrite" action. Requests that no rule covers cannot be fixed, so they
+// are dropped rather than forwarded as they are.
+pub struct Rewriter {
+    rules: Arc<RewriteSet>,
+}
+
+// Offset of the length field in the control packet header
+const CONTROL_LENGTH: usize = 8;
+
+impl Rewrite {
+    fn matches(&self, h: &usbr::ControlPacketHeader) -> bool {
+        let (value, index) = (h.value, h.index);
+
+        self.request == h.request && self.requesttype == h.requesttype &&
+        self.value.map_or(true, |v| v == value) && self.index.map_or(true, |i| i == index)
+    }
+}
+
+impl RewriteSet {
+    pub fn empty() -> RewriteSet {
+        RewriteSet { rules: vec![] }
+    }
+
+    // A missing folder has no rules, but every .json file in the folder must be a valid rule.
+    // Other entries (subdirectories, editor backups) are skipped.
+    pub fn load(dir_path: &str) -> Result<RewriteSet, String> {
+
+        let mut rules = vec![];
+
+        if let Ok(dir) = fs::read_dir(dir_path) {
+            for entry in dir {
+
+                let path = entry.map_err(|e| format!("could not list {}: {}", dir_path, e))?.path();
+
+                if !path.is_file() || path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                    continue;
+                }
+
+                let mut file = File::open(&path)
+                    .map_err(|e| format!("could not open {}: {}", path.display(), e))?;
+
+                let mut json_line = String::new();
+                file.read_to_string(&mut json_line)
+                    .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+
+                let rule: RewriteRule = match json::decode(&json_line) {
+                    Ok(v) => v,
+                    Err(e) => return Err(format!("invalid rewrite rule {}: {}", path.display(), e)),
+                };
+
+                let data: Vec<u8> = match rule.data.from_hex() {
+                    Ok(v) => v,
+                    Err(e) => return Err(format!("invalid replacement data in {}: {}", path.display(), e)),
+                };
+
+                rules.push(Rewrite {
+                    request: rule.request,
+                    requesttype: rule.requesttype,
+                    value: rule.value,
+                    index: rule.index,
+                    data: data,
+                });
+            }
+        }
+
+        Ok(RewriteSet { rules: rules })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+
+impl Rewriter {
+    pub fn new(dir_path: &str) -> Result<Rewriter, String> {
+        Ok(Rewriter::with_rules(Arc::new(RewriteSet::load(dir_path)?)))
+    }
+
+    pub fn with_rules(rules: Arc<RewriteSet>) -> Rewriter {
+        Rewriter { rules: rules }
+    }
+
+    // Without rules every request routed here is dropped
+    pub fn empty() -> Rewriter {
+        Rewriter::with_rules(Arc::new(RewriteSet::empty()))
+    }
+
+    fn rewrite_control(&self, req: Request) -> (u8, Vec<Request>) {
+
+        let rule = {
+            let h_ptr = req.type_header.as_ptr() as *const usbr::ControlPacketHeader;
+            let h: &usbr::ControlPacketHeader = unsafe { &*h_ptr };
+
+            self.rules.rules.iter().find(|r| r.matches(h))
+        };
+
+        match rule {
+            Some(r) => {
+                let mut type_header = req.type_header.clone();
+                let len = r.data.len() as u16;
+
+                type_header[CONTROL_LENGTH] = len as u8;
+                type_header[CONTROL_LENGTH + 1] = (len >> 8) as u8;
+
+                debug!("Rewriting control packet {}", req.get_id());
+                (0, vec![Request::new(req.get_type(), req.get_id(), type_header, r.data.clone())])
+            }
+
+            None => {
+                error!("[E003-Rewrite] No rewrite rule for control packet {}; dropping it",
+                       req.get_id());
+                (0, vec![])
+            }
+        }
+    }
+
+    fn discard(&self, req: Request) -> (u8, Vec<Request>) {
+        error!("[E004-Rewrite] Cannot rewrite request of type {}; dropping it",
+               req.get_type());
+        (0, vec![])
+    }
+}
+
+
+impl parser::HasHandlers for Rewriter {
+    fn handle_request(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        self.discard(req)
+    }
+
+    fn handle_control_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        self.rewrite_control(req)
+    }
+}
@@ -0,0 +1,219 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use modules::control_checks::third_party::CheckSet;
+use modules::control_checks::vendor::VendorSet;
+use modules::patcher::PatchSet;
+use modules::rewrite::RewriteSet;
+use modules::registry;
+use util::config::CinchConfig;
+
+// Seconds between checks of the rule folders when the configuration does not say
+pub const DEFAULT_RELOAD_INTERVAL: u64 = 5;
+
+// What the folder looked like when it was last loaded (entry, modification time, size)
+type Stamp = Vec<(PathBuf, Option<SystemTime>, u64)>;
+
+fn stamp(dir_path: &str) -> Stamp {
+
+    let mut entries: Stamp = match fs::read_dir(dir_path) {
+        Ok(dir) => {
+            dir.filter_map(|e| e.ok())
+                .map(|e| {
+                    let meta = e.metadata().ok();
+                    (e.path(),
+                     meta.as_ref().and_then(|m| m.modified().ok()),
+                     meta.as_ref().map_or(0, |m| m.len()))
+                })
+                .collect()
+        }
+
+        Err(_) => vec![],
+    };
+
+    entries.sort();
+    entries
+}
+
+
+// The third-party checks, patches, vendor requests and rewrite rules that new sessions use. Sessions take the
+// current sets when they start, so replacing a set never affects a device that is already
+// attached. A folder that fails to load leaves its previous set in place.
+pub struct Rules {
+    checks_folder: String,
+    patches_folder: Option<String>, // None if the pipeline has no patcher
+    vendor_folder: Option<String>,
+    rewrites_folder: Option<String>,
+    checks: RwLock<Arc<CheckSet>>,
+    patches: RwLock<Arc<PatchSet>>,
+    vendor_requests: RwLock<Arc<VendorSet>>,
+    rewrites: RwLock<Arc<RewriteSet>>,
+    stamps: Mutex<(Stamp, Stamp, Stamp, Stamp)>, // (checks, patches, vendor requests, rewrites)
+}
+
+// The patches folder is only required when some pipeline runs the patcher
+fn uses_patcher(config: &CinchConfig) -> bool {
+
+    let pipeline = match config.pipeline {
+        Some(ref pipeline) => pipeline.clone(),
+        None => registry::default_pipeline(config),
+    };
+
+    pipeline.iter().any(|m| {
+        m.name == "patcher" || m.ports.as_ref().map_or(false, |p| p.values().any(|t| t == "patcher"))
+    })
+}
+
+impl Rules {
+    pub fn load(config: &CinchConfig) -> Result<Rules, String> {
+
+        let patches_folder = if uses_patcher(config) { Some(config.patches.clone()) } else { None };
+
+        let checks_stamp = stamp(&config.third_party_folder);
+        let checks = CheckSet::load(&config.third_party_folder)?;
+
+        let (patches_stamp, patches) = match patches_folder {
+            Some(ref folder) => (stamp(folder), PatchSet::load(folder)?),
+            None => (vec![], PatchSet::empty()),
+        };
+
+        let (vendor_stamp, vendor_requests) = match config.vendor_requests {
+            Some(ref folder) => (stamp(folder), VendorSet::load(folder)?),
+            None => (vec![], VendorSet::empty()),
+        };
+
+        let (rewrites_stamp, rewrites) = match config.rewrites {
+            Some(ref folder) => (stamp(folder), RewriteSet::load(folder)?),
+            None => (vec![], RewriteSet::empty()),
+        };
+
+        Ok(Rules {
+            checks_folder: config.third_party_folder.clone(),
+            patches_folder: patches_folder,
+            vendor_folder: config.vendor_requests.clone(),
+            rewrites_folder: config.rewrites.clone(),
+            checks: RwLock::new(Arc::new(checks)),
+            patches: RwLock::new(Arc::new(patches)),
+            vendor_requests: RwLock::new(Arc::new(vendor_requests)),
+            rewrites: RwLock::new(Arc::new(rewrites)),
+            stamps: Mutex::new((checks_stamp, patches_stamp, vendor_stamp, rewrites_stamp)),
+        })
+    }
+
+    pub fn checks(&self) -> Arc<CheckSet> {
+        self.checks.read().unwrap().clone()
+    }
+
+    pub fn patches(&self) -> Arc<PatchSet> {
+        self.patches.read().unwrap().clone()
+    }
+
+    pub fn vendor_requests(&self) -> Arc<VendorSet> {
+        self.vendor_requests.read().unwrap().clone()
+    }
+
+    pub fn rewrites(&self) -> Arc<RewriteSet> {
+        self.rewrites.read().unwrap().clone()
+    }
+
+    // Reloads the folders that changed since they were last loaded. A folder that fails to load
+    // is reported once and retried when it changes again.
+    pub fn reload(&self) -> Result<(), String> {
+
+        let mut stamps = self.stamps.lock().unwrap();
+        let mut errors = vec![];
+
+        let checks_stamp = stamp(&self.checks_folder);
+
+        if checks_stamp != stamps.0 {
+
+            stamps.0 = checks_stamp;
+
+            match CheckSet::load(&self.checks_folder) {
+                Ok(checks) => {
+                    info!("Loaded {} third-party checks from {}", checks.len(), self.checks_folder);
+                    *self.checks.write().unwrap() = Arc::new(checks);
+                }
+
+                Err(e) => errors.push(e),
+            }
+        }
+
+        if let Some(ref folder) = self.patches_folder {
+
+            let patches_stamp = stamp(folder);
+
+            if patches_stamp != stamps.1 {
+
+                stamps.1 = patches_stamp;
+
+                match PatchSet::load(folder) {
+                    Ok(patches) => {
+                        info!("Loaded {} patches from {}", patches.len(), folder);
+                        *self.patches.write().unwrap() = Arc::new(patches);
+                    }
+
+                    Err(e) => errors.push(e),
+                }
+            }
+        }
+
+        if let Some(ref folder) = self.vendor_folder {
+
+            let vendor_stamp = stamp(folder);
+
+            if vendor_stamp != stamps.2 {
+
+                stamps.2 = vendor_stamp;
+
+                match VendorSet::load(folder) {
+                    Ok(vendor_requests) => {
+                        info!("Loaded vendor requests of {} devices from {}", vendor_requests.len(), folder);
+                        *self.vendor_requests.write().unwrap() = Arc::new(vendor_requests);
+                    }
+
+                    Err(e) => errors.push(e),
+                }
+            }
+        }
+
+        if let Some(ref folder) = self.rewrites_folder {
+
+            let rewrites_stamp = stamp(folder);
+
+            if rewrites_stamp != stamps.3 {
+
+                stamps.3 = rewrites_stamp;
+
+                match RewriteSet::load(folder) {
+                    Ok(rewrites) => {
+                        info!("Loaded {} rewrite rules from {}", rewrites.len(), folder);
+                        *self.rewrites.write().unwrap() = Arc::new(rewrites);
+                    }
+
+                    Err(e) => errors.push(e),
+                }
+            }
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors.join("; ")) }
+    }
+}
+
+
+// Polls the rule folders for changes until the process exits
+pub fn watch(rules: Arc<Rules>, interval: Duration) -> thread::JoinHandle<()> {
+
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+
+            if let Err(e) = rules.reload() {
+                error!("[E000-Rules] Keeping the previous rules: {}", e);
+            }
+        }
+    })
+}
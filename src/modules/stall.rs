@@ -0,0 +1,85 @@
+#![allow(unused_variables)]
+
+use parser;
+use parser::usbr;
+use parser::{Request, Source};
+
+// Answers an offending transfer with a STALL status instead of forwarding it (the "stall"
+// action). Only data packets carry a status, so anything else is dropped.
+pub struct Stall;
+
+// Offset of the status byte in each data packet header
+const CONTROL_STATUS: usize = 3;
+const DATA_STATUS: usize = 1;
+
+// Offset of the length field in the control packet header
+const CONTROL_LENGTH: usize = 8;
+
+impl Stall {
+    pub fn new() -> Stall {
+        Stall
+    }
+
+    fn stall_control(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+        // The device's response is replaced by a STALL with no data. A request from blue
+        // never reaches the device; blue gets the STALL straight back.
+        let mut type_header = req.type_header.clone();
+        type_header[CONTROL_STATUS] = usbr::Result::Stall as u8;
+        type_header[CONTROL_LENGTH] = 0;
+        type_header[CONTROL_LENGTH + 1] = 0;
+
+        (0, vec![stall_reply(source, Request::new(req.get_type(), req.get_id(), type_header, vec![]))])
+    }
+
+    // length_bytes are the offsets of the (possibly split) length field in the type header
+    fn stall_data(&self, source: Source, req: Request, length_bytes: &[usize]) -> (u8, Vec<Request>) {
+
+        let mut type_header = req.type_header.clone();
+        type_header[DATA_STATUS] = usbr::Result::Stall as u8;
+
+        // length_high is only present if both sides have the 32-bit bulk length cap
+        let header_len = type_header.len();
+        for i in length_bytes.iter().filter(|i| **i < header_len) {
+            type_header[*i] = 0;
+        }
+
+        (0, vec![stall_reply(source, Request::new(req.get_type(), req.get_id(), type_header, vec![]))])
+    }
+
+    fn discard(&self, req: Request) -> (u8, Vec<Request>) {
+        debug!("Cannot stall request of type {}; dropping it", req.get_type());
+        (0, vec![])
+    }
+}
+
+// Stalls for blue's requests go back to blue; stalls for red's responses go forward to blue.
+fn stall_reply(source: Source, req: Request) -> Request {
+    match source {
+        Source::Blue => req.into_reply(),
+        Source::Red => req,
+    }
+}
+
+
+impl parser::HasHandlers for Stall {
+    fn handle_request(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        self.discard(req)
+    }
+
+    fn handle_control_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        self.stall_control(source, req)
+    }
+
+    fn handle_bulk_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        self.stall_data(source, req, &[2, 3, 8, 9])
+    }
+
+    fn handle_int_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        self.stall_data(source, req, &[2, 3])
+    }
+
+    fn handle_iso_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        self.stall_data(source, req, &[2, 3])
+    }
+}
@@ -0,0 +1,154 @@
+// usbredirfilter strings, as carried by filter filter packets (see usbr-spec.txt and
+// usbredirfilter.h). A filter is a list of rules separated by '|', each of the form
+// <class>,<vendor>,<product>,<version>,<allow>, where -1 matches any value.
+
+use std::fmt;
+
+use usb;
+
+pub const RULE_SEP: char = '|';
+pub const TOKEN_SEP: char = ',';
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub class: Option<u8>, // None matches any value (-1)
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub version_bcd: Option<u16>,
+    pub allow: bool,
+}
+
+// Decimal or 0x-prefixed hexadecimal, or -1 for any value
+fn parse_value(token: &str, max: u32) -> Result<Option<u32>, String> {
+
+    let token = token.trim();
+
+    if token == "-1" {
+        return Ok(None);
+    }
+
+    let value = if token.starts_with("0x") || token.starts_with("0X") {
+        u32::from_str_radix(&token[2..], 16)
+    } else {
+        token.parse::<u32>()
+    };
+
+    match value {
+        Ok(v) if v <= max => Ok(Some(v)),
+        _ => Err(format!("invalid filter value {}", token)),
+    }
+}
+
+impl Rule {
+    pub fn parse(rule: &str) -> Result<Rule, String> {
+
+        let tokens: Vec<&str> = rule.split(TOKEN_SEP).collect();
+
+        if tokens.len() != 5 {
+            return Err(format!("filter rule {} does not have 5 fields", rule));
+        }
+
+        let allow = match parse_value(tokens[4], 1)? {
+            Some(v) => v == 1,
+            None => return Err(format!("filter rule {} neither allows nor denies", rule)),
+        };
+
+        Ok(Rule {
+            class: parse_value(tokens[0], 0xff)?.map(|v| v as u8),
+            vendor_id: parse_value(tokens[1], 0xffff)?.map(|v| v as u16),
+            product_id: parse_value(tokens[2], 0xffff)?.map(|v| v as u16),
+            version_bcd: parse_value(tokens[3], 0xffff)?.map(|v| v as u16),
+            allow: allow,
+        })
+    }
+
+    fn matches(&self, class: u8, vendor_id: u16, product_id: u16, version_bcd: u16) -> bool {
+        self.class.iter().all(|v| *v == class) && self.vendor_id.iter().all(|v| *v == vendor_id) &&
+        self.product_id.iter().all(|v| *v == product_id) && self.version_bcd.iter().all(|v| *v == version_bcd)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+        let value = |v: Option<u32>| v.map_or("-1".to_string(), |v| format!("0x{:02x}", v));
+
+        write!(f,
+               "{}{sep}{}{sep}{}{sep}{}{sep}{}",
+               value(self.class.map(|v| v as u32)),
+               value(self.vendor_id.map(|v| v as u32)),
+               value(self.product_id.map(|v| v as u32)),
+               value(self.version_bcd.map(|v| v as u32)),
+               self.allow as u8,
+               sep = TOKEN_SEP)
+    }
+}
+
+// Parses a filter string (without the 0 terminator of the packet)
+pub fn parse(filter: &str) -> Result<Vec<Rule>, String> {
+
+    let filter = filter.trim();
+
+    if filter.is_empty() {
+        return Err("empty filter".to_string());
+    }
+
+    filter.split(RULE_SEP).map(Rule::parse).collect()
+}
+
+pub fn to_string(rules: &[Rule]) -> String {
+
+    let rules: Vec<String> = rules.iter().map(|r| r.to_string()).collect();
+    rules.join(&RULE_SEP.to_string())
+}
+
+// Parses the data of a filter filter packet, which must be a 0-terminated ASCII string
+pub fn parse_packet(data: &[u8]) -> Result<Vec<Rule>, String> {
+
+    match data.split_last() {
+        Some((&0, rest)) if rest.iter().all(|c| c.is_ascii() && *c != 0) => {
+            parse(&String::from_utf8_lossy(rest))
+        }
+
+        _ => Err("filter is not a 0-terminated string".to_string()),
+    }
+}
+
+// The data of a filter filter packet for the given rules
+pub fn to_packet(rules: &[Rule]) -> Vec<u8> {
+
+    let mut data = to_string(rules).into_bytes();
+    data.push(0);
+    data
+}
+
+// The first rule that matches class decides; classes that no rule matches are denied
+fn check_class(rules: &[Rule], class: u8, vendor_id: u16, product_id: u16, version_bcd: u16) -> bool {
+    match rules.iter().find(|r| r.matches(class, vendor_id, product_id, version_bcd)) {
+        Some(rule) => rule.allow,
+        None => false,
+    }
+}
+
+// Whether the filter allows a device, as usbredirfilter_check does: the device class (unless it
+// defers to its interfaces) and the class of every interface must be allowed. Interfaces are
+// (class, subclass, protocol). Like usbredir, non-boot HID interfaces of composite devices are
+// skipped (e.g., the extra keys of a webcam).
+pub fn check(rules: &[Rule],
+             device_class: u8,
+             interfaces: &[(u8, u8, u8)],
+             vendor_id: u16,
+             product_id: u16,
+             version_bcd: u16)
+             -> bool {
+
+    // Miscellaneous devices also defer to their interfaces (interface association)
+    if device_class != usb::CLASS_PER_INTERFACE && device_class != usb::CLASS_MISC &&
+       !check_class(rules, device_class, vendor_id, product_id, version_bcd) {
+        return false;
+    }
+
+    interfaces.iter()
+        .filter(|&&iface| interfaces.len() == 1 || iface != (usb::CLASS_HID, 0, 0))
+        .all(|&(class, _, _)| check_class(rules, class, vendor_id, product_id, version_bcd))
+}
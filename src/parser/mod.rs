@@ -1,10 +1,14 @@
 pub mod usbr;
+pub mod filter;
 
 use std::mem;
 use std::slice;
+use std::io;
 use std::io::{BufReader, BufWriter, Read, Write};
 use std::sync::mpsc;
 
+use byteorder::{ByteOrder, LittleEndian};
+
 macro_rules! header_type {
     ($x:expr, $header:ident) => {
        $x == usbr::HeaderType::$header as u32
@@ -26,6 +30,10 @@ pub enum ParseError {
     HeaderType,
     Source, // message sent by the wrong endpoint (red or blue machine)
     Version, // message sent by wrong version of usbr
+    Caps, // hello with malformed or invalid capabilities
+    Eof, // peer closed the connection
+    Io(io::Error), // any other failure reading from the peer
+    Oversized(usize), // frame is larger than anything usbr would legitimately send
 }
 
 
@@ -36,6 +44,7 @@ pub enum ParserState {
     Init,
     HelloR, // hello recv but not yet sent via push_outcome (needed for 32-bit id quirk)
     Hello,
+    Disconnected, // the device went away; the next one starts over with its interfaces and endpoints
     IfaceReceived,
     EpReceived,
     Informed, // guest has been informed
@@ -51,6 +60,10 @@ pub enum Source {
 pub const MAX_BULK_TRANSFER_SIZE: u32 = (128 * 1024 * 1024);
 pub const BUFFER_SIZE: usize = 65536;
 
+// Largest data payload plus room for the largest type header (ep info). Anything bigger than
+// this is not a usbr frame and we refuse to allocate for it.
+pub const MAX_REQUEST_SIZE: usize = (MAX_BULK_TRANSFER_SIZE as usize) + 512;
+
 pub struct Parser {
     pub state: ParserState,
     pub our_caps: [u32; usbr::CAPS_SIZE],
@@ -58,13 +71,40 @@ pub struct Parser {
     pub source: Source,
 }
 
+#[derive(Clone, PartialEq)]
 pub struct Request {
     pub header: [u8; usbr::REDIR_HEADER_SIZE], // main usbr header
     pub type_header: Vec<u8>, // header of request (e.g., device connect, control packet).
     pub data: Vec<u8>, // optional data associated with type header
+    pub reply: bool, // true if the request goes back to the endpoint that sent the original
 }
 
 impl Request {
+    // Builds a request from scratch (e.g., for packets synthesized by a module). The length
+    // in the usbr header is derived from the type header and data.
+    pub fn new(h_type: u32, id: u64, type_header: Vec<u8>, data: Vec<u8>) -> Request {
+
+        let mut header = [0; usbr::REDIR_HEADER_SIZE];
+
+        LittleEndian::write_u32(&mut header[0..4], h_type);
+        LittleEndian::write_u32(&mut header[4..8], (type_header.len() + data.len()) as u32);
+        LittleEndian::write_u64(&mut header[8..16], id);
+
+        Request {
+            header: header,
+            type_header: type_header,
+            data: data,
+            reply: false,
+        }
+    }
+
+    // Marks the request as an answer for the side that sent the request being handled
+    // (e.g., a STALL for a blue control request), rather than something to forward.
+    pub fn into_reply(mut self) -> Request {
+        self.reply = true;
+        self
+    }
+
     pub fn get_type(&self) -> u32 {
 
         let h_ptr = self.header.as_ptr() as *const usbr::RedirHeader;
@@ -96,144 +136,204 @@ impl Request {
 pub trait HasHandlers {
     // Handlers take as input the source and the request and output a port (typically 0)
     // and a set of requests. The port is used by the module manager for modules that
-    // have conditional outputs (e.g., rule matchers).
+    // have conditional outputs (e.g., rule matchers). Requests marked as replies (see
+    // Request::into_reply) go back to the source instead of being forwarded.
+
+    // Modules that decide the device session must end (e.g., after a failed check) return
+    // true here. The endpoints stop and close both connections once they see it.
+    fn session_ended(&self) -> bool {
+        false
+    }
+
+    // Fallback for every handler a module does not override. Modules that treat most
+    // requests alike (e.g., drop or reset) only implement this and their special cases.
+    fn handle_request(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        (0, vec![req])
+    }
 
     // Header-only packets
 
     fn handle_hello(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_connect(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_disconnect(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_disconnect_ack(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_reset(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_cancel_data_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_interface_info(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_ep_info(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_get_conf(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_set_conf(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_conf_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_get_alt_setting(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_set_alt_setting(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_alt_setting_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_start_iso_stream(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_stop_iso_stream(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_iso_stream_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_start_int_receiving(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_stop_int_receiving(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_int_receiving_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_alloc_bulk_streams(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_free_bulk_streams(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_bulk_streams_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_start_bulk_receiving(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_stop_bulk_receiving(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_bulk_receiving_status(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_filter_reject(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
+    }
+
+    fn handle_filter_filter(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
+        self.handle_request(source, req)
     }
 
 
     // Data packets
 
     fn handle_control_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_bulk_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_int_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_iso_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 
     fn handle_buffered_bulk_packet(&self, source: Source, req: Request) -> (u8, Vec<Request>) {
-        (0, vec![req])
+        self.handle_request(source, req)
     }
 }
 
 
 
+// Calls the handler for the request's type. Requests of other types are dropped.
+pub fn dispatch_request<T: HasHandlers + ?Sized>(handlers: &T, source: Source, req: Request) -> (u8, Vec<Request>) {
+
+    match req.get_type() {
+        x if header_type!(x, Hello) => handlers.handle_hello(source, req),
+        x if header_type!(x, DeviceConnect) => handlers.handle_connect(source, req),
+        x if header_type!(x, DeviceDisconnect) => handlers.handle_disconnect(source, req),
+        x if header_type!(x, InterfaceInfo) => handlers.handle_interface_info(source, req),
+        x if header_type!(x, EpInfo) => handlers.handle_ep_info(source, req),
+        x if header_type!(x, SetConf) => handlers.handle_set_conf(source, req),
+        x if header_type!(x, SetAltSetting) => handlers.handle_set_alt_setting(source, req),
+        x if header_type!(x, Reset) => handlers.handle_reset(source, req),
+        x if header_type!(x, ConfStatus) => handlers.handle_conf_status(source, req),
+        x if header_type!(x, GetAltSetting) => handlers.handle_get_alt_setting(source, req),
+        x if header_type!(x, AltSettingStatus) => handlers.handle_alt_setting_status(source, req),
+        x if header_type!(x, StartIsoStream) => handlers.handle_start_iso_stream(source, req),
+        x if header_type!(x, StopIsoStream) => handlers.handle_stop_iso_stream(source, req),
+        x if header_type!(x, IsoStreamStatus) => handlers.handle_iso_stream_status(source, req),
+        x if header_type!(x, StartIntReceiving) => handlers.handle_start_int_receiving(source, req),
+        x if header_type!(x, IntReceivingStatus) => handlers.handle_int_receiving_status(source, req),
+        x if header_type!(x, AllocBulkStreams) => handlers.handle_alloc_bulk_streams(source, req),
+        x if header_type!(x, FreeBulkStreams) => handlers.handle_free_bulk_streams(source, req),
+        x if header_type!(x, BulkStreamsStatus) => handlers.handle_bulk_streams_status(source, req),
+        x if header_type!(x, CancelDataPacket) => handlers.handle_cancel_data_packet(source, req),
+        x if header_type!(x, DeviceDisconnectAck) => handlers.handle_disconnect_ack(source, req),
+        x if header_type!(x, StartBulkReceiving) => handlers.handle_start_bulk_receiving(source, req),
+        x if header_type!(x, StopBulkReceiving) => handlers.handle_stop_bulk_receiving(source, req),
+        x if header_type!(x, BulkReceivingStatus) => handlers.handle_bulk_receiving_status(source, req),
+        x if header_type!(x, FilterReject) => handlers.handle_filter_reject(source, req),
+        x if header_type!(x, FilterFilter) => handlers.handle_filter_filter(source, req),
+
+        // Data packets
+        x if header_type!(x, ControlPacket) => handlers.handle_control_packet(source, req),
+        x if header_type!(x, BulkPacket) => handlers.handle_bulk_packet(source, req),
+        x if header_type!(x, IsoPacket) => handlers.handle_iso_packet(source, req),
+        x if header_type!(x, IntPacket) => handlers.handle_int_packet(source, req),
+        x if header_type!(x, BufferedBulkPacket) => handlers.handle_buffered_bulk_packet(source, req),
+
+        // Other requests
+        _ => (0, vec![]),
+    }
+}
+
 
 impl Parser {
     // Constructor
@@ -260,7 +360,7 @@ impl Parser {
         assert!(caps.len() <= self.our_caps.len());
         self.our_caps[0..caps.len()].clone_from_slice(caps);
 
-        // Only guest (blue machine) has the "ACK" capability
+        // The guest (blue machine) acks disconnects, whatever caps it was given
         if self.source == Source::Blue {
             set_cap(&mut self.our_caps, usbr::Caps::DeviceDisconnectAck as usize);
         }
@@ -272,15 +372,18 @@ impl Parser {
         self.state = ParserState::Init;
     }
 
-    pub fn handle_hello(&mut self, req: &Request) {
+    // A malformed hello comes from a peer we cannot talk to, so it ends the session
+    pub fn handle_hello(&mut self, req: &Request) -> Result<(), ParseError> {
 
-        if self.state >= ParserState::Hello {
+        if self.state >= ParserState::HelloR {
             error!("[E001-Parser] Received a second hello message, ignoring");
-            return;
+            return Ok(());
         }
 
-        assert!(self.state == ParserState::Init);
-        assert!(req.data.len() % 4 == 0);
+        if req.data.len() % 4 != 0 || req.data.len() / 4 > self.peer_caps.len() {
+            error!("[E002-Parser] Capacities are invalid ({} bytes)", req.data.len());
+            return Err(ParseError::Caps);
+        }
 
         let h_ptr = req.type_header.as_ptr() as *const usbr::HelloHeader;
         let hello: &usbr::HelloHeader = unsafe { &*h_ptr }; // gives a &T from a *const T
@@ -289,19 +392,21 @@ impl Parser {
         let caps: &[u32] = unsafe { slice::from_raw_parts(d_ptr, req.data.len() / 4) };
 
         if !verify_caps(caps, "peer") {
-            panic!("[E002-Parser] Capacities are invalid");
+            error!("[E002-Parser] Capacities are invalid");
+            return Err(ParseError::Caps);
         }
 
-        assert!(caps.len() <= self.peer_caps.len());
-
-        self.peer_caps[0..caps.len()].clone_from_slice(caps);
-
         if !is_ascii(&hello.version) {
-            panic!("[E003-Parser] version string is not ascii");
+            error!("[E003-Parser] version string is not ascii");
+            return Err(ParseError::Version);
         }
 
+        self.peer_caps[0..caps.len()].clone_from_slice(caps);
+
         debug!("Peer version len: {}", hello.version.len());
         self.state = ParserState::HelloR;
+
+        Ok(())
     }
 
 
@@ -316,7 +421,7 @@ impl Parser {
             for_red = !for_red
         }
 
-        // Total of 31 message types (not including the 2 filter ones)
+        // Total of 33 message types
         match h_type {
 
             // Valid: message for either red or blue machine
@@ -447,7 +552,11 @@ impl Parser {
             }
 
             x if header_type!(x, FilterReject) => if for_red { Ok(0) } else { Err(ParseError::Source) },
-            // All others are either not supported (e.g., filter stuff) or invalid
+
+            // Valid: message for either machine (the rules are the data)
+            x if header_type!(x, FilterFilter) => Ok(0),
+
+            // All others are invalid
             _ => Err(ParseError::HeaderType),
         }
     }
@@ -462,6 +571,7 @@ impl Parser {
             x if header_type!(x, IsoPacket) => true,
             x if header_type!(x, IntPacket) => true,
             x if header_type!(x, BufferedBulkPacket) => true,
+            x if header_type!(x, FilterFilter) => true,
             _ => false,
         }
     }
@@ -511,6 +621,9 @@ impl Parser {
 
             x if header_type!(x, DeviceDisconnectAck) => {
 
+                // The ack follows the disconnect, so the device is already gone
+                connected_state = false;
+
                 if (send && !has_cap(&self.our_caps, usbr::Caps::DeviceDisconnectAck as usize)) ||
                    (!send && !has_cap(&self.peer_caps, usbr::Caps::DeviceDisconnectAck as usize)) {
 
@@ -546,6 +659,27 @@ impl Parser {
             }
 
             x if header_type!(x, FilterReject) => {
+
+                if !self.filter_allowed(send) {
+                    error!("[E062-Parser] Filter reject without capability for it");
+                    return false;
+                }
+
+                connected_state = false;
+            }
+
+            x if header_type!(x, FilterFilter) => {
+
+                if !self.filter_allowed(send) {
+                    error!("[E063-Parser] Filter filter without capability for it");
+                    return false;
+                }
+
+                if let Err(e) = filter::parse_packet(data) {
+                    error!("[E064-Parser] Invalid filter: {}", e);
+                    return false;
+                }
+
                 connected_state = false;
             }
 
@@ -986,7 +1120,7 @@ impl Parser {
 
             }
 
-            // The rest (filter and invalid types)
+            // The rest (invalid types)
             _ => return false,
         }
 
@@ -1048,26 +1182,38 @@ impl Parser {
 
     // This is usually called when we encounter a packet that is corrupted and we need to
     // get rid of the rest of the data associated with that packet so we can move on.
-    // This call either returns (which means all is good), or it panics (cannot recover)
-    pub fn discard_current_request<T: Read>(&self, input: &mut BufReader<T>, len: usize) {
+    // An error means the stream is unusable and the session should be torn down.
+    pub fn discard_current_request<T: Read>(&self,
+                                            input: &mut BufReader<T>,
+                                            len: usize)
+                                            -> Result<(), ParseError> {
 
         // Discard packet and try again
-        let mut discard: Vec<u8> = Vec::with_capacity(len);
+        let mut discard = input.take(len as u64);
 
-        match input.read_exact(&mut discard[..len]) {
+        match io::copy(&mut discard, &mut io::sink()) {
 
-            Ok(_) => {
+            Ok(n) if n as usize == len => {
                 debug!("Discarding corrupted packet");
+                Ok(())
+            }
+
+            Ok(_) => {
+                error!("[E065-Parser] Unable to read discard buffer from TcpStream. Stream closed");
+                Err(ParseError::Eof)
             }
 
             Err(e) => {
-                panic!("[E051-Parser] Unable to read discard buffer from TcpStream. {:?}", e);
+                error!("[E051-Parser] Unable to read discard buffer from TcpStream. {:?}", e);
+                Err(ParseError::Io(e))
             }
         }
     }
 
 
-    pub fn pull_next_request<T: Read>(&self, mut input: &mut BufReader<T>) -> Request {
+    pub fn pull_next_request<T: Read>(&self,
+                                      mut input: &mut BufReader<T>)
+                                      -> Result<Request, ParseError> {
 
 
         loop {
@@ -1077,6 +1223,7 @@ impl Parser {
                 header: [0; usbr::REDIR_HEADER_SIZE],
                 type_header: Vec::new(),
                 data: Vec::new(),
+                reply: false,
             };
 
             // get header (this blocks)
@@ -1089,16 +1236,22 @@ impl Parser {
                 input.read_exact(&mut request.header)
             };
 
-            match ret {
-                Ok(_) => {}
-                Err(e) => {
-                    panic!("[E052-Parser] Unable to read full header from TcpStream. {:?}", e);
-                }
+            if let Err(e) = ret {
+                debug!("[E052-Parser] Unable to read full header from TcpStream. {:?}", e);
+                return Err(read_error(e));
             }
 
             let total_len: usize = request.get_total_len();
             let h_type: u32 = request.get_type();
 
+            // We can't resynchronize with a peer that claims absurd lengths, so give up.
+            if total_len > MAX_REQUEST_SIZE {
+                error!("[E061-Parser] Request of type {} claims a length of {} bytes",
+                       h_type,
+                       total_len);
+                return Err(ParseError::Oversized(total_len));
+            }
+
             // get size of type header (assuming above is valid)
             let type_len: usize = match self.get_type_header_len(h_type, false) {
                 Ok(v) => v,
@@ -1107,7 +1260,7 @@ impl Parser {
                     error!("[E053-Parser] Could not get type header ({}), length: {:?}",
                            h_type,
                            e);
-                    self.discard_current_request(&mut input, total_len); // returns (ok) or panics
+                    self.discard_current_request(&mut input, total_len)?;
                     continue;
 
                 }
@@ -1118,19 +1271,17 @@ impl Parser {
 
                 error!("[E054-Parser] Total length does not make sense given the type of header");
 
-                self.discard_current_request(&mut input, total_len); // returns (ok) or panics
+                self.discard_current_request(&mut input, total_len)?;
                 continue;
             }
 
             // reserve space for type header and parse type header
             request.type_header.extend_from_slice(&vec![0; type_len]);
 
-            match input.read_exact(&mut request.type_header) { // this blocks
-                Ok(_) => {}
-                Err(e) => {
-                    panic!("[E055-Parser] Unable to read full type header from TcpStream. {:?}",
-                           e);
-                }
+            if let Err(e) = input.read_exact(&mut request.type_header) { // this blocks
+                error!("[E055-Parser] Unable to read full type header from TcpStream. {:?}",
+                       e);
+                return Err(read_error(e));
             }
 
 
@@ -1142,11 +1293,9 @@ impl Parser {
                 data_len = total_len - type_len;
                 request.data.extend_from_slice(&vec![0; data_len]);
 
-                match input.read_exact(&mut request.data) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        panic!("[E056-Parser] Unable to read full data from TcpStream. {:?}", e);
-                    }
+                if let Err(e) = input.read_exact(&mut request.data) {
+                    error!("[E056-Parser] Unable to read full data from TcpStream. {:?}", e);
+                    return Err(read_error(e));
                 }
             }
 
@@ -1157,7 +1306,7 @@ impl Parser {
                                        &request.data[..data_len],
                                        false) {
                 // We are good! return request.
-                return request;
+                return Ok(request);
 
             } else {
 
@@ -1170,7 +1319,10 @@ impl Parser {
         }
     }
 
-    pub fn push_outputs<W: Write>(&mut self, output: &mut BufWriter<W>, requests: Vec<Request>) {
+    pub fn push_outputs<W: Write>(&mut self,
+                                  output: &mut BufWriter<W>,
+                                  requests: Vec<Request>)
+                                  -> io::Result<()> {
 
         // TODO: this might be inefficient. Revisit if there are performance issues.
 
@@ -1180,16 +1332,33 @@ impl Parser {
                 // Quirk: USBR typically starts off with 32-bit ids, and then upgrades to 64-bit
                 // ids.
                 // We skip the first 4 bytes to account for this initial message.
-                output.write(&req.header[..usbr::REDIR_HEADER_SIZE - 4]).unwrap();
+                output.write_all(&req.header[..usbr::REDIR_HEADER_SIZE - 4])?;
                 self.process_state_change(ParserState::Hello);
             } else {
-                output.write(&req.header).unwrap();
+                output.write_all(&req.header)?;
             }
 
-            output.write(&req.type_header).unwrap();
-            output.write(&req.data).unwrap();
+            output.write_all(&req.type_header)?;
+            output.write_all(&req.data)?;
+        }
+
+        Ok(())
+    }
+
+    // Writes requests that go back to the endpoint this parser reads from. These never
+    // include the hello, so the 32-bit id quirk does not apply.
+    pub fn push_replies<W: Write>(&self,
+                                  output: &mut BufWriter<W>,
+                                  requests: Vec<Request>)
+                                  -> io::Result<()> {
+
+        for req in requests {
+            output.write_all(&req.header)?;
+            output.write_all(&req.type_header)?;
+            output.write_all(&req.data)?;
         }
 
+        Ok(())
     }
 
 
@@ -1197,7 +1366,7 @@ impl Parser {
                                            handlers: &T,
                                            req: Request,
                                            tx: &mpsc::Sender<ParserState>)
-                                           -> Vec<Request> {
+                                           -> Result<Vec<Request>, ParseError> {
 
         // (1) Figure out if operation leads to a parser state change. If so, update state and
         //     propagate info to the other parser (via channel tx).
@@ -1205,115 +1374,70 @@ impl Parser {
         // (2) Call the apropriate handler, which returns a Vec<Request> as a response
         //     The default handlers simply return the same request.
 
-        let (_, out) = match req.get_type() {
+        match req.get_type() {
 
             // Requests that could lead to a parser state change
-            x if header_type!(x, Hello) => {
-                self.handle_hello(&req);
-                handlers.handle_hello(get_sender!(self.source), req)
-            }
+            x if header_type!(x, Hello) => self.handle_hello(&req)?,
 
             x if header_type!(x, DeviceConnect) => {
-
                 if self.process_state_change(ParserState::Connected) {
                     tx.send(ParserState::Connected).unwrap();
                 }
+            }
 
-                handlers.handle_connect(get_sender!(self.source), req)
+            x if header_type!(x, DeviceDisconnect) => {
+                if self.process_state_change(ParserState::Disconnected) {
+                    tx.send(ParserState::Disconnected).unwrap();
+                }
             }
 
             x if header_type!(x, InterfaceInfo) => {
-
                 if self.process_state_change(ParserState::IfaceReceived) {
                     tx.send(ParserState::IfaceReceived).unwrap();
                 }
-
-                handlers.handle_interface_info(get_sender!(self.source), req)
-
             }
 
             x if header_type!(x, EpInfo) => {
-
                 if self.process_state_change(ParserState::EpReceived) {
                     tx.send(ParserState::EpReceived).unwrap();
                 }
-
-                handlers.handle_ep_info(get_sender!(self.source), req)
-
             }
 
-            // Requests that do not lead to a parser state change
-            x if header_type!(x, SetConf) => handlers.handle_set_conf(get_sender!(self.source), req),
-            x if header_type!(x, SetAltSetting) => handlers.handle_set_alt_setting(get_sender!(self.source), req),
-            x if header_type!(x, DeviceDisconnect) => handlers.handle_disconnect(get_sender!(self.source), req),
-            x if header_type!(x, Reset) => handlers.handle_reset(get_sender!(self.source), req),
-            x if header_type!(x, ConfStatus) => handlers.handle_conf_status(get_sender!(self.source), req),
-            x if header_type!(x, GetAltSetting) => handlers.handle_get_alt_setting(get_sender!(self.source), req),
-            x if header_type!(x, AltSettingStatus) => {
-                handlers.handle_alt_setting_status(get_sender!(self.source), req)
-            }
-            x if header_type!(x, StartIsoStream) => {
-                handlers.handle_start_iso_stream(get_sender!(self.source), req)
-            }
-            x if header_type!(x, StopIsoStream) => handlers.handle_stop_iso_stream(get_sender!(self.source), req),
-            x if header_type!(x, IsoStreamStatus) => {
-                handlers.handle_iso_stream_status(get_sender!(self.source), req)
-            }
-            x if header_type!(x, StartIntReceiving) => {
-                handlers.handle_start_int_receiving(get_sender!(self.source), req)
-            }
-            x if header_type!(x, IntReceivingStatus) => {
-                handlers.handle_int_receiving_status(get_sender!(self.source), req)
-            }
-            x if header_type!(x, AllocBulkStreams) => {
-                handlers.handle_alloc_bulk_streams(get_sender!(self.source), req)
-            }
-            x if header_type!(x, FreeBulkStreams) => {
-                handlers.handle_free_bulk_streams(get_sender!(self.source), req)
-            }
-            x if header_type!(x, BulkStreamsStatus) => {
-                handlers.handle_bulk_streams_status(get_sender!(self.source), req)
-            }
-            x if header_type!(x, CancelDataPacket) => {
-                handlers.handle_cancel_data_packet(get_sender!(self.source), req)
-            }
-            x if header_type!(x, DeviceDisconnectAck) => {
-                handlers.handle_disconnect_ack(get_sender!(self.source), req)
-            }
-            x if header_type!(x, StartBulkReceiving) => {
-                handlers.handle_start_bulk_receiving(get_sender!(self.source), req)
-            }
-            x if header_type!(x, StopBulkReceiving) => {
-                handlers.handle_stop_bulk_receiving(get_sender!(self.source), req)
-            }
-            x if header_type!(x, BulkReceivingStatus) => {
-                handlers.handle_bulk_receiving_status(get_sender!(self.source), req)
-            }
+            _ => {}
+        }
 
-            x if header_type!(x, FilterReject) => handlers.handle_filter_reject(get_sender!(self.source), req),
-            x if header_type!(x, ControlPacket) => handlers.handle_control_packet(get_sender!(self.source), req),
-            x if header_type!(x, BulkPacket) => handlers.handle_bulk_packet(get_sender!(self.source), req),
-            x if header_type!(x, IsoPacket) => handlers.handle_iso_packet(get_sender!(self.source), req),
-            x if header_type!(x, IntPacket) => handlers.handle_int_packet(get_sender!(self.source), req),
-            x if header_type!(x, BufferedBulkPacket) => {
-                handlers.handle_buffered_bulk_packet(get_sender!(self.source), req)
-            }
+        let (_, out) = dispatch_request(handlers, get_sender!(self.source), req);
+
+        Ok(out)
+    }
 
-            // Other requests
-            _ => (0, vec![]),
-        };
 
-        out
+    // Filter packets need the filter capability on the side that receives them (as in usbredir)
+    fn filter_allowed(&self, send: bool) -> bool {
+        (send && has_cap(&self.peer_caps, usbr::Caps::Filter as usize)) ||
+        (!send && has_cap(&self.our_caps, usbr::Caps::Filter as usize))
     }
 
 
     pub fn process_state_change(&mut self, state: ParserState) -> bool {
 
-        // This is straightforward except for 1 cases:
+        // This is straightforward except for 2 cases:
         //
         // if self.state U state == IfaceInfo U EpInfo => self.state = Informed
+        //
+        // Disconnected goes back from any later state, so that the next device (which sends
+        // its interface and endpoint info again) is tracked from scratch.
+
+        if state == ParserState::Disconnected {
 
-        if (self.state == ParserState::IfaceReceived && state == ParserState::EpReceived) ||
+            if self.state > ParserState::Disconnected {
+                self.state = ParserState::Disconnected;
+                return true;
+            }
+
+            return false;
+
+        } else if (self.state == ParserState::IfaceReceived && state == ParserState::EpReceived) ||
            (self.state == ParserState::EpReceived && state == ParserState::IfaceReceived) {
 
             self.state = ParserState::Informed;
@@ -1331,6 +1455,24 @@ impl Parser {
 }
 
 
+// Capabilities that Cinch advertises to both machines
+pub fn gen_caps() -> [u32; usbr::CAPS_SIZE] {
+
+    let mut caps: [u32; usbr::CAPS_SIZE] = [0; usbr::CAPS_SIZE];
+
+    set_cap(&mut caps, usbr::Caps::BulkStreams as usize);
+    set_cap(&mut caps, usbr::Caps::ConnectDeviceVersion as usize);
+    set_cap(&mut caps, usbr::Caps::EpInfoMaxPacketSize as usize);
+    set_cap(&mut caps, usbr::Caps::Cap64BitsIds as usize);
+    set_cap(&mut caps, usbr::Caps::Cap32BitsBulkLength as usize);
+    set_cap(&mut caps, usbr::Caps::BulkReceiving as usize);
+    set_cap(&mut caps, usbr::Caps::Filter as usize);
+    set_cap(&mut caps, usbr::Caps::DeviceDisconnectAck as usize);
+
+    caps
+}
+
+
 pub fn has_cap(caps: &[u32], cap: usize) -> bool {
 
     assert!(cap / 32 < usbr::CAPS_SIZE,
@@ -1380,6 +1522,16 @@ pub fn verify_caps(caps: &[u32], desc: &str) -> bool {
     true
 }
 
+// Distinguishes a peer that went away from an actual I/O failure
+fn read_error(e: io::Error) -> ParseError {
+
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        ParseError::Eof
+    } else {
+        ParseError::Io(e)
+    }
+}
+
 // Returns true if all characters as ascii (not counting extended ascii)
 pub fn is_ascii(x: &[u8]) -> bool {
 
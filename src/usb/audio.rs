@@ -0,0 +1,105 @@
+// From the USB Audio Class 1.0 and 2.0 specifications (UAC1 and UAC2) and USB MIDI 1.0
+
+// interface subclasses
+pub const SC_AUDIOCONTROL: u8 = 0x01;
+pub const SC_AUDIOSTREAMING: u8 = 0x02;
+pub const SC_MIDISTREAMING: u8 = 0x03;
+
+// interface protocols
+pub const UAC_VERSION_1: u8 = 0x00;
+pub const UAC_VERSION_2: u8 = 0x20;
+
+// class-specific descriptors start with length, type and subtype
+pub const DESC_MIN_SIZE: usize = 3;
+
+// audio control interface descriptor subtypes
+pub const HEADER: u8 = 0x01;
+pub const INPUT_TERMINAL: u8 = 0x02;
+pub const OUTPUT_TERMINAL: u8 = 0x03;
+pub const MIXER_UNIT: u8 = 0x04;
+pub const SELECTOR_UNIT: u8 = 0x05;
+pub const FEATURE_UNIT: u8 = 0x06;
+pub const PROCESSING_UNIT_V1: u8 = 0x07;
+pub const EXTENSION_UNIT_V1: u8 = 0x08;
+pub const EFFECT_UNIT: u8 = 0x07;
+pub const PROCESSING_UNIT_V2: u8 = 0x08;
+pub const EXTENSION_UNIT_V2: u8 = 0x09;
+pub const CLOCK_SOURCE: u8 = 0x0a;
+pub const CLOCK_SELECTOR: u8 = 0x0b;
+pub const CLOCK_MULTIPLIER: u8 = 0x0c;
+pub const SAMPLE_RATE_CONVERTER: u8 = 0x0d;
+
+// audio streaming interface descriptor subtypes
+pub const AS_GENERAL: u8 = 0x01;
+pub const FORMAT_TYPE: u8 = 0x02;
+pub const FORMAT_SPECIFIC: u8 = 0x03; // UAC1
+pub const ENCODER: u8 = 0x03; // UAC2
+pub const DECODER: u8 = 0x04; // UAC2
+
+// format types
+pub const FORMAT_TYPE_I: u8 = 0x01;
+pub const FORMAT_TYPE_II: u8 = 0x02;
+pub const FORMAT_TYPE_III: u8 = 0x03;
+pub const FORMAT_TYPE_IV: u8 = 0x04; // UAC2
+
+// audio streaming endpoint descriptor subtypes
+pub const EP_GENERAL: u8 = 0x01;
+
+// MIDI streaming interface and endpoint descriptor subtypes
+pub const MS_HEADER: u8 = 0x01;
+pub const MIDI_IN_JACK: u8 = 0x02;
+pub const MIDI_OUT_JACK: u8 = 0x03;
+pub const MIDI_ELEMENT: u8 = 0x04;
+pub const MS_GENERAL: u8 = 0x01;
+
+// UAC1 class request values
+pub const SET_CUR: u8 = 0x01;
+pub const SET_MIN: u8 = 0x02;
+pub const SET_MAX: u8 = 0x03;
+pub const SET_RES: u8 = 0x04;
+pub const SET_MEM: u8 = 0x05;
+pub const GET_CUR: u8 = 0x81;
+pub const GET_MIN: u8 = 0x82;
+pub const GET_MAX: u8 = 0x83;
+pub const GET_RES: u8 = 0x84;
+pub const GET_MEM: u8 = 0x85;
+pub const GET_STAT: u8 = 0xff;
+
+// UAC2 class request values (attributes; the direction tells set from get)
+pub const CUR: u8 = 0x01;
+pub const RANGE: u8 = 0x02;
+pub const MEM: u8 = 0x03;
+
+// feature unit control selectors
+pub const FU_MUTE: u8 = 0x01;
+pub const FU_VOLUME: u8 = 0x02;
+pub const FU_BASS: u8 = 0x03;
+pub const FU_MID: u8 = 0x04;
+pub const FU_TREBLE: u8 = 0x05;
+pub const FU_GRAPHIC_EQUALIZER: u8 = 0x06;
+pub const FU_AUTOMATIC_GAIN: u8 = 0x07;
+pub const FU_DELAY: u8 = 0x08;
+pub const FU_BASS_BOOST: u8 = 0x09;
+pub const FU_LOUDNESS: u8 = 0x0a;
+pub const FU_INPUT_GAIN: u8 = 0x0b; // UAC2
+pub const FU_INPUT_GAIN_PAD: u8 = 0x0c; // UAC2
+pub const FU_PHASE_INVERTER: u8 = 0x0d; // UAC2
+pub const FU_UNDERFLOW: u8 = 0x0e; // UAC2
+pub const FU_OVERFLOW: u8 = 0x0f; // UAC2
+
+// clock source, selector and multiplier control selectors (UAC2)
+pub const CS_SAM_FREQ: u8 = 0x01;
+pub const CS_CLOCK_VALID: u8 = 0x02;
+pub const CX_CLOCK_SELECTOR: u8 = 0x01;
+pub const CM_NUMERATOR: u8 = 0x01;
+pub const CM_DENOMINATOR: u8 = 0x02;
+
+// endpoint control selectors
+pub const EP_SAMPLING_FREQ: u8 = 0x01; // UAC1
+pub const EP_PITCH: u8 = 0x02; // UAC1
+pub const EP_PITCH_V2: u8 = 0x01;
+pub const EP_DATA_OVERRUN: u8 = 0x02;
+pub const EP_DATA_UNDERRUN: u8 = 0x03;
+
+// a UAC2 range is a count of subranges, each with a minimum, maximum and resolution
+pub const RANGE_HEADER_SIZE: usize = 2;
@@ -0,0 +1,40 @@
+// From the USB CDC 1.2 and PSTN 1.2 (abstract control model) specifications
+
+// communications interface subclasses
+pub const SC_ACM: u8 = 0x02;
+
+// functional descriptor subtypes (descriptor type usb::DT_CS_INTERFACE)
+pub const FD_HEADER: u8 = 0x00;
+pub const FD_CALL_MANAGEMENT: u8 = 0x01;
+pub const FD_ACM: u8 = 0x02;
+pub const FD_UNION: u8 = 0x06;
+
+// functional descriptor lengths (including length, type and subtype)
+pub const FD_MIN_SIZE: usize = 3;
+pub const FD_HEADER_SIZE: usize = 5;
+pub const FD_CALL_MANAGEMENT_SIZE: usize = 5;
+pub const FD_ACM_SIZE: usize = 4;
+pub const FD_UNION_MIN_SIZE: usize = 5;
+
+// class request values
+pub const SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+pub const GET_ENCAPSULATED_RESPONSE: u8 = 0x01;
+pub const SET_COMM_FEATURE: u8 = 0x02;
+pub const GET_COMM_FEATURE: u8 = 0x03;
+pub const CLEAR_COMM_FEATURE: u8 = 0x04;
+pub const SET_LINE_CODING: u8 = 0x20;
+pub const GET_LINE_CODING: u8 = 0x21;
+pub const SET_CONTROL_LINE_STATE: u8 = 0x22;
+pub const SEND_BREAK: u8 = 0x23;
+
+// notification values
+pub const NETWORK_CONNECTION: u8 = 0x00;
+pub const RESPONSE_AVAILABLE: u8 = 0x01;
+pub const SERIAL_STATE: u8 = 0x20;
+
+// dwDTERate, bCharFormat, bParityType, bDataBits
+pub const LINE_CODING_SIZE: usize = 7;
+
+// bmRequestType, bNotification, wValue, wIndex, wLength
+pub const NOTIFICATION_HEADER_SIZE: usize = 8;
+pub const SERIAL_STATE_SIZE: usize = 2;
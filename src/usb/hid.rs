@@ -13,6 +13,30 @@ pub const SET_REPORT: u8 = 0x09;
 pub const SET_IDLE: u8 = 0x0a;
 pub const SET_PROT: u8 = 0x0b;
 
+// interface subclass and protocols of boot devices
+pub const SUBCLASS_BOOT: u8 = 0x01;
+pub const PROTO_KEYBOARD: u8 = 0x01;
+pub const PROTO_MOUSE: u8 = 0x02;
+
+// boot keyboard reports (Appendix B.1, Page 59 in spec/usb-hid.pdf): modifiers, reserved,
+// then up to 6 keys that are down
+pub const BOOT_KEYBOARD_SIZE: usize = 8;
+pub const BOOT_KEYBOARD_KEYS: usize = 2;
+
+// modifier bits (left and right)
+pub const MOD_CTRL: u8 = 0x11;
+pub const MOD_SHIFT: u8 = 0x22;
+pub const MOD_ALT: u8 = 0x44;
+pub const MOD_GUI: u8 = 0x88;
+
+// keyboard usages (Section 10 of the HID Usage Tables)
+pub const KEY_ERROR_UNDEFINED: u8 = 0x03; // 0x00 - 0x03 are not keys
+pub const KEY_R: u8 = 0x15;
+pub const KEY_T: u8 = 0x17;
+pub const KEY_X: u8 = 0x1b;
+pub const KEY_SPACE: u8 = 0x2c;
+pub const KEY_F2: u8 = 0x3b;
+
 #[derive(Copy, Clone)]
 pub struct HidClassDescriptor {
     pub descriptor_type: u8,
@@ -51,6 +75,12 @@ pub const TAG_COLLECTION: u8 = 0x0a;
 pub const TAG_FEATURE: u8 = 0x0b;
 pub const TAG_END_COLLECTION: u8 = 0x0c;
 
+// flags of input, output and feature items (first data byte)
+
+pub const MAIN_CONSTANT: u8 = 0x01;
+pub const MAIN_VARIABLE: u8 = 0x02;
+pub const MAIN_NULL_STATE: u8 = 0x40;
+
 // global tag types
 
 pub const TAG_USAGE_PAGE: u8 = 0x00;
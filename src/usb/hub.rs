@@ -0,0 +1,65 @@
+// From Chapter 11 in spec/usb2.pdf and Chapter 10 in spec/usb3.pdf
+
+// hub descriptor types
+pub const DT_HUB: u8 = 0x29;
+pub const DT_SS_HUB: u8 = 0x2a;
+
+// class request values
+pub const GET_STATUS: u8 = 0x00;
+pub const CLEAR_FEATURE: u8 = 0x01;
+pub const SET_FEATURE: u8 = 0x03;
+pub const GET_DESCRIPTOR: u8 = 0x06;
+pub const SET_DESCRIPTOR: u8 = 0x07;
+pub const CLEAR_TT_BUFFER: u8 = 0x08;
+pub const RESET_TT: u8 = 0x09;
+pub const GET_TT_STATE: u8 = 0x0a;
+pub const STOP_TT: u8 = 0x0b;
+pub const SET_HUB_DEPTH: u8 = 0x0c; // USB 3
+pub const GET_PORT_ERR_COUNT: u8 = 0x0d; // USB 3
+
+// hub feature selectors
+pub const C_HUB_LOCAL_POWER: u16 = 0;
+pub const C_HUB_OVER_CURRENT: u16 = 1;
+
+// port feature selectors
+pub const PORT_CONNECTION: u16 = 0;
+pub const PORT_ENABLE: u16 = 1;
+pub const PORT_SUSPEND: u16 = 2;
+pub const PORT_OVER_CURRENT: u16 = 3;
+pub const PORT_RESET: u16 = 4;
+pub const PORT_LINK_STATE: u16 = 5; // USB 3
+pub const PORT_POWER: u16 = 8;
+pub const PORT_LOW_SPEED: u16 = 9;
+pub const C_PORT_CONNECTION: u16 = 16;
+pub const C_PORT_ENABLE: u16 = 17;
+pub const C_PORT_SUSPEND: u16 = 18;
+pub const C_PORT_OVER_CURRENT: u16 = 19;
+pub const C_PORT_RESET: u16 = 20;
+pub const PORT_TEST: u16 = 21;
+pub const PORT_INDICATOR: u16 = 22;
+pub const PORT_U1_TIMEOUT: u16 = 23; // USB 3
+pub const PORT_U2_TIMEOUT: u16 = 24; // USB 3
+pub const C_PORT_LINK_STATE: u16 = 25; // USB 3
+pub const C_PORT_CONFIG_ERROR: u16 = 26; // USB 3
+pub const PORT_REMOTE_WAKE_MASK: u16 = 27; // USB 3
+pub const BH_PORT_RESET: u16 = 28; // USB 3
+pub const C_BH_PORT_RESET: u16 = 29; // USB 3
+pub const FORCE_LINKPM_ACCEPT: u16 = 30; // USB 3
+
+// bLength, bDescriptorType, bNbrPorts, wHubCharacteristics, bPwrOn2PwrGood and bHubContrCurrent
+// (USB 2 hub descriptors then have DeviceRemovable and PortPwrCtrlMask, a bit per port each)
+pub const HUB_DESC_FIXED_SIZE: usize = 7;
+pub const SS_HUB_DESC_SIZE: usize = 12;
+
+// Linux supports at most 31 ports on USB 2 hubs, and USB 3 hubs have at most 15
+pub const MAX_PORTS: u8 = 31;
+pub const MAX_SS_PORTS: u8 = 15;
+
+// wHubStatus and wHubChange, or wPortStatus and wPortChange
+pub const STATUS_SIZE: usize = 4;
+
+// port status types of USB 3 hubs (in wValue of GET_PORT_STATUS)
+pub const PORT_STATUS_STANDARD: u16 = 0;
+pub const PORT_STATUS_PD: u16 = 1;
+pub const PORT_STATUS_EXT: u16 = 2;
+pub const EXT_STATUS_SIZE: usize = 8;
@@ -3,6 +3,11 @@
 pub mod hid;
 pub mod bbb;
 pub mod printer;
+pub mod cdc;
+pub mod audio;
+pub mod hub;
+pub mod video;
+pub mod scsi;
 
 // This file holds USB constants and structures that are needed for
 // USB device APIs.  These are used by the USB device model, which is
@@ -0,0 +1,73 @@
+// SCSI commands carried in the command block of a bulk-only CBW (SPC-4 and SBC-3)
+
+// operation codes
+pub const TEST_UNIT_READY: u8 = 0x00;
+pub const REQUEST_SENSE: u8 = 0x03;
+pub const FORMAT_UNIT: u8 = 0x04;
+pub const WRITE_6: u8 = 0x0a;
+pub const INQUIRY: u8 = 0x12;
+pub const MODE_SELECT_6: u8 = 0x15;
+pub const MODE_SENSE_6: u8 = 0x1a;
+pub const START_STOP_UNIT: u8 = 0x1b;
+pub const PREVENT_ALLOW_REMOVAL: u8 = 0x1e;
+pub const READ_FORMAT_CAPACITIES: u8 = 0x23;
+pub const READ_CAPACITY_10: u8 = 0x25;
+pub const READ_10: u8 = 0x28;
+pub const WRITE_10: u8 = 0x2a;
+pub const VERIFY_10: u8 = 0x2f;
+pub const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+pub const UNMAP: u8 = 0x42;
+pub const MODE_SELECT_10: u8 = 0x55;
+pub const MODE_SENSE_10: u8 = 0x5a;
+pub const READ_16: u8 = 0x88;
+pub const WRITE_16: u8 = 0x8a;
+pub const SYNCHRONIZE_CACHE_16: u8 = 0x91;
+pub const SERVICE_ACTION_IN_16: u8 = 0x9e;
+pub const READ_12: u8 = 0xa8;
+pub const WRITE_12: u8 = 0xaa;
+
+// service actions of SERVICE ACTION IN(16)
+pub const SA_READ_CAPACITY_16: u8 = 0x10;
+pub const SA_MASK: u8 = 0x1f;
+
+// INQUIRY
+pub const INQUIRY_EVPD: u8 = 0x01; // byte 1 of the command: ask for a vital product data page
+pub const INQUIRY_HEADER_SIZE: usize = 5; // up to and including the additional length
+pub const INQUIRY_STD_SIZE: usize = 36; // standard data is at least this long
+pub const VPD_HEADER_SIZE: usize = 4;
+
+// Peripheral qualifier (top 3 bits of byte 0) that SPC reserves
+pub const QUALIFIER_RESERVED: u8 = 0x02;
+
+// Peripheral device types (bottom 5 bits of byte 0) from 0x15 to 0x1d are reserved
+pub const TYPE_RESERVED_MIN: u8 = 0x15;
+pub const TYPE_RESERVED_MAX: u8 = 0x1d;
+
+// READ CAPACITY
+pub const READ_CAPACITY_10_SIZE: usize = 8;
+pub const READ_CAPACITY_16_MIN_SIZE: usize = 12; // returned logical block address and block length
+pub const BLOCK_LENGTH_MIN: u32 = 512;
+pub const BLOCK_LENGTH_MAX: u32 = 65536;
+
+// Sense data
+pub const SENSE_HEADER_SIZE: usize = 8; // up to and including the additional sense length
+pub const SENSE_FIXED_CURRENT: u8 = 0x70;
+pub const SENSE_FIXED_DEFERRED: u8 = 0x71;
+pub const SENSE_DESC_CURRENT: u8 = 0x72;
+pub const SENSE_DESC_DEFERRED: u8 = 0x73;
+pub const SENSE_CODE_MASK: u8 = 0x7f;
+pub const SENSE_KEY_MASK: u8 = 0x0f;
+pub const SENSE_KEY_RESERVED: u8 = 0x0f;
+pub const SENSE_KEY_DATA_PROTECT: u8 = 0x07;
+pub const ASC_WRITE_PROTECTED: u8 = 0x27;
+pub const SENSE_FIXED_SIZE: usize = 18;
+
+// MODE SENSE
+pub const MODE_HEADER_6_SIZE: usize = 4;
+pub const MODE_HEADER_10_SIZE: usize = 8;
+pub const MODE_LONGLBA: u8 = 0x01; // byte 4 of the MODE SENSE(10) header
+pub const MODE_WP: u8 = 0x80; // write protect, in the device-specific parameter of the header
+pub const MODE_PARAM_6: usize = 2; // offset of the device-specific parameter in MODE SENSE(6)
+pub const MODE_PARAM_10: usize = 3; // and in MODE SENSE(10)
+pub const BLOCK_DESC_SIZE: usize = 8;
+pub const LONG_BLOCK_DESC_SIZE: usize = 16;
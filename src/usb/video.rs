@@ -0,0 +1,83 @@
+// From the USB Video Class 1.1 and 1.5 specifications (UVC 1.1 and UVC 1.5) and their payload
+// format specifications
+
+// interface subclasses
+pub const SC_VIDEOCONTROL: u8 = 0x01;
+pub const SC_VIDEOSTREAMING: u8 = 0x02;
+pub const SC_VIDEO_INTERFACE_COLLECTION: u8 = 0x03;
+
+// class versions (bcdUVC)
+pub const UVC_1_0: u16 = 0x0100;
+pub const UVC_1_1: u16 = 0x0110;
+pub const UVC_1_5: u16 = 0x0150;
+
+// class-specific descriptors start with length, type and subtype
+pub const DESC_MIN_SIZE: usize = 3;
+
+// video control interface descriptor subtypes
+pub const VC_HEADER: u8 = 0x01;
+pub const VC_INPUT_TERMINAL: u8 = 0x02;
+pub const VC_OUTPUT_TERMINAL: u8 = 0x03;
+pub const VC_SELECTOR_UNIT: u8 = 0x04;
+pub const VC_PROCESSING_UNIT: u8 = 0x05;
+pub const VC_EXTENSION_UNIT: u8 = 0x06;
+pub const VC_ENCODING_UNIT: u8 = 0x07; // UVC 1.5
+
+// terminal types (wTerminalType)
+pub const TT_STREAMING: u16 = 0x0101;
+pub const ITT_VENDOR_SPECIFIC: u16 = 0x0200;
+pub const ITT_CAMERA: u16 = 0x0201;
+pub const OTT_VENDOR_SPECIFIC: u16 = 0x0300;
+pub const EXTERNAL_VENDOR_SPECIFIC: u16 = 0x0400;
+
+// video streaming interface descriptor subtypes
+pub const VS_INPUT_HEADER: u8 = 0x01;
+pub const VS_OUTPUT_HEADER: u8 = 0x02;
+pub const VS_STILL_IMAGE_FRAME: u8 = 0x03;
+pub const VS_FORMAT_UNCOMPRESSED: u8 = 0x04;
+pub const VS_FRAME_UNCOMPRESSED: u8 = 0x05;
+pub const VS_FORMAT_MJPEG: u8 = 0x06;
+pub const VS_FRAME_MJPEG: u8 = 0x07;
+pub const VS_FORMAT_MPEG2TS: u8 = 0x0a;
+pub const VS_FORMAT_DV: u8 = 0x0c;
+pub const VS_COLORFORMAT: u8 = 0x0d;
+pub const VS_FORMAT_FRAME_BASED: u8 = 0x10;
+pub const VS_FRAME_FRAME_BASED: u8 = 0x11;
+pub const VS_FORMAT_STREAM_BASED: u8 = 0x12;
+pub const VS_FORMAT_H264: u8 = 0x13; // UVC 1.5
+pub const VS_FRAME_H264: u8 = 0x14; // UVC 1.5
+pub const VS_FORMAT_H264_SIMULCAST: u8 = 0x15; // UVC 1.5
+pub const VS_FORMAT_VP8: u8 = 0x16; // UVC 1.5
+pub const VS_FRAME_VP8: u8 = 0x17; // UVC 1.5
+pub const VS_FORMAT_VP8_SIMULCAST: u8 = 0x18; // UVC 1.5
+
+// descriptor sizes
+pub const FORMAT_UNCOMPRESSED_SIZE: usize = 27;
+pub const FORMAT_MJPEG_SIZE: usize = 11;
+pub const FORMAT_FRAME_BASED_SIZE: usize = 28;
+pub const COLORFORMAT_SIZE: usize = 6;
+pub const FRAME_FIXED_SIZE: usize = 26; // frame descriptors without their intervals
+pub const FRAME_CONTINUOUS_SIZE: usize = 38; // frame descriptors with a range of intervals
+
+// video control endpoint descriptor subtypes
+pub const EP_INTERRUPT: u8 = 0x03;
+pub const EP_INTERRUPT_SIZE: usize = 5;
+
+// class request values
+pub const SET_CUR: u8 = 0x01;
+pub const GET_CUR: u8 = 0x81;
+pub const GET_MIN: u8 = 0x82;
+pub const GET_MAX: u8 = 0x83;
+pub const GET_RES: u8 = 0x84;
+pub const GET_LEN: u8 = 0x85;
+pub const GET_INFO: u8 = 0x86;
+pub const GET_DEF: u8 = 0x87;
+
+// video streaming interface control selectors
+pub const VS_PROBE_CONTROL: u8 = 0x01;
+pub const VS_COMMIT_CONTROL: u8 = 0x02;
+
+// sizes of the probe and commit controls in each class version
+pub const PROBE_SIZE_1_0: usize = 26;
+pub const PROBE_SIZE_1_1: usize = 34;
+pub const PROBE_SIZE_1_5: usize = 48;
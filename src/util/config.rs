@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 #[derive(RustcDecodable, RustcEncodable, Clone)]
 pub struct CinchConfig {
     pub red_addr: String, // ip:port
@@ -8,4 +10,81 @@ pub struct CinchConfig {
     pub patch_active: bool,
     pub patches: String, // Folder containing patches
     pub third_party_folder: String, // Folder containing third-party checks
+    pub vendor_requests: Option<String>, // Folder containing the vendor requests that devices may receive
+    pub check_actions: Option<HashMap<String, String>>, // check name -> drop, stall, rewrite, ...
+    pub rewrites: Option<String>, // Folder containing rewrite rules
+    pub pipeline: Option<Vec<ModuleConfig>>, // Module graph (derived from the flags above if absent)
+    pub reload_interval: Option<u64>, // Seconds between checks for new third-party checks, patches and vendor requests (0: never)
+    pub keystrokes: Option<KeystrokeConfig>, // Thresholds of the keystroke injection detector
+    pub device_classes: Option<DeviceClassConfig>, // Interface classes allowed together (built-in roles if absent)
+    pub authorization: Option<AuthorizationConfig>, // Rules of the authorize module
+    pub fingerprints: Option<String>, // File with the fingerprints of known devices
+    pub filter: Option<String>, // usbredir filter rules of the filter module (e.g., 0x08,-1,-1,-1,1|-1,-1,-1,-1,0)
+}
+
+// What the keystroke module considers human typing. Absent fields take the module's defaults.
+#[derive(RustcDecodable, RustcEncodable, Clone)]
+pub struct KeystrokeConfig {
+    pub min_delay: Option<u64>, // Milliseconds after enumeration before the first key press (0: no limit)
+    pub max_rate: Option<usize>, // Key presses per second (0: no limit)
+    pub chords: Option<bool>, // Flag key chords that open a shell or run dialog (default: true)
+}
+
+// One entry of the module pipeline. Modules run in the order in which they are listed.
+#[derive(RustcDecodable, RustcEncodable, Clone)]
+pub struct ModuleConfig {
+    pub name: String, // name of the module in the registry (e.g., logger, checks)
+    pub red: Option<bool>, // run on requests coming from the red machine (default: true)
+    pub blue: Option<bool>, // run on requests coming from the blue machine (default: true)
+    pub ports: Option<HashMap<String, String>>, // output port -> module that handles it
+}
+
+impl ModuleConfig {
+    pub fn new(name: &str) -> ModuleConfig {
+        ModuleConfig {
+            name: name.to_string(),
+            red: None,
+            blue: None,
+            ports: None,
+        }
+    }
+}
+
+// Which interface classes a device may expose together. A device takes the role listed for its
+// VID:PID, or else the first role whose primary class is the class of its first interface.
+#[derive(RustcDecodable, RustcEncodable, Clone)]
+pub struct DeviceClassConfig {
+    pub roles: Vec<RoleConfig>,
+    pub devices: Option<HashMap<String, String>>, // VID:PID in hex (e.g., 0781:5567) -> role name
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone)]
+pub struct RoleConfig {
+    pub name: String,
+    pub primary: Option<u8>, // class of the first interface of devices that take this role
+    pub classes: Vec<u8>, // interface classes that devices in this role may have
+}
+
+// Which devices may attach. The first rule that matches a device decides; devices that no rule
+// matches get the default outcome.
+#[derive(RustcDecodable, RustcEncodable, Clone)]
+pub struct AuthorizationConfig {
+    pub rules: Vec<AuthorizationRule>,
+    pub default: Option<String>, // allow, deny or ask (default: deny)
+    pub approvals: Option<String>, // File listing the devices approved after an ask
+}
+
+// Absent fields match anything
+#[derive(RustcDecodable, RustcEncodable, Clone)]
+pub struct AuthorizationRule {
+    pub action: String, // allow, deny or ask
+    pub vendor_id: Option<String>, // hex ID or range (e.g., 0781 or 5500-55ff)
+    pub product_id: Option<String>, // hex ID or range
+    pub bcd_device: Option<String>, // hex release number or range
+    pub class: Option<u8>, // class, subclass and protocol of the device or one of its interfaces
+    pub subclass: Option<u8>,
+    pub protocol: Option<u8>,
+    pub serial: Option<String>, // serial number string
+    pub speed: Option<String>, // low, full, high or super
+    pub fingerprint: Option<String>, // fingerprint of the device's descriptors and strings
 }
@@ -0,0 +1,280 @@
+extern crate cinch;
+
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+
+use cinch::capture;
+use cinch::capture::decode;
+use cinch::capture::pcap;
+use cinch::capture::replay;
+use cinch::modules::registry::Registry;
+use cinch::parser;
+use cinch::parser::usbr;
+use cinch::parser::Source;
+use cinch::util::config::{CinchConfig, ModuleConfig};
+
+
+fn util_record(source: Source, h_type: usbr::HeaderType, th: Vec<u8>, data: Vec<u8>) -> capture::Record {
+
+    let h_type = h_type as u32;
+
+    capture::Record {
+        timestamp: Duration::new(1500000000, 250000000),
+        source: source,
+        session: 3,
+        h_type: h_type,
+        request: parser::Request::new(h_type, 7, th, data),
+    }
+}
+
+fn util_capture(records: &[capture::Record]) -> Vec<u8> {
+
+    let mut out = vec![];
+
+    {
+        let mut writer = capture::Writer::new(&mut out).unwrap();
+
+        for record in records {
+            writer.write(record).unwrap();
+        }
+
+        writer.flush().unwrap();
+    }
+
+    out
+}
+
+fn util_config() -> CinchConfig {
+    CinchConfig {
+        red_addr: String::new(),
+        cinch_addr: String::new(),
+        log: false,
+        log_prefix: String::new(),
+        checks_active: false,
+        patch_active: false,
+        patches: String::new(),
+        third_party_folder: "third-party-checks".to_string(),
+        vendor_requests: None,
+        check_actions: None,
+        rewrites: None,
+        pipeline: None,
+        reload_interval: None,
+        keystrokes: None,
+        device_classes: None,
+        authorization: None,
+        fingerprints: None,
+        filter: None,
+    }
+}
+
+// A session up to the point where the device is connected
+fn util_handshake() -> Vec<capture::Record> {
+
+    let caps: Vec<u8> = parser::gen_caps()
+        .iter()
+        .flat_map(|c| vec![*c as u8, (*c >> 8) as u8, (*c >> 16) as u8, (*c >> 24) as u8])
+        .collect();
+
+    let mut iface = vec![0; 132];
+    iface[0] = 1;
+    iface[36] = 3; // hid
+
+    let mut eps = vec![usbr::TransferType::Invalid as u8; 32];
+    eps[0] = usbr::TransferType::Control as u8;
+    eps[16] = usbr::TransferType::Control as u8;
+    eps.extend_from_slice(&[0; 64 + 64 + 128]);
+
+    vec![util_record(Source::Red, usbr::HeaderType::Hello, vec![0; 64], caps.clone()),
+         util_record(Source::Blue, usbr::HeaderType::Hello, vec![0; 64], caps),
+         util_record(Source::Red, usbr::HeaderType::InterfaceInfo, iface, vec![]),
+         util_record(Source::Red, usbr::HeaderType::EpInfo, eps, vec![]),
+         util_record(Source::Red, usbr::HeaderType::DeviceConnect, vec![1, 0, 0, 0, 0x6d, 0x04, 0x69, 0xc0, 0, 1], vec![])]
+}
+
+// Sends control packets from the red machine to port 1
+struct Flagger;
+
+impl parser::HasHandlers for Flagger {
+    fn handle_control_packet(&self, source: Source, req: parser::Request) -> (u8, Vec<parser::Request>) {
+        (if source == Source::Red { 1 } else { 0 }, vec![req])
+    }
+}
+
+
+#[test]
+fn capture_round_trip() {
+
+    // A payload that used to terminate a log entry early
+    let data = b"[End Cinch log]".to_vec();
+
+    let records = vec![util_record(Source::Blue, usbr::HeaderType::BulkPacket, vec![0x81, 0, 15, 0, 0, 0, 0, 0, 0, 0], vec![]),
+                       util_record(Source::Red, usbr::HeaderType::BulkPacket, vec![0x81, 0, 15, 0, 0, 0, 0, 0, 0, 0], data.clone()),
+                       util_record(Source::Red, usbr::HeaderType::DeviceDisconnect, vec![], vec![])];
+
+    let bytes = util_capture(&records);
+    let reader = capture::Reader::new(Cursor::new(bytes)).unwrap();
+    let read: Vec<capture::Record> = reader.map(|r| r.unwrap()).collect();
+
+    assert_eq!(read.len(), 3);
+
+    for (a, b) in records.iter().zip(read.iter()) {
+        assert!(a.timestamp == b.timestamp);
+        assert!(a.source == b.source);
+        assert_eq!(a.session, b.session);
+        assert_eq!(a.h_type, b.h_type);
+        assert_eq!(a.request.header, b.request.header);
+        assert_eq!(a.request.type_header, b.request.type_header);
+        assert_eq!(a.request.data, b.request.data);
+    }
+
+    assert_eq!(read[1].request.data, data);
+}
+
+#[test]
+fn capture_bad_magic() {
+
+    let bytes = b"CINCHLOG\x01\x00\x00\x00".to_vec();
+    assert!(capture::Reader::new(Cursor::new(bytes)).is_err());
+}
+
+#[test]
+fn capture_truncated_record() {
+
+    let records = vec![util_record(Source::Red, usbr::HeaderType::BulkPacket, vec![0x81, 0, 4, 0, 0, 0, 0, 0], vec![1, 2, 3, 4])];
+
+    let mut bytes = util_capture(&records);
+    bytes.pop();
+
+    let mut reader = capture::Reader::new(Cursor::new(bytes)).unwrap();
+
+    assert!(reader.next().unwrap().is_err());
+    assert!(reader.next().is_none());
+}
+
+#[test]
+fn capture_pcap_export() {
+
+    let records = vec![util_record(Source::Blue, usbr::HeaderType::Hello, vec![0; 64], vec![]),
+                       util_record(Source::Blue, usbr::HeaderType::ControlPacket, vec![0, 6, 0x80, 0, 0, 1, 0, 0, 18, 0], vec![]),
+                       util_record(Source::Red, usbr::HeaderType::ControlPacket, vec![0, 6, 0x80, 0, 0, 1, 0, 0, 18, 0], vec![0; 18])];
+
+    let bytes = util_capture(&records);
+    let reader = capture::Reader::new(Cursor::new(bytes)).unwrap();
+
+    let mut out = vec![];
+    assert_eq!(pcap::export(reader, &mut out).unwrap(), 2);
+
+    // global header + two packets (packet header + usbmon header + data)
+    assert_eq!(out.len(), 24 + (16 + 48) + (16 + 48 + 18));
+
+    // the submission carries the setup packet, the completion the descriptor
+    assert_eq!(out[24 + 16 + 8], b'S');
+    assert_eq!(out[24 + 16 + 48 + 16 + 8], b'C');
+}
+
+#[test]
+fn decode_header_type_names() {
+
+    assert_eq!(decode::header_type_from_name("bulk"), Some(usbr::HeaderType::BulkPacket as u32));
+    assert_eq!(decode::header_type_from_name("BulkPacket"), Some(usbr::HeaderType::BulkPacket as u32));
+    assert_eq!(decode::header_type_from_name("ep-info"), Some(usbr::HeaderType::EpInfo as u32));
+    assert_eq!(decode::header_type_from_name("103"), Some(usbr::HeaderType::IntPacket as u32));
+    assert_eq!(decode::header_type_from_name("bogus"), None);
+
+    assert_eq!(decode::header_type_name(usbr::HeaderType::DeviceConnect as u32), "device connect");
+}
+
+#[test]
+fn decode_device_descriptor() {
+
+    let setup = vec![0, 6, 0x80, 0, 0, 1, 0, 0, 18, 0];
+    let data = vec![18, 1, 0, 2, 0, 0, 0, 64, 0x6d, 0x04, 0x69, 0xc0, 0, 1, 1, 2, 3, 1];
+
+    let record = util_record(Source::Red, usbr::HeaderType::ControlPacket, setup, data);
+    let lines = decode::Decoder::new().decode(&record);
+
+    assert!(lines[0].contains("red -> blue control packet"));
+    assert!(lines.iter().any(|l| l.contains("get descriptor (in, standard, device)")));
+    assert!(lines.iter().any(|l| l.contains("vendor: 0x046d, product: 0xc069")));
+}
+
+#[test]
+fn decode_bbb_command() {
+
+    let mut decoder = decode::Decoder::new();
+
+    // Mass storage (bulk-only) interface 0 with bulk endpoint 0x02
+    let mut iface = vec![0; 132];
+    iface[0] = 1;
+    iface[36] = 8;
+    iface[68] = 6;
+    iface[100] = 0x50;
+
+    let mut eps = vec![usbr::TransferType::Invalid as u8; 32];
+    eps.extend_from_slice(&[0; 64]);
+    eps.extend_from_slice(&[0; 64 + 128]);
+    eps[2] = usbr::TransferType::Bulk as u8;
+
+    decoder.decode(&util_record(Source::Red, usbr::HeaderType::InterfaceInfo, iface, vec![]));
+    decoder.decode(&util_record(Source::Red, usbr::HeaderType::EpInfo, eps, vec![]));
+
+    // READ(10) CBW for 512 bytes
+    let mut cbw = vec![0x55, 0x53, 0x42, 0x43, 0x11, 0, 0, 0, 0, 2, 0, 0, 0x80, 0, 10, 0x28];
+    cbw.extend_from_slice(&[0; 15]);
+
+    let record = util_record(Source::Blue, usbr::HeaderType::BulkPacket, vec![2, 0, 31, 0, 0, 0, 0, 0, 0, 0], cbw);
+    let lines = decoder.decode(&record);
+
+    assert!(lines.iter().any(|l| l.contains("cbw tag: 0x00000011, transfer length: 512, direction: in")));
+    assert!(lines.iter().any(|l| l.contains("command: read(10)")));
+
+    let mut filter = decode::Filter::new();
+    filter.ep = Some(2);
+    assert!(filter.matches(&record));
+
+    filter.source = Some(Source::Red);
+    assert!(!filter.matches(&record));
+}
+
+#[test]
+fn replay_reports_hits() {
+
+    let setup = vec![0x80, 6, 0x80, 0, 0, 1, 0, 0, 18, 0];
+
+    let mut records = util_handshake();
+    records.push(util_record(Source::Blue, usbr::HeaderType::ControlPacket, setup.clone(), vec![]));
+    records.push(util_record(Source::Red, usbr::HeaderType::ControlPacket, setup, vec![0; 18]));
+
+    // OUT request that claims 4 bytes of data but has none
+    records.push(util_record(Source::Blue, usbr::HeaderType::ControlPacket, vec![0, 9, 0, 0, 1, 0, 0, 0, 4, 0], vec![]));
+
+    let mut flagger = ModuleConfig::new("flagger");
+    flagger.ports = Some(vec![("1".to_string(), "drop".to_string())].into_iter().collect());
+
+    let mut config = util_config();
+    config.pipeline = Some(vec![ModuleConfig::new("logger"), flagger]);
+
+    let mut registry = Registry::new();
+    registry.register("flagger", Box::new(|_| Ok(Arc::new(Flagger))));
+
+    let bytes = util_capture(&records);
+    let reader = capture::Reader::new(Cursor::new(bytes)).unwrap();
+    let report = replay::replay(reader, registry, &config).unwrap();
+
+    assert_eq!(report.records, 8);
+    assert_eq!(report.forwarded, 6);
+    assert_eq!(report.rejected, vec![7]);
+    assert!(report.ended.is_empty());
+
+    assert_eq!(report.hits.len(), 2);
+
+    assert_eq!(report.hits[0].record, 6);
+    assert_eq!(report.hits[0].module, "flagger");
+    assert_eq!(report.hits[0].port, 1);
+    assert!(!report.hits[0].rewritten);
+
+    assert_eq!(report.hits[1].record, 6);
+    assert_eq!(report.hits[1].module, "drop");
+    assert_eq!(report.hits[1].outputs, 0);
+}
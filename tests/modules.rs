@@ -0,0 +1,1772 @@
+extern crate cinch;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use cinch::modules;
+use cinch::util::config::{AuthorizationConfig, AuthorizationRule, CinchConfig, ModuleConfig};
+use cinch::parser;
+use cinch::parser::usbr;
+use cinch::parser::HasHandlers;
+
+
+fn util_control_request(id: u64) -> parser::Request {
+    parser::Request::new(usbr::HeaderType::ControlPacket as u32, id, vec![0; 10], vec![])
+}
+
+// GET_DESCRIPTOR (string 2) response with the given data
+fn util_control_response(id: u64, data: Vec<u8>) -> parser::Request {
+    let h = vec![0x80, 0x06, 0x80, 0, 2, 3, 0, 0, data.len() as u8, 0];
+    parser::Request::new(usbr::HeaderType::ControlPacket as u32, id, h, data)
+}
+
+// Control packet with the given setup fields: blue's request, or red's response with its data
+fn util_control(id: u64, request: u8, requesttype: u8, value: u16, index: u16, length: u16, data: Vec<u8>)
+                -> parser::Request {
+    let h = vec![0x00, request, requesttype, 0, value as u8, (value >> 8) as u8, index as u8, (index >> 8) as u8,
+                 length as u8, (length >> 8) as u8];
+    parser::Request::new(usbr::HeaderType::ControlPacket as u32, id, h, data)
+}
+
+// Red's device descriptor (3340:3457 with the given class, subclass and protocol) and then the
+// given configuration descriptor, as answers to blue's GET_DESCRIPTOR requests. Fills in the
+// total length of the configuration. Returns the port of the configuration descriptor.
+fn util_describe_device(x: &HasHandlers, class: [u8; 3], mut config: Vec<u8>) -> u8 {
+
+    let device = vec![18, 0x01, 0x00, 0x02, class[0], class[1], class[2], 64, 0x40, 0x33, 0x57, 0x34, 0x00, 0x01,
+                      1, 2, 3, 1];
+    let h = vec![0x80, 0x06, 0x80, 0, 0x00, 0x01, 0, 0, 18, 0];
+    let req = parser::Request::new(usbr::HeaderType::ControlPacket as u32, 1, h, device);
+    assert_eq!(x.handle_control_packet(parser::Source::Red, req).0, 0);
+
+    let len = config.len();
+    config[2] = len as u8;
+    config[3] = (len >> 8) as u8;
+
+    let h = vec![0x80, 0x06, 0x80, 0, 0x00, 0x02, 0, 0, len as u8, (len >> 8) as u8];
+    let req = parser::Request::new(usbr::HeaderType::ControlPacket as u32, 2, h, config);
+    x.handle_control_packet(parser::Source::Red, req).0
+}
+
+fn util_config() -> CinchConfig {
+    CinchConfig {
+        red_addr: String::new(),
+        cinch_addr: String::new(),
+        log: false,
+        log_prefix: String::new(),
+        checks_active: false,
+        patch_active: false,
+        patches: String::new(),
+        third_party_folder: "third-party-checks".to_string(),
+        vendor_requests: None,
+        check_actions: None,
+        rewrites: None,
+        pipeline: None,
+        reload_interval: None,
+        keystrokes: None,
+        device_classes: None,
+        authorization: None,
+        fingerprints: None,
+        filter: None,
+    }
+}
+
+fn util_module(name: &str, red: bool, blue: bool, ports: &[(&str, &str)]) -> ModuleConfig {
+    let mut m = ModuleConfig::new(name);
+    m.red = Some(red);
+    m.blue = Some(blue);
+    m.ports = Some(ports.iter().map(|&(p, t)| (p.to_string(), t.to_string())).collect());
+    m
+}
+
+// Sends every control packet to port 1
+struct Flagger;
+
+impl parser::HasHandlers for Flagger {
+    fn handle_control_packet(&self, _: parser::Source, req: parser::Request) -> (u8, Vec<parser::Request>) {
+        (1, vec![req])
+    }
+}
+
+// Emits every control packet twice
+struct Splitter;
+
+impl parser::HasHandlers for Splitter {
+    fn handle_control_packet(&self, _: parser::Source, req: parser::Request) -> (u8, Vec<parser::Request>) {
+        let copy = parser::Request::new(req.get_type(), req.get_id() + 100, req.type_header.clone(), vec![]);
+        (0, vec![req, copy])
+    }
+}
+
+// Answers control packets itself
+struct Responder;
+
+impl parser::HasHandlers for Responder {
+    fn handle_control_packet(&self, _: parser::Source, req: parser::Request) -> (u8, Vec<parser::Request>) {
+        (0, vec![req.into_reply()])
+    }
+}
+
+// Counts the control packets it sees
+struct Counter {
+    count: AtomicUsize,
+}
+
+impl parser::HasHandlers for Counter {
+    fn handle_control_packet(&self, _: parser::Source, req: parser::Request) -> (u8, Vec<parser::Request>) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        (0, vec![req])
+    }
+}
+
+fn util_policy(check: &str, action: &str) -> modules::policy::CheckPolicy {
+    let mut config = HashMap::new();
+    config.insert(check.to_string(), action.to_string());
+    modules::policy::CheckPolicy::from_config(&config).unwrap()
+}
+
+// A ControlCheck that has seen the descriptors of a bulk-only mass storage device with
+// endpoints 0x81 (in) and 0x02 (out)
+fn util_storage_check(policy: modules::policy::CheckPolicy) -> modules::control_checks::ControlCheck {
+
+    let x = modules::control_checks::ControlCheck::new("third-party-checks", policy);
+
+    let device = vec![18, 0x01, 0x00, 0x02, 0, 0, 0, 64, 0x40, 0x33, 0x57, 0x34, 0x00, 0x01, 1, 2, 3, 1];
+    let h = vec![0x80, 0x06, 0x80, 0, 0x00, 0x01, 0, 0, 18, 0];
+    let req = parser::Request::new(usbr::HeaderType::ControlPacket as u32, 1, h, device);
+    assert_eq!(x.handle_control_packet(parser::Source::Red, req).0, 0);
+
+    let config = vec![9, 0x02, 32, 0, 1, 1, 0, 0x80, 50, // configuration
+                      9, 0x04, 0, 0, 2, 0x08, 0x06, 0x50, 0, // interface (SCSI, bulk-only)
+                      7, 0x05, 0x81, 0x02, 0x00, 0x02, 0, // bulk in
+                      7, 0x05, 0x02, 0x02, 0x00, 0x02, 0]; // bulk out
+    let h = vec![0x80, 0x06, 0x80, 0, 0x00, 0x02, 0, 0, 32, 0];
+    let req = parser::Request::new(usbr::HeaderType::ControlPacket as u32, 2, h, config);
+    assert_eq!(x.handle_control_packet(parser::Source::Red, req).0, 0);
+
+    x
+}
+
+fn util_bulk(id: u64, ep: u8, data: Vec<u8>) -> parser::Request {
+    let h = vec![ep, 0, data.len() as u8, (data.len() >> 8) as u8, 0, 0, 0, 0, 0, 0];
+    parser::Request::new(usbr::HeaderType::BulkPacket as u32, id, h, data)
+}
+
+fn util_command(tag: u8, transfer_length: u16, flags: u8, lun: u8, cb: &[u8]) -> Vec<u8> {
+    let mut cbw = vec![0x55, 0x53, 0x42, 0x43, tag, 0, 0, 0];
+    cbw.extend_from_slice(&[transfer_length as u8, (transfer_length >> 8) as u8, 0, 0]);
+    cbw.extend_from_slice(&[flags, lun, cb.len() as u8]);
+    cbw.extend_from_slice(cb);
+    cbw.resize(31, 0);
+    cbw
+}
+
+// TEST UNIT READY without data, otherwise READ(10) or WRITE(10) of one block
+fn util_cbw(tag: u8, transfer_length: u8, flags: u8, lun: u8) -> Vec<u8> {
+
+    let opcode = if transfer_length == 0 {
+        0x00
+    } else if flags & 0x80 != 0 {
+        0x28
+    } else {
+        0x2a
+    };
+
+    util_command(tag, transfer_length as u16, flags, lun, &[opcode, 0, 0, 0, 0, 0, 0, 0, 1, 0])
+}
+
+fn util_csw(tag: u8, residue: u8, status: u8) -> Vec<u8> {
+    vec![0x55, 0x53, 0x42, 0x53, tag, 0, 0, 0, residue, 0, 0, 0, status]
+}
+
+
+#[test]
+fn request_new() {
+
+    let req = parser::Request::new(usbr::HeaderType::BulkPacket as u32, 7, vec![0; 10], vec![1; 5]);
+
+    assert_eq!(req.get_type(), usbr::HeaderType::BulkPacket as u32);
+    assert_eq!(req.get_total_len(), 15);
+    assert_eq!(req.get_id(), 7);
+}
+
+
+#[test]
+fn reset_from_red() {
+
+    let x = modules::reset::Reset::new();
+    assert!(!x.session_ended());
+
+    // Bad response from the device: blue is told the device went away, red resets it
+    let (port, out) = x.handle_control_packet(parser::Source::Red, util_control_request(1));
+
+    assert_eq!(port, 0);
+    assert_eq!(out.len(), 2);
+    assert_eq!(out[0].get_type(), usbr::HeaderType::DeviceDisconnect as u32);
+    assert_eq!(out[0].get_total_len(), 0);
+    assert!(!out[0].reply);
+    assert_eq!(out[1].get_type(), usbr::HeaderType::Reset as u32);
+    assert!(out[1].reply);
+    assert!(x.session_ended());
+
+    // Anything after that is discarded
+    let (_, out) = x.handle_control_packet(parser::Source::Red, util_control_request(2));
+    assert!(out.is_empty());
+}
+
+
+#[test]
+fn reset_from_blue() {
+
+    let x = modules::reset::Reset::new();
+
+    // Bad request from the guest: same messages, but the disconnect is now the reply
+    let (_, out) = x.handle_control_packet(parser::Source::Blue, util_control_request(1));
+
+    assert_eq!(out.len(), 2);
+    assert_eq!(out[0].get_type(), usbr::HeaderType::Reset as u32);
+    assert!(!out[0].reply);
+    assert_eq!(out[1].get_type(), usbr::HeaderType::DeviceDisconnect as u32);
+    assert!(out[1].reply);
+    assert!(x.session_ended());
+}
+
+
+#[test]
+fn modules_session_ended() {
+
+    let mut x = modules::Modules::new();
+    let reset = Arc::new(modules::reset::Reset::new());
+
+    x.add_nonterminal(0, 0, Arc::new(modules::null::Null::new()));
+    x.add_terminal(0, reset.clone());
+
+    assert!(!x.session_ended());
+
+    let _ = x.handle_control_packet(parser::Source::Red, util_control_request(1));
+
+    assert!(reset.session_ended());
+    assert!(x.session_ended());
+}
+
+
+#[test]
+fn check_policy() {
+
+    let x = modules::policy::CheckPolicy::new();
+    assert_eq!(x.action("get_descriptor"), modules::policy::Action::Reset);
+
+    let mut config = HashMap::new();
+    config.insert("default".to_string(), "drop".to_string());
+    config.insert("get_descriptor".to_string(), "stall".to_string());
+
+    let x = modules::policy::CheckPolicy::from_config(&config).unwrap();
+    assert_eq!(x.action("get_descriptor"), modules::policy::Action::Stall);
+    assert_eq!(x.action("get_status"), modules::policy::Action::Drop);
+    assert_eq!(x.action("get_descriptor").port(), modules::policy::PORT_STALL);
+
+    config.insert("get_status".to_string(), "explode".to_string());
+    assert!(modules::policy::CheckPolicy::from_config(&config).is_err());
+}
+
+
+#[test]
+fn control_check_action_port() {
+
+    // Requests of the reserved type fail the "request_type" check
+    let x = modules::control_checks::ControlCheck::new("third-party-checks",
+                                                       util_policy("request_type", "stall"));
+
+    let h = vec![0, 0x01, 0xe0, 0, 0, 0, 0, 0, 0, 0];
+    let req = parser::Request::new(usbr::HeaderType::ControlPacket as u32, 1, h, vec![]);
+
+    let (port, out) = x.handle_control_packet(parser::Source::Blue, req);
+    assert_eq!(port, modules::policy::PORT_STALL);
+    assert_eq!(out.len(), 1);
+}
+
+
+#[test]
+fn control_check_bbb_transport() {
+
+    let x = util_storage_check(modules::policy::CheckPolicy::new());
+    let reset = modules::policy::PORT_RESET;
+    let check = |source, req| x.handle_bulk_packet(source, req).0;
+
+    // READ with 8 bytes of data-in
+    assert_eq!(check(parser::Source::Blue, util_bulk(3, 0x02, util_cbw(1, 8, 0x80, 0))), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(4, 0x81, vec![0; 8])), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(5, 0x81, util_csw(1, 0, 0))), 0);
+
+    // The device cuts the data phase short and reports the residue
+    assert_eq!(check(parser::Source::Blue, util_bulk(6, 0x02, util_cbw(2, 8, 0x80, 0))), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(7, 0x81, vec![0; 4])), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(8, 0x81, util_csw(2, 4, 0))), 0);
+
+    // CSW with the wrong tag
+    assert_eq!(check(parser::Source::Blue, util_bulk(9, 0x02, util_cbw(3, 0, 0, 0))), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(10, 0x81, util_csw(4, 0, 0))), reset);
+    assert_eq!(check(parser::Source::Red, util_bulk(11, 0x81, util_csw(3, 0, 0))), 0);
+
+    // More data-in than the CBW asked for
+    assert_eq!(check(parser::Source::Blue, util_bulk(12, 0x02, util_cbw(5, 4, 0x80, 0))), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(13, 0x81, vec![0; 8])), reset);
+
+    let x = util_storage_check(modules::policy::CheckPolicy::new());
+    let check = |source, req| x.handle_bulk_packet(source, req).0;
+
+    // Data-out on a data-in command
+    assert_eq!(check(parser::Source::Blue, util_bulk(3, 0x02, util_cbw(1, 8, 0x80, 0))), 0);
+    assert_eq!(check(parser::Source::Blue, util_bulk(4, 0x02, vec![0; 8])), reset);
+
+    let x = util_storage_check(modules::policy::CheckPolicy::new());
+    let check = |source, req| x.handle_bulk_packet(source, req).0;
+
+    // The device stalls half way through data-out, then claims it took all of it
+    let mut stalled = util_bulk(5, 0x02, vec![]);
+    stalled.type_header[1] = usbr::Result::Stall as u8;
+
+    assert_eq!(check(parser::Source::Blue, util_bulk(3, 0x02, util_cbw(1, 8, 0, 0))), 0);
+    assert_eq!(check(parser::Source::Blue, util_bulk(4, 0x02, vec![0; 4])), 0);
+    assert_eq!(check(parser::Source::Red, stalled), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(6, 0x81, util_csw(1, 0, 0))), reset);
+
+    // Reserved flag bits and a LUN the device never reported
+    let x = util_storage_check(modules::policy::CheckPolicy::new());
+    assert_eq!(x.handle_bulk_packet(parser::Source::Blue, util_bulk(3, 0x02, util_cbw(1, 0, 0x01, 0))).0,
+               reset);
+    assert_eq!(x.handle_bulk_packet(parser::Source::Blue, util_bulk(4, 0x02, util_cbw(1, 0, 0, 1))).0,
+               reset);
+}
+
+
+#[test]
+fn control_check_scsi() {
+
+    let x = util_storage_check(util_policy("scsi", "drop"));
+    let drop = modules::policy::PORT_DROP;
+    let check = |source, req| x.handle_bulk_packet(source, req).0;
+
+    // INQUIRY for 36 bytes: peripheral 0, 31 more bytes, printable vendor/product/revision
+    let inquiry = [0x12, 0, 0, 0, 36, 0];
+    let mut data = vec![0, 0x80, 0x04, 0x02, 31, 0, 0, 0];
+    data.extend_from_slice(b"Vendor  Product         1.00");
+
+    assert_eq!(check(parser::Source::Blue, util_bulk(3, 0x02, util_command(1, 36, 0x80, 0, &inquiry))), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(4, 0x81, data.clone())), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(5, 0x81, util_csw(1, 0, 0))), 0);
+
+    // The same with a control character in the product name
+    data[20] = 0x1b;
+
+    assert_eq!(check(parser::Source::Blue, util_bulk(6, 0x02, util_command(2, 36, 0x80, 0, &inquiry))), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(7, 0x81, data.clone())), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(8, 0x81, util_csw(2, 0, 0))), drop);
+
+    // More INQUIRY data than the allocation length, in a CBW that allows it
+    data[20] = b'P';
+    data.extend_from_slice(&[0x20; 4]);
+
+    assert_eq!(check(parser::Source::Blue, util_bulk(9, 0x02, util_command(3, 96, 0x80, 0, &inquiry))), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(10, 0x81, data.clone())), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(11, 0x81, util_csw(3, 56, 0))), drop);
+
+    // REQUEST SENSE with an invalid response code
+    let sense = [0x03, 0, 0, 0, 18, 0];
+    let mut data = vec![0x55, 0, 0x06, 0, 0, 0, 0, 10];
+    data.extend_from_slice(&[0; 10]);
+
+    assert_eq!(check(parser::Source::Blue, util_bulk(12, 0x02, util_command(4, 18, 0x80, 0, &sense))), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(13, 0x81, data.clone())), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(14, 0x81, util_csw(4, 0, 0))), drop);
+
+    // Fixed format sense data is fine
+    data[0] = 0x70;
+
+    assert_eq!(check(parser::Source::Blue, util_bulk(15, 0x02, util_command(5, 18, 0x80, 0, &sense))), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(16, 0x81, data)), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(17, 0x81, util_csw(5, 0, 0))), 0);
+
+    // READ CAPACITY(10) with a block length that is not a power of two
+    let capacity = [0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    assert_eq!(check(parser::Source::Blue, util_bulk(18, 0x02, util_command(6, 8, 0x80, 0, &capacity))), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(19, 0x81, vec![0, 0, 0x0f, 0xff, 0, 0, 0x03, 0])), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(20, 0x81, util_csw(6, 0, 0))), drop);
+
+    // 4096 blocks of 512 bytes
+    assert_eq!(check(parser::Source::Blue, util_bulk(21, 0x02, util_command(7, 8, 0x80, 0, &capacity))), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(22, 0x81, vec![0, 0, 0x0f, 0xff, 0, 0, 0x02, 0])), 0);
+    assert_eq!(check(parser::Source::Red, util_bulk(23, 0x81, util_csw(7, 0, 0))), 0);
+
+    // Reads must match the block length and stay on the medium
+    let read = |lba: u8, blocks: u8| [0x28, 0, 0, 0, 0x0f, lba, 0, 0, blocks, 0];
+
+    // The device never sees a dropped CBW, so the host recovers with a bulk-only reset
+    let reset = || {
+        let h = vec![0, 0xff, 0x21, 0, 0, 0, 0, 0, 0, 0];
+        let req = parser::Request::new(usbr::HeaderType::ControlPacket as u32, 30, h, vec![]);
+        assert_eq!(x.handle_control_packet(parser::Source::Blue, req).0, 0);
+    };
+
+    assert_eq!(check(parser::Source::Blue, util_bulk(24, 0x02, util_command(8, 512, 0x80, 0, &read(0, 2)))),
+               drop);
+    reset();
+
+    assert_eq!(check(parser::Source::Blue, util_bulk(25, 0x02, util_command(9, 1024, 0x80, 0, &read(0xff, 2)))),
+               drop);
+    reset();
+
+    assert_eq!(check(parser::Source::Blue, util_bulk(26, 0x02, util_command(10, 1024, 0x80, 0, &read(0xfe, 2)))),
+               0);
+}
+
+
+// Describes a device (3340:3457) with the given interfaces to x. Returns the port of the
+// configuration descriptor.
+fn util_describe(x: &modules::control_checks::ControlCheck, interfaces: &[u8]) -> u8 {
+
+    let mut config = vec![9, 0x02, 0, 0, interfaces.len() as u8, 1, 0, 0x80, 50];
+
+    for (i, class) in interfaces.iter().enumerate() {
+        if *class == 0x03 {
+            config.extend_from_slice(&[9, 0x04, i as u8, 0, 1, 0x03, 0, 0, 0,
+                                       9, 0x21, 0x11, 0x01, 0, 1, 0x22, 8, 0,
+                                       7, 0x05, 0x81 + i as u8, 0x03, 0x08, 0x00, 10]);
+        } else {
+            config.extend_from_slice(&[9, 0x04, i as u8, 0, 2, *class, 0x06, 0x50, 0,
+                                       7, 0x05, 0x81 + i as u8, 0x02, 0x00, 0x02, 0,
+                                       7, 0x05, 0x01 + i as u8, 0x02, 0x00, 0x02, 0]);
+        }
+    }
+
+    util_describe_device(x, [0, 0, 0], config)
+}
+
+
+#[test]
+fn control_check_interface_assoc() {
+
+    let reset = modules::policy::PORT_RESET;
+
+    // Two mass storage interfaces grouped by an interface association descriptor (8 bytes with
+    // its header) with the given function class
+    let describe = |function_class: u8| {
+
+        let x = modules::control_checks::ControlCheck::new("third-party-checks", modules::policy::CheckPolicy::new());
+
+        let mut config = vec![9, 0x02, 0, 0, 2, 1, 0, 0x80, 50,
+                              8, 0x0b, 0, 2, function_class, 0x06, 0x50, 0];
+
+        for i in 0..2 {
+            config.extend_from_slice(&[9, 0x04, i, 0, 2, 0x08, 0x06, 0x50, 0,
+                                       7, 0x05, 0x81 + i, 0x02, 0x00, 0x02, 0,
+                                       7, 0x05, 0x01 + i, 0x02, 0x00, 0x02, 0]);
+        }
+
+        util_describe_device(&x, [0xef, 0x02, 0x01], config)
+    };
+
+    assert_eq!(describe(0x08), 0);
+    assert_eq!(describe(0x00), reset);
+}
+
+
+#[test]
+fn control_check_device_classes() {
+
+    use modules::control_checks::ControlCheck;
+    use modules::control_checks::classes::{ClassHistory, ClassPolicy};
+    use cinch::util::config::{DeviceClassConfig, RoleConfig};
+
+    let mut actions = HashMap::new();
+    actions.insert("device_classes".to_string(), "drop".to_string());
+    let policy = || modules::policy::CheckPolicy::from_config(&actions).unwrap();
+    let drop = modules::policy::PORT_DROP;
+
+    // A flash drive with a keyboard
+    let x = ControlCheck::new("third-party-checks", policy());
+    assert_eq!(util_describe(&x, &[0x08, 0x03]), drop);
+
+    let x = ControlCheck::new("third-party-checks", policy());
+    assert_eq!(util_describe(&x, &[0x08]), 0);
+
+    // The same device comes back as a keyboard
+    let history = Arc::new(ClassHistory::new());
+
+    let x = ControlCheck::new("third-party-checks", policy()).with_classes(ClassPolicy::new(history.clone()));
+    assert_eq!(util_describe(&x, &[0x08]), 0);
+
+    let x = ControlCheck::new("third-party-checks", policy()).with_classes(ClassPolicy::new(history.clone()));
+    assert_eq!(util_describe(&x, &[0x03]), drop);
+
+    let x = ControlCheck::new("third-party-checks", policy()).with_classes(ClassPolicy::new(history.clone()));
+    assert_eq!(util_describe(&x, &[0x08]), 0);
+
+    // Roles from the configuration
+    let mut config = DeviceClassConfig {
+        roles: vec![RoleConfig {
+                        name: "dock".to_string(),
+                        primary: None,
+                        classes: vec![0x08, 0x03],
+                    }],
+        devices: Some(vec![("3340:3457".to_string(), "dock".to_string())].into_iter().collect()),
+    };
+
+    let classes = ClassPolicy::from_config(&config, Arc::new(ClassHistory::new())).unwrap();
+    let x = ControlCheck::new("third-party-checks", policy()).with_classes(classes);
+    assert_eq!(util_describe(&x, &[0x08, 0x03]), 0);
+
+    config.devices = Some(vec![("3340".to_string(), "dock".to_string())].into_iter().collect());
+    assert!(ClassPolicy::from_config(&config, Arc::new(ClassHistory::new())).is_err());
+
+    config.devices = Some(vec![("3340:3457".to_string(), "hub".to_string())].into_iter().collect());
+    assert!(ClassPolicy::from_config(&config, Arc::new(ClassHistory::new())).is_err());
+}
+
+
+// A ControlCheck that has seen the descriptors of a HID mouse with interrupt endpoint 0x81 and
+// the given report descriptor
+fn util_hid_check(report: Vec<u8>) -> modules::control_checks::ControlCheck {
+
+    let x = modules::control_checks::ControlCheck::new("third-party-checks", modules::policy::CheckPolicy::new());
+
+    let config = vec![9, 0x02, 0, 0, 1, 1, 0, 0x80, 50, // configuration
+                      9, 0x04, 0, 0, 1, 0x03, 0x01, 0x02, 0, // interface (boot mouse)
+                      9, 0x21, 0x11, 0x01, 0, 1, 0x22, report.len() as u8, 0, // hid
+                      7, 0x05, 0x81, 0x03, 0x08, 0x00, 10]; // interrupt in
+    assert_eq!(util_describe_device(&x, [0, 0, 0], config), 0);
+
+    let h = vec![0x80, 0x06, 0x81, 0, 0x00, 0x22, 0, 0, report.len() as u8, 0];
+    let req = parser::Request::new(usbr::HeaderType::ControlPacket as u32, 3, h, report);
+    assert_eq!(x.handle_control_packet(parser::Source::Red, req).0, 0);
+
+    x
+}
+
+fn util_int(id: u64, ep: u8, data: Vec<u8>) -> parser::Request {
+    let h = vec![ep, 0, data.len() as u8, (data.len() >> 8) as u8];
+    parser::Request::new(usbr::HeaderType::IntPacket as u32, id, h, data)
+}
+
+
+#[test]
+fn control_check_hid_report() {
+
+    // Report 1: 3 buttons, 5 bits of padding, then x and y
+    let report = vec![0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x85, 0x01,
+                      0x05, 0x09, 0x19, 0x01, 0x29, 0x03, 0x15, 0x00, 0x25, 0x01,
+                      0x95, 0x03, 0x75, 0x01, 0x81, 0x02, 0x95, 0x01, 0x75, 0x05, 0x81, 0x01,
+                      0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x15, 0x81, 0x25, 0x7f,
+                      0x75, 0x08, 0x95, 0x02, 0x81, 0x06, 0xc0];
+
+    let x = util_hid_check(report);
+    let reset = modules::policy::PORT_RESET;
+    let check = |req| x.handle_int_packet(parser::Source::Red, req).0;
+
+    assert_eq!(check(util_int(4, 0x81, vec![1, 0x01, 0x10, 0xf0])), 0);
+    assert_eq!(check(util_int(5, 0x81, vec![])), 0);
+
+    // Undeclared report id
+    assert_eq!(check(util_int(6, 0x81, vec![2, 0x01, 0x10, 0xf0])), reset);
+
+    // Too long and too short
+    assert_eq!(check(util_int(7, 0x81, vec![1, 0x01, 0x10, 0xf0, 0])), reset);
+    assert_eq!(check(util_int(8, 0x81, vec![1, 0x01])), reset);
+
+    // x and y range from -127 to 127
+    assert_eq!(check(util_int(12, 0x81, vec![1, 0x01, 0x81, 0x7f])), 0);
+    assert_eq!(check(util_int(13, 0x81, vec![1, 0x01, 0x80, 0x00])), reset);
+
+    // Boot protocol reports do not follow the report descriptor
+    let h = vec![0x00, 0x0b, 0x21, 0, 0x00, 0x00, 0, 0, 0, 0];
+    let req = parser::Request::new(usbr::HeaderType::ControlPacket as u32, 9, h, vec![]);
+    assert_eq!(x.handle_control_packet(parser::Source::Blue, req).0, 0);
+
+    assert_eq!(check(util_int(10, 0x81, vec![0x01, 0x10, 0xf0])), 0);
+    assert_eq!(check(util_int(11, 0x81, vec![0; 9])), reset);
+
+    // Without report ids, reports have no id byte
+    let report = vec![0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x75, 0x08, 0x95, 0x03, 0x81, 0x02, 0xc0];
+    let x = util_hid_check(report);
+    let check = |req| x.handle_int_packet(parser::Source::Red, req).0;
+
+    assert_eq!(check(util_int(4, 0x81, vec![2, 0x10, 0xf0])), 0);
+    assert_eq!(check(util_int(5, 0x81, vec![2, 0x10, 0xf0, 0])), reset);
+    // The next device of the session is checked against its own descriptors
+    let req = parser::Request::new(usbr::HeaderType::DeviceDisconnect as u32, 0, vec![], vec![]);
+    assert_eq!(x.handle_disconnect(parser::Source::Red, req).0, 0);
+    assert_eq!(check(util_int(6, 0x81, vec![2, 0x10, 0xf0, 0])), 0);
+}
+
+
+#[test]
+fn control_check_hid_report_desc() {
+
+    let x = modules::control_checks::ControlCheck::new("third-party-checks", modules::policy::CheckPolicy::new());
+    let get = |id, data: Vec<u8>| {
+        let h = vec![0x80, 0x06, 0x81, 0, 0x00, 0x22, 0, 0, data.len() as u8, 0];
+        let req = parser::Request::new(usbr::HeaderType::ControlPacket as u32, id, h, data);
+        x.handle_control_packet(parser::Source::Red, req).0
+    };
+
+    let device = vec![18, 0x01, 0x00, 0x02, 0, 0, 0, 64, 0x40, 0x33, 0x57, 0x34, 0x00, 0x01, 1, 2, 3, 1];
+    let h = vec![0x80, 0x06, 0x80, 0, 0x00, 0x01, 0, 0, 18, 0];
+    let req = parser::Request::new(usbr::HeaderType::ControlPacket as u32, 1, h, device);
+    assert_eq!(x.handle_control_packet(parser::Source::Red, req).0, 0);
+
+    let config = vec![9, 0x02, 34, 0, 1, 1, 0, 0x80, 50,
+                      9, 0x04, 0, 0, 1, 0x03, 0x00, 0x00, 0,
+                      9, 0x21, 0x11, 0x01, 0, 1, 0x22, 8, 0,
+                      7, 0x05, 0x81, 0x03, 0x08, 0x00, 10];
+    let h = vec![0x80, 0x06, 0x80, 0, 0x00, 0x02, 0, 0, 34, 0];
+    let req = parser::Request::new(usbr::HeaderType::ControlPacket as u32, 2, h, config);
+    assert_eq!(x.handle_control_packet(parser::Source::Red, req).0, 0);
+
+    let reset = modules::policy::PORT_RESET;
+
+    // Pop without push
+    assert_eq!(get(3, vec![0xb4, 0x75, 0x08, 0x95, 0x01, 0x81, 0x02, 0xc0]), reset);
+
+    // Logical minimum above logical maximum
+    assert_eq!(get(4, vec![0x15, 0x05, 0x25, 0x01, 0x75, 0x08, 0x81, 0x02]), reset);
+
+    // A report without id after one with an id
+    assert_eq!(get(5, vec![0x75, 0x08, 0x81, 0x02, 0x85, 0x01, 0x81, 0x02]), reset);
+
+    // Logical maximum of 255 in a single byte
+    assert_eq!(get(6, vec![0x15, 0x00, 0x25, 0xff, 0x75, 0x08, 0x81, 0x02]), 0);
+}
+
+
+// Red's string descriptor (index) for blue's GET_DESCRIPTOR. Returns the port.
+fn util_string(x: &HasHandlers, index: u8, value: &str) -> u8 {
+
+    let mut string = vec![2 + 2 * value.len() as u8, 0x03];
+
+    for c in value.bytes() {
+        string.extend_from_slice(&[c, 0]);
+    }
+
+    let h = vec![0x80, 0x06, 0x80, 0, index, 0x03, 0x09, 0x04, string.len() as u8, 0];
+    let req = parser::Request::new(usbr::HeaderType::ControlPacket as u32, 3 + index as u64, h, string);
+    x.handle_control_packet(parser::Source::Red, req).0
+}
+
+
+#[test]
+fn control_check_fingerprint() {
+
+    use modules::control_checks::ControlCheck;
+    use modules::fingerprint::FingerprintDb;
+
+    let db = env::temp_dir().join(format!("cinch-fingerprints-{}", std::process::id()));
+    let _ = fs::remove_file(&db);
+
+    let mut actions = HashMap::new();
+    actions.insert("fingerprint".to_string(), "drop".to_string());
+    let checks = || {
+        let policy = modules::policy::CheckPolicy::from_config(&actions).unwrap();
+        ControlCheck::new("third-party-checks", policy).with_fingerprints(FingerprintDb::new(db.to_str().unwrap()))
+    };
+    let drop = modules::policy::PORT_DROP;
+
+    // The first time the device is seen, its fingerprint is recorded
+    let x = checks();
+    assert_eq!(util_describe(&x, &[0x08]), 0);
+    assert_eq!(util_string(&x, 1, "Vendor"), 0);
+    assert_eq!(util_string(&x, 2, "Drive"), 0);
+    assert_eq!(util_string(&x, 3, "SN1"), 0);
+
+    let known = FingerprintDb::new(db.to_str().unwrap()).lookup("3340:3457:SN1").unwrap();
+    assert!(known.is_some());
+
+    let x = checks();
+    assert_eq!(util_describe(&x, &[0x08]), 0);
+    assert_eq!(util_string(&x, 1, "Vendor"), 0);
+    assert_eq!(util_string(&x, 2, "Drive"), 0);
+    assert_eq!(util_string(&x, 3, "SN1"), 0);
+
+    // Same serial number, different strings
+    let x = checks();
+    assert_eq!(util_describe(&x, &[0x08]), 0);
+    assert_eq!(util_string(&x, 1, "Vendor"), 0);
+    assert_eq!(util_string(&x, 2, "Keyboard"), 0);
+    assert_eq!(util_string(&x, 3, "SN1"), drop);
+
+    // Another device
+    let x = checks();
+    assert_eq!(util_describe(&x, &[0x08]), 0);
+    assert_eq!(util_string(&x, 1, "Vendor"), 0);
+    assert_eq!(util_string(&x, 2, "Keyboard"), 0);
+    assert_eq!(util_string(&x, 3, "SN2"), 0);
+
+    assert_eq!(FingerprintDb::new(db.to_str().unwrap()).lookup("3340:3457:SN1").unwrap(), known);
+    assert!(FingerprintDb::new(db.to_str().unwrap()).lookup("3340:3457:SN2").unwrap().is_some());
+
+    fs::remove_file(&db).unwrap();
+}
+
+
+// Describes a CDC-ACM device with the given functional descriptors to x: interface 0 has the
+// notification endpoint 0x81 (8-byte packets), and data interface 1 has bulk endpoints 0x82 and
+// 0x02. Returns the port of the configuration descriptor.
+fn util_cdc(x: &modules::control_checks::ControlCheck, functional: &[u8]) -> u8 {
+
+    let mut config = vec![9, 0x02, 0, 0, 2, 1, 0, 0x80, 50, // configuration
+                          9, 0x04, 0, 0, 1, 0x02, 0x02, 0x01, 0]; // interface (ACM)
+    config.extend_from_slice(functional);
+    config.extend_from_slice(&[7, 0x05, 0x81, 0x03, 0x08, 0x00, 16, // interrupt in
+                               9, 0x04, 1, 0, 2, 0x0a, 0, 0, 0, // interface (CDC data)
+                               7, 0x05, 0x82, 0x02, 0x00, 0x02, 0, // bulk in
+                               7, 0x05, 0x02, 0x02, 0x00, 0x02, 0]); // bulk out
+
+    util_describe_device(x, [0x02, 0, 0], config)
+}
+
+
+#[test]
+fn control_check_cdc() {
+
+    let new = || modules::control_checks::ControlCheck::new("third-party-checks", modules::policy::CheckPolicy::new());
+    let reset = modules::policy::PORT_RESET;
+
+    let header = [5, 0x24, 0x00, 0x10, 0x01];
+    let call = [5, 0x24, 0x01, 0x00, 0x01];
+    let acm = [4, 0x24, 0x02, 0x02];
+    let union = [5, 0x24, 0x06, 0x00, 0x01];
+
+    // Functional descriptors: header first, ACM required, union controlled by interface 0
+    assert_eq!(util_cdc(&new(), &[&call[..], &header, &acm, &union].concat()), reset);
+    assert_eq!(util_cdc(&new(), &[&header[..], &call, &union].concat()), reset);
+    assert_eq!(util_cdc(&new(), &[&header[..], &acm, &[5, 0x24, 0x06, 0x01, 0x00]].concat()), reset);
+    assert_eq!(util_cdc(&new(), &[&header[..], &acm, &[4, 0x24, 0x02, 0x10]].concat()), reset);
+
+    let x = new();
+    assert_eq!(util_cdc(&x, &[&header[..], &call, &acm, &union].concat()), 0);
+
+    let control = |source, req| x.handle_control_packet(source, req).0;
+
+    let blue = parser::Source::Blue;
+    let red = parser::Source::Red;
+
+    // SET_LINE_CODING: 9600 baud, 1 stop bit, no parity, 8 data bits
+    assert_eq!(control(blue, util_control(3, 0x20, 0x21, 0, 0, 7, vec![0x80, 0x25, 0, 0, 0, 0, 8])), 0);
+    assert_eq!(control(blue, util_control(4, 0x20, 0x21, 0, 0, 7, vec![0x80, 0x25, 0, 0, 0, 0, 9])), reset);
+    assert_eq!(control(red, util_control(5, 0x20, 0x21, 0, 0, 7, vec![0x80, 0x25, 0, 0, 0, 0, 8])), reset);
+
+    // GET_LINE_CODING
+    assert_eq!(control(red, util_control(6, 0x21, 0xa1, 0, 0, 7, vec![0x80, 0x25, 0, 0, 2, 4, 7])), 0);
+    assert_eq!(control(red, util_control(7, 0x21, 0xa1, 0, 0, 7, vec![0x80, 0x25, 0, 0, 0, 5, 8])), reset);
+    assert_eq!(control(red, util_control(8, 0x21, 0xa1, 0, 0, 7, vec![0x80, 0x25, 0, 0, 0, 0, 8, 0])), reset);
+
+    // SET_CONTROL_LINE_STATE: DTR and RTS only
+    assert_eq!(control(blue, util_control(9, 0x22, 0x21, 0x03, 0, 0, vec![])), 0);
+    assert_eq!(control(blue, util_control(10, 0x22, 0x21, 0x04, 0, 0, vec![])), reset);
+
+    // Unknown request, and a known one in the wrong direction
+    assert_eq!(control(blue, util_control(11, 0x43, 0x21, 0, 0, 0, vec![])), reset);
+    assert_eq!(control(blue, util_control(12, 0x21, 0x21, 0, 0, 7, vec![])), reset);
+
+    // SERIAL_STATE (DCD and DSR) takes two packets on an endpoint with 8-byte packets
+    let check = |req| x.handle_int_packet(red, req).0;
+
+    assert_eq!(check(util_int(13, 0x81, vec![0xa1, 0x20, 0, 0, 0, 0, 2, 0])), 0);
+    assert_eq!(check(util_int(14, 0x81, vec![0x03, 0x00])), 0);
+
+    // Reserved bits of the UART state
+    assert_eq!(check(util_int(15, 0x81, vec![0xa1, 0x20, 0, 0, 0, 0, 2, 0])), 0);
+    assert_eq!(check(util_int(16, 0x81, vec![0x00, 0x01])), reset);
+
+    // Wrong interface, and a notification cut short
+    assert_eq!(check(util_int(17, 0x81, vec![0xa1, 0x01, 0, 0, 1, 0, 0, 0])), reset);
+    assert_eq!(check(util_int(18, 0x81, vec![0xa1, 0x20, 0, 0])), reset);
+    assert_eq!(check(util_int(19, 0x81, vec![0xa1, 0x01, 0, 0, 0, 0, 0, 0])), 0);
+}
+
+
+// Describes a UAC1 speaker to x: audio control interface 0 with the given entities (after the
+// header), and audio streaming interface 1 whose alternate setting 1 has the given class-specific
+// descriptors and isochronous endpoint 0x01. Returns the port of the configuration descriptor.
+fn util_audio(x: &modules::control_checks::ControlCheck, entities: &[u8], streaming: &[u8]) -> u8 {
+
+    let total = (9 + entities.len()) as u8;
+
+    let mut config = vec![9, 0x02, 0, 0, 2, 1, 0, 0x80, 50, // configuration
+                          9, 0x04, 0, 0, 0, 0x01, 0x01, 0x00, 0, // interface (audio control)
+                          9, 0x24, 0x01, 0x00, 0x01, total, 0, 1, 1]; // header
+    config.extend_from_slice(entities);
+    config.extend_from_slice(&[9, 0x04, 1, 0, 0, 0x01, 0x02, 0x00, 0, // interface (audio streaming)
+                               9, 0x04, 1, 1, 1, 0x01, 0x02, 0x00, 0]); // alternate setting 1
+    config.extend_from_slice(streaming);
+    config.extend_from_slice(&[9, 0x05, 0x01, 0x09, 0xc8, 0x00, 1, 0, 0, // isochronous out
+                               7, 0x25, 0x01, 0x01, 0, 0, 0]); // general (sampling frequency)
+
+    util_describe_device(x, [0, 0, 0], config)
+}
+
+
+#[test]
+fn control_check_audio() {
+
+    let new = || modules::control_checks::ControlCheck::new("third-party-checks", modules::policy::CheckPolicy::new());
+    let reset = modules::policy::PORT_RESET;
+
+    // Input terminal 1 (USB streaming) -> feature unit 2 (master, left and right) -> output
+    // terminal 3 (speaker)
+    let input = [12, 0x24, 0x02, 1, 0x01, 0x01, 0, 2, 0x03, 0x00, 0, 0];
+    let feature = [10, 0x24, 0x06, 2, 1, 1, 0x01, 0x02, 0x02, 0];
+    let output = [9, 0x24, 0x03, 3, 0x01, 0x03, 0, 2, 0];
+    let general = [7, 0x24, 0x01, 1, 1, 0x01, 0x00];
+    let format = [11, 0x24, 0x02, 0x01, 2, 2, 16, 1, 0x44, 0xac, 0x00];
+
+    let streaming = [&general[..], &format].concat();
+
+    let entities = |feature: &[u8]| [&input[..], feature, &output].concat();
+
+    // Unknown source, a loop and no control size
+    assert_eq!(util_audio(&new(), &entities(&[10, 0x24, 0x06, 2, 9, 1, 0x01, 0x02, 0x02, 0]), &streaming), reset);
+    assert_eq!(util_audio(&new(), &entities(&[10, 0x24, 0x06, 2, 3, 1, 0x01, 0x02, 0x02, 0]), &streaming), reset);
+    assert_eq!(util_audio(&new(), &entities(&[10, 0x24, 0x06, 2, 1, 0, 0x01, 0x02, 0x02, 0]), &streaming), reset);
+
+    // A link to an unknown terminal, and 24 bits that do not fit in a 2-byte subframe
+    let link = [&[7, 0x24, 0x01, 7, 1, 0x01, 0x00][..], &format].concat();
+    let bits = [&general[..], &[11, 0x24, 0x02, 0x01, 2, 2, 24, 1, 0x44, 0xac, 0x00]].concat();
+
+    assert_eq!(util_audio(&new(), &entities(&feature), &link), reset);
+    assert_eq!(util_audio(&new(), &entities(&feature), &bits), reset);
+
+    let x = new();
+    assert_eq!(util_audio(&x, &entities(&feature), &streaming), 0);
+
+    let control = |source, req| x.handle_control_packet(source, req).0;
+
+    let blue = parser::Source::Blue;
+    let red = parser::Source::Red;
+
+    // SET_CUR of the volume (2 bytes) of the left channel of feature unit 2
+    assert_eq!(control(blue, util_control(3, 0x01, 0x21, 0x0201, 0x0200, 2, vec![0x00, 0xf0])), 0);
+    assert_eq!(control(blue, util_control(4, 0x01, 0x21, 0x0201, 0x0200, 1, vec![0x00])), reset);
+
+    // GET_CUR of the mute (1 byte) of the master channel
+    assert_eq!(control(red, util_control(5, 0x81, 0xa1, 0x0100, 0x0200, 1, vec![1])), 0);
+    assert_eq!(control(red, util_control(6, 0x81, 0xa1, 0x0100, 0x0200, 2, vec![1, 0])), reset);
+
+    // No such channel, entity or request
+    assert_eq!(control(blue, util_control(7, 0x01, 0x21, 0x0203, 0x0200, 2, vec![0x00, 0xf0])), reset);
+    assert_eq!(control(blue, util_control(8, 0x01, 0x21, 0x0201, 0x0500, 2, vec![0x00, 0xf0])), reset);
+    assert_eq!(control(blue, util_control(9, 0x07, 0x21, 0x0201, 0x0200, 2, vec![0x00, 0xf0])), reset);
+
+    // The sampling frequency (3 bytes) of endpoint 0x01, once alternate setting 1 is chosen
+    assert_eq!(control(blue, util_control(10, 0x0b, 0x01, 1, 1, 0, vec![])), 0);
+    assert_eq!(control(blue, util_control(11, 0x01, 0x22, 0x0100, 0x0001, 3, vec![0x44, 0xac, 0x00])), 0);
+    assert_eq!(control(blue, util_control(12, 0x01, 0x22, 0x0100, 0x0001, 2, vec![0x44, 0xac])), reset);
+}
+
+
+// Describes a high-speed hub with a single TT to x: interface 0 has the status change endpoint
+// 0x81. Returns the port of the configuration descriptor.
+fn util_hub(x: &modules::control_checks::ControlCheck) -> u8 {
+
+    let config = vec![9, 0x02, 0, 0, 1, 1, 0, 0xe0, 50,
+                      9, 0x04, 0, 0, 1, 0x09, 0x00, 0x00, 0,
+                      7, 0x05, 0x81, 0x03, 0x01, 0x00, 12];
+
+    util_describe_device(x, [0x09, 0, 1], config)
+}
+
+
+#[test]
+fn control_check_hub() {
+
+    let x = modules::control_checks::ControlCheck::new("third-party-checks", modules::policy::CheckPolicy::new());
+    let reset = modules::policy::PORT_RESET;
+
+    assert_eq!(util_hub(&x), 0);
+
+    let control = |source, req| x.handle_control_packet(source, req).0;
+
+    let blue = parser::Source::Blue;
+    let red = parser::Source::Red;
+    let check = |req| x.handle_int_packet(red, req).0;
+
+    // Ports are unknown before the hub descriptor
+    assert_eq!(control(blue, util_control(3, 0x03, 0x23, 8, 1, 0, vec![])), reset);
+    assert_eq!(check(util_int(4, 0x81, vec![0x02])), reset);
+
+    // Hub descriptor: a DeviceRemovable bit for a fifth port, the wrong length, and 4 ports
+    let descriptor = |id, data| control(red, util_control(id, 0x06, 0xa0, 0x2900, 0, 9, data));
+
+    assert_eq!(descriptor(5, vec![9, 0x29, 4, 0x09, 0, 50, 100, 0x20, 0xff]), reset);
+    assert_eq!(descriptor(6, vec![11, 0x29, 4, 0x09, 0, 50, 100, 0, 0xff]), reset);
+    assert_eq!(descriptor(7, vec![9, 0x29, 4, 0x09, 0, 50, 100, 0, 0xff]), 0);
+
+    // GET_STATUS of the hub and of port 2 (connected, enabled, powered, high speed)
+    assert_eq!(control(red, util_control(8, 0x00, 0xa0, 0, 0, 4, vec![0x01, 0, 0x00, 0])), 0);
+    assert_eq!(control(red, util_control(9, 0x00, 0xa0, 0, 0, 4, vec![0x04, 0, 0x00, 0])), reset);
+    assert_eq!(control(red, util_control(10, 0x00, 0xa3, 0, 2, 4, vec![0x03, 0x05, 0x01, 0])), 0);
+
+    // Low and high speed at once, and a reserved change bit
+    assert_eq!(control(red, util_control(11, 0x00, 0xa3, 0, 2, 4, vec![0x03, 0x07, 0x01, 0])), reset);
+    assert_eq!(control(red, util_control(12, 0x00, 0xa3, 0, 2, 4, vec![0x03, 0x05, 0x20, 0])), reset);
+
+    // SET_FEATURE(PORT_POWER) and CLEAR_FEATURE(C_PORT_CONNECTION) for ports 1-4 only
+    assert_eq!(control(blue, util_control(13, 0x03, 0x23, 8, 4, 0, vec![])), 0);
+    assert_eq!(control(blue, util_control(14, 0x03, 0x23, 8, 5, 0, vec![])), reset);
+    assert_eq!(control(blue, util_control(15, 0x01, 0x23, 16, 0, 0, vec![])), reset);
+    assert_eq!(control(blue, util_control(16, 0x01, 0x23, 16, 1, 0, vec![])), 0);
+
+    // A USB 3 feature on a USB 2 hub, and data from the device for an OUT request
+    assert_eq!(control(blue, util_control(17, 0x03, 0x23, 23, 1, 0, vec![])), reset);
+    assert_eq!(control(red, util_control(18, 0x03, 0x23, 8, 1, 0, vec![0])), reset);
+
+    // Status change bitmap: the hub and ports 1-4 fit in a byte
+    assert_eq!(check(util_int(19, 0x81, vec![0x12])), 0);
+    assert_eq!(check(util_int(20, 0x81, vec![0x20])), reset);
+    assert_eq!(check(util_int(21, 0x81, vec![0x02, 0x00])), reset);
+}
+
+
+// Describes a UVC 1.1 webcam to x: video control interface 0 has a camera terminal (1), a
+// processing unit (2) and a streaming output terminal (3), and the first alternate setting of
+// video streaming interface 1 has the given formats and frames. Returns the port of the
+// configuration descriptor.
+fn util_video(x: &modules::control_checks::ControlCheck, formats: &[u8]) -> u8 {
+
+    let total = (14 + formats.len()) as u8;
+
+    let mut config = vec![9, 0x02, 0, 0, 2, 1, 0, 0x80, 250, // configuration
+                          8, 0x0b, 0, 2, 0x0e, 0x03, 0x00, 0, // interface association
+                          9, 0x04, 0, 0, 1, 0x0e, 0x01, 0x00, 0, // interface (video control)
+                          13, 0x24, 0x01, 0x10, 0x01, 52, 0, 0x80, 0x8d, 0x5b, 0x00, 1, 1, // header
+                          18, 0x24, 0x02, 1, 0x01, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0x0a, 0, 0, // camera
+                          12, 0x24, 0x05, 2, 1, 0, 0, 2, 0x7f, 0x05, 0, 0, // processing unit
+                          9, 0x24, 0x03, 3, 0x01, 0x01, 0, 2, 0, // output terminal (streaming)
+                          7, 0x05, 0x83, 0x03, 0x10, 0x00, 6, // interrupt in
+                          5, 0x25, 0x03, 0x10, 0x00, // interrupt endpoint
+                          9, 0x04, 1, 0, 0, 0x0e, 0x02, 0x00, 0, // interface (video streaming)
+                          14, 0x24, 0x01, 1, total, 0, 0x81, 0, 3, 0, 0, 0, 1, 0]; // input header
+    config.extend_from_slice(formats);
+    config.extend_from_slice(&[9, 0x04, 1, 1, 1, 0x0e, 0x02, 0x00, 0, // alternate setting 1
+                               7, 0x05, 0x81, 0x05, 0x00, 0x0c, 1]); // isochronous in
+
+    util_describe_device(x, [0xef, 0x02, 0x01], config)
+}
+
+
+#[test]
+fn control_check_video() {
+
+    let new = || modules::control_checks::ControlCheck::new("third-party-checks", modules::policy::CheckPolicy::new());
+    let reset = modules::policy::PORT_RESET;
+
+    // YUY2 (16 bits per pixel) with a 640x480 frame at 30 frames per second (a 614400-byte
+    // buffer), and the sRGB color format
+    let format = [27, 0x24, 0x04, 1, 1, 0x59, 0x55, 0x59, 0x32, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00,
+                  0xaa, 0x00, 0x38, 0x9b, 0x71, 16, 1, 0, 0, 0, 0];
+    let frame = [30, 0x24, 0x05, 1, 0, 0x80, 0x02, 0xe0, 0x01, 0x00, 0x00, 0xca, 0x08, 0x00, 0x00, 0xca,
+                 0x08, 0x00, 0x60, 0x09, 0x00, 0x15, 0x16, 0x05, 0x00, 1, 0x15, 0x16, 0x05, 0x00];
+    let color = [6, 0x24, 0x0d, 1, 1, 4];
+
+    // Frames without their format, a second frame the format does not declare, and a frame
+    // interval of 0
+    assert_eq!(util_video(&new(), &[&frame[..], &format, &color].concat()), reset);
+    assert_eq!(util_video(&new(), &[&format[..], &frame, &frame, &color].concat()), reset);
+    assert_eq!(util_video(&new(), &[&format[..], &frame[..26], &[0, 0, 0, 0], &color].concat()), reset);
+
+    let x = new();
+    assert_eq!(util_video(&x, &[&format[..], &frame, &color].concat()), 0);
+
+    let control = |source, req| x.handle_control_packet(source, req).0;
+
+    let blue = parser::Source::Blue;
+    let red = parser::Source::Red;
+
+    // Probe control of format 1 and frame 1, with the given dwMaxVideoFrameSize
+    let probe = |frame: u8, size: u32| {
+        let mut data = vec![0; 34];
+        data[2] = 1;
+        data[3] = frame;
+        data[18..22].copy_from_slice(&[size as u8, (size >> 8) as u8, (size >> 16) as u8, (size >> 24) as u8]);
+        data
+    };
+
+    // SET_CUR of the probe control has 34 bytes in UVC 1.1
+    assert_eq!(control(blue, util_control(3, 0x01, 0x21, 0x0100, 1, 34, probe(1, 0))), 0);
+    assert_eq!(control(blue, util_control(4, 0x01, 0x21, 0x0100, 1, 26, probe(1, 0)[..26].to_vec())), reset);
+
+    // GET_CUR: the frame must exist and dwMaxVideoFrameSize fit in its 614400-byte buffer
+    assert_eq!(control(red, util_control(5, 0x81, 0xa1, 0x0100, 1, 34, probe(1, 614400))), 0);
+    assert_eq!(control(red, util_control(6, 0x81, 0xa1, 0x0100, 1, 34, probe(1, 16 * 1024 * 1024))), reset);
+    assert_eq!(control(red, util_control(7, 0x81, 0xa1, 0x0200, 1, 34, probe(2, 614400))), reset);
+    assert_eq!(control(red, util_control(11, 0x81, 0xa1, 0x0100, 1, 34, probe(1, 460800))), 0);
+    assert_eq!(control(red, util_control(12, 0x81, 0xa1, 0x0100, 1, 34, probe(1, 0))), reset);
+
+    // GET_DEF before the host negotiated a format and frame
+    assert_eq!(control(red, util_control(13, 0x87, 0xa1, 0x0100, 1, 34, vec![0; 34])), 0);
+
+    // GET_INFO of a processing unit control, and of a unit that does not exist
+    assert_eq!(control(red, util_control(8, 0x86, 0xa1, 0x0200, 0x0200, 1, vec![0x03])), 0);
+    assert_eq!(control(red, util_control(9, 0x86, 0xa1, 0x0200, 0x0500, 1, vec![0x03])), reset);
+    assert_eq!(control(red, util_control(10, 0x86, 0xa1, 0x0200, 0x0200, 1, vec![0x83])), reset);
+}
+
+
+#[test]
+fn readonly() {
+
+    let x = modules::readonly::ReadOnly::new();
+
+    // Interface 0 is bulk-only mass storage with bulk endpoints 0x02 and 0x81
+    let mut iface = vec![0; 132];
+    iface[0] = 1;
+    iface[36] = 0x08;
+    iface[68] = 0x06;
+    iface[100] = 0x50;
+
+    let mut eps = vec![usbr::TransferType::Invalid as u8; 32];
+    eps.resize(288, 0);
+    eps[2] = usbr::TransferType::Bulk as u8;
+    eps[17] = usbr::TransferType::Bulk as u8;
+
+    x.handle_interface_info(parser::Source::Red,
+                            parser::Request::new(usbr::HeaderType::InterfaceInfo as u32, 0, iface, vec![]));
+    x.handle_ep_info(parser::Source::Red,
+                     parser::Request::new(usbr::HeaderType::EpInfo as u32, 0, eps, vec![]));
+
+    // WRITE(10) of one block never reaches the device
+    let write = [0x2a, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+    let (_, out) = x.handle_bulk_packet(parser::Source::Blue,
+                                        util_bulk(1, 0x02, util_command(7, 512, 0, 0, &write)));
+    assert!(out.len() == 1 && out[0].reply);
+
+    let (_, out) = x.handle_bulk_packet(parser::Source::Blue, util_bulk(2, 0x02, vec![0xaa; 512]));
+    assert!(out.len() == 1 && out[0].reply && out[0].data.is_empty());
+
+    let (_, out) = x.handle_bulk_packet(parser::Source::Blue, util_bulk(3, 0x81, vec![]));
+    assert!(out.len() == 1 && out[0].reply);
+    assert_eq!(out[0].data, vec![0x55, 0x53, 0x42, 0x53, 7, 0, 0, 0, 0, 2, 0, 0, 1]);
+
+    // The sense data says why
+    let sense = [0x03, 0, 0, 0, 18, 0];
+    let (_, out) = x.handle_bulk_packet(parser::Source::Blue,
+                                        util_bulk(4, 0x02, util_command(8, 18, 0x80, 0, &sense)));
+    assert!(out[0].reply);
+
+    let (_, out) = x.handle_bulk_packet(parser::Source::Blue, util_bulk(5, 0x81, vec![]));
+    assert_eq!(out[0].data.len(), 18);
+    assert_eq!((out[0].data[2], out[0].data[12]), (0x07, 0x27));
+
+    let (_, out) = x.handle_bulk_packet(parser::Source::Blue, util_bulk(6, 0x81, vec![]));
+    assert_eq!(out[0].data, vec![0x55, 0x53, 0x42, 0x53, 8, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+    // Reads go to the device
+    let read = [0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+    let (_, out) = x.handle_bulk_packet(parser::Source::Blue,
+                                        util_bulk(7, 0x02, util_command(9, 512, 0x80, 0, &read)));
+    assert!(out.len() == 1 && !out[0].reply);
+
+    let (_, out) = x.handle_bulk_packet(parser::Source::Red, util_bulk(8, 0x81, vec![0; 512]));
+    assert_eq!(out[0].data, vec![0; 512]);
+
+    x.handle_bulk_packet(parser::Source::Red, util_bulk(9, 0x81, util_csw(9, 0, 0)));
+
+    // MODE SENSE(6) comes back write-protected
+    let mode_sense = [0x1a, 0, 0x3f, 0, 192, 0];
+    let (_, out) = x.handle_bulk_packet(parser::Source::Blue,
+                                        util_bulk(10, 0x02, util_command(10, 192, 0x80, 0, &mode_sense)));
+    assert!(!out[0].reply);
+
+    let (_, out) = x.handle_bulk_packet(parser::Source::Red, util_bulk(11, 0x81, vec![3, 0, 0, 0]));
+    assert_eq!(out[0].data, vec![3, 0, 0x80, 0]);
+}
+
+
+// Describes interface 0 as a boot keyboard with interrupt endpoint 0x81, and interface 1 as a
+// vendor-specific interface with interrupt endpoint 0x82
+fn util_keyboard(x: &modules::keystroke::KeystrokeDetector) {
+
+    let mut iface = vec![0; 132];
+    iface[0] = 2;
+    iface[5] = 1;
+    iface[36] = 0x03;
+    iface[37] = 0xff;
+    iface[68] = 0x01;
+    iface[100] = 0x01;
+
+    let mut eps = vec![usbr::TransferType::Invalid as u8; 32];
+    eps.resize(288, 0);
+    eps[17] = usbr::TransferType::Interrupt as u8;
+    eps[18] = usbr::TransferType::Interrupt as u8;
+    eps[64 + 18] = 1;
+
+    x.handle_interface_info(parser::Source::Red,
+                            parser::Request::new(usbr::HeaderType::InterfaceInfo as u32, 0, iface, vec![]));
+    x.handle_ep_info(parser::Source::Red,
+                     parser::Request::new(usbr::HeaderType::EpInfo as u32, 0, eps, vec![]));
+}
+
+// Boot keyboard report with one key down (0: none)
+fn util_key(id: u64, modifiers: u8, key: u8) -> parser::Request {
+    util_int(id, 0x81, vec![modifiers, 0, key, 0, 0, 0, 0, 0])
+}
+
+
+#[test]
+fn keystroke() {
+
+    let forwarded = |x: &modules::keystroke::KeystrokeDetector, req| {
+        x.handle_int_packet(parser::Source::Red, req).1.len() == 1
+    };
+
+    // GUI+R opens the run dialog
+    let x = modules::keystroke::KeystrokeDetector::new(0, 0, true);
+    util_keyboard(&x);
+
+    assert!(forwarded(&x, util_key(1, 0, 0x04)));
+    assert!(forwarded(&x, util_key(2, 0, 0)));
+    assert!(forwarded(&x, util_key(3, 0x08, 0)));
+    assert!(!forwarded(&x, util_key(4, 0x08, 0x15)));
+
+    // The keyboard stays quarantined, but not the other interface
+    assert!(!forwarded(&x, util_key(5, 0, 0)));
+    assert!(!forwarded(&x, util_key(6, 0, 0x04)));
+    assert!(forwarded(&x, util_int(7, 0x82, vec![0; 8])));
+
+    // Until the device goes away: the next one starts over
+    x.handle_disconnect(parser::Source::Red,
+                        parser::Request::new(usbr::HeaderType::DeviceDisconnect as u32, 0, vec![], vec![]));
+    util_keyboard(&x);
+
+    assert!(forwarded(&x, util_key(8, 0, 0x04)));
+
+    // Typing right after enumeration
+    let x = modules::keystroke::KeystrokeDetector::new(1000, 0, false);
+    util_keyboard(&x);
+
+    assert!(forwarded(&x, util_key(1, 0, 0)));
+    assert!(!forwarded(&x, util_key(2, 0, 0x04)));
+
+    let x = modules::keystroke::KeystrokeDetector::new(20, 0, false);
+    util_keyboard(&x);
+    std::thread::sleep(std::time::Duration::from_millis(40));
+
+    assert!(forwarded(&x, util_key(1, 0, 0x04)));
+    assert!(forwarded(&x, util_key(2, 0x08, 0x15)));
+
+    // Typing faster than 5 keys per second (holding a key down counts once)
+    let x = modules::keystroke::KeystrokeDetector::new(0, 5, true);
+    util_keyboard(&x);
+
+    for i in 0..5 {
+        assert!(forwarded(&x, util_key(i * 3, 0, 0x04 + i as u8)));
+        assert!(forwarded(&x, util_key(i * 3 + 1, 0, 0x04 + i as u8)));
+        assert!(forwarded(&x, util_key(i * 3 + 2, 0, 0)));
+    }
+
+    assert!(!forwarded(&x, util_key(15, 0, 0x10)));
+
+    // Interrupt packets of other devices are left alone
+    let x = modules::keystroke::KeystrokeDetector::new(1000, 1, true);
+    assert!(forwarded(&x, util_key(1, 0x08, 0x15)));
+}
+
+
+fn util_rule(action: &str) -> AuthorizationRule {
+    AuthorizationRule {
+        action: action.to_string(),
+        vendor_id: None,
+        product_id: None,
+        bcd_device: None,
+        class: None,
+        subclass: None,
+        protocol: None,
+        serial: None,
+        speed: None,
+        fingerprint: None,
+    }
+}
+
+// Connects a full speed device (with one interface of the given class) to x. Returns the port.
+fn util_connect(x: &HasHandlers, vendor_id: u16, product_id: u16, class: u8) -> u8 {
+
+    let mut iface = vec![0; 132];
+    iface[0] = 1;
+    iface[36] = class;
+    x.handle_interface_info(parser::Source::Red,
+                            parser::Request::new(usbr::HeaderType::InterfaceInfo as u32, 0, iface, vec![]));
+
+    let h = vec![usbr::Speed::Full as u8, 0, 0, 0,
+                 vendor_id as u8, (vendor_id >> 8) as u8, product_id as u8, (product_id >> 8) as u8, 0x00, 0x01];
+    x.handle_connect(parser::Source::Red,
+                     parser::Request::new(usbr::HeaderType::DeviceConnect as u32, 0, h, vec![])).0
+}
+
+// Whether blue's set configuration reaches the device
+fn util_configure(x: &modules::authorizer::Authorizer) -> bool {
+    let req = parser::Request::new(usbr::HeaderType::SetConf as u32, 9, vec![1], vec![]);
+    let (_, out) = x.handle_set_conf(parser::Source::Blue, req);
+    assert_eq!(out.len(), 1);
+    !out[0].reply
+}
+
+
+#[test]
+fn authorize() {
+
+    let deny = modules::authorizer::PORT_DENY;
+    let mut config = AuthorizationConfig {
+        rules: vec![],
+        default: None,
+        approvals: None,
+    };
+
+    let mut sandisk = util_rule("allow");
+    sandisk.vendor_id = Some("0781".to_string());
+    sandisk.product_id = Some("5500-55ff".to_string());
+
+    let mut keyboards = util_rule("deny");
+    keyboards.class = Some(0x03);
+    keyboards.speed = Some("full".to_string());
+
+    config.rules = vec![sandisk, keyboards];
+
+    let x = modules::authorizer::Authorizer::new(&config).unwrap();
+    assert_eq!(util_connect(&x, 0x0781, 0x5567, 0x08), 0);
+    assert!(util_configure(&x));
+
+    let x = modules::authorizer::Authorizer::new(&config).unwrap();
+    assert_eq!(util_connect(&x, 0x0781, 0x5600, 0x08), deny);
+    assert!(!util_configure(&x));
+
+    let x = modules::authorizer::Authorizer::new(&config).unwrap();
+    assert_eq!(util_connect(&x, 0x0781, 0x5567, 0x03), 0);
+
+    let x = modules::authorizer::Authorizer::new(&config).unwrap();
+    assert_eq!(util_connect(&x, 0x3340, 0x3457, 0x03), deny);
+
+    // Serial numbers are only known once the descriptors are read
+    let mut serial = util_rule("allow");
+    serial.serial = Some("AB".to_string());
+    config.rules = vec![serial];
+
+    let descriptors = |x: &modules::authorizer::Authorizer, serial: &[u8]| {
+        let device = vec![18, 0x01, 0x00, 0x02, 0, 0, 0, 64, 0x40, 0x33, 0x57, 0x34, 0x00, 0x01, 1, 2, 3, 1];
+        let h = vec![0x80, 0x06, 0x80, 0, 0x00, 0x01, 0, 0, 18, 0];
+        let req = parser::Request::new(usbr::HeaderType::ControlPacket as u32, 1, h, device);
+        assert_eq!(x.handle_control_packet(parser::Source::Red, req).0, 0);
+
+        let mut string = vec![2 + 2 * serial.len() as u8, 0x03];
+        for c in serial {
+            string.extend_from_slice(&[*c, 0]);
+        }
+
+        let h = vec![0x80, 0x06, 0x80, 0, 0x03, 0x03, 0x09, 0x04, string.len() as u8, 0];
+        let req = parser::Request::new(usbr::HeaderType::ControlPacket as u32, 2, h, string);
+        x.handle_control_packet(parser::Source::Red, req).0
+    };
+
+    let x = modules::authorizer::Authorizer::new(&config).unwrap();
+    assert_eq!(util_connect(&x, 0x3340, 0x3457, 0x08), 0);
+    assert_eq!(descriptors(&x, b"AB"), 0);
+    assert!(util_configure(&x));
+
+    let x = modules::authorizer::Authorizer::new(&config).unwrap();
+    assert_eq!(util_connect(&x, 0x3340, 0x3457, 0x08), 0);
+    assert_eq!(descriptors(&x, b"CD"), deny);
+
+    // Undecided devices are not configured
+    let x = modules::authorizer::Authorizer::new(&config).unwrap();
+    assert_eq!(util_connect(&x, 0x3340, 0x3457, 0x08), 0);
+    assert!(!util_configure(&x));
+
+    // Asking waits for the approvals file
+    let approvals = env::temp_dir().join(format!("cinch-approvals-{}", std::process::id()));
+    let _ = fs::remove_file(&approvals);
+
+    config.rules = vec![];
+    config.default = Some("ask".to_string());
+    config.approvals = Some(approvals.to_str().unwrap().to_string());
+
+    let x = modules::authorizer::Authorizer::new(&config).unwrap();
+    assert_eq!(util_connect(&x, 0x3340, 0x3457, 0x08), 0);
+    assert!(!util_configure(&x));
+
+    File::create(&approvals).unwrap().write_all(b"1234:5678\n3340:3457\n").unwrap();
+    assert!(util_configure(&x));
+
+    fs::remove_file(&approvals).unwrap();
+
+    config.default = Some("maybe".to_string());
+    assert!(modules::authorizer::Authorizer::new(&config).is_err());
+}
+
+
+#[test]
+fn filter() {
+
+    use modules::filter::{DeviceFilter, PORT_REJECT};
+
+    let hello = |x: &DeviceFilter, source: parser::Source, caps: u32| {
+        let mut data = vec![0; 4];
+        data[0] = caps as u8;
+        let req = parser::Request::new(usbr::HeaderType::Hello as u32, 0, vec![0; 64], data);
+        x.handle_hello(source, req).1
+    };
+
+    let filter_cap = 1 << usbr::Caps::Filter as u32;
+
+    // Cinch's rules follow blue's hello to red, which learns that cinch takes filter packets
+    let x = DeviceFilter::new("0x08,-1,-1,-1,1|-1,-1,-1,-1,0").unwrap();
+
+    let out = hello(&x, parser::Source::Blue, 0);
+    assert_eq!(out.len(), 2);
+    assert_eq!(out[0].data[0] as u32 & filter_cap, filter_cap);
+    assert_eq!(out[1].get_type(), usbr::HeaderType::FilterFilter as u32);
+    assert_eq!(&out[1].data[..], &b"0x08,-1,-1,-1,1|-1,-1,-1,-1,0\0"[..]);
+    assert!(!out[1].reply);
+
+    // Later modules see the rules as a filter packet
+    struct Filters {
+        count: AtomicUsize,
+    }
+
+    impl parser::HasHandlers for Filters {
+        fn handle_filter_filter(&self, _: parser::Source, req: parser::Request) -> (u8, Vec<parser::Request>) {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            (0, vec![req])
+        }
+    }
+
+    let filters = Arc::new(Filters { count: AtomicUsize::new(0) });
+    let mut chain = modules::Modules::new();
+
+    chain.add_nonterminal(0, 0, Arc::new(DeviceFilter::new("-1,-1,-1,-1,1").unwrap()));
+    chain.add_nonterminal(1, 0, filters.clone());
+    chain.add_terminal(0, Arc::new(modules::null::Null::new()));
+
+    let req = parser::Request::new(usbr::HeaderType::Hello as u32, 0, vec![0; 64], vec![0; 4]);
+    assert_eq!(chain.handle_hello(parser::Source::Blue, req).1.len(), 2);
+    assert_eq!(filters.count.load(Ordering::SeqCst), 1);
+
+    // Blue's own filter stays with blue
+    let req = parser::Request::new(usbr::HeaderType::FilterFilter as u32, 0, vec![], b"-1,-1,-1,-1,1\0".to_vec());
+    assert!(x.handle_filter_filter(parser::Source::Blue, req).1.is_empty());
+
+    // Rejected devices are never connected to blue
+    assert_eq!(hello(&x, parser::Source::Red, filter_cap).len(), 1);
+    assert_eq!(util_connect(&x, 0x0781, 0x5567, 0x08), 0);
+
+    let (port, out) = {
+        let mut iface = vec![0; 132];
+        iface[0] = 1;
+        iface[36] = 0x03;
+        x.handle_interface_info(parser::Source::Red,
+                                parser::Request::new(usbr::HeaderType::InterfaceInfo as u32, 0, iface, vec![]));
+
+        let req = parser::Request::new(usbr::HeaderType::DeviceConnect as u32, 0, vec![0; 10], vec![]);
+        x.handle_connect(parser::Source::Red, req)
+    };
+    assert_eq!(port, 0);
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0].get_type(), usbr::HeaderType::FilterReject as u32);
+    assert!(out[0].reply);
+
+    // Red cannot be told without the capability
+    let x = DeviceFilter::new("0x08,-1,-1,-1,1").unwrap();
+    hello(&x, parser::Source::Red, 0);
+    assert_eq!(util_connect(&x, 0x3340, 0x3457, 0x03), PORT_REJECT);
+
+    assert!(DeviceFilter::new("0x08,-1,-1,1").is_err());
+}
+
+
+#[test]
+fn discard() {
+
+    let x = modules::discard::Discard::new();
+
+    let (_, out) = x.handle_control_packet(parser::Source::Red, util_control_request(1));
+    assert!(out.is_empty());
+
+    let connect = parser::Request::new(usbr::HeaderType::DeviceConnect as u32, 2, vec![], vec![]);
+    let (_, out) = x.handle_connect(parser::Source::Red, connect);
+    assert!(out.is_empty());
+}
+
+
+#[test]
+fn stall() {
+
+    let x = modules::stall::Stall::new();
+
+    let (_, out) = x.handle_control_packet(parser::Source::Red, util_control_response(5, vec![4, 3, 0x41, 0]));
+
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0].get_id(), 5);
+    assert_eq!(out[0].get_total_len(), 10);
+    assert_eq!(out[0].type_header[3], usbr::Result::Stall as u8);
+    assert_eq!(&out[0].type_header[8..10], &[0, 0]);
+    assert!(out[0].data.is_empty());
+    assert!(!out[0].reply);
+
+    // A request from blue is answered directly
+    let (_, out) = x.handle_control_packet(parser::Source::Blue, util_control_request(6));
+
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0].get_id(), 6);
+    assert_eq!(out[0].type_header[3], usbr::Result::Stall as u8);
+    assert!(out[0].reply);
+}
+
+
+#[test]
+fn rewrite() {
+
+    let dir = env::temp_dir().join("cinch-test-rewrite");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let mut f = File::create(dir.join("string.json")).unwrap();
+    f.write_all(b"{ \"request\": 6, \"requesttype\": 128, \"value\": 770, \"data\": \"04034100\" }")
+     .unwrap();
+
+    // Entries that are not rules are skipped
+    fs::create_dir_all(dir.join("old")).unwrap();
+    File::create(dir.join("string.json~")).unwrap().write_all(b"{").unwrap();
+
+    let x = modules::rewrite::Rewriter::new(dir.to_str().unwrap()).unwrap();
+
+    // Matching rule: payload is replaced
+    let (_, out) = x.handle_control_packet(parser::Source::Red, util_control_response(3, vec![0xff; 7]));
+
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0].data, vec![4, 3, 0x41, 0]);
+    assert_eq!(out[0].type_header[8], 4);
+    assert_eq!(out[0].get_total_len(), 14);
+
+    // No rule covers it: dropped
+    let (_, out) = x.handle_control_packet(parser::Source::Red, util_control_request(4));
+    assert!(out.is_empty());
+
+    // A broken rule is an error rather than a panic
+    File::create(dir.join("broken.json")).unwrap().write_all(b"{ \"request\": 6 }").unwrap();
+    assert!(modules::rewrite::Rewriter::new(dir.to_str().unwrap()).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+
+#[test]
+fn default_pipeline() {
+
+    let mut config = util_config();
+    config.checks_active = true;
+    config.patch_active = true;
+
+    let pipeline = modules::registry::default_pipeline(&config);
+    let names: Vec<&str> = pipeline.iter().map(|m| &m.name[..]).collect();
+
+    assert_eq!(names, vec!["checks", "patcher"]);
+    assert_eq!(pipeline[0].ports.as_ref().unwrap().len(), 4);
+    assert_eq!(pipeline[1].blue, Some(false));
+
+    assert!(modules::registry::Registry::new().validate(&config, &pipeline).is_ok());
+}
+
+
+#[test]
+fn registry_validate() {
+
+    let x = modules::registry::Registry::new();
+    let mut config = util_config();
+
+    assert!(x.validate(&config, &[util_module("checks", true, true, &[("1", "reset")])]).is_ok());
+    assert!(x.validate(&config, &[util_module("nonexistent", true, true, &[])]).is_err());
+    assert!(x.validate(&config, &[util_module("checks", true, true, &[("1", "nonexistent")])]).is_err());
+    assert!(x.validate(&config, &[util_module("checks", true, true, &[("0", "reset")])]).is_err());
+    assert!(x.validate(&config, &[util_module("checks", true, true, &[("one", "reset")])]).is_err());
+
+    // Ports that the module may use must lead somewhere
+    assert!(x.validate(&config, &[util_module("checks", true, true, &[])]).is_err());
+    assert!(x.validate(&config, &[util_module("patcher", false, true, &[])]).is_err());
+
+    let mut actions = HashMap::new();
+    actions.insert("default".to_string(), "stall".to_string());
+    config.check_actions = Some(actions.clone());
+
+    assert!(x.validate(&config, &[util_module("checks", true, true, &[("1", "reset")])]).is_err());
+    assert!(x.validate(&config, &[util_module("checks", true, true, &[("3", "stall")])]).is_ok());
+
+    // So must the configuration of the module
+    actions.insert("default".to_string(), "nonexistent".to_string());
+    config.check_actions = Some(actions);
+
+    assert!(x.validate(&config, &[util_module("checks", true, true, &[("3", "stall")])]).is_err());
+
+    // readonly answers commands that checks would otherwise expect the device to answer
+    let checks = util_module("checks", true, true, &[("1", "reset")]);
+    let readonly = util_module("readonly", true, true, &[]);
+
+    assert!(x.validate(&util_config(), &[readonly.clone(), checks.clone()]).is_ok());
+    assert!(x.validate(&util_config(), &[checks, readonly]).is_err());
+}
+
+
+#[test]
+fn registry_build() {
+
+    let mut x = modules::registry::Registry::new();
+    x.register("flagger", Box::new(|_| Ok(Arc::new(Flagger))));
+
+    // flagger only runs on red, and its port 1 leads to drop
+    let pipeline = vec![util_module("flagger", true, false, &[("1", "drop")])];
+    let (blue, red) = x.build_pipeline(&util_config(), &pipeline).unwrap();
+
+    let (_, out) = red.handle_control_packet(parser::Source::Red, util_control_request(1));
+    assert!(out.is_empty());
+
+    let (_, out) = blue.handle_control_packet(parser::Source::Blue, util_control_request(2));
+    assert_eq!(out.len(), 1);
+
+    // Other requests go through flagger's port 0
+    let connect = parser::Request::new(usbr::HeaderType::DeviceConnect as u32, 3, vec![], vec![]);
+    let (_, out) = red.handle_connect(parser::Source::Red, connect);
+    assert_eq!(out.len(), 1);
+}
+
+
+#[test]
+fn registry_shared_instances() {
+
+    let x = modules::registry::Registry::new();
+
+    // reset is the second module on red and the only one on blue
+    let pipeline = vec![util_module("null", true, false, &[]),
+                        util_module("reset", true, true, &[])];
+
+    let (blue, red) = x.build_pipeline(&util_config(), &pipeline).unwrap();
+
+    let _ = blue.handle_control_packet(parser::Source::Blue, util_control_request(1));
+
+    // Both directions see the same reset module
+    assert!(blue.session_ended());
+    assert!(red.session_ended());
+}
+
+
+#[test]
+fn modules_fan_out() {
+
+    let mut x = modules::Modules::new();
+    let counter = Arc::new(Counter { count: AtomicUsize::new(0) });
+
+    x.add_nonterminal(0, 0, Arc::new(Splitter));
+    x.add_nonterminal(1, 0, Arc::new(Splitter));
+    x.add_nonterminal(2, 0, counter.clone());
+    x.add_terminal(0, Arc::new(modules::null::Null::new()));
+
+    let (_, out) = x.handle_control_packet(parser::Source::Red, util_control_request(1));
+
+    // Each request emitted by a stage goes through the rest of the chain
+    assert_eq!(out.len(), 4);
+    assert_eq!(counter.count.load(Ordering::SeqCst), 4);
+    assert_eq!(out[0].get_id(), 1);
+    assert_eq!(out[1].get_id(), 101);
+}
+
+
+#[test]
+fn modules_emit_other_types() {
+
+    let mut x = modules::Modules::new();
+
+    x.add_nonterminal(0, 0, Arc::new(modules::reset::Reset::new()));
+    x.add_nonterminal(1, 0, Arc::new(Flagger));
+    x.add_terminal(0, Arc::new(modules::null::Null::new()));
+    x.add_terminal(1, Arc::new(modules::discard::Discard::new()));
+
+    // Reset turns the control packet into a disconnect, which flagger does not send to port 1
+    let (_, out) = x.handle_control_packet(parser::Source::Red, util_control_request(1));
+
+    assert_eq!(out.len(), 2);
+    assert!(out.iter().any(|r| !r.reply && r.get_type() == usbr::HeaderType::DeviceDisconnect as u32));
+    assert!(out.iter().any(|r| r.reply && r.get_type() == usbr::HeaderType::Reset as u32));
+}
+
+
+#[test]
+fn modules_drop() {
+
+    let mut x = modules::Modules::new();
+    let counter = Arc::new(Counter { count: AtomicUsize::new(0) });
+
+    x.add_nonterminal(0, 0, Arc::new(Flagger));
+    x.add_nonterminal(1, 0, counter.clone());
+    x.add_nonterminal(1, 1, Arc::new(modules::discard::Discard::new()));
+    x.add_nonterminal(2, 0, counter.clone());
+    x.add_terminal(0, Arc::new(modules::null::Null::new()));
+
+    // Flagger sends control packets to discard, which drops them mid-chain
+    let (_, out) = x.handle_control_packet(parser::Source::Red, util_control_request(1));
+
+    assert!(out.is_empty());
+    assert_eq!(counter.count.load(Ordering::SeqCst), 0);
+
+    // Everything else goes through both counters
+    let connect = parser::Request::new(usbr::HeaderType::DeviceConnect as u32, 2, vec![], vec![]);
+    let (_, out) = x.handle_connect(parser::Source::Red, connect);
+    assert_eq!(out.len(), 1);
+}
+
+
+#[test]
+fn modules_reply() {
+
+    let mut x = modules::Modules::new();
+    let counter = Arc::new(Counter { count: AtomicUsize::new(0) });
+
+    x.add_nonterminal(0, 0, Arc::new(Responder));
+    x.add_nonterminal(1, 0, counter.clone());
+    x.add_terminal(0, Arc::new(modules::discard::Discard::new()));
+
+    // The reply skips the rest of the chain, including the terminal
+    let (_, out) = x.handle_control_packet(parser::Source::Blue, util_control_request(1));
+
+    assert_eq!(out.len(), 1);
+    assert!(out[0].reply);
+    assert_eq!(counter.count.load(Ordering::SeqCst), 0);
+}
+
+
+#[test]
+fn rules_reload() {
+
+    let dir = env::temp_dir().join("cinch-test-rules");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let check = b"{ \"ids\": [ { \"vendor_id\": 1, \"product_id\": 2 } ], \"constraints\": [] }";
+    File::create(dir.join("a.json")).unwrap().write_all(check).unwrap();
+
+    let mut config = util_config();
+    config.third_party_folder = dir.to_str().unwrap().to_string();
+
+    let rules = modules::rules::Rules::load(&config).unwrap();
+    let before = rules.checks();
+
+    assert_eq!(before.len(), 1);
+
+    // Nothing changed
+    assert!(rules.reload().is_ok());
+    assert!(Arc::ptr_eq(&before, &rules.checks()));
+
+    // An invalid file is rejected and the previous set is kept
+    File::create(dir.join("b.json")).unwrap().write_all(b"{ \"ids\": [").unwrap();
+
+    assert!(rules.reload().is_err());
+    assert!(Arc::ptr_eq(&before, &rules.checks()));
+
+    // Once it is fixed, new sessions get the new set
+    let check = b"{ \"ids\": [ { \"vendor_id\": 3, \"product_id\": 4 } ], \"constraints\": [] }";
+    File::create(dir.join("b.json")).unwrap().write_all(check).unwrap();
+
+    assert!(rules.reload().is_ok());
+    assert_eq!(rules.checks().len(), 2);
+    assert_eq!(before.len(), 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+
+#[test]
+fn rules_rewrites() {
+
+    let dir = env::temp_dir().join("cinch-test-rules-rewrites");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let rule = b"{ \"request\": 6, \"requesttype\": 128, \"data\": \"00\" }";
+    File::create(dir.join("a.json")).unwrap().write_all(rule).unwrap();
+
+    let mut config = util_config();
+    config.rewrites = Some(dir.to_str().unwrap().to_string());
+
+    let rules = modules::rules::Rules::load(&config).unwrap();
+    assert_eq!(rules.rewrites().len(), 1);
+
+    // An invalid rule keeps the previous set, and keeps cinch from starting
+    File::create(dir.join("b.json")).unwrap().write_all(b"{ \"request\": 6 }").unwrap();
+
+    assert!(rules.reload().is_err());
+    assert_eq!(rules.rewrites().len(), 1);
+    assert!(modules::rules::Rules::load(&config).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+
+#[test]
+fn control_check_vendor() {
+
+    let dir = env::temp_dir().join("cinch-test-vendor");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    // Device 3340:3457 may read 2 to 4 bytes with request 1 (wIndex 0-3), and send request 3
+    // without data
+    let policy = b"{ \"ids\": [ { \"vendor_id\": 13120, \"product_id\": 13399 } ], \"requests\": [
+        { \"direction\": \"in\", \"request\": { \"min\": 1, \"max\": 1 }, \"index\": { \"min\": 0, \"max\": 3 },
+          \"length\": { \"min\": 2, \"max\": 4 } },
+        { \"direction\": \"out\", \"request\": { \"min\": 3, \"max\": 3 } } ] }";
+    File::create(dir.join("a.json")).unwrap().write_all(policy).unwrap();
+
+    // Subdirectories and backups are skipped
+    fs::create_dir_all(dir.join("old")).unwrap();
+    File::create(dir.join("a.json~")).unwrap().write_all(b"{").unwrap();
+
+    let vendor = Arc::new(modules::control_checks::vendor::VendorSet::load(dir.to_str().unwrap()).unwrap());
+    assert_eq!(vendor.len(), 1);
+
+    let new = || modules::control_checks::ControlCheck::new("third-party-checks", modules::policy::CheckPolicy::new());
+    let reset = modules::policy::PORT_RESET;
+
+    let control = |x: &modules::control_checks::ControlCheck, source, req| x.handle_control_packet(source, req).0;
+
+    let blue = parser::Source::Blue;
+    let red = parser::Source::Red;
+
+    // Without a policy, every vendor request fails
+    let x = new();
+    assert_eq!(util_describe(&x, &[0xff]), 0);
+    assert_eq!(control(&x, blue, util_control(3, 0x01, 0xc0, 0, 0, 4, vec![])), reset);
+
+    let x = new().with_vendor_requests(vendor);
+    assert_eq!(util_describe(&x, &[0xff]), 0);
+
+    // Request 1 with wIndex and wLength in range, and a response of 2 to 4 bytes
+    assert_eq!(control(&x, blue, util_control(3, 0x01, 0xc0, 0, 3, 4, vec![])), 0);
+    assert_eq!(control(&x, red, util_control(3, 0x01, 0xc0, 0, 3, 4, vec![1, 2, 3])), 0);
+    assert_eq!(control(&x, red, util_control(3, 0x01, 0xc0, 0, 3, 4, vec![1])), reset);
+    assert_eq!(control(&x, red, util_control(3, 0x01, 0xc0, 0, 3, 4, vec![1, 2, 3, 4, 5])), reset);
+    assert_eq!(control(&x, blue, util_control(3, 0x01, 0xc0, 0, 4, 4, vec![])), reset);
+    assert_eq!(control(&x, blue, util_control(3, 0x01, 0xc0, 0, 0, 8, vec![])), reset);
+
+    // Request 3 has no data stage, and requests that are not listed fail
+    assert_eq!(control(&x, blue, util_control(3, 0x03, 0x40, 0, 0, 0, vec![])), 0);
+    assert_eq!(control(&x, blue, util_control(3, 0x03, 0x40, 0, 0, 1, vec![0])), reset);
+    assert_eq!(control(&x, blue, util_control(3, 0x01, 0x40, 0, 0, 0, vec![])), reset);
+    assert_eq!(control(&x, blue, util_control(3, 0x02, 0xc0, 0, 0, 4, vec![])), reset);
+
+    // Policies with empty ranges or unknown directions do not load
+    File::create(dir.join("b.json")).unwrap().write_all(b"{ \"ids\": [], \"requests\": [
+        { \"direction\": \"up\", \"request\": { \"min\": 1, \"max\": 1 } } ] }").unwrap();
+    assert!(modules::control_checks::vendor::VendorSet::load(dir.to_str().unwrap()).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -187,10 +187,11 @@ fn util_host_parser_with_hello() -> parser::Parser {
         header: [0; 16],
         type_header: h_type,
         data: data,
+        reply: false,
     };
     req.header[0..h.len()].clone_from_slice(&h);
 
-    x.handle_hello(&req);
+    x.handle_hello(&req).unwrap();
     return x;
 }
 
@@ -208,11 +209,12 @@ fn util_guest_parser_with_hello() -> parser::Parser {
         header: [0; 16],
         type_header: h_type,
         data: data,
+        reply: false,
     };
     req.header[0..h.len()].clone_from_slice(&h);
 
 
-    x.handle_hello(&req);
+    x.handle_hello(&req).unwrap();
     return x;
 }
 
@@ -406,12 +408,13 @@ fn handle_hello() {
         header: [0; 16],
         type_header: h_type,
         data: data,
+        reply: false,
     };
 
     assert!(h.len() <= req.header.len());
     req.header[0..h.len()].clone_from_slice(&h);
 
-    x.handle_hello(&req);
+    x.handle_hello(&req).unwrap();
 
     assert_eq!(caps, x.peer_caps);
     assert!(x.state >= parser::ParserState::HelloR);
@@ -420,8 +423,7 @@ fn handle_hello() {
 
 
 #[test]
-#[should_panic]
-fn handle_hello_should_panic() {
+fn handle_hello_bad_version() {
 
     // parser is for host and has correct version and size 1 capabilities
     let mut x = util_host_parser();
@@ -436,19 +438,20 @@ fn handle_hello_should_panic() {
         header: [0; 16],
         type_header: h_type,
         data: data,
+        reply: false,
     };
     req.header[0..h.len()].clone_from_slice(&h);
 
-    x.handle_hello(&req);
+    assert!(x.handle_hello(&req).is_err());
+    assert!(x.state < parser::ParserState::HelloR);
 }
 
 
 #[test]
-#[should_panic]
-fn handle_hello_should_panic2() {
+fn handle_hello_bad_caps() {
 
     // parser is for host, has correct version and capabilities > CAP_SIZE
-    // parser should bitch at.
+    // parser should reject it.
     let mut x = util_host_parser();
     let caps: [u32; 1] = util_init_caps();
     let version: &[u8; 7] = b"1.4.2.5";
@@ -468,22 +471,20 @@ fn handle_hello_should_panic2() {
         header: [0; 16],
         type_header: h_type,
         data: data,
+        reply: false,
     };
 
     assert!(h.len() <= req.header.len());
 
     req.header[0..h.len()].clone_from_slice(&h);
 
-    x.handle_hello(&req);
-
-    assert_eq!(caps, x.peer_caps);
-    assert!(x.state == parser::ParserState::HelloR);
+    assert!(x.handle_hello(&req).is_err());
+    assert!(x.state < parser::ParserState::HelloR);
 }
 
 
 #[test]
-#[should_panic]
-fn handle_hello_should_panic3() {
+fn handle_hello_bad_caps2() {
 
     // Should be no different for guest parser
 
@@ -507,15 +508,14 @@ fn handle_hello_should_panic3() {
         header: [0; 16],
         type_header: h_type,
         data: data,
+        reply: false,
     };
 
     assert!(h.len() <= req.header.len());
     req.header[0..h.len()].clone_from_slice(&h);
 
-    x.handle_hello(&req);
-
-    assert_eq!(caps, x.peer_caps);
-    assert!(x.state == parser::ParserState::HelloR);
+    assert!(x.handle_hello(&req).is_err());
+    assert!(x.state < parser::ParserState::HelloR);
 }
 
 
@@ -593,19 +593,19 @@ fn pull_next_request() {
 
     let mut buf = wrap_reader!(h);
 
-    let req: parser::Request = x.pull_next_request(&mut buf);
+    let req: parser::Request = x.pull_next_request(&mut buf).unwrap();
 
     assert_eq!(req.type_header.len(), 10);
     assert_eq!(req.get_total_len(), 10);
     assert_eq!(req.get_id(), 1);
 
-    let req: parser::Request = x.pull_next_request(&mut buf);
+    let req: parser::Request = x.pull_next_request(&mut buf).unwrap();
 
     assert_eq!(req.type_header.len(), 132);
     assert_eq!(req.get_total_len(), 132);
     assert_eq!(req.get_id(), 2);
 
-    let req: parser::Request = x.pull_next_request(&mut buf);
+    let req: parser::Request = x.pull_next_request(&mut buf).unwrap();
 
     assert_eq!(req.type_header.len(), 132);
     assert_eq!(req.get_total_len(), 132);
@@ -646,13 +646,13 @@ fn pull_next_request_2() {
 
     let mut buf = wrap_reader!(h);
 
-    let req: parser::Request = x.pull_next_request(&mut buf);
+    let req: parser::Request = x.pull_next_request(&mut buf).unwrap();
 
     assert_eq!(req.type_header.len(), 10);
     assert_eq!(req.get_total_len(), 10);
     assert_eq!(req.get_id(), 1);
 
-    let req: parser::Request = x.pull_next_request(&mut buf);
+    let req: parser::Request = x.pull_next_request(&mut buf).unwrap();
 
     assert_eq!(req.type_header.len(), 132);
     assert_eq!(req.get_total_len(), 132);
@@ -661,8 +661,7 @@ fn pull_next_request_2() {
 
 
 #[test]
-#[should_panic]
-fn pull_next_request_should_panic() {
+fn pull_next_request_eof() {
 
     let _ = env_logger::init();
     let mut x = util_host_parser_with_hello();
@@ -678,14 +677,16 @@ fn pull_next_request_should_panic() {
     let mut buf = wrap_reader!(h);
 
     // This should trigger an error in get_type_header_len(). Since we don't have
-    // any other packets, there is no next request, so it will panic.
-    let _ = x.pull_next_request(&mut buf);
+    // any other packets, there is no next request, so we reach the end of the stream.
+    match x.pull_next_request(&mut buf) {
+        Err(parser::ParseError::Eof) => {}
+        _ => panic!("expected end of stream"),
+    }
 }
 
 
 #[test]
-#[should_panic]
-fn pull_next_request_should_panic_2() {
+fn pull_next_request_eof_2() {
 
     let _ = env_logger::init();
     let x = util_guest_parser_with_hello();
@@ -699,14 +700,16 @@ fn pull_next_request_should_panic_2() {
     let mut buf = wrap_reader!(h);
 
     // This should trigger an error in get_type_header_len(). Since we don't have
-    // any other packets, there is no next request, so it will panic.
-    let _ = x.pull_next_request(&mut buf);
+    // any other packets, there is no next request, so we reach the end of the stream.
+    match x.pull_next_request(&mut buf) {
+        Err(parser::ParseError::Eof) => {}
+        _ => panic!("expected end of stream"),
+    }
 }
 
 
 #[test]
-#[should_panic]
-fn pull_next_request_should_panic_3() {
+fn pull_next_request_eof_3() {
 
     let _ = env_logger::init();
     let mut x = util_guest_parser_with_hello();
@@ -725,7 +728,7 @@ fn pull_next_request_should_panic_3() {
 
     let mut buf = wrap_reader!(h);
 
-    let req: parser::Request = x.pull_next_request(&mut buf);
+    let req: parser::Request = x.pull_next_request(&mut buf).unwrap();
 
     assert_eq!(req.type_header.len(), 10);
     assert_eq!(req.get_total_len(), 10);
@@ -735,11 +738,133 @@ fn pull_next_request_should_panic_3() {
     // roll back state into an illegal state
     x.state = parser::ParserState::HelloR;
 
-    // Below should panic because state of parser should not be correct.
-    let _: parser::Request = x.pull_next_request(&mut buf);
+    // Below should fail because state of parser should not be correct.
+    match x.pull_next_request(&mut buf) {
+        Err(parser::ParseError::Eof) => {}
+        _ => panic!("expected end of stream"),
+    }
+}
+
+
+#[test]
+fn pull_next_request_truncated() {
+
+    let _ = env_logger::init();
+    let mut x = util_guest_parser_with_hello();
+
+    // pretend guest has already received EpInfo and IfaceInfo
+    x.state = parser::ParserState::Informed;
+
+    // interface info request that is cut off halfway through its type header
+    let mut h = redir_header!(InterfaceInfo, InterfaceInfoHeader, 1);
+    h.extend(&util_interface_header(32)[..50]);
+
+    let mut buf = wrap_reader!(h);
+
+    match x.pull_next_request(&mut buf) {
+        Err(parser::ParseError::Eof) => {}
+        _ => panic!("expected end of stream"),
+    }
+}
+
+
+#[test]
+fn pull_next_request_oversized() {
+
+    let _ = env_logger::init();
+    let mut x = util_guest_parser_with_hello();
+
+    // pretend guest has already received EpInfo and IfaceInfo
+    x.state = parser::ParserState::Informed;
+
+    // bulk packet header claiming a length far beyond anything usbr sends
+    let h = util_redir_header(usbr::HeaderType::BulkPacket as u32, 0xffff_ffff, 1);
+
+    let mut buf = wrap_reader!(h);
+
+    match x.pull_next_request(&mut buf) {
+        Err(parser::ParseError::Oversized(len)) => assert_eq!(len, 0xffff_ffff),
+        _ => panic!("expected oversized frame"),
+    }
+}
+
+
+
+#[test]
+fn pull_next_request_filter() {
+
+    let _ = env_logger::init();
+
+    let filter = |h: &mut Vec<u8>, id: u64, rules: &[u8]| {
+        h.extend(&util_redir_header(usbr::HeaderType::FilterFilter as u32, rules.len() as u32, id));
+        h.extend(rules);
+    };
+
+    let mut h = vec![];
+    filter(&mut h, 1, b"0x08,-1,-1,-1,1|-1,-1,-1,-1,0\0");
+    filter(&mut h, 2, b"0x08,-1,-1,-1,1"); // not 0 terminated
+    filter(&mut h, 3, b"0x08,-1,-1,1\0"); // missing a field
+    filter(&mut h, 4, b"3,0x1234,-1,-1,0\0");
+
+    // Only parsers with the filter capability take filter packets
+    let mut x = util_guest_parser_with_hello();
+    x.state = parser::ParserState::Hello;
+    parser::set_cap(&mut x.our_caps, usbr::Caps::Filter as usize);
+
+    let mut buf = wrap_reader!(h.clone());
+
+    let req: parser::Request = x.pull_next_request(&mut buf).unwrap();
+    assert_eq!(req.get_id(), 1);
+    assert!(req.type_header.is_empty());
+
+    let req: parser::Request = x.pull_next_request(&mut buf).unwrap();
+    assert_eq!(req.get_id(), 4);
+
+    let mut x = util_guest_parser_with_hello();
+    x.state = parser::ParserState::Hello;
+
+    let mut buf = wrap_reader!(h);
+
+    match x.pull_next_request(&mut buf) {
+        Err(parser::ParseError::Eof) => {}
+        _ => panic!("expected end of stream"),
+    }
 }
 
 
+#[test]
+fn filter_rules() {
+
+    use cinch::parser::filter;
+
+    let rules = filter::parse("0x08,-1,-1,-1,1|3,0x046d,-1,-1,1|-1,-1,-1,-1,0").unwrap();
+
+    assert_eq!(rules.len(), 3);
+    assert_eq!(rules[0].class, Some(8));
+    assert_eq!(rules[1].vendor_id, Some(0x046d));
+    assert_eq!(rules[2].product_id, None);
+    assert!(!rules[2].allow);
+
+    assert_eq!(filter::parse(&filter::to_string(&rules)).unwrap(), rules);
+    assert_eq!(filter::parse_packet(&filter::to_packet(&rules)).unwrap(), rules);
+
+    // A flash drive, a keyboard by another vendor, and a flash drive with a keyboard
+    assert!(filter::check(&rules, 0, &[(8, 6, 0x50)], 0x0781, 0x5567, 0x0100));
+    assert!(!filter::check(&rules, 0, &[(3, 1, 1)], 0x3340, 0x3457, 0x0100));
+    assert!(filter::check(&rules, 0, &[(3, 1, 1)], 0x046d, 0xc31c, 0x0100));
+    assert!(!filter::check(&rules, 0, &[(8, 6, 0x50), (3, 1, 1)], 0x0781, 0x5567, 0x0100));
+
+    // The device class counts unless the device defers to its interfaces
+    assert!(!filter::check(&rules, 9, &[(8, 6, 0x50)], 0x0781, 0x5567, 0x0100));
+
+    // Non-boot HID interfaces of composite devices are skipped
+    assert!(filter::check(&rules, 0xef, &[(8, 6, 0x50), (3, 0, 0)], 0x0781, 0x5567, 0x0100));
+
+    for invalid in &["", "8,-1,-1,-1", "8,-1,-1,-1,2", "256,-1,-1,-1,1", "8,-2,-1,-1,1", "8,x,-1,-1,1"] {
+        assert!(filter::parse(invalid).is_err());
+    }
+}
+
 
 #[test]
 fn process_request() {
@@ -767,8 +892,8 @@ fn process_request() {
 
     let mut buf = wrap_reader!(h);
 
-    let req: parser::Request = x.pull_next_request(&mut buf);
-    let _: Vec<parser::Request> = x.process_request(&handler, req, &tx);
+    let req: parser::Request = x.pull_next_request(&mut buf).unwrap();
+    let _: Vec<parser::Request> = x.process_request(&handler, req, &tx).unwrap();
 
     assert_eq!(rx.try_recv().err(), Some(mpsc::TryRecvError::Empty));
     assert_eq!(x.state, parser::ParserState::HelloR);
@@ -808,24 +933,87 @@ fn state_promotion() {
 
     let mut buf = wrap_reader!(h);
 
-    let req: parser::Request = x.pull_next_request(&mut buf);
-    let _ = x.process_request(&handler, req, &tx);
+    let req: parser::Request = x.pull_next_request(&mut buf).unwrap();
+    x.process_request(&handler, req, &tx).unwrap();
 
     assert_eq!(rx.try_recv().err(), Some(mpsc::TryRecvError::Empty));
     assert_eq!(x.state, parser::ParserState::HelloR);
 
     x.process_state_change(parser::ParserState::Hello);
 
-    let req: parser::Request = x.pull_next_request(&mut buf);
-    let _ = x.process_request(&handler, req, &tx);
+    let req: parser::Request = x.pull_next_request(&mut buf).unwrap();
+    x.process_request(&handler, req, &tx).unwrap();
 
     assert_eq!(x.state, parser::ParserState::EpReceived);
     assert_eq!(rx.try_recv().unwrap(), parser::ParserState::EpReceived);
 
 
-    let req: parser::Request = x.pull_next_request(&mut buf);
-    let _ = x.process_request(&handler, req, &tx);
+    let req: parser::Request = x.pull_next_request(&mut buf).unwrap();
+    x.process_request(&handler, req, &tx).unwrap();
 
     assert_eq!(x.state, parser::ParserState::Informed);
     assert_eq!(rx.try_recv().unwrap(), parser::ParserState::IfaceReceived);
 }
+
+
+#[test]
+fn disconnect_reconnect() {
+
+    let _ = env_logger::init();
+    let mut x = util_guest_parser_with_hello();
+    let handler: FakeHandler = FakeHandler { id: 0 };
+    let (tx, rx) = mpsc::channel();
+
+    x.state = parser::ParserState::Hello;
+
+    let mut h = vec![];
+
+    for i in 0..2 {
+        h.extend(&redir_header!(EpInfo, EpInfoHeader, 10 * i + 1));
+        h.extend(&util_ep_header());
+        h.extend(&redir_header!(InterfaceInfo, InterfaceInfoHeader, 10 * i + 2));
+        h.extend(&util_interface_header(1));
+        h.extend(&redir_header!(DeviceConnect, ConnectHeader, 10 * i + 3));
+        h.extend(&util_connect_header());
+        h.extend(&util_redir_header(usbr::HeaderType::DeviceDisconnect as u32, 0, 10 * i + 4));
+    }
+
+    let mut buf = wrap_reader!(h);
+    let mut next = |x: &mut parser::Parser| {
+        let req: parser::Request = x.pull_next_request(&mut buf).unwrap();
+        x.process_request(&handler, req, &tx).unwrap();
+    };
+
+    for _ in 0..2 {
+
+        next(&mut x);
+        next(&mut x);
+        assert_eq!(x.state, parser::ParserState::Informed);
+
+        next(&mut x);
+        assert_eq!(x.state, parser::ParserState::Connected);
+
+        // The next device has to send its interfaces and endpoints again
+        next(&mut x);
+        assert_eq!(x.state, parser::ParserState::Disconnected);
+    }
+
+    let states: Vec<parser::ParserState> = rx.try_iter().collect();
+    assert_eq!(states.iter().filter(|s| **s == parser::ParserState::Disconnected).count(), 2);
+
+    // Data packets need a connected device, acks do not
+    let mut y = util_host_parser_with_hello();
+    y.state = parser::ParserState::Disconnected;
+    parser::set_cap(&mut y.peer_caps, usbr::Caps::DeviceDisconnectAck as usize);
+
+    let mut h = redir_header!(ControlPacket, ControlPacketHeader, 1);
+    h.extend(&[0x80, 0x06, 0x80, 0, 0x00, 0x01, 0, 0, 18, 0]);
+    h.extend(&util_redir_header(usbr::HeaderType::DeviceDisconnectAck as u32, 0, 2));
+
+    let mut buf = wrap_reader!(h);
+    let req: parser::Request = y.pull_next_request(&mut buf).unwrap();
+    assert_eq!(req.get_id(), 2);
+
+    assert!(!y.process_state_change(parser::ParserState::Disconnected));
+    assert!(y.process_state_change(parser::ParserState::IfaceReceived));
+}